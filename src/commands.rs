@@ -1,6 +1,12 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use crate::dovi::{
+    batch::BatchAction,
+    rpu_injector::{FrameCountMismatch, HeaderNals},
+    RpuPacking,
+};
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "dovi_tool", about = "Stuff about Dolby Vision")]
 pub enum Command {
@@ -27,6 +33,13 @@ pub enum Command {
 
         #[structopt(long, help = "EL output file location", parse(from_os_str))]
         el_out: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "Also writes the SEI NALs of the BL to this file",
+            parse(from_os_str)
+        )]
+        sei_out: Option<PathBuf>,
     },
 
     ExtractRpu {
@@ -49,6 +62,30 @@ pub enum Command {
 
         #[structopt(long, help = "RPU output file location", parse(from_os_str))]
         rpu_out: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            default_value = "annexb",
+            possible_values = &["annexb", "x265", "nal", "standalone", "raw"],
+            help = "Form of the written RPUs. --help for more info",
+            long_help = "Form of the written RPUs.\nannexb, or x265: start code and payload, without the 0x7C01 NAL header, as expected by x265\nnal, or standalone: start code and complete NAL, with the 0x7C01 NAL header, as expected by most other tools\nraw: payload only, without delimiter, for a single RPU"
+        )]
+        packing: RpuPacking,
+
+        #[structopt(
+            short = "f",
+            long,
+            help = "Only extracts the RPU of this frame, in decoding order, stopping once found"
+        )]
+        frame: Option<usize>,
+
+        #[cfg(feature = "async")]
+        #[structopt(
+            long,
+            conflicts_with = "frame",
+            help = "Writes the RPUs as they are read, in decoding order and without edits. Raw HEVC input only"
+        )]
+        stream: bool,
     },
 
     Editor {
@@ -109,6 +146,36 @@ pub enum Command {
         discard: bool,
     },
 
+    Batch {
+        #[structopt(
+            name = "inputs",
+            required = true,
+            help = "Sets the input files to process",
+            parse(from_os_str)
+        )]
+        inputs: Vec<PathBuf>,
+
+        #[structopt(
+            short = "a",
+            long,
+            default_value = "extract-rpu",
+            possible_values = &["demux", "convert", "extract-rpu"],
+            help = "Processing applied to every input"
+        )]
+        action: BatchAction,
+
+        #[structopt(
+            short = "o",
+            long,
+            help = "Output directory, defaults to the directory of each input. Outputs are named after the input",
+            parse(from_os_str)
+        )]
+        output_dir: Option<PathBuf>,
+
+        #[structopt(short = "d", long, help = "Discard the EL stream, when converting")]
+        discard: bool,
+    },
+
     InjectRpu {
         #[structopt(
             name = "input",
@@ -129,6 +196,44 @@ pub enum Command {
 
         #[structopt(long, help = "Output HEVC file location", parse(from_os_str))]
         output: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            default_value = "preserve",
+            possible_values = &["preserve", "regenerate"],
+            help = "AUD and parameter set NALs: copied from the input, or an AUD generated per frame and the parameter sets repeated before IRAP frames"
+        )]
+        headers: HeaderNals,
+
+        #[structopt(
+            long,
+            default_value = "error",
+            possible_values = &["error", "truncate"],
+            help = "Input and RPU file with different frame counts: error, or inject the frames having both"
+        )]
+        mismatch: FrameCountMismatch,
+    },
+
+    InjectMp4 {
+        #[structopt(
+            name = "input",
+            short = "i",
+            long,
+            help = "Sets the input MP4 file to use",
+            parse(from_os_str)
+        )]
+        input: PathBuf,
+
+        #[structopt(
+            name = "rpu_in",
+            long,
+            help = "Sets the input RPU file to use",
+            parse(from_os_str)
+        )]
+        rpu_in: PathBuf,
+
+        #[structopt(long, help = "Output MP4 file location", parse(from_os_str))]
+        output: Option<PathBuf>,
     },
 
     Info {
@@ -148,5 +253,178 @@ pub enum Command {
             help = "Frame number to show info for"
         )]
         frame: Option<usize>,
+
+        #[structopt(
+            long,
+            help = "Prints the L2 trims interpolated for a target_max_pq value, for the selected frame"
+        )]
+        interpolate_l2: Option<u16>,
+
+        #[structopt(
+            long,
+            help = "Prints the pivots and mapping method of each component, for the selected frame"
+        )]
+        mapping: bool,
+
+        #[structopt(
+            long,
+            help = "Prints coefficient_log2_denom and the real mapping coefficients, for the selected frame"
+        )]
+        coefficients: bool,
+
+        #[structopt(
+            long,
+            requires = "frame",
+            help = "Prints the mapping curves sampled at this many points as CSV, for the selected frame"
+        )]
+        lut: Option<usize>,
+
+        #[structopt(
+            long,
+            help = "Lists the distinct L2 target_max_pq values, with the number of frames trimmed for each"
+        )]
+        l2_targets: bool,
+
+        #[structopt(long, help = "Lists the runs of consecutive identical RPUs")]
+        duplicates: bool,
+
+        #[structopt(
+            long,
+            help = "Summarizes the polynomial and MMR mapped frames and the pivot counts of each component, for the whole stream"
+        )]
+        mapping_summary: bool,
+
+        #[structopt(
+            long,
+            help = "Compares the bits parsed to the CRC32 position, for the selected frame or listing the RPUs with more than the alignment padding left"
+        )]
+        bit_size: bool,
+
+        #[structopt(
+            long,
+            parse(try_from_str = crate::parse_resolution),
+            help = "Prints the displayed aspect ratio of the L5 active area for a WIDTHxHEIGHT frame, for the selected frame or as a list of ranges"
+        )]
+        aspect_ratio: Option<(u16, u16)>,
+
+        #[structopt(
+            long,
+            require_delimiter = true,
+            possible_values = &["1", "2", "3", "4", "5", "6", "9", "10"],
+            help = "Lists the frames whose DM metadata lacks one of these ext block levels, comma separated"
+        )]
+        missing_blocks: Vec<u8>,
+
+        #[structopt(
+            long,
+            help = "Lists the RPUs lacking the 0x80 terminator byte, checked before parsing"
+        )]
+        terminators: bool,
+    },
+
+    Export {
+        #[structopt(
+            name = "input",
+            short = "i",
+            long,
+            help = "Sets the input RPU file to use",
+            parse(from_os_str)
+        )]
+        input: PathBuf,
+
+        #[structopt(
+            short = "o",
+            long,
+            help = "JSON or MessagePack RPU list, measurements or statistics output file location",
+            parse(from_os_str)
+        )]
+        output: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "Adds the HH:MM:SS:FF timecode of every frame, for the frame rate (e.g. 23.976)"
+        )]
+        fps: Option<f64>,
+
+        #[structopt(
+            long,
+            requires = "fps",
+            help = "Uses drop frame timecodes, for 29.97 and 59.94 fps"
+        )]
+        drop_frame: bool,
+
+        #[structopt(
+            long,
+            conflicts_with = "fps",
+            help = "Exports the L1 peak and average luminance of every frame as text, instead of the JSON RPU list"
+        )]
+        measurements: bool,
+
+        #[structopt(
+            long,
+            conflicts_with_all = &["fps", "measurements"],
+            help = "Exports the RPU list as MessagePack instead of JSON, for compact storage"
+        )]
+        msgpack: bool,
+
+        #[structopt(
+            long,
+            conflicts_with_all = &["fps", "measurements", "msgpack"],
+            help = "Exports the metadata only where it changes, with the range of frames it applies to"
+        )]
+        changes_only: bool,
+
+        #[structopt(
+            long,
+            conflicts_with_all = &["fps", "measurements", "msgpack", "changes-only"],
+            help = "Exports aggregate statistics of the stream as JSON: profiles, scene cuts, luminance, L2 targets, block levels, MaxCLL and MaxFALL"
+        )]
+        stats: bool,
+    },
+
+    Import {
+        #[structopt(
+            name = "input",
+            short = "i",
+            long,
+            help = "Sets the input JSON or MessagePack RPU list file to use",
+            parse(from_os_str)
+        )]
+        input: PathBuf,
+
+        #[structopt(long, help = "RPU output file location", parse(from_os_str))]
+        rpu_out: Option<PathBuf>,
+    },
+
+    Concat {
+        #[structopt(
+            name = "inputs",
+            required = true,
+            help = "Sets the RPU files to join, in order",
+            parse(from_os_str)
+        )]
+        inputs: Vec<PathBuf>,
+
+        #[structopt(long, help = "RPU output file location", parse(from_os_str))]
+        rpu_out: Option<PathBuf>,
+    },
+
+    Merge {
+        #[structopt(
+            long,
+            help = "Sets the RPU file the header, mapping and NLQ data are taken from",
+            parse(from_os_str)
+        )]
+        mapping: PathBuf,
+
+        #[structopt(
+            long,
+            help = "Sets the RPU file the DM metadata is taken from",
+            parse(from_os_str)
+        )]
+        dm: PathBuf,
+
+        #[structopt(long, help = "RPU output file location", parse(from_os_str))]
+        rpu_out: Option<PathBuf>,
     },
 }
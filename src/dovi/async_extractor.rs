@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use hevc_parser::hevc::NAL_UNSPEC62;
+use hevc_parser::HevcParser;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use super::rpu::{parse_dovi_rpu, DoviRpu};
+use super::split_nals;
+
+/// Extracts the RPUs of an HEVC stream read asynchronously.
+///
+/// Yields the frame index and the parsed RPU, in decoding order.
+/// The NALs are split like the synchronous extractor does, only the reads are async.
+pub struct AsyncRpuExtractor<R> {
+    reader: R,
+    lenient: bool,
+
+    parser: HevcParser,
+    offsets: Vec<usize>,
+    read_buf: Vec<u8>,
+    /// Data read, starting with the NAL left from the previous reads
+    chunk: Vec<u8>,
+
+    rpus: VecDeque<(usize, Result<DoviRpu, String>)>,
+    rpu_count: usize,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRpuExtractor<R> {
+    pub fn new(reader: R, lenient: bool) -> Self {
+        Self {
+            reader,
+            lenient,
+            parser: HevcParser::default(),
+            offsets: Vec::with_capacity(2048),
+            read_buf: vec![0; 100_000],
+            chunk: Vec::new(),
+            rpus: VecDeque::new(),
+            rpu_count: 0,
+            eof: false,
+        }
+    }
+
+    /// Parses the complete NALs of the chunk, the last one is kept unless at the end of the input
+    fn split_chunk(&mut self) {
+        self.parser.get_offsets(&self.chunk, &mut self.offsets);
+
+        if self.offsets.is_empty() {
+            return;
+        }
+
+        let last = if self.eof {
+            *self.offsets.last().unwrap()
+        } else {
+            self.offsets.pop().unwrap()
+        };
+
+        let nals = split_nals(&mut self.parser, &self.chunk, &self.offsets, last, false);
+
+        for nal in nals.iter().filter(|nal| nal.nal_type == NAL_UNSPEC62) {
+            let rpu = parse_dovi_rpu(&self.chunk[nal.start..nal.end], self.lenient);
+
+            self.rpus.push_back((self.rpu_count, rpu));
+            self.rpu_count += 1;
+        }
+
+        self.chunk.drain(..last);
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for AsyncRpuExtractor<R> {
+    type Item = io::Result<(usize, Result<DoviRpu, String>)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(rpu) = this.rpus.pop_front() {
+                return Poll::Ready(Some(Ok(rpu)));
+            }
+
+            if this.eof {
+                return Poll::Ready(None);
+            }
+
+            let mut buf = ReadBuf::new(&mut this.read_buf);
+
+            match Pin::new(&mut this.reader).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) => {
+                    let read = buf.filled().len();
+
+                    if read == 0 {
+                        this.eof = true;
+                    } else {
+                        this.chunk.extend_from_slice(&this.read_buf[..read]);
+                    }
+
+                    this.split_chunk();
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
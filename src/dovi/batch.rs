@@ -0,0 +1,168 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ansi_term::Colour::{Green, Red, Yellow};
+
+use super::io::{DoviReader, DoviWriter, ProcessingSummary};
+use super::{input_format, Format, RpuOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchAction {
+    Demux,
+    Convert,
+    ExtractRpu,
+}
+
+/// Outcome of an input of the batch
+pub type BatchResult = (PathBuf, Result<ProcessingSummary, String>);
+
+/// Processes several inputs with the same action and options.
+/// A failed input is reported, without stopping the batch.
+pub struct Batch {
+    action: BatchAction,
+    output_dir: Option<PathBuf>,
+    options: RpuOptions,
+}
+
+impl Batch {
+    pub fn process(
+        inputs: Vec<PathBuf>,
+        action: BatchAction,
+        output_dir: Option<PathBuf>,
+        options: RpuOptions,
+    ) -> Vec<BatchResult> {
+        let batch = Batch {
+            action,
+            output_dir,
+            options,
+        };
+
+        if let Some(ref output_dir) = batch.output_dir {
+            if let Err(e) = std::fs::create_dir_all(output_dir) {
+                println!(
+                    "{}",
+                    Red.paint(format!("Can't create the output directory: {}", e))
+                );
+                return Vec::new();
+            }
+        }
+
+        let results: Vec<BatchResult> = inputs
+            .into_iter()
+            .enumerate()
+            .map(|(index, input)| {
+                println!("[{}] {}", index + 1, input.display());
+
+                // The parsers panic on some malformed streams, only that input fails
+                let result = panic::catch_unwind(AssertUnwindSafe(|| batch.process_file(&input)))
+                    .unwrap_or_else(|e| Err(panic_message(e)));
+
+                if let Err(ref e) = result {
+                    println!("{}", Red.paint(format!("Failed: {}", e)));
+                }
+
+                (input, result)
+            })
+            .collect();
+
+        print_report(&results);
+
+        results
+    }
+
+    fn process_file(&self, input: &Path) -> Result<ProcessingSummary, String> {
+        let format = match input_format(input) {
+            Ok(Format::Matroska) => return Err("Matroska input is unsupported".to_string()),
+            Ok(Format::RawStdin) => return Err("stdin input is unsupported".to_string()),
+            Ok(format) => format,
+            Err(msg) => return Err(msg.to_string()),
+        };
+
+        let output = |suffix| Some(self.output_path(input, suffix));
+        let (bl_out, el_out, rpu_out, single_layer_out) = match self.action {
+            BatchAction::Demux => (output("BL.hevc"), output("EL.hevc"), None, None),
+            BatchAction::Convert => (None, None, None, output("BL_EL.hevc")),
+            BatchAction::ExtractRpu => (None, None, output("RPU.bin"), None),
+        };
+
+        let mut dovi_writer = DoviWriter::create(
+            bl_out.as_deref(),
+            el_out.as_deref(),
+            rpu_out.as_deref(),
+            single_layer_out.as_deref(),
+        )
+        .map_err(|e| format!("Can't create file: {}", e))?;
+
+        let pb = super::initialize_progress_bar(&format, input);
+        let mut dovi_reader = DoviReader::new(self.options.clone());
+
+        dovi_reader
+            .read_write_from_io(&format, input, Some(&pb), &mut dovi_writer)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Output of an input, named after it: `{stem}_{suffix}`
+    fn output_path(&self, input: &Path, suffix: &str) -> PathBuf {
+        let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+        let dir = match self.output_dir {
+            Some(ref output_dir) => output_dir.as_path(),
+            None => input.parent().unwrap_or_else(|| Path::new("")),
+        };
+
+        dir.join(format!("{}_{}", stem, suffix))
+    }
+}
+
+fn print_report(results: &[BatchResult]) {
+    println!("\nBatch summary:");
+
+    let mut dovi_count = 0;
+    let mut failed_count = 0;
+
+    for (input, result) in results {
+        let status = match result {
+            Ok(summary) if summary.frames > 0 => {
+                dovi_count += 1;
+                Green.paint(format!("Dolby Vision, {}", summary))
+            }
+            Ok(_) => Yellow.paint("no Dolby Vision RPU found".to_string()),
+            Err(e) => {
+                failed_count += 1;
+                Red.paint(format!("failed: {}", e))
+            }
+        };
+
+        println!("  {}: {}", input.display(), status);
+    }
+
+    println!(
+        "{} files, {} with Dolby Vision, {} failed",
+        results.len(),
+        dovi_count,
+        failed_count
+    );
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown error".to_string(),
+        },
+    }
+}
+
+impl FromStr for BatchAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "demux" => Ok(BatchAction::Demux),
+            "convert" => Ok(BatchAction::Convert),
+            "extract-rpu" => Ok(BatchAction::ExtractRpu),
+            _ => Err(format!("Unknown batch action: {}", action)),
+        }
+    }
+}
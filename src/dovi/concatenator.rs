@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+
+use super::rpu::{rpu_stream::IdRenumbering, DoviRpu};
+use super::{parse_rpu_file, print_warnings, write_rpu_file};
+
+/// Joins the RPU files of several parts, e.g. episodes or reels, into one RPU file
+pub struct Concatenator;
+
+impl Concatenator {
+    pub fn concat(
+        inputs: Vec<PathBuf>,
+        rpu_out: Option<PathBuf>,
+        renumber_ids: Option<u64>,
+        lenient: bool,
+    ) {
+        let rpu_out = match rpu_out {
+            Some(path) => path,
+            None => PathBuf::from("RPU_concat.bin"),
+        };
+
+        match concat_rpus(&inputs, &rpu_out, renumber_ids, lenient) {
+            Ok(count) => println!("{} RPUs written", count),
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+/// Writes the RPUs of the inputs one after the other, returning the number of RPUs written.
+/// When renumbering, every input gets new metadata ids, continuing from the previous input's.
+pub fn concat_rpus(
+    inputs: &[PathBuf],
+    rpu_out: &Path,
+    renumber_ids: Option<u64>,
+    lenient: bool,
+) -> Result<usize, String> {
+    let mut id_renumbering = renumber_ids.map(IdRenumbering::new).transpose()?;
+
+    let mut rpus: Vec<DoviRpu> = Vec::new();
+    let mut profiles: Vec<(&PathBuf, u8)> = Vec::new();
+
+    for input in inputs {
+        let mut input_rpus = parse_rpu_file(input, lenient)
+            .ok_or_else(|| format!("{}: no RPU found", input.display()))?;
+
+        profiles.push((input, input_rpus[0].dovi_profile));
+
+        if let Some(ref mut id_renumbering) = id_renumbering {
+            id_renumbering.next_stream();
+            input_rpus
+                .iter_mut()
+                .for_each(|rpu| id_renumbering.renumber(rpu));
+        }
+
+        rpus.append(&mut input_rpus);
+    }
+
+    if let Some((first_input, first_profile)) = profiles.first() {
+        let warnings: Vec<String> = profiles
+            .iter()
+            .filter(|(_, profile)| profile != first_profile)
+            .map(|(input, profile)| {
+                format!(
+                    "{} is profile {}, {} is profile {}",
+                    input.display(),
+                    profile,
+                    first_input.display(),
+                    first_profile
+                )
+            })
+            .collect();
+
+        print_warnings(warnings.iter());
+    }
+
+    write_rpu_file(rpu_out, &mut rpus).map_err(|e| e.to_string())?;
+
+    Ok(rpus.len())
+}
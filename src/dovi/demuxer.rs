@@ -11,15 +11,23 @@ pub struct Demuxer {
     input: PathBuf,
     bl_out: PathBuf,
     el_out: PathBuf,
+    sei_out: Option<PathBuf>,
 }
 
 impl Demuxer {
-    pub fn new(format: Format, input: PathBuf, bl_out: PathBuf, el_out: PathBuf) -> Self {
+    pub fn new(
+        format: Format,
+        input: PathBuf,
+        bl_out: PathBuf,
+        el_out: PathBuf,
+        sei_out: Option<PathBuf>,
+    ) -> Self {
         Self {
             format,
             input,
             bl_out,
             el_out,
+            sei_out,
         }
     }
 
@@ -28,6 +36,7 @@ impl Demuxer {
         stdin: Option<PathBuf>,
         bl_out: Option<PathBuf>,
         el_out: Option<PathBuf>,
+        sei_out: Option<PathBuf>,
         options: RpuOptions,
     ) {
         let input = match input {
@@ -50,7 +59,7 @@ impl Demuxer {
                     None => PathBuf::from("EL.hevc"),
                 };
 
-                let demuxer = Demuxer::new(format, input, bl_out, el_out);
+                let demuxer = Demuxer::new(format, input, bl_out, el_out, sei_out);
                 demuxer.process_input(options);
             }
             Err(msg) => println!("{}", msg),
@@ -70,6 +79,10 @@ impl Demuxer {
         let mut dovi_reader = DoviReader::new(options);
         let mut dovi_writer = DoviWriter::new(Some(&self.bl_out), Some(&self.el_out), None, None);
 
+        if let Some(ref sei_out) = self.sei_out {
+            dovi_writer.set_sei_out(sei_out).expect("Can't create file");
+        }
+
         match dovi_reader.read_write_from_io(&self.format, &self.input, pb, &mut dovi_writer) {
             Ok(_) => (),
             Err(e) => panic!("{}", e),
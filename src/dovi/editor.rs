@@ -1,8 +1,20 @@
 use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::{collections::HashMap, path::PathBuf};
-
-use super::{parse_rpu_file, rpu::vdr_dm_data::ExtMetadataBlockLevel5, write_rpu_file, DoviRpu};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+};
+
+use super::{
+    parse_rpu_file, print_warnings,
+    rpu::{
+        dedup_mappings, minify_rpus,
+        rpu_stream::RpuStream,
+        snap_scene_cuts,
+        vdr_dm_data::{ExtBlockLevel, ExtMetadataBlockLevel5},
+    },
+    write_rpu_file, DoviRpu,
+};
 
 pub struct Editor {
     input: PathBuf,
@@ -22,6 +34,66 @@ pub struct EditConfig {
 
     #[serde(default)]
     p5_to_p81: bool,
+
+    /// Profile 8.4 (HLG) to 8.1 (PQ)
+    #[serde(default)]
+    p84_to_p81: bool,
+
+    #[serde(default)]
+    dedup: bool,
+
+    #[serde(default)]
+    minimal_use_prev: bool,
+
+    /// Smallest RPUs decoding to the same metadata
+    #[serde(default)]
+    minify: bool,
+
+    /// Levels of the DM ext blocks to remove
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    remove_ext_blocks: Vec<ExtBlockLevel>,
+
+    /// L2 blocks relabeled to another target display, keeping their trims
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    retarget_l2: Vec<L2Retarget>,
+
+    /// Scene cuts moved onto the nearby boundaries of a shot list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snap_scene_cuts: Option<SceneCutSnap>,
+
+    /// L6 block inserted in the frames lacking one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    insert_l6: Option<L6Insertion>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct L2Retarget {
+    /// target_max_pq of the L2 block to change
+    from: u16,
+    /// New target_max_pq
+    to: u16,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct SceneCutSnap {
+    /// First frames of the shots
+    shots: Vec<usize>,
+    /// Largest distance a scene cut is moved by, in frames
+    #[serde(default = "SceneCutSnap::default_tolerance")]
+    tolerance: usize,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct L6Insertion {
+    /// MaxCLL in nits, computed from the L1 metadata when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_cll: Option<u16>,
+    /// MaxFALL in nits, computed from the L1 metadata when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_fall: Option<u16>,
+    /// Replaces the existing L6 blocks
+    #[serde(default)]
+    overwrite: bool,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -46,7 +118,7 @@ pub struct ActiveAreaOffsets {
 }
 
 impl Editor {
-    pub fn edit(input: PathBuf, json_path: PathBuf, rpu_out: Option<PathBuf>) {
+    pub fn edit(input: PathBuf, json_path: PathBuf, rpu_out: Option<PathBuf>, lenient: bool) {
         let out_path = if let Some(out_path) = rpu_out {
             out_path
         } else {
@@ -69,7 +141,7 @@ impl Editor {
 
         println!("{:#?}", config);
 
-        editor.rpus = parse_rpu_file(&editor.input);
+        editor.rpus = parse_rpu_file(&editor.input, lenient);
 
         if let Some(ref mut rpus) = editor.rpus {
             config.execute(rpus);
@@ -82,8 +154,106 @@ impl Editor {
     }
 }
 
+impl L2Retarget {
+    fn execute(&self, rpus: &mut [DoviRpu]) {
+        println!("Changing L2 target_max_pq {} to {}...", self.from, self.to);
+
+        let mut count = 0;
+
+        for (index, rpu) in rpus.iter_mut().enumerate() {
+            let has_target = rpu
+                .vdr_dm_data
+                .as_ref()
+                .is_some_and(|dm| dm.l2_targets().any(|t| t == self.from));
+
+            // Frames without the trim are left untouched
+            if has_target {
+                if let Err(e) = rpu.set_l2_target(self.from, self.to) {
+                    panic!("Frame {}: {}", index, e);
+                }
+
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            panic!("No L2 block with target_max_pq {}", self.from);
+        }
+
+        println!("{} blocks changed", count);
+    }
+}
+
+impl SceneCutSnap {
+    fn default_tolerance() -> usize {
+        2
+    }
+
+    fn execute(&self, rpus: &mut [DoviRpu]) {
+        println!(
+            "Snapping scene cuts to {} shots, up to {} frames away...",
+            self.shots.len(),
+            self.tolerance
+        );
+
+        let moves = snap_scene_cuts(rpus, &self.shots, self.tolerance);
+
+        // Number of cuts moved by each offset
+        let mut offsets: BTreeMap<i64, usize> = BTreeMap::new();
+        for (from, to) in &moves {
+            *offsets.entry(*to as i64 - *from as i64).or_insert(0) += 1;
+        }
+
+        let offsets: Vec<String> = offsets
+            .iter()
+            .map(|(offset, count)| format!("{} by {:+}", count, offset))
+            .collect();
+
+        if moves.is_empty() {
+            println!("No scene cut moved");
+        } else {
+            println!("{} scene cuts moved: {}", moves.len(), offsets.join(", "));
+        }
+    }
+}
+
+impl L6Insertion {
+    fn execute(&self, rpus: &mut [DoviRpu]) {
+        let (max_cll, max_fall) = match (self.max_cll, self.max_fall) {
+            (Some(max_cll), Some(max_fall)) => (max_cll, max_fall),
+            (max_cll, max_fall) => {
+                // Computed from the L1 luminance, the existing L6 values aren't used
+                let luminance = match RpuStream::new(rpus).stats().luminance {
+                    Some(luminance) => luminance,
+                    None => panic!("No L1 metadata to compute the MaxCLL and MaxFALL from"),
+                };
+
+                (
+                    max_cll.unwrap_or(luminance.max_nits.round() as u16),
+                    max_fall.unwrap_or(luminance.max_avg_nits.round() as u16),
+                )
+            }
+        };
+
+        println!(
+            "Inserting L6 blocks with MaxCLL {} and MaxFALL {} nits...",
+            max_cll, max_fall
+        );
+
+        let mut count = 0;
+
+        for rpu in rpus.iter_mut() {
+            if rpu.insert_l6(max_cll, max_fall, self.overwrite) {
+                count += 1;
+            }
+        }
+
+        println!("{} L6 blocks inserted", count);
+    }
+}
+
 impl EditConfig {
-    fn execute(&self, rpus: &mut Vec<DoviRpu>) {
+    fn execute(&self, rpus: &mut [DoviRpu]) {
         // Convert with mode
         if self.mode > 0 && !self.p5_to_p81 {
             self.convert_with_mode(rpus);
@@ -91,12 +261,92 @@ impl EditConfig {
             self.convert_p5_to_p81(rpus);
         }
 
+        if self.p84_to_p81 {
+            self.convert_p84_to_p81(rpus);
+        }
+
         if let Some(active_area) = &self.active_area {
             active_area.execute(rpus);
         }
+
+        for level in &self.remove_ext_blocks {
+            self.remove_ext_blocks(rpus, *level);
+        }
+
+        for retarget in &self.retarget_l2 {
+            retarget.execute(rpus);
+        }
+
+        if let Some(snap) = &self.snap_scene_cuts {
+            snap.execute(rpus);
+        }
+
+        if let Some(insert_l6) = &self.insert_l6 {
+            insert_l6.execute(rpus);
+        }
+
+        if self.dedup {
+            self.dedup(rpus);
+        }
+
+        if self.minimal_use_prev {
+            println!("Minimizing the RPUs using the previous mapping...");
+
+            let count = rpus
+                .iter_mut()
+                .map(|rpu| rpu.minimize_use_prev())
+                .filter(|minimized| *minimized)
+                .count();
+
+            println!("{} RPUs minimized", count);
+        }
+
+        if self.minify {
+            println!("Minifying the RPUs...");
+
+            let (original_size, new_size) = minify_rpus(rpus);
+
+            println!(
+                "{} -> {} bytes ({:.1}%)",
+                original_size,
+                new_size,
+                new_size as f64 / original_size as f64 * 100.0
+            );
+        }
     }
 
-    fn convert_with_mode(&self, rpus: &mut Vec<DoviRpu>) {
+    fn remove_ext_blocks(&self, rpus: &mut [DoviRpu], level: ExtBlockLevel) {
+        println!("Removing L{} metadata...", level.as_u8());
+
+        let count: usize = rpus
+            .iter_mut()
+            .map(|rpu| rpu.remove_ext_blocks(level))
+            .sum();
+
+        println!("{} blocks removed", count);
+    }
+
+    fn dedup(&self, rpus: &mut [DoviRpu]) {
+        println!("Deduplicating mappings...");
+
+        let size = |rpus: &mut [DoviRpu]| -> usize {
+            rpus.iter_mut().map(|rpu| rpu.write_rpu_data().len()).sum()
+        };
+
+        let original_size = size(rpus);
+        let count = dedup_mappings(rpus);
+        let new_size = size(rpus);
+
+        println!(
+            "{} RPUs now reuse the previous mapping, {} -> {} bytes ({:.1}%)",
+            count,
+            original_size,
+            new_size,
+            new_size as f64 / original_size as f64 * 100.0
+        );
+    }
+
+    fn convert_with_mode(&self, rpus: &mut [DoviRpu]) {
         println!("Converting with mode {}...", self.mode);
         rpus.iter_mut()
             .for_each(|rpu| rpu.convert_with_mode(self.mode));
@@ -126,14 +376,44 @@ impl EditConfig {
         }
     }
 
-    fn convert_p5_to_p81(&self, rpus: &mut Vec<DoviRpu>) {
+    fn convert_p5_to_p81(&self, rpus: &mut [DoviRpu]) {
         println!("Converting from profile 5 to profile 8.1 (experimental)");
         rpus.iter_mut().for_each(|rpu| rpu.p5_to_p81());
     }
+
+    fn convert_p84_to_p81(&self, rpus: &mut [DoviRpu]) {
+        println!("Converting from profile 8.4 (HLG) to profile 8.1 (PQ)...");
+
+        let mut converted = 0;
+        let mut max_error: f64 = 0.0;
+
+        for (index, rpu) in rpus.iter_mut().enumerate() {
+            match rpu.p84_to_p81() {
+                Ok(Some(fit_error)) => {
+                    converted += 1;
+                    max_error = max_error.max(fit_error);
+                }
+                Ok(None) => (),
+                Err(e) => panic!("Frame {}: {}", index, e),
+            }
+        }
+
+        let violations: BTreeSet<String> = rpus
+            .iter()
+            .flat_map(DoviRpu::validate_profile81)
+            .map(|violation| format!("Converted RPU not profile 8.1: {}", violation))
+            .collect();
+        print_warnings(violations.iter());
+
+        println!(
+            "{} RPUs converted, largest luma mapping fit error: {:.5}",
+            converted, max_error
+        );
+    }
 }
 
 impl ActiveArea {
-    fn execute(&self, rpus: &mut Vec<DoviRpu>) {
+    fn execute(&self, rpus: &mut [DoviRpu]) {
         if self.crop {
             self.crop(rpus);
         }
@@ -145,12 +425,12 @@ impl ActiveArea {
         }
     }
 
-    fn crop(&self, rpus: &mut Vec<DoviRpu>) {
+    fn crop(&self, rpus: &mut [DoviRpu]) {
         println!("Cropping...");
         rpus.iter_mut().for_each(|rpu| rpu.crop());
     }
 
-    fn do_edits(&self, edits: &HashMap<String, u16>, rpus: &mut Vec<DoviRpu>) {
+    fn do_edits(&self, edits: &HashMap<String, u16>, rpus: &mut [DoviRpu]) {
         if let Some(presets) = &self.presets {
             println!("Editing active area offsets...");
 
@@ -158,7 +438,7 @@ impl ActiveArea {
                 let (start, end) = EditConfig::range_string_to_tuple(edit.0);
                 let preset_id = *edit.1;
 
-                if end as usize > rpus.len() {
+                if end > rpus.len() {
                     panic!("Invalid range: {} > {} available RPUs", start, rpus.len());
                 }
 
@@ -172,7 +452,11 @@ impl ActiveArea {
                         );
 
                         if let Some(block) = ExtMetadataBlockLevel5::get_mut(rpu) {
-                            block.set_offsets(left, right, top, bottom);
+                            if let Err(e) = block.set_offsets(left, right, top, bottom) {
+                                panic!("Invalid preset {}: {}", preset_id, e);
+                            }
+
+                            rpu.modified = true;
                         }
                     });
                 } else {
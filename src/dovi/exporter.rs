@@ -0,0 +1,220 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use super::rpu::{
+    rpu_stream::RpuStream,
+    to_rpu_list_json, to_rpu_list_msgpack,
+    vdr_dm_data::{ExtMetadataBlock, ExtMetadataBlockLevel1},
+};
+use super::{parse_rpu_file, timecode::Timecode, DoviRpu};
+
+pub struct Exporter {
+    input: PathBuf,
+    output: PathBuf,
+    timecode: Option<Timecode>,
+    kind: ExportKind,
+    rpus: Option<Vec<DoviRpu>>,
+}
+
+/// What is exported from the RPUs
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ExportKind {
+    /// JSON RPU list
+    #[default]
+    Json,
+    /// L1 luminance of every frame, as text
+    Measurements,
+    /// RPU list as MessagePack
+    Msgpack,
+    /// Metadata of the frames where it changes
+    Changes,
+    /// Aggregate statistics of the stream, as JSON
+    Stats,
+}
+
+impl Exporter {
+    pub fn export(
+        input: PathBuf,
+        output: Option<PathBuf>,
+        fps: Option<f64>,
+        drop_frame: bool,
+        kind: ExportKind,
+        lenient: bool,
+    ) {
+        let output = output.unwrap_or_else(|| {
+            PathBuf::from(match kind {
+                ExportKind::Json => "RPU_export.json",
+                ExportKind::Measurements => "RPU_measurements.txt",
+                ExportKind::Msgpack => "RPU_export.msgpack",
+                ExportKind::Changes => "RPU_changes.json",
+                ExportKind::Stats => "RPU_stats.json",
+            })
+        });
+
+        let timecode = fps.map(|fps| match Timecode::new(fps, drop_frame) {
+            Ok(timecode) => timecode,
+            Err(e) => panic!("{}", e),
+        });
+
+        let mut exporter = Exporter {
+            input,
+            output,
+            timecode,
+            kind,
+            rpus: None,
+        };
+
+        exporter.rpus = parse_rpu_file(&exporter.input, lenient);
+
+        if let Some(ref rpus) = exporter.rpus {
+            let result = match exporter.kind {
+                ExportKind::Json => exporter.write_json(rpus),
+                ExportKind::Measurements => exporter.write_measurements(rpus),
+                ExportKind::Msgpack => exporter.write_msgpack(rpus),
+                ExportKind::Changes => exporter.write_changes(rpus),
+                ExportKind::Stats => exporter.write_stats(rpus),
+            };
+
+            match result {
+                Ok(_) => (),
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    fn write_json(&self, rpus: &[DoviRpu]) -> Result<(), std::io::Error> {
+        println!("Exporting metadata...");
+
+        let json = if let Some(ref timecode) = self.timecode {
+            let mut list = serde_json::to_value(rpus)?;
+
+            // Ignored on import
+            if let Value::Array(ref mut rpus) = list {
+                for (frame, rpu) in rpus.iter_mut().enumerate() {
+                    if let Value::Object(ref mut rpu) = rpu {
+                        rpu.insert("timecode".to_string(), timecode.format(frame).into());
+                    }
+                }
+            }
+
+            list.to_string()
+        } else {
+            to_rpu_list_json(rpus)?
+        };
+
+        let mut writer = BufWriter::new(File::create(&self.output)?);
+        writer.write_all(json.as_bytes())?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn write_msgpack(&self, rpus: &[DoviRpu]) -> Result<(), std::io::Error> {
+        println!("Exporting metadata...");
+
+        let data = to_rpu_list_msgpack(rpus)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let mut writer = BufWriter::new(File::create(&self.output)?);
+        writer.write_all(&data)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes the metadata of the frames where it changes, with the frame range it applies to.
+    /// The `use_prev_vdr_rpu_flag` references are resolved, only actual metadata changes start a range.
+    fn write_changes(&self, rpus: &[DoviRpu]) -> Result<(), std::io::Error> {
+        println!("Exporting metadata changes...");
+
+        let runs = RpuStream::new(rpus).metadata_runs();
+
+        let mut writer = BufWriter::new(File::create(&self.output)?);
+        serde_json::to_writer(&mut writer, &runs)?;
+        writer.flush()?;
+
+        println!(
+            "{} metadata changes over {} frames",
+            runs.len().saturating_sub(1),
+            rpus.len()
+        );
+
+        Ok(())
+    }
+
+    /// Writes the aggregate statistics of the stream, see `StreamStats`
+    fn write_stats(&self, rpus: &[DoviRpu]) -> Result<(), std::io::Error> {
+        println!("Exporting statistics...");
+
+        let stats = RpuStream::new(rpus).stats();
+
+        let mut writer = BufWriter::new(File::create(&self.output)?);
+        serde_json::to_writer_pretty(&mut writer, &stats)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes the L1 luminance of every frame, for players tone mapping HDR10 dynamically.
+    /// See `measurements_text` for the format.
+    fn write_measurements(&self, rpus: &[DoviRpu]) -> Result<(), std::io::Error> {
+        println!("Exporting measurements...");
+
+        let mut writer = BufWriter::new(File::create(&self.output)?);
+        writer.write_all(measurements_text(rpus).as_bytes())?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Frame indexed measurements, in dovi_tool's own text format, documented in the README:
+/// a header comment line, then one line per frame with `frame max_nits avg_nits scene_cut`, separated by spaces.
+///
+/// The luminances are the L1 `max_pq` and `avg_pq` in nits, with 4 decimals,
+/// from the DM metadata set active for the frame.
+/// The `avg_pq` is normalized for the CM version, with the L3 offset of CM v4.0 applied.
+/// `scene_cut` is 1 for the frames with `scene_refresh_flag` set, 0 otherwise.
+/// Frames without L1 metadata repeat the previous frame's values, or 0 at the start.
+pub fn measurements_text(rpus: &[DoviRpu]) -> String {
+    let stream = RpuStream::new(rpus);
+
+    let mut text = String::from("# frame max_nits avg_nits scene_cut\n");
+    let mut luminance = (0.0, 0.0);
+
+    for (frame, rpu) in rpus.iter().enumerate() {
+        let active_l1 = stream.effective_dm_set(frame).and_then(|vdr_dm_data| {
+            let block = vdr_dm_data
+                .ext_metadata_blocks
+                .iter()
+                .find_map(|ext| match ext {
+                    ExtMetadataBlock::Level1(block) => Some(block),
+                    _ => None,
+                })?;
+
+            Some((vdr_dm_data, block))
+        });
+
+        if let Some((vdr_dm_data, block)) = active_l1 {
+            let avg_nits =
+                ExtMetadataBlockLevel1::normalized_avg_nits(vdr_dm_data, vdr_dm_data.cm_version());
+
+            luminance = (block.max_nits(), avg_nits.unwrap_or_default());
+        }
+
+        let scene_cut = rpu
+            .vdr_dm_data
+            .as_ref()
+            .is_some_and(|vdr_dm_data| vdr_dm_data.is_scene_cut());
+
+        text.push_str(&format!(
+            "{} {:.4} {:.4} {}\n",
+            frame, luminance.0, luminance.1, scene_cut as u8
+        ));
+    }
+
+    text
+}
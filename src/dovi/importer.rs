@@ -0,0 +1,49 @@
+use std::fs::read;
+use std::path::PathBuf;
+
+use super::{
+    rpu::{from_rpu_list_json, from_rpu_list_msgpack},
+    write_rpu_file,
+};
+
+pub struct Importer {
+    input: PathBuf,
+    rpu_out: PathBuf,
+}
+
+impl Importer {
+    pub fn import(input: PathBuf, rpu_out: Option<PathBuf>, lenient: bool) {
+        let rpu_out = match rpu_out {
+            Some(path) => path,
+            None => PathBuf::from("RPU_import.bin"),
+        };
+
+        let importer = Importer { input, rpu_out };
+
+        println!("Importing metadata...");
+
+        let data = read(&importer.input).unwrap();
+
+        let mut rpus = if is_json(&data) {
+            match from_rpu_list_json(&String::from_utf8_lossy(&data), lenient) {
+                Ok(rpus) => rpus,
+                Err(e) => panic!("Invalid RPU list JSON: {}", e),
+            }
+        } else {
+            match from_rpu_list_msgpack(&data, lenient) {
+                Ok(rpus) => rpus,
+                Err(e) => panic!("Invalid RPU list MessagePack: {}", e),
+            }
+        };
+
+        match write_rpu_file(&importer.rpu_out, &mut rpus) {
+            Ok(_) => (),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+}
+
+/// A JSON RPU list starts with `[`, MessagePack arrays never do
+fn is_json(data: &[u8]) -> bool {
+    data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[')
+}
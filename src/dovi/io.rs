@@ -1,3 +1,4 @@
+use std::collections::BTreeSet;
 use std::io::{stdout, BufRead, BufReader, BufWriter, Write};
 use std::{fs::File, path::Path};
 
@@ -5,16 +6,52 @@ use ansi_term::Colour::Red;
 use indicatif::ProgressBar;
 use std::io::Read;
 
-use super::rpu::parse_dovi_rpu;
-use super::{Format, RpuOptions, OUT_NAL_HEADER};
+use super::rpu::{
+    mapping_curves_match, parse_dovi_rpu, repair_rpu_terminator, rpu_changes, rpu_crc32,
+    rpu_stream::IdRenumbering, vdr_dm_data::VdrDmData, DoviRpu,
+};
+use super::ts::{SharedDoviConfig, TsReader};
+use super::{
+    nal_unit_type, print_warnings, split_nals, Format, RpuOptions, RpuPacking, OUT_NAL_HEADER,
+};
 
 use hevc_parser::hevc::NALUnit;
-use hevc_parser::hevc::{NAL_UNSPEC62, NAL_UNSPEC63};
+use hevc_parser::hevc::{
+    NAL_PPS, NAL_SEI_PREFIX, NAL_SEI_SUFFIX, NAL_SPS, NAL_UNSPEC62, NAL_UNSPEC63, NAL_VPS,
+};
 use hevc_parser::HevcParser;
 
+/// Largest mapping difference accepted when requantizing the coefficients, one 12 bit codeword
+const REQUANTIZATION_TOLERANCE: f64 = 1.0 / 4095.0;
+
 pub struct DoviReader {
     options: RpuOptions,
+    dm_template: Option<VdrDmData>,
     rpu_nals: Vec<RpuNal>,
+    rpu_count: usize,
+    single_layer: Option<bool>,
+    /// VPS, SPS and PPS found in the EL before its first slice
+    el_parameter_sets: [bool; 3],
+    el_decodable: Option<bool>,
+    /// Dolby Vision configuration of the container, checked against the first RPU
+    dovi_config: Option<SharedDoviConfig>,
+    dovi_config_checked: bool,
+    warnings: BTreeSet<String>,
+    summary: ProcessingSummary,
+    /// Changed fields of the edited RPUs, by decoded frame index
+    changes: Vec<(usize, Vec<String>)>,
+    /// Largest Y, Cb and Cr mapping differences caused by requantizing the coefficients
+    requantization_errors: Option<[f64; 3]>,
+    id_renumbering: Option<IdRenumbering>,
+    /// Offset of the current chunk in the input
+    chunk_offset: usize,
+    /// Bytes read from the input at once
+    chunk_size: usize,
+    /// Single frame extracted, in decoding order
+    frame: Option<usize>,
+    /// Raw HEVC inputs are memory mapped
+    #[cfg(feature = "mmap")]
+    mapped: bool,
 }
 
 pub struct DoviWriter {
@@ -22,6 +59,24 @@ pub struct DoviWriter {
     el_writer: Option<BufWriter<File>>,
     rpu_writer: Option<BufWriter<File>>,
     sl_writer: Option<BufWriter<File>>,
+    /// Copy of the SEI NALs, kept in the BL too
+    sei_writer: Option<BufWriter<File>>,
+    rpu_packing: RpuPacking,
+}
+
+/// Counters of the processed RPUs
+#[derive(Debug, Default)]
+pub struct ProcessingSummary {
+    /// RPUs found in the stream, one per frame
+    pub frames: usize,
+    /// RPUs parsed and rewritten with the mode
+    pub converted: usize,
+    /// Invalid RPUs, skipped or copied untouched
+    pub invalid: usize,
+    /// Parsed RPUs with scene_refresh_flag set
+    pub scene_cuts: usize,
+    /// RPUs whose missing 0x80 terminator byte was repaired
+    pub repaired: usize,
 }
 
 #[derive(Debug)]
@@ -31,6 +86,18 @@ pub struct RpuNal {
     data: Vec<u8>,
 }
 
+impl RpuNal {
+    /// `rpu_data_crc32` stored in the NAL, `None` if it isn't an RPU NAL
+    pub fn stored_crc(&self) -> Option<u32> {
+        rpu_crc32(&self.data).ok().map(|(stored, _)| stored)
+    }
+
+    /// CRC32 computed from the NAL payload, `None` if it isn't an RPU NAL
+    pub fn computed_crc(&self) -> Option<u32> {
+        rpu_crc32(&self.data).ok().map(|(_, computed)| computed)
+    }
+}
+
 impl DoviWriter {
     pub fn new(
         bl_out: Option<&Path>,
@@ -38,66 +105,162 @@ impl DoviWriter {
         rpu_out: Option<&Path>,
         single_layer_out: Option<&Path>,
     ) -> DoviWriter {
+        DoviWriter::create(bl_out, el_out, rpu_out, single_layer_out).expect("Can't create file")
+    }
+
+    /// Like `new`, returning the error when an output file can't be created
+    pub fn create(
+        bl_out: Option<&Path>,
+        el_out: Option<&Path>,
+        rpu_out: Option<&Path>,
+        single_layer_out: Option<&Path>,
+    ) -> Result<DoviWriter, std::io::Error> {
         let chunk_size = 100_000;
-        let bl_writer = if let Some(bl_out) = bl_out {
-            Some(BufWriter::with_capacity(
-                chunk_size,
-                File::create(bl_out).expect("Can't create file"),
-            ))
-        } else {
-            None
+        let create = |path: Option<&Path>| -> Result<Option<BufWriter<File>>, std::io::Error> {
+            path.map(|path| Ok(BufWriter::with_capacity(chunk_size, File::create(path)?)))
+                .transpose()
         };
 
-        let el_writer = if let Some(el_out) = el_out {
-            Some(BufWriter::with_capacity(
-                chunk_size,
-                File::create(el_out).expect("Can't create file"),
-            ))
-        } else {
-            None
-        };
+        Ok(DoviWriter {
+            bl_writer: create(bl_out)?,
+            el_writer: create(el_out)?,
+            rpu_writer: create(rpu_out)?,
+            sl_writer: create(single_layer_out)?,
+            sei_writer: None,
+            rpu_packing: RpuPacking::default(),
+        })
+    }
 
-        let rpu_writer = if let Some(rpu_out) = rpu_out {
-            Some(BufWriter::with_capacity(
-                chunk_size,
-                File::create(rpu_out).expect("Can't create file"),
-            ))
-        } else {
-            None
-        };
+    pub fn set_rpu_packing(&mut self, rpu_packing: RpuPacking) {
+        self.rpu_packing = rpu_packing;
+    }
 
-        let sl_writer = if let Some(single_layer_out) = single_layer_out {
-            Some(BufWriter::with_capacity(
-                chunk_size,
-                File::create(single_layer_out).expect("Can't create file"),
-            ))
-        } else {
-            None
-        };
+    /// Also writes the SEI NALs of the BL to `sei_out`
+    pub fn set_sei_out(&mut self, sei_out: &Path) -> Result<(), std::io::Error> {
+        self.sei_writer = Some(BufWriter::with_capacity(100_000, File::create(sei_out)?));
 
-        DoviWriter {
-            bl_writer,
-            el_writer,
-            rpu_writer,
-            sl_writer,
-        }
+        Ok(())
     }
 }
 
 impl DoviReader {
     pub fn new(options: RpuOptions) -> DoviReader {
+        let dm_template = options.dm_template.as_ref().map(|path| {
+            let file = File::open(path).expect("Can't open the DM template");
+
+            let template: VdrDmData = match serde_json::from_reader(BufReader::new(file)) {
+                Ok(template) => template,
+                Err(e) => panic!("Invalid DM template: {}", e),
+            };
+
+            if let Some(error) = template.out_of_range_fields().first() {
+                panic!("Invalid DM template: {}", error);
+            }
+
+            template
+        });
+
+        let id_renumbering = options
+            .renumber_ids
+            .map(|first_id| IdRenumbering::new(first_id).unwrap_or_else(|e| panic!("{}", e)));
+
         DoviReader {
             options,
+            dm_template,
+            id_renumbering,
             rpu_nals: Vec::new(),
+            rpu_count: 0,
+            single_layer: None,
+            el_parameter_sets: [false; 3],
+            el_decodable: None,
+            dovi_config: None,
+            dovi_config_checked: false,
+            warnings: BTreeSet::new(),
+            summary: ProcessingSummary::default(),
+            changes: Vec::new(),
+            requantization_errors: None,
+            chunk_offset: 0,
+            chunk_size: 100_000,
+            frame: None,
+            #[cfg(feature = "mmap")]
+            mapped: true,
         }
     }
 
+    /// Only extracts the RPU of a frame, in decoding order, stopping once it is found
+    pub fn set_frame(&mut self, frame: usize) {
+        self.frame = Some(frame);
+    }
+
+    /// Sets the number of bytes read at once, NALs can span any number of chunks
+    #[cfg(test)]
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+
+    /// Reads raw HEVC inputs through a buffer instead of a memory map
+    #[cfg(all(test, feature = "mmap"))]
+    pub fn set_mapped(&mut self, mapped: bool) {
+        self.mapped = mapped;
+    }
+
     pub fn read_write_from_io(
         &mut self,
         format: &Format,
         input: &Path,
         pb: Option<&ProgressBar>,
         dovi_writer: &mut DoviWriter,
+    ) -> Result<ProcessingSummary, std::io::Error> {
+        let mut parser = HevcParser::default();
+
+        match format {
+            #[cfg(feature = "mmap")]
+            Format::Raw if self.mapped => self.read_mapped(input, pb, &mut parser, dovi_writer)?,
+            _ => self.read_buffered(format, input, pb, &mut parser, dovi_writer)?,
+        }
+
+        if let Some(pb) = pb {
+            pb.finish_and_clear();
+        }
+
+        parser.finish();
+
+        print_warnings(self.warnings.iter());
+
+        self.flush_writer(&parser, dovi_writer)?;
+
+        self.summary.frames = self.rpu_count;
+        println!("{}", self.summary);
+
+        if self.options.change_log {
+            self.print_change_log();
+        }
+
+        if let Some([y, cb, cr]) = self.requantization_errors {
+            println!(
+                "Requantized mapping curves, max error: Y {:.2e}, Cb {:.2e}, Cr {:.2e}",
+                y, cb, cr
+            );
+        }
+
+        if let Some(bl_compat_id) = self.options.bl_compat_id {
+            println!(
+                "Dolby Vision configuration to mux with: profile 8.{}, dv_bl_signal_compatibility_id {}",
+                bl_compat_id, bl_compat_id
+            );
+        }
+
+        Ok(std::mem::take(&mut self.summary))
+    }
+
+    /// Reads the input in chunks, the NALs left incomplete are carried over to the next chunk
+    fn read_buffered(
+        &mut self,
+        format: &Format,
+        input: &Path,
+        pb: Option<&ProgressBar>,
+        parser: &mut HevcParser,
+        dovi_writer: &mut DoviWriter,
     ) -> Result<(), std::io::Error> {
         //BufReader & BufWriter
         let stdin = std::io::stdin();
@@ -106,101 +269,163 @@ impl DoviReader {
         if let Format::Raw = format {
             let file = File::open(input)?;
             reader = Box::new(BufReader::with_capacity(100_000, file));
+            skip_bytes(&mut reader, self.options.skip_bytes)?;
+        } else if let Format::TransportStream = format {
+            let mut file = BufReader::with_capacity(100_000, File::open(input)?);
+            skip_bytes(&mut file, self.options.skip_bytes)?;
+
+            let ts_reader = TsReader::new(file, self.options.pid);
+
+            self.dovi_config = Some(ts_reader.dovi_config());
+            reader = Box::new(BufReader::with_capacity(100_000, ts_reader));
+        } else {
+            skip_bytes(&mut reader, self.options.skip_bytes)?;
         }
 
-        let chunk_size = 100_000;
+        let chunk_size = self.chunk_size;
 
-        let mut main_buf = vec![0; 100_000];
-        let mut sec_buf = vec![0; 50_000];
+        let mut main_buf = vec![0; chunk_size];
 
         let mut chunk = Vec::with_capacity(chunk_size);
-        let mut end: Vec<u8> = Vec::with_capacity(100_000);
 
         let mut consumed = 0;
-
-        let mut parser = HevcParser::default();
+        let mut stream_offset = self.options.skip_bytes;
 
         let mut offsets = Vec::with_capacity(2048);
-        let parse_nals = dovi_writer.rpu_writer.is_some();
+        // A single frame is kept in decoding order, without reordering
+        let parse_nals = dovi_writer.rpu_writer.is_some() && self.frame.is_none();
+
+        loop {
+            // Only the last chunk is partial
+            let read_bytes = read_chunk(&mut reader, &mut main_buf)?;
+            let eof = read_bytes < chunk_size;
+
+            chunk.extend_from_slice(&main_buf[..read_bytes]);
+
+            // The chunk starts with the NAL left from the previous ones
+            self.chunk_offset = stream_offset + read_bytes - chunk.len();
+            stream_offset += read_bytes;
 
-        while let Ok(n) = reader.read(&mut main_buf) {
-            let mut read_bytes = n;
-            if read_bytes == 0 {
+            let last =
+                self.write_chunk(parser, &chunk, &mut offsets, eof, parse_nals, dovi_writer)?;
+
+            if eof || self.frame.is_some_and(|frame| self.rpu_count > frame) {
                 break;
             }
 
-            if *format == Format::RawStdin {
-                chunk.extend_from_slice(&main_buf[..read_bytes]);
-
-                loop {
-                    match reader.read(&mut sec_buf) {
-                        Ok(num) => {
-                            if num > 0 {
-                                read_bytes += num;
+            chunk.drain(..last);
 
-                                chunk.extend_from_slice(&sec_buf[..num]);
+            consumed += read_bytes;
 
-                                if read_bytes >= chunk_size {
-                                    break;
-                                }
-                            } else {
-                                break;
-                            }
-                        }
-                        Err(e) => panic!("{:?}", e),
-                    }
+            if consumed >= 100_000_000 {
+                if let Some(pb) = pb {
+                    pb.inc(1);
+                    pb.set_message(&format!("{} frames", self.rpu_count));
+                    consumed = 0;
                 }
-            } else if read_bytes < chunk_size {
-                chunk.extend_from_slice(&main_buf[..read_bytes]);
-            } else {
-                chunk.extend_from_slice(&main_buf);
             }
+        }
 
-            parser.get_offsets(&chunk, &mut offsets);
+        Ok(())
+    }
 
-            if offsets.is_empty() {
-                continue;
-            }
+    /// Reads a raw HEVC file through a memory map.
+    /// The chunks are windows of the mapped file, the NALs spanning them are never copied.
+    #[cfg(feature = "mmap")]
+    fn read_mapped(
+        &mut self,
+        input: &Path,
+        pb: Option<&ProgressBar>,
+        parser: &mut HevcParser,
+        dovi_writer: &mut DoviWriter,
+    ) -> Result<(), std::io::Error> {
+        let file = File::open(input)?;
+
+        // Safety: the mapping is only valid while the file is unchanged.
+        // Another process writing to it changes the data under the parser,
+        // truncating it makes reading the mapped pages past the new end a SIGBUS.
+        // Like with the buffered reads, the input must not be modified while it is processed.
+        let data = unsafe { memmap2::Mmap::map(&file)? };
+
+        if data.len() < self.options.skip_bytes {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Input shorter than the {} bytes to skip",
+                    self.options.skip_bytes
+                ),
+            ));
+        }
 
-            let last = if read_bytes < chunk_size {
-                *offsets.last().unwrap()
-            } else {
-                let last = offsets.pop().unwrap();
+        let mut start = self.options.skip_bytes;
+        let mut end = start;
+        let mut consumed = 0;
+
+        let mut offsets = Vec::with_capacity(2048);
+        // A single frame is kept in decoding order, without reordering
+        let parse_nals = dovi_writer.rpu_writer.is_some() && self.frame.is_none();
 
-                end.clear();
-                end.extend_from_slice(&chunk[last..]);
+        loop {
+            let read_bytes = self.chunk_size.min(data.len() - end);
+            end += read_bytes;
 
-                last
-            };
+            let eof = end == data.len();
 
-            let nals: Vec<NALUnit> = parser.split_nals(&chunk, &offsets, last, parse_nals);
-            self.write_nals(&chunk, dovi_writer, &nals)?;
+            // The window starts with the NAL left from the previous ones
+            self.chunk_offset = start;
 
-            chunk.clear();
+            let chunk = &data[start..end];
+            let last =
+                self.write_chunk(parser, chunk, &mut offsets, eof, parse_nals, dovi_writer)?;
 
-            if !end.is_empty() {
-                chunk.extend_from_slice(&end);
+            if eof || self.frame.is_some_and(|frame| self.rpu_count > frame) {
+                break;
             }
 
+            start += last;
+
             consumed += read_bytes;
 
             if consumed >= 100_000_000 {
                 if let Some(pb) = pb {
                     pb.inc(1);
+                    pb.set_message(&format!("{} frames", self.rpu_count));
                     consumed = 0;
                 }
             }
         }
 
-        if let Some(pb) = pb {
-            pb.finish_and_clear();
+        Ok(())
+    }
+
+    /// Writes the complete NALs of a chunk, returning the length written.
+    /// The last NAL is only complete at the end of the input,
+    /// otherwise it is kept for the next chunk, however many it spans.
+    fn write_chunk(
+        &mut self,
+        parser: &mut HevcParser,
+        chunk: &[u8],
+        offsets: &mut Vec<usize>,
+        eof: bool,
+        parse_nals: bool,
+        dovi_writer: &mut DoviWriter,
+    ) -> Result<usize, std::io::Error> {
+        parser.get_offsets(chunk, offsets);
+
+        if offsets.is_empty() {
+            return Ok(0);
         }
 
-        parser.finish();
+        let last = if eof {
+            *offsets.last().unwrap()
+        } else {
+            offsets.pop().unwrap()
+        };
 
-        self.flush_writer(&parser, dovi_writer)?;
+        let nals = split_nals(parser, chunk, offsets, last, parse_nals);
+        self.write_nals(chunk, dovi_writer, &nals)?;
 
-        Ok(())
+        Ok(last)
     }
 
     pub fn write_nals(
@@ -209,7 +434,22 @@ impl DoviReader {
         dovi_writer: &mut DoviWriter,
         nals: &[NALUnit],
     ) -> Result<(), std::io::Error> {
-        for nal in nals {
+        // NALs before this index were already written with a previous BL NAL
+        let mut copied_until = 0;
+
+        for (index, nal) in nals.iter().enumerate() {
+            if index < copied_until {
+                continue;
+            }
+
+            if self.options.verbose {
+                self.log_nal(nal, dovi_writer);
+            }
+
+            if nal.nal_type == NAL_UNSPEC62 && !self.dovi_config_checked {
+                self.check_dovi_config(&chunk[nal.start..nal.end]);
+            }
+
             if let Some(ref mut sl_writer) = dovi_writer.sl_writer {
                 if nal.nal_type == NAL_UNSPEC63 && self.options.discard_el {
                     continue;
@@ -218,22 +458,23 @@ impl DoviReader {
                 sl_writer.write_all(OUT_NAL_HEADER)?;
 
                 if nal.nal_type == NAL_UNSPEC62 {
-                    if let Some(mode) = self.options.mode {
-                        match parse_dovi_rpu(&chunk[nal.start..nal.end]) {
-                            Ok(mut dovi_rpu) => {
-                                dovi_rpu.convert_with_mode(mode);
+                    let frame = self.rpu_count;
+                    self.rpu_count += 1;
 
-                                if self.options.crop {
-                                    dovi_rpu.crop();
-                                }
+                    // Invalid RPUs are copied untouched
+                    let dovi_rpu = self
+                        .options
+                        .mode
+                        .and_then(|_| self.parse_rpu(&chunk[nal.start..nal.end], frame));
 
-                                let modified_data = dovi_rpu.write_rpu_data();
-                                sl_writer.write_all(&modified_data)?;
+                    if let (Some(mode), Some(mut dovi_rpu)) = (self.options.mode, dovi_rpu) {
+                        self.edit_rpu(&mut dovi_rpu, mode, frame);
 
-                                continue;
-                            }
-                            Err(e) => panic!("{}", Red.paint(e)),
-                        }
+                        let modified_data = dovi_rpu.write_rpu_data();
+                        sl_writer.write_all(&modified_data)?;
+                        self.summary.converted += 1;
+
+                        continue;
                     }
                 }
 
@@ -245,11 +486,28 @@ impl DoviReader {
             match nal.nal_type {
                 NAL_UNSPEC63 => {
                     if let Some(ref mut el_writer) = dovi_writer.el_writer {
+                        // The EL NAL is wrapped in a 0x7E01 NAL header
                         el_writer.write_all(OUT_NAL_HEADER)?;
                         el_writer.write_all(&chunk[nal.start + 2..nal.end])?;
+
+                        self.check_el_nal(&chunk[nal.start + 2..nal.end]);
                     }
                 }
                 NAL_UNSPEC62 => {
+                    // Profile 5 has no EL, the RPU stays in the BL
+                    if dovi_writer.el_writer.is_some()
+                        && self.is_single_layer(&chunk[nal.start..nal.end])
+                    {
+                        self.rpu_count += 1;
+
+                        if let Some(ref mut bl_writer) = dovi_writer.bl_writer {
+                            bl_writer.write_all(OUT_NAL_HEADER)?;
+                            bl_writer.write_all(&chunk[nal.start..nal.end])?;
+                        }
+
+                        continue;
+                    }
+
                     if let Some(ref mut el_writer) = dovi_writer.el_writer {
                         el_writer.write_all(OUT_NAL_HEADER)?;
                     }
@@ -258,46 +516,94 @@ impl DoviReader {
                     // Mode 0: Parse, untouched
                     // Mode 1: to MEL
                     // Mode 2: to 8.1
+                    let decoded_index = self.rpu_count;
+                    self.rpu_count += 1;
+
+                    if dovi_writer.rpu_writer.is_some()
+                        && self.frame.is_some_and(|frame| frame != decoded_index)
+                    {
+                        continue;
+                    }
+
                     if let Some(mode) = self.options.mode {
-                        match parse_dovi_rpu(&chunk[nal.start..nal.end]) {
-                            Ok(mut dovi_rpu) => {
-                                dovi_rpu.convert_with_mode(mode);
-
-                                if self.options.crop {
-                                    dovi_rpu.crop();
-                                }
-
-                                let modified_data = dovi_rpu.write_rpu_data();
-
-                                if let Some(ref mut _rpu_writer) = dovi_writer.rpu_writer {
-                                    // RPU for x265, remove 0x7C01
-                                    self.rpu_nals.push(RpuNal {
-                                        decoded_index: self.rpu_nals.len(),
-                                        presentation_number: 0,
-                                        data: modified_data[2..].to_vec(),
-                                    });
-                                } else if let Some(ref mut el_writer) = dovi_writer.el_writer {
-                                    el_writer.write_all(&modified_data)?;
-                                }
+                        let dovi_rpu = self.parse_rpu(&chunk[nal.start..nal.end], decoded_index);
+
+                        // Invalid RPUs are dropped from the extracted RPUs, copied otherwise
+                        let modified_data = dovi_rpu.map(|mut dovi_rpu| {
+                            self.edit_rpu(&mut dovi_rpu, mode, decoded_index);
+
+                            dovi_rpu.write_rpu_data()
+                        });
+
+                        if modified_data.is_some() {
+                            self.summary.converted += 1;
+                        }
+
+                        if let Some(ref mut _rpu_writer) = dovi_writer.rpu_writer {
+                            // RPU for x265, remove 0x7C01
+                            if let Some(modified_data) = modified_data {
+                                self.rpu_nals.push(RpuNal {
+                                    decoded_index,
+                                    presentation_number: 0,
+                                    data: modified_data[2..].to_vec(),
+                                });
+                            }
+                        } else if let Some(ref mut el_writer) = dovi_writer.el_writer {
+                            match modified_data {
+                                Some(modified_data) => el_writer.write_all(&modified_data)?,
+                                None => el_writer.write_all(&chunk[nal.start..nal.end])?,
                             }
-                            Err(e) => panic!("{}", Red.paint(e)),
                         }
                     } else if let Some(ref mut _rpu_writer) = dovi_writer.rpu_writer {
                         // RPU for x265, remove 0x7C01
-                        self.rpu_nals.push(RpuNal {
-                            decoded_index: self.rpu_nals.len(),
+                        let rpu_nal = RpuNal {
+                            decoded_index,
                             presentation_number: 0,
                             data: chunk[nal.start + 2..nal.end].to_vec(),
-                        });
+                        };
+
+                        // Copied without parsing, only the CRC32 is checked
+                        if let (Some(stored), Some(computed)) =
+                            (rpu_nal.stored_crc(), rpu_nal.computed_crc())
+                        {
+                            if stored != computed {
+                                self.warnings.insert(format!(
+                                    "Frame {}: RPU CRC32 mismatch, computed {:#010x}, stored {:#010x}",
+                                    decoded_index, computed, stored
+                                ));
+                            }
+                        }
+
+                        self.rpu_nals.push(rpu_nal);
                     } else if let Some(ref mut el_writer) = dovi_writer.el_writer {
                         el_writer.write_all(&chunk[nal.start..nal.end])?;
                     }
                 }
-                _ => {
+                NAL_SEI_PREFIX | NAL_SEI_SUFFIX => {
                     if let Some(ref mut bl_writer) = dovi_writer.bl_writer {
                         bl_writer.write_all(OUT_NAL_HEADER)?;
                         bl_writer.write_all(&chunk[nal.start..nal.end])?;
                     }
+
+                    if let Some(ref mut sei_writer) = dovi_writer.sei_writer {
+                        sei_writer.write_all(OUT_NAL_HEADER)?;
+                        sei_writer.write_all(&chunk[nal.start..nal.end])?;
+                    }
+                }
+                _ => {
+                    // Every NAL is logged when verbose
+                    let run_end = if self.options.verbose {
+                        index + 1
+                    } else {
+                        bl_run_end(nals, index)
+                    };
+
+                    if let Some(ref mut bl_writer) = dovi_writer.bl_writer {
+                        bl_writer.write_all(OUT_NAL_HEADER)?;
+                        bl_writer.write_all(&chunk[nal.start..nals[run_end - 1].end])?;
+                    }
+
+                    copied_until = run_end;
                 }
             }
         }
@@ -305,6 +611,304 @@ impl DoviReader {
         Ok(())
     }
 
+    /// Converts the RPU with the mode, then applies the edits from the options
+    fn edit_rpu(&mut self, dovi_rpu: &mut DoviRpu, mode: u8, frame: usize) {
+        let before = if self.options.change_log {
+            Some(serde_json::to_value(&*dovi_rpu).unwrap())
+        } else {
+            None
+        };
+
+        dovi_rpu.convert_with_mode(mode);
+
+        if let Some(bl_compat_id) = self.options.bl_compat_id {
+            let profile = dovi_rpu.header.get_dovi_profile();
+
+            if profile != 8 {
+                self.warnings.insert(format!(
+                    "dv_bl_signal_compatibility_id {} requires profile 8 RPUs, found profile {}",
+                    bl_compat_id, profile
+                ));
+            } else if let Some(rpu_compat_id) = dovi_rpu.profile8_compatibility_id() {
+                if rpu_compat_id != bl_compat_id {
+                    self.warnings.insert(format!(
+                        "dv_bl_signal_compatibility_id {} doesn't match the profile 8.{} signal_eotf of the RPUs",
+                        bl_compat_id, rpu_compat_id
+                    ));
+                }
+            }
+        }
+
+        if self.options.crop {
+            dovi_rpu.crop();
+        }
+
+        if self.options.clear_active_area {
+            dovi_rpu.clear_active_area();
+        }
+
+        if let Some([left, right, top, bottom]) = self.options.active_area_deltas {
+            dovi_rpu.adjust_active_area(left, right, top, bottom);
+        }
+
+        if self.options.strip_dm {
+            dovi_rpu.strip_dm_metadata();
+        }
+
+        if let Some(ref template) = self.dm_template {
+            dovi_rpu.apply_dm_template(template, !self.options.dm_template_l1);
+        }
+
+        if self.options.cmv40 {
+            dovi_rpu.convert_to_cmv40();
+        }
+
+        if let Some((min_nits, avg_nits, max_nits)) = self.options.l1_fixed {
+            dovi_rpu.set_l1_fixed(min_nits, avg_nits, max_nits);
+        }
+
+        if let Some(max_nits) = self.options.clamp_l1_max {
+            dovi_rpu.clamp_l1_max(max_nits);
+        }
+
+        if let Some((max_cll, max_fall)) = self.options.insert_l6 {
+            dovi_rpu.insert_l6(max_cll, max_fall, self.options.overwrite_l6);
+        }
+
+        if let Some((min, max)) = self.options.source_diagonal {
+            dovi_rpu.clamp_source_diagonal(min, max);
+        }
+
+        if let Some(index) = self.options.source_primaries {
+            dovi_rpu.set_source_primaries(index);
+        }
+
+        if let Some(index) = self.options.target_primaries {
+            if let Err(e) = dovi_rpu.set_target_primaries(index) {
+                self.warnings.insert(e);
+            }
+        }
+
+        if let Some(factor) = self.options.scale_l2_trims {
+            dovi_rpu.scale_l2_trims(factor, &self.options.scale_l2_fields);
+        }
+
+        if frame == 0 && self.options.first_scene_cut {
+            dovi_rpu.set_scene_cut(true);
+        } else if frame > 0 && self.options.clear_scene_cuts {
+            dovi_rpu.set_scene_cut(false);
+        }
+
+        if let Some(coefficient_log2_denom) = self.options.coefficient_log2_denom {
+            let original = dovi_rpu.clone();
+
+            if let Err(e) = dovi_rpu.requantize_coefficients(coefficient_log2_denom) {
+                self.warnings.insert(e);
+            } else if let Some(comparison) =
+                mapping_curves_match(&original, dovi_rpu, REQUANTIZATION_TOLERANCE)
+            {
+                let max_errors = self.requantization_errors.get_or_insert([0.0; 3]);
+
+                for (max_error, error) in max_errors.iter_mut().zip(&comparison.max_errors) {
+                    *max_error = max_error.max(*error);
+                }
+
+                if !comparison.matches {
+                    self.warnings.insert(format!(
+                        "Requantized mapping curves differ by more than a 12 bit codeword with coefficient_log2_denom {}",
+                        coefficient_log2_denom
+                    ));
+                }
+            }
+        }
+
+        if let Some(ref mut id_renumbering) = self.id_renumbering {
+            id_renumbering.renumber(dovi_rpu);
+        }
+
+        if let Some(length) = self.options.pad_rpu {
+            if let Err(e) = dovi_rpu.pad(length) {
+                self.warnings.insert(e);
+            }
+        }
+
+        // Checks the conversion's output
+        if mode == 2 {
+            for violation in dovi_rpu.validate_profile81() {
+                self.warnings
+                    .insert(format!("Converted RPU not profile 8.1: {}", violation));
+            }
+        }
+
+        if let Some(before) = before {
+            self.changes.push((frame, rpu_changes(&before, dovi_rpu)));
+        }
+    }
+
+    /// Logs the type, offset, size and destination of a NAL at the debug level
+    fn log_nal(&self, nal: &NALUnit, dovi_writer: &DoviWriter) {
+        let destination = if dovi_writer.sl_writer.is_some() {
+            if nal.nal_type == NAL_UNSPEC63 && self.options.discard_el {
+                "discarded"
+            } else {
+                "single layer"
+            }
+        } else {
+            match nal.nal_type {
+                NAL_UNSPEC62 => "RPU",
+                NAL_UNSPEC63 => "EL",
+                NAL_SEI_PREFIX | NAL_SEI_SUFFIX => "BL SEI",
+                _ => "BL",
+            }
+        };
+
+        log::debug!(
+            "NAL at {:#x}: type {}, {} bytes, {}",
+            self.chunk_offset + nal.start,
+            nal.nal_type,
+            nal.end - nal.start,
+            destination
+        );
+    }
+
+    /// Prints the changes of each range of consecutive frames with the same changes
+    fn print_change_log(&mut self) {
+        self.changes.sort_by_key(|(frame, _)| *frame);
+
+        println!("Changes:");
+
+        let mut start = 0;
+
+        for end in 1..=self.changes.len() {
+            if end < self.changes.len()
+                && self.changes[end].1 == self.changes[start].1
+                && self.changes[end].0 == self.changes[end - 1].0 + 1
+            {
+                continue;
+            }
+
+            let (first, ref changes) = self.changes[start];
+            let last = self.changes[end - 1].0;
+
+            if changes.is_empty() {
+                println!("  Frames {}-{}: unchanged", first, last);
+            } else {
+                println!("  Frames {}-{}: {}", first, last, changes.join(", "));
+            }
+
+            start = end;
+        }
+    }
+
+    /// Compares the profile of the first valid RPU to the container configuration
+    fn check_dovi_config(&mut self, data: &[u8]) {
+        let config = match self.dovi_config.as_ref().and_then(|config| config.get()) {
+            Some(config) => config,
+            None => return,
+        };
+
+        if let Ok(dovi_rpu) = parse_dovi_rpu(data, true) {
+            if let Some(warning) = config
+                .check_rpu_profile(dovi_rpu.dovi_profile, dovi_rpu.profile8_compatibility_id())
+            {
+                self.warnings.insert(warning);
+            }
+
+            self.dovi_config_checked = true;
+        }
+    }
+
+    /// The EL is only decodable on its own when its parameter sets precede its first slice.
+    fn check_el_nal(&mut self, data: &[u8]) {
+        if self.el_decodable.is_some() {
+            return;
+        }
+
+        let nal_type = match data.first() {
+            Some(&header) => nal_unit_type(header),
+            None => return,
+        };
+
+        match nal_type {
+            NAL_VPS | NAL_SPS | NAL_PPS => {
+                self.el_parameter_sets[(nal_type - NAL_VPS) as usize] = true;
+            }
+            // VCL NAL
+            0..=31 => {
+                let decodable = self.el_parameter_sets.iter().all(|found| *found);
+
+                if !decodable {
+                    self.warnings.insert(
+                        "EL: no VPS, SPS and PPS before the first slice, the EL can't be decoded on its own"
+                            .to_string(),
+                    );
+                }
+
+                self.el_decodable = Some(decodable);
+            }
+            _ => (),
+        }
+    }
+
+    /// Whether the stream is single layer (profile 5), decided from the first valid RPU.
+    fn is_single_layer(&mut self, data: &[u8]) -> bool {
+        if self.single_layer.is_none() {
+            self.single_layer = parse_dovi_rpu(data, true)
+                .ok()
+                .map(|dovi_rpu| dovi_rpu.dovi_profile == 5);
+
+            if self.single_layer == Some(true) {
+                println!("Profile 5 stream: no EL, keeping the RPUs in the BL.");
+            }
+        }
+
+        self.single_layer == Some(true)
+    }
+
+    /// Parses the RPU of a frame, `frame` being the index in decoded order.
+    /// A corrupted RPU is reported and skipped, instead of stopping the whole process.
+    fn parse_rpu(&mut self, data: &[u8], frame: usize) -> Option<DoviRpu> {
+        let repaired;
+        // The RPUs that can't be repaired fail parsing
+        let data = if self.options.repair_terminator {
+            match repair_rpu_terminator(&data[2..]) {
+                Ok(Some(payload)) => {
+                    self.summary.repaired += 1;
+
+                    repaired = [&data[..2], &payload].concat();
+                    &repaired
+                }
+                _ => data,
+            }
+        } else {
+            data
+        };
+
+        match parse_dovi_rpu(data, self.options.lenient) {
+            Ok(mut dovi_rpu) => {
+                self.warnings.extend(dovi_rpu.warnings.drain(..));
+
+                if let Some(ref vdr_dm_data) = dovi_rpu.vdr_dm_data {
+                    if vdr_dm_data.is_scene_cut() {
+                        self.summary.scene_cuts += 1;
+                    }
+                }
+
+                Some(dovi_rpu)
+            }
+            Err(e) => {
+                self.summary.invalid += 1;
+
+                println!(
+                    "{}",
+                    Red.paint(format!("Skipping RPU of frame {}: {}", frame, e))
+                );
+
+                None
+            }
+        }
+    }
+
     fn flush_writer(
         &mut self,
         parser: &HevcParser,
@@ -318,12 +922,42 @@ impl DoviReader {
             el_writer.flush()?;
         }
 
+        if let Some(ref mut sei_writer) = dovi_writer.sei_writer {
+            sei_writer.flush()?;
+        }
+
+        if let (Some(frame), Some(ref mut rpu_writer)) = (self.frame, &mut dovi_writer.rpu_writer) {
+            let rpu = match self.rpu_nals.first() {
+                Some(rpu) => rpu,
+                None if self.rpu_count > frame => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Frame {}: invalid RPU", frame),
+                    ))
+                }
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Frame {} out of range, {} RPUs", frame, self.rpu_count),
+                    ))
+                }
+            };
+
+            dovi_writer.rpu_packing.write(rpu_writer, &rpu.data)?;
+            rpu_writer.flush()?;
+
+            return Ok(());
+        }
+
         // Reorder RPUs to display output order
         if let Some(ref mut rpu_writer) = dovi_writer.rpu_writer {
             let frames = parser.ordered_frames();
 
             if frames.is_empty() {
-                panic!("No frames parsed!");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "No frames parsed!",
+                ));
             }
 
             print!("Reordering metadata... ");
@@ -349,8 +983,7 @@ impl DoviReader {
 
             // Write data to file
             for rpu in self.rpu_nals.iter_mut() {
-                rpu_writer.write_all(OUT_NAL_HEADER)?;
-                rpu_writer.write_all(&rpu.data)?;
+                dovi_writer.rpu_packing.write(rpu_writer, &rpu.data)?;
             }
 
             rpu_writer.flush()?;
@@ -359,3 +992,65 @@ impl DoviReader {
         Ok(())
     }
 }
+
+impl std::fmt::Display for ProcessingSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Frames: {}, converted RPUs: {}, invalid RPUs: {}, scene cuts: {}",
+            self.frames, self.converted, self.invalid, self.scene_cuts
+        )?;
+
+        if self.repaired > 0 {
+            write!(f, ", repaired terminators: {}", self.repaired)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// End of the run of BL NALs starting at `index`, which can be copied at once.
+/// The NALs are contiguous in the input when their start codes are 4 bytes,
+/// like the start codes they are written with.
+fn bl_run_end(nals: &[NALUnit], index: usize) -> usize {
+    nals[index + 1..]
+        .iter()
+        .position(|nal| {
+            nal.start_code_len != 4
+                || matches!(
+                    nal.nal_type,
+                    NAL_UNSPEC62 | NAL_UNSPEC63 | NAL_SEI_PREFIX | NAL_SEI_SUFFIX
+                )
+        })
+        .map_or(nals.len(), |position| index + 1 + position)
+}
+
+/// Consumes the first `count` bytes of the input
+fn skip_bytes<R: BufRead>(reader: &mut R, count: usize) -> Result<(), std::io::Error> {
+    let skipped = std::io::copy(&mut reader.take(count as u64), &mut std::io::sink())?;
+
+    if skipped < count as u64 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("Input shorter than the {} bytes to skip", count),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Reads until the buffer is full or the input ends, returning the number of bytes read
+fn read_chunk<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    let mut read = 0;
+
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(num) => read += num,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(read)
+}
@@ -0,0 +1,31 @@
+use std::path::PathBuf;
+
+use super::rpu::merge_rpus;
+use super::{parse_rpu_file, write_rpu_file};
+
+/// Combines the mapping of an RPU file with the DM metadata of another, frame by frame
+pub struct Merger;
+
+impl Merger {
+    pub fn merge(mapping: PathBuf, dm: PathBuf, rpu_out: Option<PathBuf>, lenient: bool) {
+        let rpu_out = match rpu_out {
+            Some(path) => path,
+            None => PathBuf::from("RPU_merged.bin"),
+        };
+
+        let parse = |input: &PathBuf| match parse_rpu_file(input, lenient) {
+            Some(rpus) => rpus,
+            None => panic!("{}: no RPU found", input.display()),
+        };
+
+        let mut rpus = match merge_rpus(&parse(&mapping), &parse(&dm)) {
+            Ok(rpus) => rpus,
+            Err(e) => panic!("{}", e),
+        };
+
+        match write_rpu_file(&rpu_out, &mut rpus) {
+            Ok(_) => println!("{} RPUs written", rpus.len()),
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
@@ -1,22 +1,42 @@
+#[cfg(feature = "async")]
+pub mod async_extractor;
+pub mod batch;
+pub mod concatenator;
 pub mod converter;
 pub mod demuxer;
 pub mod editor;
+pub mod exporter;
+pub mod importer;
+pub mod merger;
+pub mod mp4_injector;
 pub mod rpu_extractor;
 pub mod rpu_info;
 pub mod rpu_injector;
 
 mod io;
 mod rpu;
+#[cfg(test)]
+mod tests;
+mod timecode;
+mod ts;
 
 use hevc_parser::{
-    hevc::{Frame, NAL_AUD},
+    hevc::{Frame, NALUnit, NAL_AUD},
     HevcParser,
 };
+pub use rpu::vdr_dm_data::L2TrimField;
 use rpu::{parse_dovi_rpu, DoviRpu};
 
+use ansi_term::Colour::{Red, Yellow};
 use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::BTreeSet;
 use std::io::{stdout, BufReader, Read, Write};
-use std::{fs::File, io::BufWriter, path::Path};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
 
 use super::bitvec_reader::BitVecReader;
 use super::bitvec_writer::BitVecWriter;
@@ -29,13 +49,105 @@ pub enum Format {
     Raw,
     RawStdin,
     Matroska,
+    TransportStream,
+}
+
+/// Form of the RPUs written to an RPU file.
+/// `x265` and `standalone` name the `AnnexB` and `Nal` forms by their use.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum RpuPacking {
+    /// Start code and payload, without the 0x7C01 NAL header, as expected by x265
+    #[default]
+    AnnexB,
+    /// Start code and complete NAL, with the 0x7C01 NAL header
+    Nal,
+    /// Payload only, without delimiter, for a single RPU
+    Raw,
 }
 
-#[derive(Debug)]
+impl std::str::FromStr for RpuPacking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "annexb" | "x265" => Ok(RpuPacking::AnnexB),
+            "nal" | "standalone" => Ok(RpuPacking::Nal),
+            "raw" => Ok(RpuPacking::Raw),
+            _ => Err(format!("Unknown RPU packing: {}", s)),
+        }
+    }
+}
+
+impl RpuPacking {
+    /// Writes an RPU, `payload` being the NAL without its 0x7C01 header
+    pub fn write<W: Write>(self, writer: &mut W, payload: &[u8]) -> Result<(), std::io::Error> {
+        match self {
+            RpuPacking::AnnexB => writer.write_all(OUT_NAL_HEADER)?,
+            RpuPacking::Nal => {
+                writer.write_all(OUT_NAL_HEADER)?;
+                writer.write_all(&[0x7C, 0x01])?;
+            }
+            RpuPacking::Raw => (),
+        }
+
+        writer.write_all(payload)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct RpuOptions {
     pub mode: Option<u8>,
     pub crop: bool,
+    /// Remove the L5 blocks rather than zeroing the offsets
+    pub clear_active_area: bool,
+    pub strip_dm: bool,
+    /// Make the CM v2.9 DM metadata CM v4.0
+    pub cmv40: bool,
+    /// Left, right, top and bottom active area offset deltas
+    pub active_area_deltas: Option<[i32; 4]>,
+    pub clamp_l1_max: Option<f64>,
+    /// L1 min, avg and max of every frame, in nits
+    pub l1_fixed: Option<(f64, f64, f64)>,
+    /// MaxCLL and MaxFALL of the L6 blocks inserted, in nits
+    pub insert_l6: Option<(u16, u16)>,
+    /// Replace the existing L6 blocks too
+    pub overwrite_l6: bool,
+    /// Range `source_diagonal` is clamped to, in inches. Equal bounds set it
+    pub source_diagonal: Option<(u16, u16)>,
+    /// L9 source_primary_index, the block is inserted when absent
+    pub source_primaries: Option<u8>,
+    /// L10 target_primary_index
+    pub target_primaries: Option<u8>,
+    /// Factor the L2 trims are scaled by, around their neutral value
+    pub scale_l2_trims: Option<f64>,
+    pub scale_l2_fields: Vec<L2TrimField>,
+    /// Set scene_refresh_flag on the first frame
+    pub first_scene_cut: bool,
+    /// Clear scene_refresh_flag on every frame but the first
+    pub clear_scene_cuts: bool,
+    /// dv_bl_signal_compatibility_id of the profile 8 output
+    pub bl_compat_id: Option<u8>,
+    pub coefficient_log2_denom: Option<u64>,
+    /// First vdr_rpu_id and DM metadata id when renumbering them
+    pub renumber_ids: Option<u64>,
+    /// Length the RPU NALs are padded to, in bytes
+    pub pad_rpu: Option<usize>,
+    /// Repair the RPUs lacking the 0x80 terminator byte before parsing them
+    pub repair_terminator: bool,
+    /// JSON DM metadata applied to every frame
+    pub dm_template: Option<PathBuf>,
+    /// Replace the L1 metadata by the template's too
+    pub dm_template_l1: bool,
+    /// HEVC PID of a transport stream input
+    pub pid: Option<u16>,
+    /// Leading bytes of the input ignored, e.g. a capture header
+    pub skip_bytes: usize,
     pub discard_el: bool,
+    pub lenient: bool,
+    /// Report the fields changed by the mode and edits
+    pub change_log: bool,
+    /// Log the type, offset and size of every NAL
+    pub verbose: bool,
 }
 
 pub fn initialize_progress_bar(format: &Format, input: &Path) -> ProgressBar {
@@ -53,7 +165,8 @@ pub fn initialize_progress_bar(format: &Format, input: &Path) -> ProgressBar {
 
         pb = ProgressBar::new(bytes_count);
         pb.set_style(
-            ProgressStyle::default_bar().template("[{elapsed_precise}] {bar:60.cyan} {percent}%"),
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:60.cyan} {percent}% {msg}"),
         );
     }
 
@@ -66,11 +179,12 @@ impl std::fmt::Display for Format {
             Format::Matroska => write!(f, "Matroska file"),
             Format::Raw => write!(f, "HEVC file"),
             Format::RawStdin => write!(f, "HEVC pipe"),
+            Format::TransportStream => write!(f, "MPEG transport stream"),
         }
     }
 }
 
-pub fn parse_rpu_file(input: &Path) -> Option<Vec<DoviRpu>> {
+pub fn parse_rpu_file(input: &Path, lenient: bool) -> Option<Vec<DoviRpu>> {
     println!("Parsing RPU file...");
     stdout().flush().ok();
 
@@ -88,15 +202,183 @@ pub fn parse_rpu_file(input: &Path) -> Option<Vec<DoviRpu>> {
     let mut data = vec![0; metadata.len() as usize];
     reader.read_exact(&mut data).unwrap();
 
+    // Parsed on every core, the RPUs are returned in decoding order all the same
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+
+    let parsed: Vec<(usize, Result<DoviRpu, String>)> = if is_hex_text(&data) {
+        let hex_nals = match parse_hex_lines(&String::from_utf8_lossy(&data)) {
+            Ok(nals) => nals,
+            Err(e) => panic!("{}", e),
+        };
+
+        par_parse_rpu_nals(
+            hex_nals.iter().map(Vec::as_slice).collect(),
+            lenient,
+            threads,
+        )
+    } else if threads > 1 {
+        par_parse_rpu_nals(split_rpu_file(&data), lenient, threads)
+    } else {
+        iter_rpus(&data, lenient)
+            .map(|(frame, rpu, _)| (frame, rpu))
+            .collect()
+    };
+
+    let count = parsed.len();
+
+    let rpus: Vec<DoviRpu> = parsed
+        .into_iter()
+        .filter_map(|(index, rpu)| match rpu {
+            Ok(dovi_rpu) => Some(dovi_rpu),
+            Err(e) => {
+                println!("{}", Red.paint(format!("Frame {}: {}", index, e)));
+                None
+            }
+        })
+        .collect();
+
+    let warnings: BTreeSet<&String> = rpus.iter().flat_map(|rpu| &rpu.warnings).collect();
+    print_warnings(warnings.into_iter());
+
+    if count > 0 && rpus.len() == count {
+        Some(rpus)
+    } else if count == 0 {
+        panic!("No RPU found");
+    } else {
+        panic!("Number of valid RPUs different from total");
+    }
+}
+
+/// Frames of an RPU file whose NAL lacks the 0x80 terminator byte,
+/// with the error when the terminator can't be repaired.
+/// Only the NAL structure is checked, the RPUs aren't parsed.
+pub fn unterminated_rpus(data: &[u8]) -> Vec<(usize, Option<String>)> {
+    let hex_nals;
+
+    let nals: Vec<&[u8]> = if is_hex_text(data) {
+        hex_nals = match parse_hex_lines(&String::from_utf8_lossy(data)) {
+            Ok(nals) => nals,
+            Err(e) => panic!("{}", e),
+        };
+
+        hex_nals.iter().map(Vec::as_slice).collect()
+    } else {
+        split_rpu_file(data)
+    };
+
+    nals.into_iter()
+        .enumerate()
+        .filter_map(|(frame, nal)| {
+            match rpu::repair_rpu_terminator(nal.get(2..).unwrap_or_default()) {
+                Ok(None) => None,
+                Ok(Some(_)) => Some((frame, None)),
+                Err(e) => Some((frame, Some(e))),
+            }
+        })
+        .collect()
+}
+
+/// Iterates the RPUs of an Annex B RPU file, in decoding order.
+/// Yields the frame index, the parsed RPU and the payload it was parsed from, without the 0x7C01 NAL header.
+///
+/// The payload is the RPU as in the file, to write back the frames left untouched
+/// with the fields the parser doesn't know intact.
+pub fn iter_rpus(
+    data: &[u8],
+    lenient: bool,
+) -> impl Iterator<Item = (usize, Result<DoviRpu, String>, &[u8])> {
+    parse_rpu_nals(split_rpu_file(data), lenient)
+}
+
+/// NALs starting 2 bytes before the RPU payload, like the ones returned by `split_rpu_file`
+fn parse_rpu_nals(
+    nals: Vec<&[u8]>,
+    lenient: bool,
+) -> impl Iterator<Item = (usize, Result<DoviRpu, String>, &[u8])> {
+    nals.into_iter().enumerate().map(move |(frame, nal)| {
+        let payload = nal.get(2..).unwrap_or_default();
+
+        (frame, parse_dovi_rpu(nal, lenient), payload)
+    })
+}
+
+/// Number of NALs parsed at once by a thread of `par_parse_rpu_nals`
+const PARSE_BATCH_SIZE: usize = 256;
+
+/// Parses the NALs like `parse_rpu_nals`, on up to `threads` threads.
+///
+/// The threads take the NALs by batches, in any order. Every RPU is tagged with its frame index,
+/// and the results are sorted back into decoding order, identical to parsing serially.
+fn par_parse_rpu_nals(
+    nals: Vec<&[u8]>,
+    lenient: bool,
+    threads: usize,
+) -> Vec<(usize, Result<DoviRpu, String>)> {
+    let threads = threads.min(nals.len() / PARSE_BATCH_SIZE + 1);
+
+    if threads <= 1 {
+        return parse_rpu_nals(nals, lenient)
+            .map(|(frame, rpu, _)| (frame, rpu))
+            .collect();
+    }
+
+    let next_batch = AtomicUsize::new(0);
+
+    let parsed = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut parsed = Vec::new();
+
+                    loop {
+                        let start = next_batch.fetch_add(PARSE_BATCH_SIZE, Ordering::Relaxed);
+
+                        if start >= nals.len() {
+                            break parsed;
+                        }
+
+                        let end = (start + PARSE_BATCH_SIZE).min(nals.len());
+
+                        for (frame, nal) in nals[start..end].iter().enumerate() {
+                            parsed.push((start + frame, parse_dovi_rpu(nal, lenient)));
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+
+    in_decoding_order(parsed)
+}
+
+/// Sorts results tagged with their frame index back into decoding order
+fn in_decoding_order<T>(mut tagged: Vec<(usize, T)>) -> Vec<(usize, T)> {
+    tagged.sort_unstable_by_key(|(frame, _)| *frame);
+    tagged
+}
+
+/// Splits the NALs of an RPU file.
+/// The returned slices start 2 bytes before the RPU payload, in place of the 0x7C01 NAL header.
+/// RPUs written with their NAL header are accepted too, the payload starts with rpu_nal_prefix.
+fn split_rpu_file(data: &[u8]) -> Vec<&[u8]> {
     let mut offsets = Vec::with_capacity(200_000);
     let mut parser = HevcParser::default();
 
-    parser.get_offsets(&data, &mut offsets);
+    parser.get_offsets(data, &mut offsets);
+
+    if offsets.is_empty() {
+        return Vec::new();
+    }
 
     let count = offsets.len();
     let last = *offsets.last().unwrap();
 
-    let rpus: Vec<DoviRpu> = offsets
+    offsets
         .iter()
         .enumerate()
         .map(|(index, offset)| {
@@ -118,21 +400,52 @@ pub fn parse_rpu_file(input: &Path) -> Option<Vec<DoviRpu>> {
             let start = *offset + 1;
             let end = start + size;
 
-            parse_dovi_rpu(&data[start..end])
+            match data.get(start + 2..start + 4) {
+                Some([0x7C, 0x01]) => &data[start + 2..end],
+                _ => &data[start..end],
+            }
         })
-        .filter_map(Result::ok)
-        .collect();
+        .collect()
+}
 
-    if count > 0 && rpus.len() == count {
-        Some(rpus)
-    } else if count == 0 {
-        panic!("No RPU found");
-    } else {
-        panic!("Number of valid RPUs different from total");
+/// Whether the file is a text RPU list rather than an Annex B stream
+fn is_hex_text(data: &[u8]) -> bool {
+    !data.is_empty()
+        && data
+            .iter()
+            .all(|b| b.is_ascii_hexdigit() || b.is_ascii_whitespace())
+}
+
+/// Parses a text RPU list: one RPU NAL per line in hex, starting with the 7C01 NAL header.
+/// Empty lines are ignored.
+fn parse_hex_lines(text: &str) -> Result<Vec<Vec<u8>>, String> {
+    text.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(index, line)| {
+            if !line.is_ascii() || line.len() % 2 != 0 {
+                return Err(format!("Line {}: invalid hex RPU", index + 1));
+            }
+
+            (0..line.len())
+                .step_by(2)
+                .map(|i| {
+                    u8::from_str_radix(&line[i..i + 2], 16)
+                        .map_err(|e| format!("Line {}: {}", index + 1, e))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn print_warnings<'a>(warnings: impl Iterator<Item = &'a String>) {
+    for warning in warnings {
+        println!("{}", Yellow.paint(format!("Warning: {}", warning)));
     }
 }
 
-pub fn write_rpu_file(output_path: &Path, rpus: &mut Vec<DoviRpu>) -> Result<(), std::io::Error> {
+pub fn write_rpu_file(output_path: &Path, rpus: &mut [DoviRpu]) -> Result<(), std::io::Error> {
     println!("Writing RPU file...");
     let mut writer = BufWriter::with_capacity(
         100_000,
@@ -153,6 +466,36 @@ pub fn write_rpu_file(output_path: &Path, rpus: &mut Vec<DoviRpu>) -> Result<(),
     Ok(())
 }
 
+/// nal_unit_type of an HEVC NAL, from the first byte of its 2 bytes header.
+///
+/// The header is forbidden_zero_bit (1 bit), nal_unit_type (6 bits), nuh_layer_id (6 bits)
+/// and nuh_temporal_id_plus1 (3 bits), the forbidden_zero_bit is masked out.
+pub fn nal_unit_type(header: u8) -> u8 {
+    (header >> 1) & 0x3F
+}
+
+/// Splits the complete NALs of a chunk, see `HevcParser::split_nals`.
+/// Without parsing the NALs, the parser takes the type from the whole first header byte,
+/// it is extracted again so that a set forbidden_zero_bit doesn't change it.
+pub fn split_nals(
+    parser: &mut HevcParser,
+    chunk: &[u8],
+    offsets: &[usize],
+    last: usize,
+    parse_nals: bool,
+) -> Vec<NALUnit> {
+    let mut nals = parser.split_nals(chunk, offsets, last, parse_nals);
+
+    for nal in nals.iter_mut() {
+        if let Some(&header) = chunk.get(nal.start) {
+            nal.nal_type = nal_unit_type(header);
+        }
+    }
+
+    nals
+}
+
+/// AUD NAL of a frame, with its start code
 pub fn get_aud(frame: &Frame) -> Vec<u8> {
     let pic_type: u8 = match &frame.frame_type {
         2 => 0,
@@ -168,11 +511,15 @@ pub fn get_aud(frame: &Frame) -> Vec<u8> {
     writer.write(false);
 
     writer.write_n(&(NAL_AUD).to_be_bytes(), 6);
-    writer.write_n(&(0 as u8).to_be_bytes(), 6);
-    writer.write_n(&(0 as u8).to_be_bytes(), 3);
+    // nuh_layer_id, nuh_temporal_id_plus1
+    writer.write_n(&0_u8.to_be_bytes(), 6);
+    writer.write_n(&1_u8.to_be_bytes(), 3);
 
     writer.write_n(&pic_type.to_be_bytes(), 3);
 
+    // rbsp_stop_one_bit
+    writer.write(true);
+
     data.extend_from_slice(writer.as_slice());
 
     data
@@ -0,0 +1,787 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{stdout, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use super::{parse_rpu_file, DoviRpu};
+
+/// Boxes holding only other boxes, after `N` bytes of fields
+const CONTAINERS: &[(&[u8; 4], usize)] = &[
+    (b"moov", 0),
+    (b"trak", 0),
+    (b"mdia", 0),
+    (b"minf", 0),
+    (b"stbl", 0),
+    (b"stsd", 8),
+    // VisualSampleEntry fields
+    (b"hvc1", 78),
+    (b"hev1", 78),
+];
+
+/// Max luma pixel rate and width of the Dolby Vision levels 1 to 13
+const DOVI_LEVELS: [(u64, u16); 13] = [
+    (22_118_400, 1280),
+    (27_648_000, 1280),
+    (49_766_400, 1920),
+    (62_208_000, 2560),
+    (124_416_000, 3840),
+    (199_065_600, 3840),
+    (248_832_000, 3840),
+    (398_131_200, 3840),
+    (497_664_000, 3840),
+    (995_328_000, 3840),
+    (995_328_000, 7680),
+    (1_990_656_000, 7680),
+    (3_981_312_000, 7680),
+];
+
+pub struct Mp4Injector {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+/// An MP4 box. Containers have their fields in `data`, followed by the children.
+#[derive(Debug, Clone)]
+pub struct Mp4Box {
+    pub kind: [u8; 4],
+    pub data: Vec<u8>,
+    pub children: Vec<Mp4Box>,
+}
+
+/// Sample sizes and chunks of a track
+struct SampleTable {
+    sizes: Vec<u32>,
+    /// Offset and sample count of the chunks
+    chunks: Vec<(u64, usize)>,
+}
+
+/// Track to write, the HEVC track getting the RPUs
+struct Track {
+    table: SampleTable,
+    hevc: Option<HevcTrack>,
+}
+
+struct HevcTrack {
+    /// Size of the NAL length prefix
+    length_size: usize,
+    /// Display index of the samples, in decoding order
+    display_order: Vec<usize>,
+}
+
+impl Mp4Injector {
+    pub fn inject_mp4(input: PathBuf, rpu_in: PathBuf, output: Option<PathBuf>, lenient: bool) {
+        let output = match output {
+            Some(path) => path,
+            None => PathBuf::from("injected_output.mp4"),
+        };
+
+        let mut rpus = parse_rpu_file(&rpu_in, lenient).unwrap();
+        let injector = Mp4Injector::new(input, output);
+
+        if let Err(e) = injector.inject(&mut rpus) {
+            panic!("{}", e);
+        }
+    }
+
+    pub fn new(input: PathBuf, output: PathBuf) -> Mp4Injector {
+        Mp4Injector { input, output }
+    }
+
+    /// Writes the output MP4, the RPUs in display order.
+    /// The RPU NALs are appended to the samples of the HEVC track, replacing existing ones,
+    /// and a `dvvC` configuration box is added to its sample entry.
+    pub fn inject(&self, rpus: &mut [DoviRpu]) -> Result<(), String> {
+        if let Some(rpu) = rpus.iter().find(|rpu| rpu.dovi_profile != 8) {
+            return Err(format!(
+                "MP4 injection requires profile 8 RPUs, found profile {}",
+                rpu.dovi_profile
+            ));
+        }
+
+        println!("Reading MP4 boxes...");
+        stdout().flush().ok();
+
+        let file = File::open(&self.input).map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(file);
+
+        let (ftyp, mut moov, other_boxes) = read_top_level_boxes(&mut reader)?;
+        let other_size: u64 = other_boxes.iter().map(Mp4Box::size).sum();
+
+        let mut tracks = Vec::new();
+
+        for trak in moov.children.iter_mut().filter(|b| &b.kind == b"trak") {
+            tracks.push(prepare_track(trak, rpus)?);
+        }
+
+        let hevc_track = match tracks.iter().position(|t| t.hevc.is_some()) {
+            Some(index) => index,
+            None => return Err("No HEVC track found".to_string()),
+        };
+
+        let sample_count = tracks[hevc_track].table.sizes.len();
+        if sample_count != rpus.len() {
+            return Err(format!(
+                "Number of samples of input and RPU file are different: {} and {}",
+                sample_count,
+                rpus.len()
+            ));
+        }
+
+        println!("Rewriting samples with RPU NALs...");
+        stdout().flush().ok();
+
+        let rpu_nals: Vec<Vec<u8>> = rpus.iter_mut().map(DoviRpu::write_rpu_data).collect();
+
+        // Rewritten sizes of the HEVC samples
+        let mut hevc_sizes = Vec::with_capacity(sample_count);
+        let hevc = tracks[hevc_track].hevc.as_ref().unwrap();
+
+        for (sample, offset) in sample_offsets(&tracks[hevc_track].table) {
+            let data = read_sample(&mut reader, offset, tracks[hevc_track].table.sizes[sample])?;
+            let rpu_nal = &rpu_nals[hevc.display_order[sample]];
+
+            hevc_sizes.push(rewrite_sample(&data, rpu_nal, hevc.length_size)?.len() as u32);
+        }
+
+        // Chunks of every track, written to the new mdat in their original order
+        let mut chunks: Vec<(u64, usize, usize, usize)> = Vec::new();
+
+        for (index, track) in tracks.iter().enumerate() {
+            let mut first_sample = 0;
+
+            for (chunk, &(offset, count)) in track.table.chunks.iter().enumerate() {
+                chunks.push((offset, index, chunk, first_sample));
+                first_sample += count;
+            }
+        }
+
+        chunks.sort_unstable();
+
+        let chunk_size = |track: usize, chunk: usize, first_sample: usize| -> u64 {
+            let count = tracks[track].table.chunks[chunk].1;
+            let sizes = if track == hevc_track {
+                &hevc_sizes
+            } else {
+                &tracks[track].table.sizes
+            };
+
+            sizes[first_sample..first_sample + count]
+                .iter()
+                .map(|&s| s as u64)
+                .sum()
+        };
+
+        let mdat_size: u64 = chunks
+            .iter()
+            .map(|&(_, track, chunk, first)| chunk_size(track, chunk, first))
+            .sum();
+
+        let mdat_header_size = if mdat_size + 8 > u32::MAX as u64 {
+            16
+        } else {
+            8
+        };
+
+        // Same moov size with placeholder offsets, 64 bits offsets if they don't fit
+        let traks = moov.children.iter_mut().filter(|b| &b.kind == b"trak");
+        for (trak, track) in traks.zip(&tracks) {
+            let sizes = track.hevc.as_ref().map(|_| hevc_sizes.as_slice());
+            set_sample_table(trak, sizes, &vec![0; track.table.chunks.len()], false)?;
+        }
+
+        let mdat_start = |moov: &Mp4Box| ftyp.size() + moov.size() + other_size + mdat_header_size;
+        let co64 = mdat_start(&moov) + mdat_size > u32::MAX as u64;
+
+        let mut new_offsets: Vec<Vec<u64>> = tracks
+            .iter()
+            .map(|t| vec![0; t.table.chunks.len()])
+            .collect();
+
+        let traks = moov.children.iter_mut().filter(|b| &b.kind == b"trak");
+        for (trak, offsets) in traks.zip(&new_offsets) {
+            set_sample_table(trak, None, offsets, co64)?;
+        }
+
+        let mut position = mdat_start(&moov);
+        for &(_, track, chunk, first_sample) in &chunks {
+            new_offsets[track][chunk] = position;
+            position += chunk_size(track, chunk, first_sample);
+        }
+
+        let traks = moov.children.iter_mut().filter(|b| &b.kind == b"trak");
+        for (trak, offsets) in traks.zip(&new_offsets) {
+            set_sample_table(trak, None, offsets, co64)?;
+        }
+
+        let mut writer = BufWriter::with_capacity(
+            100_000,
+            File::create(&self.output).map_err(|e| e.to_string())?,
+        );
+
+        let mut header = Vec::new();
+        ftyp.write(&mut header);
+        moov.write(&mut header);
+
+        for other in &other_boxes {
+            other.write(&mut header);
+        }
+
+        if mdat_header_size == 16 {
+            header.extend_from_slice(&1_u32.to_be_bytes());
+            header.extend_from_slice(b"mdat");
+            header.extend_from_slice(&(mdat_size + 16).to_be_bytes());
+        } else {
+            header.extend_from_slice(&((mdat_size + 8) as u32).to_be_bytes());
+            header.extend_from_slice(b"mdat");
+        }
+
+        writer.write_all(&header).map_err(|e| e.to_string())?;
+
+        for &(offset, track, chunk, first_sample) in &chunks {
+            let count = tracks[track].table.chunks[chunk].1;
+            let sizes = &tracks[track].table.sizes[first_sample..first_sample + count];
+
+            if track == hevc_track {
+                let mut sample_offset = offset;
+
+                for (sample, &size) in (first_sample..).zip(sizes) {
+                    let data = read_sample(&mut reader, sample_offset, size)?;
+                    let rpu_nal = &rpu_nals[hevc.display_order[sample]];
+
+                    let sample_data = rewrite_sample(&data, rpu_nal, hevc.length_size)?;
+                    writer.write_all(&sample_data).map_err(|e| e.to_string())?;
+
+                    sample_offset += size as u64;
+                }
+            } else {
+                let size: u64 = sizes.iter().map(|&s| s as u64).sum();
+                let data = read_sample(&mut reader, offset, size)?;
+
+                writer.write_all(&data).map_err(|e| e.to_string())?;
+            }
+        }
+
+        writer.flush().map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
+
+impl Mp4Box {
+    /// Size of the box, with its header
+    pub fn size(&self) -> u64 {
+        8 + self.data.len() as u64 + self.children.iter().map(Mp4Box::size).sum::<u64>()
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.size() as u32).to_be_bytes());
+        out.extend_from_slice(&self.kind);
+        out.extend_from_slice(&self.data);
+
+        for child in &self.children {
+            child.write(out);
+        }
+    }
+
+    pub fn child(&self, kind: &[u8; 4]) -> Option<&Mp4Box> {
+        self.children.iter().find(|b| &b.kind == kind)
+    }
+
+    fn child_mut(&mut self, kind: &[u8; 4]) -> Option<&mut Mp4Box> {
+        self.children.iter_mut().find(|b| &b.kind == kind)
+    }
+
+    /// Descendant box, following the path of box types
+    pub fn find(&self, path: &[&[u8; 4]]) -> Option<&Mp4Box> {
+        path.iter().try_fold(self, |b, kind| b.child(kind))
+    }
+
+    fn find_mut(&mut self, path: &[&[u8; 4]]) -> Option<&mut Mp4Box> {
+        path.iter().try_fold(self, |b, kind| b.child_mut(kind))
+    }
+}
+
+/// Parses consecutive boxes, the containers of the sample tables recursively
+pub fn parse_boxes(data: &[u8]) -> Result<Vec<Mp4Box>, String> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let (header_size, size) = match data.get(offset..offset + 8) {
+            Some(header) => box_size(header, &data[offset..])?,
+            None => return Err("Truncated MP4 box header".to_string()),
+        };
+
+        let size = size.unwrap_or((data.len() - offset) as u64) as usize;
+
+        if size < header_size || offset + size > data.len() {
+            return Err("Invalid MP4 box size".to_string());
+        }
+
+        let kind: [u8; 4] = data[offset + 4..offset + 8].try_into().unwrap();
+        let content = &data[offset + header_size..offset + size];
+
+        boxes.push(parse_box(kind, content)?);
+        offset += size;
+    }
+
+    Ok(boxes)
+}
+
+fn parse_box(kind: [u8; 4], content: &[u8]) -> Result<Mp4Box, String> {
+    let fields_size = CONTAINERS.iter().find(|(k, _)| *k == &kind).map(|c| c.1);
+
+    match fields_size {
+        Some(fields_size) if content.len() >= fields_size => Ok(Mp4Box {
+            kind,
+            data: content[..fields_size].to_vec(),
+            children: parse_boxes(&content[fields_size..])?,
+        }),
+        Some(_) => Err(format!("Truncated {} box", String::from_utf8_lossy(&kind))),
+        None => Ok(Mp4Box {
+            kind,
+            data: content.to_vec(),
+            children: Vec::new(),
+        }),
+    }
+}
+
+/// Header size and box size, `None` for a box extending to the end of the file
+fn box_size(header: &[u8], data: &[u8]) -> Result<(usize, Option<u64>), String> {
+    match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+        0 => Ok((8, None)),
+        1 => match data.get(8..16) {
+            Some(size) => Ok((16, Some(u64::from_be_bytes(size.try_into().unwrap())))),
+            None => Err("Truncated MP4 box header".to_string()),
+        },
+        size => Ok((8, Some(size as u64))),
+    }
+}
+
+/// Reads the `ftyp` and `moov` boxes, and the other top level boxes to copy, skipping the media data
+fn read_top_level_boxes<R: Read + Seek>(
+    reader: &mut R,
+) -> Result<(Mp4Box, Mp4Box, Vec<Mp4Box>), String> {
+    let file_size = reader.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+    let mut offset = 0;
+
+    let mut ftyp = None;
+    let mut moov = None;
+    let mut other_boxes = Vec::new();
+
+    while offset < file_size {
+        let mut header = [0; 16];
+        let available = (file_size - offset).min(16) as usize;
+
+        reader
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| reader.read_exact(&mut header[..available]))
+            .map_err(|e| e.to_string())?;
+
+        if available < 8 {
+            return Err("Truncated MP4 box header".to_string());
+        }
+
+        let (header_size, size) = box_size(&header, &header[..available])?;
+        let size = size.unwrap_or(file_size - offset);
+
+        if size < header_size as u64 || offset + size > file_size {
+            return Err("Invalid MP4 box size".to_string());
+        }
+
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+
+        match &kind {
+            b"ftyp" | b"moov" => {
+                let content = read_sample(
+                    reader,
+                    offset + header_size as u64,
+                    size - header_size as u64,
+                )?;
+                let parsed = parse_box(kind, &content)?;
+
+                if &kind == b"ftyp" {
+                    ftyp = Some(parsed);
+                } else {
+                    moov = Some(parsed);
+                }
+            }
+            b"moof" => return Err("Fragmented MP4 isn't supported".to_string()),
+            b"mdat" => (),
+            // Copied untouched, before the new mdat
+            _ => {
+                let content = read_sample(
+                    reader,
+                    offset + header_size as u64,
+                    size - header_size as u64,
+                )?;
+
+                other_boxes.push(Mp4Box {
+                    kind,
+                    data: content,
+                    children: Vec::new(),
+                });
+            }
+        }
+
+        offset += size;
+    }
+
+    match (ftyp, moov) {
+        (Some(ftyp), Some(moov)) => Ok((ftyp, moov, other_boxes)),
+        (None, _) => Err("Missing ftyp box, not an MP4 file".to_string()),
+        (_, None) => Err("Missing moov box".to_string()),
+    }
+}
+
+fn read_sample<R: Read + Seek, S: Into<u64>>(
+    reader: &mut R,
+    offset: u64,
+    size: S,
+) -> Result<Vec<u8>, String> {
+    let mut data = vec![0; size.into() as usize];
+
+    reader
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| reader.read_exact(&mut data))
+        .map_err(|e| format!("Can't read MP4 sample data: {}", e))?;
+
+    Ok(data)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    match data.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_be_bytes(bytes.try_into().unwrap())),
+        None => Err("Truncated MP4 sample table".to_string()),
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, String> {
+    match data.get(offset..offset + 8) {
+        Some(bytes) => Ok(u64::from_be_bytes(bytes.try_into().unwrap())),
+        None => Err("Truncated MP4 sample table".to_string()),
+    }
+}
+
+/// Entries of a full box table, each `entry_size` bytes after the version, flags and count
+fn table_entries(
+    table: &Mp4Box,
+    header_size: usize,
+    entry_size: usize,
+) -> Result<impl Iterator<Item = &[u8]>, String> {
+    let count = read_u32(&table.data, header_size - 4)? as usize;
+    let entries = table
+        .data
+        .get(header_size..header_size + count * entry_size);
+
+    match entries {
+        Some(entries) => Ok(entries.chunks_exact(entry_size)),
+        None => Err(format!(
+            "Truncated {} box",
+            String::from_utf8_lossy(&table.kind)
+        )),
+    }
+}
+
+fn stbl_child<'a>(stbl: &'a Mp4Box, kind: &[u8; 4]) -> Result<&'a Mp4Box, String> {
+    stbl.child(kind)
+        .ok_or_else(|| format!("Missing {} box", String::from_utf8_lossy(kind)))
+}
+
+/// Reads the sample table of a track, and adds the `dvvC` box if it's the HEVC track
+fn prepare_track(trak: &mut Mp4Box, rpus: &[DoviRpu]) -> Result<Track, String> {
+    let stbl = trak
+        .find(&[b"mdia", b"minf", b"stbl"])
+        .ok_or("Missing stbl box")?;
+
+    let table = read_sample_table(stbl)?;
+
+    let is_hevc = stbl
+        .child(b"stsd")
+        .and_then(|stsd| stsd.children.first())
+        .is_some_and(|entry| matches!(&entry.kind, b"hvc1" | b"hev1"));
+
+    if !is_hevc {
+        return Ok(Track { table, hevc: None });
+    }
+
+    let display_order = display_order(stbl, table.sizes.len())?;
+
+    let timescale = trak
+        .find(&[b"mdia", b"mdhd"])
+        .map(|mdhd| match mdhd.data.first() {
+            Some(1) => read_u32(&mdhd.data, 20),
+            _ => read_u32(&mdhd.data, 12),
+        })
+        .ok_or("Missing mdhd box")??;
+
+    let duration: u64 = table_entries(stbl_child(stbl, b"stts")?, 8, 8)?
+        .map(|e| read_u32(e, 0).unwrap() as u64 * read_u32(e, 4).unwrap() as u64)
+        .sum();
+
+    let fps = if duration > 0 {
+        table.sizes.len() as f64 * timescale as f64 / duration as f64
+    } else {
+        24.0
+    };
+
+    let compat_id = rpus
+        .first()
+        .and_then(DoviRpu::profile8_compatibility_id)
+        .unwrap_or(1);
+
+    let entry = trak
+        .find_mut(&[b"mdia", b"minf", b"stbl", b"stsd"])
+        .and_then(|stsd| stsd.children.first_mut())
+        .unwrap();
+
+    let length_size = match entry.child(b"hvcC").and_then(|hvcc| hvcc.data.get(21)) {
+        Some(byte) => (byte & 0x3) as usize + 1,
+        None => return Err("Missing hvcC box".to_string()),
+    };
+
+    let width = u16::from_be_bytes(entry.data[24..26].try_into().unwrap());
+    let height = u16::from_be_bytes(entry.data[26..28].try_into().unwrap());
+
+    entry
+        .children
+        .retain(|b| &b.kind != b"dvcC" && &b.kind != b"dvvC");
+    entry
+        .children
+        .push(dvvc_box(dovi_level(width, height, fps), compat_id));
+
+    Ok(Track {
+        table,
+        hevc: Some(HevcTrack {
+            length_size,
+            display_order,
+        }),
+    })
+}
+
+fn read_sample_table(stbl: &Mp4Box) -> Result<SampleTable, String> {
+    let stsz = stbl_child(stbl, b"stsz")?;
+    let sample_size = read_u32(&stsz.data, 4)?;
+
+    let sizes: Vec<u32> = if sample_size == 0 {
+        table_entries(stsz, 12, 4)?
+            .map(|e| read_u32(e, 0).unwrap())
+            .collect()
+    } else {
+        vec![sample_size; read_u32(&stsz.data, 8)? as usize]
+    };
+
+    let offsets: Vec<u64> = if let Some(stco) = stbl.child(b"stco") {
+        table_entries(stco, 8, 4)?
+            .map(|e| read_u32(e, 0).unwrap() as u64)
+            .collect()
+    } else {
+        table_entries(stbl_child(stbl, b"co64")?, 8, 8)?
+            .map(|e| read_u64(e, 0).unwrap())
+            .collect()
+    };
+
+    // First chunk (1 based) and samples per chunk
+    let stsc: Vec<(usize, usize)> = table_entries(stbl_child(stbl, b"stsc")?, 8, 12)?
+        .map(|e| {
+            (
+                read_u32(e, 0).unwrap() as usize,
+                read_u32(e, 4).unwrap() as usize,
+            )
+        })
+        .collect();
+
+    let chunks: Vec<(u64, usize)> = offsets
+        .iter()
+        .enumerate()
+        .map(|(index, &offset)| {
+            let count = stsc
+                .iter()
+                .rev()
+                .find(|(first_chunk, _)| *first_chunk <= index + 1)
+                .map_or(0, |e| e.1);
+
+            (offset, count)
+        })
+        .collect();
+
+    if chunks.iter().map(|c| c.1).sum::<usize>() != sizes.len() {
+        return Err("Inconsistent MP4 sample table".to_string());
+    }
+
+    Ok(SampleTable { sizes, chunks })
+}
+
+/// Display index of the samples from their composition time, in decoding order
+fn display_order(stbl: &Mp4Box, sample_count: usize) -> Result<Vec<usize>, String> {
+    // Run-length tables of sample count and value
+    let expand = |table: &Mp4Box| -> Result<Vec<u32>, String> {
+        let mut values = Vec::with_capacity(sample_count);
+
+        for e in table_entries(table, 8, 8)? {
+            let count = read_u32(e, 0)? as usize;
+            values.resize(values.len() + count, read_u32(e, 4)?);
+        }
+
+        Ok(values)
+    };
+
+    let deltas = expand(stbl_child(stbl, b"stts")?)?;
+    let offsets = match stbl.child(b"ctts") {
+        Some(ctts) => expand(ctts)?,
+        None => Vec::new(),
+    };
+
+    if deltas.len() < sample_count {
+        return Err("Inconsistent MP4 time to sample table".to_string());
+    }
+
+    let mut decode_time = 0;
+    let mut composition_times: Vec<(i64, usize)> = Vec::with_capacity(sample_count);
+
+    for (sample, delta) in deltas.iter().take(sample_count).enumerate() {
+        // Version 0 offsets are unsigned but usually written as signed
+        let offset = offsets.get(sample).map_or(0, |&o| o as i32 as i64);
+
+        composition_times.push((decode_time + offset, sample));
+        decode_time += *delta as i64;
+    }
+
+    composition_times.sort_unstable();
+
+    let mut order = vec![0; sample_count];
+    for (display_index, (_, sample)) in composition_times.into_iter().enumerate() {
+        order[sample] = display_index;
+    }
+
+    Ok(order)
+}
+
+/// Sample index and offset of every sample of the track
+fn sample_offsets(table: &SampleTable) -> Vec<(usize, u64)> {
+    let mut samples = Vec::with_capacity(table.sizes.len());
+    let mut sample = 0;
+
+    for &(offset, count) in &table.chunks {
+        let mut offset = offset;
+
+        for size in &table.sizes[sample..sample + count] {
+            samples.push((sample, offset));
+
+            offset += *size as u64;
+            sample += 1;
+        }
+    }
+
+    samples
+}
+
+/// Replaces the sample sizes if changed, and the chunk offsets
+fn set_sample_table(
+    trak: &mut Mp4Box,
+    sizes: Option<&[u32]>,
+    offsets: &[u64],
+    co64: bool,
+) -> Result<(), String> {
+    let stbl = trak
+        .find_mut(&[b"mdia", b"minf", b"stbl"])
+        .ok_or("Missing stbl box")?;
+
+    if let Some(sizes) = sizes {
+        let stsz = stbl.child_mut(b"stsz").ok_or("Missing stsz box")?;
+
+        stsz.data.truncate(4);
+        stsz.data.extend_from_slice(&0_u32.to_be_bytes());
+        stsz.data
+            .extend_from_slice(&(sizes.len() as u32).to_be_bytes());
+        sizes
+            .iter()
+            .for_each(|s| stsz.data.extend_from_slice(&s.to_be_bytes()));
+    }
+
+    let mut data = vec![0; 4];
+    data.extend_from_slice(&(offsets.len() as u32).to_be_bytes());
+
+    for &offset in offsets {
+        if co64 {
+            data.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            data.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+    }
+
+    stbl.children
+        .retain(|b| &b.kind != b"stco" && &b.kind != b"co64");
+    stbl.children.push(Mp4Box {
+        kind: if co64 { *b"co64" } else { *b"stco" },
+        data,
+        children: Vec::new(),
+    });
+
+    Ok(())
+}
+
+/// Sample with the RPU NAL appended, existing RPU and EL NALs removed
+fn rewrite_sample(data: &[u8], rpu_nal: &[u8], length_size: usize) -> Result<Vec<u8>, String> {
+    let mut sample = Vec::with_capacity(data.len() + rpu_nal.len() + length_size);
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let length = match data.get(offset..offset + length_size) {
+            Some(bytes) => bytes.iter().fold(0, |acc, &b| (acc << 8) | b as usize),
+            None => return Err("Truncated NAL length in MP4 sample".to_string()),
+        };
+
+        let end = offset + length_size + length;
+        let nal = match data.get(offset + length_size..end) {
+            Some(nal) => nal,
+            None => return Err("Truncated NAL in MP4 sample".to_string()),
+        };
+
+        let nal_type = nal.first().map_or(0, |b| (b >> 1) & 0x3F);
+
+        if nal_type != 62 && nal_type != 63 {
+            sample.extend_from_slice(&data[offset..end]);
+        }
+
+        offset = end;
+    }
+
+    let length = rpu_nal.len().to_be_bytes();
+    sample.extend_from_slice(&length[length.len() - length_size..]);
+    sample.extend_from_slice(rpu_nal);
+
+    Ok(sample)
+}
+
+/// Lowest level allowing the resolution and frame rate
+fn dovi_level(width: u16, height: u16, fps: f64) -> u8 {
+    let pixel_rate = (width as f64 * height as f64 * fps).round() as u64;
+
+    DOVI_LEVELS
+        .iter()
+        .position(|&(max_rate, max_width)| pixel_rate <= max_rate && width <= max_width)
+        .map_or(DOVI_LEVELS.len(), |index| index + 1) as u8
+}
+
+/// Dolby Vision configuration box of a profile 8 stream, with a BL and RPU
+fn dvvc_box(level: u8, compat_id: u8) -> Mp4Box {
+    let mut data = vec![0; 24];
+
+    // dv_version_major, dv_version_minor
+    data[0] = 1;
+    data[1] = 0;
+
+    // dv_profile, dv_level, rpu_present_flag, el_present_flag, bl_present_flag
+    let flags: u16 = (8 << 9) | ((level as u16) << 3) | 0b101;
+    data[2..4].copy_from_slice(&flags.to_be_bytes());
+
+    // dv_bl_signal_compatibility_id
+    data[4] = compat_id << 4;
+
+    Mp4Box {
+        kind: *b"dvvC",
+        data,
+        children: Vec::new(),
+    }
+}
@@ -1,5 +1,8 @@
+pub(crate) mod pq;
 pub(crate) mod rpu_data;
 pub(crate) mod rpu_data_header;
+pub(crate) mod rpu_stream;
+#[cfg(test)]
 mod tests;
 pub(crate) mod vdr_dm_data;
 pub(crate) mod vdr_rpu_data;
@@ -9,31 +12,703 @@ pub(crate) use rpu_data::DoviRpu;
 use rpu_data_header::RpuDataHeader;
 
 use super::{BitVecReader, BitVecWriter};
-use hevc_parser::utils::{
-    add_start_code_emulation_prevention_3_byte, clear_start_code_emulation_prevention_3_byte,
-};
+use std::collections::{BTreeMap, BTreeSet};
 
 #[inline(always)]
-pub fn parse_dovi_rpu(data: &[u8]) -> Result<DoviRpu, String> {
-    // Clear start code emulation prevention 3 byte
-    let bytes: Vec<u8> = clear_start_code_emulation_prevention_3_byte(&data[2..]);
+pub fn parse_dovi_rpu(data: &[u8], lenient: bool) -> Result<DoviRpu, String> {
+    if data.len() < 2 {
+        return Err(format!("Invalid RPU: too short ({} bytes)", data.len()));
+    }
 
-    let len = bytes.len();
+    let (bytes, trailing_zeroes) = unescape_rpu_payload(&data[2..])?;
 
-    let mut received_crc32 = DoviRpu::compute_crc32(&bytes[1..len - 5]);
-    let last_byte = bytes[len - 1];
+    // Check the CRC32 before parsing, a corrupted payload can't be parsed reliably
+    let (stored_crc32, received_crc32) = payload_crc32(&bytes, trailing_zeroes);
 
-    // Final RPU exception
-    if last_byte == 0 && bytes[len - 2] == 0x80 {
-        received_crc32 = DoviRpu::compute_crc32(&bytes[1..len - 6]);
-    } else if last_byte != 0x80 {
-        return Err(format!("Invalid RPU\n{:?}", &bytes));
+    if received_crc32 != stored_crc32 {
+        return Err(format!(
+            "Invalid RPU: CRC32 mismatch, computed {:#010x}, stored {:#010x}",
+            received_crc32, stored_crc32
+        ));
     }
 
-    let mut dovi_rpu = DoviRpu::read_rpu_data(bytes, last_byte);
-    assert_eq!(received_crc32, dovi_rpu.rpu_data_crc32);
+    let mut dovi_rpu = DoviRpu::read_rpu_data(bytes, trailing_zeroes, lenient)?;
+
+    if received_crc32 != dovi_rpu.rpu_data_crc32 {
+        return Err("Invalid RPU: payload size doesn't match the CRC32 position".to_string());
+    }
 
     dovi_rpu.dovi_profile = dovi_rpu.header.get_dovi_profile();
 
     Ok(dovi_rpu)
 }
+
+/// Stored and computed CRC32 of an RPU NAL, without the 0x7C01 NAL header.
+/// Only the NAL structure is checked, the payload isn't parsed.
+pub fn rpu_crc32(payload: &[u8]) -> Result<(u32, u32), String> {
+    let (bytes, trailing_zeroes) = unescape_rpu_payload(payload)?;
+
+    Ok(payload_crc32(&bytes, trailing_zeroes))
+}
+
+/// Repairs an RPU NAL, without the 0x7C01 NAL header, lacking the 0x80 terminator byte after the CRC32.
+/// Returns `None` when the terminator is there, else the payload with the terminator appended,
+/// or replacing the corrupted byte after the CRC32. The zero bytes after the CRC32 are kept after the terminator.
+///
+/// The CRC32 is located by matching it to the payload before it, an RPU without a valid CRC32 can't be repaired.
+pub fn repair_rpu_terminator(payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let bytes = clear_start_code_emulation_prevention_3_byte(payload);
+    let zeroes = bytes.iter().rev().take_while(|b| **b == 0).count();
+
+    // The CRC32 ends before the terminator, or before zero bytes when it's missing.
+    // It may end with zero bytes itself.
+    let crc32_end = (bytes.len() - zeroes).saturating_sub(1)..=bytes.len();
+
+    let crc32_end = crc32_end
+        .filter(|end| *end >= 5)
+        .find(|end| {
+            let stored_crc32 = u32::from_be_bytes([
+                bytes[end - 4],
+                bytes[end - 3],
+                bytes[end - 2],
+                bytes[end - 1],
+            ]);
+
+            DoviRpu::compute_crc32(&bytes[1..end - 4]) == stored_crc32
+        })
+        .ok_or("Invalid RPU: CRC32 not found, the terminator can't be repaired")?;
+
+    if bytes.get(crc32_end) == Some(&0x80) {
+        return Ok(None);
+    }
+
+    let mut repaired = bytes[..crc32_end].to_vec();
+    repaired.push(0x80);
+    add_start_code_emulation_prevention_3_byte(&mut repaired);
+
+    // Whether missing or zeroed, the zero bytes after the CRC32 are trailing zeroes
+    let trailing_zeroes = (bytes.len() - crc32_end).min(zeroes);
+    repaired.resize(repaired.len() + trailing_zeroes, 0);
+
+    Ok(Some(repaired))
+}
+
+/// Parses the DM metadata alone, from unescaped bytes starting at affected_dm_metadata_id.
+/// The ext blocks are parsed up to the end of the data, there's no CRC32 nor terminator.
+#[cfg(test)]
+pub fn parse_vdr_dm_data(data: &[u8]) -> Result<vdr_dm_data::VdrDmData, String> {
+    let mut reader = BitVecReader::new(data.to_vec());
+    let mut warnings = Vec::new();
+
+    vdr_dm_data::VdrDmData::vdr_dm_data_payload(
+        &mut reader,
+        0,
+        rpu_data::MAX_EXT_BLOCKS,
+        false,
+        &mut warnings,
+    )
+}
+
+/// Clears the emulation prevention bytes of an RPU NAL payload, from the rpu_nal_prefix.
+/// Returns the payload and the number of zero bytes after the terminator byte, kept at the end.
+fn unescape_rpu_payload(payload: &[u8]) -> Result<(Vec<u8>, usize), String> {
+    // Zero bytes after the terminator byte: final RPU exception or padding
+    let trailing_zeroes = payload.iter().rev().take_while(|b| **b == 0).count();
+
+    // Clear start code emulation prevention 3 byte
+    let mut bytes: Vec<u8> =
+        clear_start_code_emulation_prevention_3_byte(&payload[..payload.len() - trailing_zeroes]);
+
+    // rpu_nal_prefix, CRC32 and terminator byte
+    if bytes.len() < 6 {
+        return Err(format!("Invalid RPU: too short ({} bytes)", bytes.len()));
+    }
+
+    if bytes[bytes.len() - 1] != 0x80 {
+        return Err(format!(
+            "Invalid RPU: no 0x80 terminator byte, ends with {:#04x}",
+            bytes[bytes.len() - 1]
+        ));
+    }
+
+    bytes.resize(bytes.len() + trailing_zeroes, 0);
+
+    Ok((bytes, trailing_zeroes))
+}
+
+/// Inserts an emulation_prevention_three_byte after every two zero bytes followed by a byte up to 3,
+/// so that the payload contains no start code prefix.
+pub fn add_start_code_emulation_prevention_3_byte(data: &mut Vec<u8>) {
+    let mut escaped = Vec::with_capacity(data.len() + data.len() / 64);
+    let mut zeroes = 0;
+
+    for &byte in data.iter() {
+        if zeroes >= 2 && byte <= 3 {
+            escaped.push(3);
+            zeroes = 0;
+        }
+
+        escaped.push(byte);
+        zeroes = if byte == 0 { zeroes + 1 } else { 0 };
+    }
+
+    *data = escaped;
+}
+
+/// Removes the emulation_prevention_three_byte following every two zero bytes,
+/// the inverse of `add_start_code_emulation_prevention_3_byte`.
+pub fn clear_start_code_emulation_prevention_3_byte(data: &[u8]) -> Vec<u8> {
+    let mut zeroes = 0;
+
+    data.iter()
+        .filter(|&&byte| {
+            if zeroes >= 2 && byte == 3 {
+                zeroes = 0;
+                return false;
+            }
+
+            zeroes = if byte == 0 { zeroes + 1 } else { 0 };
+            true
+        })
+        .copied()
+        .collect()
+}
+
+/// Stored and computed CRC32 of an unescaped payload
+fn payload_crc32(bytes: &[u8], trailing_zeroes: usize) -> (u32, u32) {
+    let crc32_end = bytes.len() - trailing_zeroes - 1;
+
+    let stored_crc32 = u32::from_be_bytes([
+        bytes[crc32_end - 4],
+        bytes[crc32_end - 3],
+        bytes[crc32_end - 2],
+        bytes[crc32_end - 1],
+    ]);
+
+    (
+        stored_crc32,
+        DoviRpu::compute_crc32(&bytes[1..crc32_end - 4]),
+    )
+}
+
+/// Serializes an RPU stream to the JSON RPU list format
+pub fn to_rpu_list_json(rpus: &[DoviRpu]) -> Result<String, serde_json::Error> {
+    serde_json::to_string(rpus)
+}
+
+/// Deserializes an RPU stream from the JSON RPU list format.
+/// The CRC32 is recomputed when writing the imported RPUs.
+pub fn from_rpu_list_json(json: &str, lenient: bool) -> Result<Vec<DoviRpu>, serde_json::Error> {
+    let mut rpus: Vec<DoviRpu> = serde_json::from_str(json)?;
+
+    for rpu in rpus.iter_mut() {
+        rpu.modified = true;
+        rpu.validate(lenient).map_err(serde::de::Error::custom)?;
+    }
+
+    Ok(rpus)
+}
+
+/// Serializes an RPU stream to MessagePack, with the fields of the JSON RPU list
+pub fn to_rpu_list_msgpack(rpus: &[DoviRpu]) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    // Named fields, the optional ones can be missing
+    rmp_serde::to_vec_named(rpus)
+}
+
+/// Deserializes an RPU stream from MessagePack, like `from_rpu_list_json`
+pub fn from_rpu_list_msgpack(
+    data: &[u8],
+    lenient: bool,
+) -> Result<Vec<DoviRpu>, rmp_serde::decode::Error> {
+    let mut rpus: Vec<DoviRpu> = rmp_serde::from_slice(data)?;
+
+    for rpu in rpus.iter_mut() {
+        rpu.modified = true;
+        rpu.validate(lenient)
+            .map_err(rmp_serde::decode::Error::Syntax)?;
+    }
+
+    Ok(rpus)
+}
+
+/// Counts the frames trimmed for each L2 target_max_pq
+pub fn l2_target_counts(rpus: &[DoviRpu]) -> BTreeMap<u16, usize> {
+    let mut counts = BTreeMap::new();
+
+    for vdr_dm_data in rpus.iter().filter_map(|rpu| rpu.vdr_dm_data.as_ref()) {
+        let targets: BTreeSet<u16> = vdr_dm_data.l2_targets().collect();
+
+        for target_max_pq in targets {
+            *counts.entry(target_max_pq).or_insert(0) += 1;
+        }
+    }
+
+    counts
+}
+
+/// Finds the runs of consecutive identical RPUs, as (first frame, length) pairs
+pub fn duplicate_runs(rpus: &[DoviRpu]) -> Vec<(usize, usize)> {
+    let serialized: Vec<String> = rpus
+        .iter()
+        .map(|rpu| serde_json::to_string(rpu).unwrap())
+        .collect();
+
+    let mut runs = Vec::new();
+    let mut start = 0;
+
+    for i in 1..=serialized.len() {
+        if i == serialized.len() || serialized[i] != serialized[start] {
+            if i - start > 1 {
+                runs.push((start, i - start));
+            }
+
+            start = i;
+        }
+    }
+
+    runs
+}
+
+/// Replaces the mapping of every RPU repeating the mapping of the previous one by a reference to it.
+/// Unlike `dedup_mappings`, the DM metadata can differ. Returns the number of replaced mappings.
+///
+/// The references follow the order of the list, which must be the decoding order.
+pub fn reuse_previous_mappings(rpus: &mut [DoviRpu]) -> usize {
+    let mut count = 0;
+    // RPU defining the last mapping
+    let mut source: Option<usize> = None;
+
+    for index in 0..rpus.len() {
+        let header = &rpus[index].header;
+
+        if header.rpu_type != 2 || header.use_prev_vdr_rpu_flag {
+            continue;
+        }
+
+        match source {
+            Some(source) if same_mapping(&rpus[source], &rpus[index]) => {
+                let vdr_rpu_id = rpus[source].header.vdr_rpu_id;
+
+                rpus[index].use_prev_mapping(vdr_rpu_id);
+                count += 1;
+            }
+            _ => source = Some(index),
+        }
+    }
+
+    count
+}
+
+/// Whether the RPUs have the same mapping and header, the vdr_rpu_id and DM metadata aside
+fn same_mapping(a: &DoviRpu, b: &DoviRpu) -> bool {
+    let mapping = |rpu: &DoviRpu| {
+        let mut header = serde_json::to_value(&rpu.header).unwrap();
+
+        if let serde_json::Value::Object(ref mut header) = header {
+            header.remove("vdr_rpu_id");
+            header.remove("vdr_dm_metadata_present_flag");
+        }
+
+        (
+            header,
+            serde_json::to_value(&rpu.vdr_rpu_data).unwrap(),
+            serde_json::to_value(&rpu.nlq_data).unwrap(),
+        )
+    };
+
+    mapping(a) == mapping(b)
+}
+
+/// Reduces the RPUs to the smallest stream decoding to the same metadata:
+/// the repeated mappings reference the previous one, and the RPUs are minified, see `DoviRpu::minify`.
+/// Returns the total size of the written RPUs, before and after.
+pub fn minify_rpus(rpus: &mut [DoviRpu]) -> (usize, usize) {
+    let size = |rpus: &mut [DoviRpu]| -> usize {
+        rpus.iter_mut().map(|rpu| rpu.write_rpu_data().len()).sum()
+    };
+
+    let original_size = size(rpus);
+
+    reuse_previous_mappings(rpus);
+    rpus.iter_mut().for_each(DoviRpu::minify);
+
+    (original_size, size(rpus))
+}
+
+/// Replaces the mapping of the repeated RPUs of every run by a reference to the first RPU.
+/// Returns the number of RPUs now using `use_prev_vdr_rpu_flag`.
+///
+/// The references follow the order of the list, which must be the decoding order.
+pub fn dedup_mappings(rpus: &mut [DoviRpu]) -> usize {
+    let mut count = 0;
+
+    for (start, len) in duplicate_runs(rpus) {
+        let header = &rpus[start].header;
+
+        if header.rpu_type != 2 || header.use_prev_vdr_rpu_flag {
+            continue;
+        }
+
+        let vdr_rpu_id = header.vdr_rpu_id;
+
+        for rpu in &mut rpus[start + 1..start + len] {
+            rpu.use_prev_mapping(vdr_rpu_id);
+            count += 1;
+        }
+    }
+
+    count
+}
+
+/// Combines two streams frame by frame: the header, mapping and NLQ data of `mapping_source`,
+/// with the DM metadata of `dm_source`. Both must have the same number of frames.
+///
+/// The frames of `dm_source` without DM metadata stay without, inheriting the previous DM metadata as in `dm_source`.
+///
+/// The unparsed bits after the DM metadata belong to it, those of `mapping_source` are dropped.
+/// They can't be moved with the DM metadata, their alignment depends on the mapping size,
+/// so a `dm_source` frame with unparsed bits errors.
+pub fn merge_rpus(
+    mapping_source: &[DoviRpu],
+    dm_source: &[DoviRpu],
+) -> Result<Vec<DoviRpu>, String> {
+    if mapping_source.len() != dm_source.len() {
+        return Err(format!(
+            "Frame counts differ: {} frames for the mapping, {} for the DM metadata",
+            mapping_source.len(),
+            dm_source.len()
+        ));
+    }
+
+    mapping_source
+        .iter()
+        .zip(dm_source)
+        .enumerate()
+        .map(|(frame, (mapping_rpu, dm_rpu))| {
+            if mapping_rpu.header.rpu_type != 2 {
+                return Err(format!(
+                    "Frame {}: rpu_type {} can't carry DM metadata",
+                    frame, mapping_rpu.header.rpu_type
+                ));
+            }
+
+            if dm_rpu.remaining.any() {
+                return Err(format!(
+                    "Frame {}: the DM metadata is followed by {} unparsed bits, they can't be merged",
+                    frame,
+                    dm_rpu.remaining.len()
+                ));
+            }
+
+            let mut rpu = mapping_rpu.clone();
+            rpu.remaining.clear();
+            rpu.set_dm_data(dm_rpu.vdr_dm_data.clone());
+
+            Ok(rpu)
+        })
+        .collect()
+}
+
+/// Moves the scene cuts near a boundary of the `shots` frame list onto the nearest boundary,
+/// at most `tolerance` frames away. The earlier boundary wins a tie.
+///
+/// Scene cuts farther from every boundary, or whose boundary frame has no DM metadata, are kept.
+/// Returns the moved cuts, as (original frame, new frame) pairs.
+pub fn snap_scene_cuts(
+    rpus: &mut [DoviRpu],
+    shots: &[usize],
+    tolerance: usize,
+) -> Vec<(usize, usize)> {
+    let boundaries: BTreeSet<usize> = shots.iter().copied().collect();
+
+    let is_scene_cut = |rpu: &DoviRpu| {
+        rpu.vdr_dm_data
+            .as_ref()
+            .is_some_and(|vdr_dm_data| vdr_dm_data.is_scene_cut())
+    };
+
+    // Decided from the original cuts, before moving any
+    let moves: Vec<(usize, usize)> = rpus
+        .iter()
+        .enumerate()
+        .filter(|(_, rpu)| is_scene_cut(rpu))
+        .filter_map(|(frame, _)| {
+            let nearest = boundaries
+                .range(frame.saturating_sub(tolerance)..=frame.saturating_add(tolerance))
+                .min_by_key(|boundary| boundary.abs_diff(frame))?;
+
+            let movable = rpus
+                .get(*nearest)
+                .is_some_and(|rpu| rpu.vdr_dm_data.is_some());
+
+            if *nearest != frame && movable {
+                Some((frame, *nearest))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    for (from, _) in &moves {
+        rpus[*from].set_scene_cut(false);
+    }
+
+    for (_, to) in &moves {
+        rpus[*to].set_scene_cut(true);
+    }
+
+    moves
+}
+
+/// Dolby Vision profile of a configuration, with the profile 8 variant
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DoviProfile {
+    /// Dual layer, SDR compatible BL
+    Profile4,
+    /// Single layer IPTPQc2, without compatible BL
+    Profile5,
+    /// Dual layer, HDR10 compatible BL with a MEL or FEL
+    Profile7,
+    /// Single layer, HDR10 compatible BL
+    Profile81,
+    /// Single layer, SDR compatible BL
+    Profile82,
+    /// Single layer, HLG compatible BL
+    Profile84,
+    /// Single layer AVC, SDR compatible BL
+    Profile9,
+    /// Single layer AV1, without compatible BL
+    Profile10,
+    /// Single layer AV1, HDR10 compatible BL
+    Profile101,
+    /// Single layer AV1, SDR compatible BL
+    Profile102,
+    /// Single layer AV1, HLG compatible BL
+    Profile104,
+    /// Configuration not describing a known profile
+    Unknown {
+        profile: u8,
+        bl_compat_id: Option<u8>,
+    },
+}
+
+impl std::fmt::Display for DoviProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DoviProfile::Profile4 => write!(f, "4"),
+            DoviProfile::Profile5 => write!(f, "5"),
+            DoviProfile::Profile7 => write!(f, "7"),
+            DoviProfile::Profile81 => write!(f, "8.1"),
+            DoviProfile::Profile82 => write!(f, "8.2"),
+            DoviProfile::Profile84 => write!(f, "8.4"),
+            DoviProfile::Profile9 => write!(f, "9"),
+            DoviProfile::Profile10 => write!(f, "10"),
+            DoviProfile::Profile101 => write!(f, "10.1"),
+            DoviProfile::Profile102 => write!(f, "10.2"),
+            DoviProfile::Profile104 => write!(f, "10.4"),
+            DoviProfile::Unknown { profile, .. } => write!(f, "{} (unknown)", profile),
+        }
+    }
+}
+
+/// Profile of a Dolby Vision configuration record or stream descriptor, without any RPU.
+///
+/// Profile 8 requires its dv_bl_signal_compatibility_id: 1 (HDR10), 2 (SDR) or 4 (HLG).
+/// Profile 10 also allows 0, without compatible BL, and profile 9 only allows 2 (SDR).
+/// The other profiles have a single variant, their compatibility id isn't checked.
+/// Levels outside of 1 to 13 make the configuration unknown.
+pub fn profile_from_config(dv_profile: u8, dv_level: u8, bl_compat_id: Option<u8>) -> DoviProfile {
+    let unknown = DoviProfile::Unknown {
+        profile: dv_profile,
+        bl_compat_id,
+    };
+
+    if !(1..=13).contains(&dv_level) {
+        return unknown;
+    }
+
+    match (dv_profile, bl_compat_id) {
+        (4, _) => DoviProfile::Profile4,
+        (5, _) => DoviProfile::Profile5,
+        (7, _) => DoviProfile::Profile7,
+        (8, Some(1)) => DoviProfile::Profile81,
+        (8, Some(2)) => DoviProfile::Profile82,
+        (8, Some(4)) => DoviProfile::Profile84,
+        (9, Some(2)) => DoviProfile::Profile9,
+        (10, Some(0)) => DoviProfile::Profile10,
+        (10, Some(1)) => DoviProfile::Profile101,
+        (10, Some(2)) => DoviProfile::Profile102,
+        (10, Some(4)) => DoviProfile::Profile104,
+        _ => unknown,
+    }
+}
+
+/// Field of an RPU not respecting a constraint
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub field: &'static str,
+    pub expected: String,
+    pub found: String,
+}
+
+impl Violation {
+    pub fn new(field: &'static str, expected: impl ToString, found: impl ToString) -> Violation {
+        Violation {
+            field,
+            expected: expected.to_string(),
+            found: found.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} should be {}, found {}",
+            self.field, self.expected, self.found
+        )
+    }
+}
+
+/// Samples of each BL component when comparing mapping curves
+const CURVE_SAMPLES: usize = 17;
+
+/// Result of a mapping curves comparison
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurveComparison {
+    /// Largest difference of the Y, Cb and Cr outputs, normalized to [0, 1]
+    pub max_errors: [f64; 3],
+    pub matches: bool,
+}
+
+/// Compares the mapping curves of two RPUs, sampled on a grid of BL pixels.
+/// The curves match if no component's output differs by more than `tolerance`.
+///
+/// `None` if either RPU has no mapping, or uses segments predicted from another one.
+pub fn mapping_curves_match(a: &DoviRpu, b: &DoviRpu, tolerance: f64) -> Option<CurveComparison> {
+    let a_mapping = a.vdr_rpu_data.as_ref()?;
+    let b_mapping = b.vdr_rpu_data.as_ref()?;
+
+    let sample = |i: usize| i as f64 / (CURVE_SAMPLES - 1) as f64;
+    let mut max_errors = [0.0; 3];
+
+    for y in 0..CURVE_SAMPLES {
+        for cb in 0..CURVE_SAMPLES {
+            for cr in 0..CURVE_SAMPLES {
+                let pixel = [sample(y), sample(cb), sample(cr)];
+
+                for (cmp, max_error) in max_errors.iter_mut().enumerate() {
+                    let a_value = a_mapping.map_pixel(&a.header, cmp, pixel)?;
+                    let b_value = b_mapping.map_pixel(&b.header, cmp, pixel)?;
+
+                    *max_error = f64::max(*max_error, (a_value - b_value).abs());
+                }
+            }
+        }
+    }
+
+    Some(CurveComparison {
+        max_errors,
+        matches: max_errors.iter().all(|error| *error <= tolerance),
+    })
+}
+
+/// Lists the fields changed from `before`, the serialized RPU before editing, to `after`.
+/// The changes are formatted as `path: before -> after`.
+/// Lists of values are reported as a whole, and missing fields or blocks as added or removed.
+pub fn rpu_changes(before: &serde_json::Value, after: &DoviRpu) -> Vec<String> {
+    value_changes(before, &serde_json::to_value(after).unwrap())
+}
+
+/// Lists the fields changed between two serialized RPUs, like `rpu_changes`
+pub fn value_changes(before: &serde_json::Value, after: &serde_json::Value) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    diff_values("", before, after, &mut changes);
+
+    changes
+}
+
+fn diff_values(
+    path: &str,
+    before: &serde_json::Value,
+    after: &serde_json::Value,
+    changes: &mut Vec<String>,
+) {
+    use serde_json::Value;
+
+    if before == after {
+        return;
+    }
+
+    let join = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+
+    match (before, after) {
+        (Value::Null, _) => changes.push(format!("{}: added", path)),
+        (_, Value::Null) => changes.push(format!("{}: removed", path)),
+        (Value::Object(before), Value::Object(after)) => {
+            for (key, value) in before {
+                match after.get(key) {
+                    Some(after_value) => diff_values(&join(key), value, after_value, changes),
+                    None => changes.push(format!("{}: removed", join(key))),
+                }
+            }
+
+            for key in after.keys().filter(|key| !before.contains_key(*key)) {
+                changes.push(format!("{}: added", join(key)));
+            }
+        }
+        // Lists of blocks are compared block by block
+        (Value::Array(before), Value::Array(after))
+            if before.iter().chain(after).any(Value::is_object) =>
+        {
+            for i in 0..before.len().max(after.len()) {
+                let item_path = format!("{}[{}]", path, i);
+
+                match (before.get(i), after.get(i)) {
+                    (Some(b), Some(a)) => diff_values(&item_path, b, a, changes),
+                    (Some(_), None) => changes.push(format!("{}: removed", item_path)),
+                    _ => changes.push(format!("{}: added", item_path)),
+                }
+            }
+        }
+        (Value::Array(_), Value::Array(_)) => changes.push(format!("{}: changed", path)),
+        _ => changes.push(format!("{}: {} -> {}", path, before, after)),
+    }
+}
+
+/// (De)serializes the unparsed bits as a string of 0s and 1s
+pub(crate) mod bitvec_serde {
+    use super::prelude::*;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bits: &BitVec<Msb0, u8>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let bits: String = bits.iter().map(|b| if *b { '1' } else { '0' }).collect();
+
+        serializer.serialize_str(&bits)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BitVec<Msb0, u8>, D::Error> {
+        let bits = String::deserialize(deserializer)?;
+
+        bits.chars()
+            .map(|c| match c {
+                '0' => Ok(false),
+                '1' => Ok(true),
+                _ => Err(D::Error::custom(format!("Invalid bit value: {}", c))),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,65 @@
+// SMPTE ST 2084 constants
+const M1: f64 = 2610.0 / 16384.0;
+const M2: f64 = 2523.0 / 4096.0 * 128.0;
+const C1: f64 = 3424.0 / 4096.0;
+const C2: f64 = 2413.0 / 4096.0 * 32.0;
+const C3: f64 = 2392.0 / 4096.0 * 32.0;
+
+const MAX_PQ_LUMINANCE: f64 = 10000.0;
+
+// ARIB STD-B67 (HLG) constants
+const HLG_A: f64 = 0.17883277;
+const HLG_B: f64 = 0.28466892;
+const HLG_C: f64 = 0.55991073;
+
+/// Peak luminance of the HLG reference display
+pub const HLG_REFERENCE_NITS: f64 = 1000.0;
+
+/// Converts a luminance in nits to a 12-bit PQ code value
+pub fn nits_to_pq(nits: f64) -> u16 {
+    (nits_to_normalized_pq(nits) * 4095.0).round() as u16
+}
+
+/// Converts a luminance in nits to a PQ value normalized to [0, 1]
+pub fn nits_to_normalized_pq(nits: f64) -> f64 {
+    let y = (nits / MAX_PQ_LUMINANCE).clamp(0.0, 1.0).powf(M1);
+
+    ((C1 + C2 * y) / (1.0 + C3 * y)).powf(M2)
+}
+
+/// Converts a normalized HLG signal to the luminance displayed by a `peak_nits` display.
+/// The HLG OOTF is applied to the signal as if it was the luma, rather than per RGB component.
+pub fn hlg_to_nits(hlg: f64, peak_nits: f64) -> f64 {
+    let hlg = hlg.clamp(0.0, 1.0);
+
+    // Inverse OETF, normalized scene light
+    let scene = if hlg <= 0.5 {
+        hlg * hlg / 3.0
+    } else {
+        (((hlg - HLG_C) / HLG_A).exp() + HLG_B) / 12.0
+    };
+
+    let gamma = 1.2 + 0.42 * (peak_nits / 1000.0).log10();
+
+    peak_nits * scene.powf(gamma)
+}
+
+/// Converts a 12-bit PQ code value to a luminance in nits
+pub fn pq_to_nits(pq: u16) -> f64 {
+    let e = (pq as f64 / 4095.0).powf(1.0 / M2);
+    let y = ((e - C1).max(0.0) / (C2 - C3 * e)).powf(1.0 / M1);
+
+    y * MAX_PQ_LUMINANCE
+}
+
+/// Roundest multiple of `unit` nits with the 12-bit PQ code value, e.g. 1000 nits rather than 1000.6 for 3079.
+/// A code value covers a range of luminances, the one it was converted from is most likely round.
+pub fn pq_to_round_nits(pq: u16, unit: f64) -> f64 {
+    let nits = pq_to_nits(pq);
+
+    [1000.0, 100.0, 10.0, 1.0]
+        .iter()
+        .map(|step| (nits / (unit * step)).round() * unit * step)
+        .find(|rounded| nits_to_pq(*rounded) == pq)
+        .unwrap_or_else(|| (nits / unit).round() * unit)
+}
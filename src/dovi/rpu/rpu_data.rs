@@ -1,28 +1,66 @@
 use super::{
-    add_start_code_emulation_prevention_3_byte, rpu_data_header,
-    vdr_dm_data::{self, ExtMetadataBlockLevel5},
-    vdr_rpu_data, BitVecReader, BitVecWriter,
+    add_start_code_emulation_prevention_3_byte, bitvec_serde, pq, rpu_data_header,
+    vdr_dm_data::{
+        self, ExtBlockLevel, ExtMetadataBlock, ExtMetadataBlockLevel1, ExtMetadataBlockLevel5,
+        L2TrimField,
+    },
+    vdr_rpu_data, BitVecReader, BitVecWriter, Violation,
 };
 
 use super::prelude::*;
 use crc::{Crc, CRC_32_MPEG_2};
 use rpu_data_header::RpuDataHeader;
+use serde::{Deserialize, Serialize};
 use vdr_dm_data::VdrDmData;
-use vdr_rpu_data::{NlqData, VdrRpuData};
+use vdr_rpu_data::{ComponentMapping, NlqData, VdrRpuData};
+
+/// Upper bound of num_ext_blocks, a larger count is most likely corrupted
+pub const MAX_EXT_BLOCKS: u64 = 255;
+
+/// Bits consumed by the parsed payload, compared to the position of the CRC32
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PayloadBits {
+    /// Bits read by the parser, from the rpu_nal_prefix
+    pub parsed: usize,
+    /// Bits before the CRC32, from the rpu_nal_prefix
+    pub total: usize,
+}
 
-#[derive(Default, Debug)]
+impl PayloadBits {
+    /// Bits left between the end of the parsed payload and the CRC32
+    pub fn leftover(&self) -> usize {
+        self.total - self.parsed
+    }
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
 pub struct DoviRpu {
     pub dovi_profile: u8,
+    #[serde(skip)]
     pub reader: BitVecReader,
     pub header: RpuDataHeader,
+    #[serde(rename = "rpu_data_mapping")]
     pub vdr_rpu_data: Option<VdrRpuData>,
+    #[serde(rename = "rpu_data_nlq")]
     pub nlq_data: Option<NlqData>,
     pub vdr_dm_data: Option<VdrDmData>,
+    #[serde(with = "bitvec_serde", default)]
     pub remaining: BitVec<Msb0, u8>,
+    #[serde(skip)]
     pub rpu_data_crc32: u32,
+    #[serde(default = "DoviRpu::default_last_byte")]
     pub last_byte: u8,
-
+    /// Zero bytes written after the terminator byte, outside of the NAL payload
+    #[serde(skip)]
+    pub trailing_zeroes: usize,
+    /// Bits consumed by the parser, for a parsed RPU
+    #[serde(skip)]
+    pub payload_bits: Option<PayloadBits>,
+
+    #[serde(skip)]
     pub modified: bool,
+    #[serde(skip)]
+    pub warnings: Vec<String>,
 }
 
 impl DoviRpu {
@@ -33,62 +71,172 @@ impl DoviRpu {
         }
     }
 
+    fn default_last_byte() -> u8 {
+        0x80
+    }
+
+    /// Whether more than the byte alignment padding was left between the parsed payload and the CRC32.
+    /// The parser then stopped short of the payload end, the fields after are most likely misread.
+    pub fn has_payload_desync(&self) -> bool {
+        self.payload_bits
+            .is_some_and(|bits| bits.leftover() >= 8 || self.remaining.any())
+    }
+
+    /// Bits parsed and left before the CRC32, for diagnosing a desync
+    pub fn payload_bits_report(&self) -> String {
+        match self.payload_bits {
+            Some(bits) => {
+                let status = if self.has_payload_desync() {
+                    "desync, non padding bits left"
+                } else {
+                    "alignment padding"
+                };
+
+                format!(
+                    "{} bits parsed, {} bits before the CRC32: {} bits left ({})",
+                    bits.parsed,
+                    bits.total,
+                    bits.leftover(),
+                    status
+                )
+            }
+            None => "Payload not parsed".to_string(),
+        }
+    }
+
     #[inline(always)]
-    pub fn read_rpu_data(bytes: Vec<u8>, end_byte: u8) -> DoviRpu {
+    pub fn read_rpu_data(
+        bytes: Vec<u8>,
+        trailing_zeroes: usize,
+        lenient: bool,
+    ) -> Result<DoviRpu, String> {
+        let bytes_len = bytes.len();
         let mut dovi_rpu = DoviRpu::new(bytes);
-        dovi_rpu.last_byte = end_byte;
+        dovi_rpu.last_byte = if trailing_zeroes > 0 { 0 } else { 0x80 };
+        dovi_rpu.trailing_zeroes = trailing_zeroes;
+
+        dovi_rpu.header = RpuDataHeader::parse(&mut dovi_rpu.reader)
+            .map_err(|e| at_bit_position(e, &dovi_rpu.reader, bytes_len))?;
+
+        // The explicit filter coefficients syntax isn't known, the payload can't be parsed past it.
+        // In lenient mode, it's kept as is in the remaining bits, to be written back untouched.
+        if dovi_rpu.header.chroma_resampling_explicit_filter_flag {
+            if !lenient {
+                return Err(
+                    "Unsupported chroma_resampling_explicit_filter_flag: explicit resampling filters can't be parsed, --lenient keeps the RPU unparsed"
+                        .to_string(),
+                );
+            }
 
-        let reader = &mut dovi_rpu.reader;
-        dovi_rpu.header = RpuDataHeader::rpu_data_header(reader);
+            let reader = &mut dovi_rpu.reader;
+            let final_len = 40 + 8 * trailing_zeroes;
+
+            while reader.available() > final_len {
+                dovi_rpu.remaining.push(reader.get());
+            }
+
+            dovi_rpu.rpu_data_crc32 = reader.get_n(32);
+            dovi_rpu.warnings.push(
+                "chroma_resampling_explicit_filter_flag is set, the RPU is kept unparsed"
+                    .to_string(),
+            );
+
+            return Ok(dovi_rpu);
+        }
 
         // Preliminary header validation
-        dovi_rpu.dovi_profile = dovi_rpu.header.get_dovi_profile();
+        dovi_rpu.validate(lenient)?;
 
-        dovi_rpu.header.validate(dovi_rpu.dovi_profile);
+        let reader = &mut dovi_rpu.reader;
+
+        // EOF case
+        let final_len = 40 + 8 * trailing_zeroes;
+        let mut parse_warnings = Vec::new();
 
         if dovi_rpu.header.rpu_type == 2 {
             if !dovi_rpu.header.use_prev_vdr_rpu_flag {
                 let (vdr_rpu_data, nlq_data) =
-                    VdrRpuData::vdr_rpu_data_payload(reader, &mut dovi_rpu.header);
+                    VdrRpuData::vdr_rpu_data_payload(reader, &mut dovi_rpu.header)
+                        .map_err(|e| at_bit_position(e, reader, bytes_len))?;
 
                 dovi_rpu.vdr_rpu_data = vdr_rpu_data;
                 dovi_rpu.nlq_data = nlq_data;
             }
 
             if dovi_rpu.header.vdr_dm_metadata_present_flag {
-                dovi_rpu.vdr_dm_data = Some(VdrDmData::vdr_dm_data_payload(reader));
+                let vdr_dm_data = VdrDmData::vdr_dm_data_payload(
+                    reader,
+                    final_len,
+                    MAX_EXT_BLOCKS,
+                    lenient,
+                    &mut parse_warnings,
+                )
+                .map_err(|e| at_bit_position(e, reader, bytes_len))?;
+
+                dovi_rpu.vdr_dm_data = Some(vdr_dm_data);
             }
 
-            while !reader.is_aligned() {
-                dovi_rpu.remaining.push(reader.get());
+            // CRC32 is at the end, the bits left before are the CM v4.0 ext blocks,
+            // the alignment or unknown data
+            let offset = (8 - reader.available() % 8) % 8;
+            let mut left = BitVec::<Msb0, u8>::new();
+
+            while reader.available() > final_len {
+                left.push(reader.get());
             }
 
-            // EOF case
-            let final_len = if end_byte == 0 { 48 } else { 40 };
+            dovi_rpu.remaining = match dovi_rpu.vdr_dm_data {
+                // More than alignment zero bits
+                Some(ref mut vdr_dm_data) if left.len() >= 8 && left.any() => vdr_dm_data
+                    .parse_cmv40_ext_blocks(&left, offset, lenient, &mut parse_warnings)
+                    .map_err(|e| at_bit_position(e, reader, bytes_len))?,
+                _ => left,
+            };
 
-            // CRC32 is at the end, apparently sometimes there is more unknown data
-            if reader.available() != final_len {
-                while reader.available() != final_len {
-                    dovi_rpu.remaining.push(reader.get());
-                }
-            }
+            let total_bits = 8 * (bytes_len - trailing_zeroes) - 40;
+
+            dovi_rpu.payload_bits = Some(PayloadBits {
+                parsed: total_bits - dovi_rpu.remaining.len(),
+                total: total_bits,
+            });
 
             dovi_rpu.rpu_data_crc32 = reader.get_n(32);
 
             let last_byte: u8 = reader.get_n(8);
-            assert_eq!(last_byte, 0x80);
+
+            if last_byte != 0x80 {
+                return Err(at_bit_position(
+                    format!("Invalid RPU terminator byte: {:#04x}", last_byte),
+                    reader,
+                    bytes_len,
+                ));
+            }
         }
 
-        dovi_rpu.validate();
+        dovi_rpu.validate(lenient)?;
+        dovi_rpu.warnings.extend(parse_warnings);
 
-        dovi_rpu
+        Ok(dovi_rpu)
     }
 
     fn convert_to_mel(&mut self) {
+        if !self.has_nlq() {
+            panic!("Not profile 7, cannot convert to MEL!");
+        }
+
         if let Some(ref mut nlq_data) = self.nlq_data {
             nlq_data.convert_to_mel();
-        } else {
-            panic!("Not profile 7, cannot convert to MEL!");
+        }
+    }
+
+    /// Soft MEL: identity residual, the NLQ offsets, mapping and DM are kept
+    fn neutralize_residual(&mut self) {
+        if !self.has_nlq() {
+            panic!("Not profile 7, cannot neutralize the EL residual!");
+        }
+
+        if let Some(ref mut nlq_data) = self.nlq_data {
+            nlq_data.neutralize_residual();
         }
     }
 
@@ -127,6 +275,11 @@ impl DoviRpu {
 
         self.remaining.iter().for_each(|b| writer.write(*b));
 
+        // rpu_alignment_zero_bit, part of the remaining bits unless the payload size changed
+        while !writer.is_aligned() {
+            writer.write(false);
+        }
+
         let computed_crc32 = DoviRpu::compute_crc32(&writer.as_slice()[1..]);
 
         if !self.modified {
@@ -138,21 +291,61 @@ impl DoviRpu {
         writer.write_n(&computed_crc32.to_be_bytes(), 32);
         writer.write_n(&[0x80], 8);
 
-        if self.last_byte != 0x80 {
-            writer.write_n(&[self.last_byte], 8);
-        }
-
         // Back to a u8 slice
         let mut data_to_write = writer.as_slice().to_vec();
         add_start_code_emulation_prevention_3_byte(&mut data_to_write);
 
+        // A last_byte of 0 is the final RPU exception, a single zero byte
+        let trailing_zeroes = if self.last_byte == 0 {
+            self.trailing_zeroes.max(1)
+        } else {
+            self.trailing_zeroes
+        };
+
+        data_to_write.resize(data_to_write.len() + trailing_zeroes, 0);
+
         // Put back NAL unit type
         data_to_write.insert(0, 0x01);
         data_to_write.insert(0, 0x7C);
 
+        #[cfg(feature = "debug-reparse")]
+        self.check_reparse(&data_to_write);
+
         data_to_write
     }
 
+    /// Re-parses the written RPU, panicking with the differences if it doesn't match
+    #[cfg(feature = "debug-reparse")]
+    fn check_reparse(&self, data: &[u8]) {
+        let reparsed = match super::parse_dovi_rpu(data, true) {
+            Ok(reparsed) => reparsed,
+            Err(e) => panic!("Written RPU can't be parsed: {}", e),
+        };
+
+        // rpu_alignment_zero_bit added when the payload size changed
+        let alignment = reparsed.remaining.len() - self.remaining.len();
+        let aligned_only = reparsed.remaining.starts_with(&self.remaining)
+            && alignment < 8
+            && reparsed.remaining[self.remaining.len()..].not_any();
+
+        let changes: Vec<String> =
+            super::rpu_changes(&serde_json::to_value(self).unwrap(), &reparsed)
+                .into_iter()
+                // Derived from the header, updated on parsing
+                .filter(|change| !change.starts_with("dovi_profile:"))
+                .filter(|change| !(aligned_only && change.starts_with("remaining:")))
+                // Zero bytes after the terminator are parsed as the final RPU exception
+                .filter(|change| !(self.trailing_zeroes > 0 && change.starts_with("last_byte:")))
+                .collect();
+
+        if !changes.is_empty() {
+            panic!(
+                "Written RPU doesn't match after re-parsing:\n{}",
+                changes.join("\n")
+            );
+        }
+    }
+
     pub fn write_vdr_rpu_data(&self, writer: &mut BitVecWriter) {
         if let Some(ref vdr_rpu_data) = self.vdr_rpu_data {
             vdr_rpu_data.write(writer, &self.header);
@@ -173,7 +366,7 @@ impl DoviRpu {
     pub fn compute_crc32(data: &[u8]) -> u32 {
         let crc = Crc::<u32>::new(&CRC_32_MPEG_2);
         let mut digest = crc.digest();
-        digest.update(&data);
+        digest.update(data);
 
         digest.finalize()
     }
@@ -187,6 +380,7 @@ impl DoviRpu {
             match mode {
                 1 => self.convert_to_mel(),
                 2 => self.convert_to_81(),
+                3 => self.neutralize_residual(),
                 _ => (),
             }
         } else if mode != 0 {
@@ -194,12 +388,355 @@ impl DoviRpu {
         }
     }
 
+    /// Sets the offsets of every L5 block to 0
     pub fn crop(&mut self) {
         self.modified = true;
 
+        if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
+            for ext in vdr_dm_data.ext_metadata_blocks.iter_mut() {
+                if let ExtMetadataBlock::Level5(block) = ext {
+                    block.crop();
+                }
+            }
+        }
+    }
+
+    /// Applies `f` to every ext block of the frame, see `VdrDmData::map_blocks`.
+    /// The CRC32 is computed again when writing. Frames without DM metadata are left untouched.
+    pub fn map_ext_blocks(&mut self, f: impl FnMut(&mut ExtMetadataBlock)) -> Result<(), String> {
+        self.modified = true;
+
+        match self.vdr_dm_data {
+            Some(ref mut vdr_dm_data) => vdr_dm_data.map_blocks(f),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the L5 blocks, leaving the whole frame active.
+    /// Returns the number of removed blocks.
+    pub fn clear_active_area(&mut self) -> usize {
+        self.remove_ext_blocks(ExtBlockLevel::L5ActiveArea)
+    }
+
+    /// Drops the mapping, referencing the one of the previous RPU with `prev_vdr_rpu_id` instead
+    pub fn use_prev_mapping(&mut self, prev_vdr_rpu_id: u64) {
+        self.modified = true;
+
+        let header = &mut self.header;
+
+        header.use_prev_vdr_rpu_flag = true;
+        header.prev_vdr_rpu_id = prev_vdr_rpu_id;
+
+        header.vdr_rpu_id = 0;
+        header.mapping_color_space = 0;
+        header.mapping_chroma_format_idc = 0;
+        header.num_pivots_minus_2 = [0; 3];
+        header.pred_pivot_value.clear();
+        header.nlq_method_idc = None;
+        header.nlq_num_pivots_minus2 = None;
+        header.num_x_partitions_minus1 = 0;
+        header.num_y_partitions_minus1 = 0;
+
+        self.vdr_rpu_data = None;
+        self.nlq_data = None;
+    }
+
+    /// Reduces an RPU using the previous mapping to the minimal NAL:
+    /// the header referencing `prev_vdr_rpu_id` and the CRC32, without DM metadata.
+    /// Returns false for RPUs carrying their own mapping, left untouched.
+    pub fn minimize_use_prev(&mut self) -> bool {
+        if !self.header.use_prev_vdr_rpu_flag {
+            return false;
+        }
+
+        self.modified = true;
+
+        self.vdr_rpu_data = None;
+        self.nlq_data = None;
+
+        self.header.vdr_dm_metadata_present_flag = false;
+        self.vdr_dm_data = None;
+
+        if self.remaining.not_any() {
+            self.remaining.clear();
+        }
+
+        true
+    }
+
+    /// Drops what doesn't change the decoded metadata: the no-op ext blocks,
+    /// the zero bits before the CRC32 and the zero bytes after the terminator byte.
+    pub fn minify(&mut self) {
+        self.modified = true;
+
+        if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
+            vdr_dm_data.remove_no_op_blocks();
+        }
+
+        if self.remaining.not_any() {
+            self.remaining.clear();
+        }
+
+        self.last_byte = 0x80;
+        self.trailing_zeroes = 0;
+    }
+
+    /// Shifts the L5 active area offsets by signed deltas, clamped to 0 and 8191
+    pub fn adjust_active_area(&mut self, left: i32, right: i32, top: i32, bottom: i32) {
+        self.modified = true;
+
         if let Some(block) = ExtMetadataBlockLevel5::get_mut(self) {
-            block.crop();
+            block.adjust_offsets(left, right, top, bottom);
+        }
+    }
+
+    /// Displayed aspect ratio of a `width`x`height` frame, after cropping the L5 active area.
+    /// Frames without L5 metadata are displayed uncropped.
+    pub fn active_area_aspect_ratio(&self, width: u16, height: u16) -> Result<f64, String> {
+        if width == 0 || height == 0 {
+            return Err(format!("Invalid resolution: {}x{}", width, height));
+        }
+
+        match ExtMetadataBlockLevel5::get(self) {
+            Some(block) => block.aspect_ratio(width, height),
+            None => Ok(width as f64 / height as f64),
+        }
+    }
+
+    /// Pads the written RPU NAL to `length` bytes, header included, with zero bytes after the terminator byte.
+    /// The zero bytes are trailing_zero_8bits of the byte stream: the payload and CRC32 are unchanged.
+    pub fn pad(&mut self, length: usize) -> Result<(), String> {
+        self.last_byte = 0x80;
+        self.trailing_zeroes = 0;
+
+        let unpadded_length = self.write_rpu_data().len();
+
+        if unpadded_length > length {
+            return Err(format!(
+                "RPU of {} bytes can't be padded to {} bytes",
+                unpadded_length, length
+            ));
+        }
+
+        self.trailing_zeroes = length - unpadded_length;
+
+        Ok(())
+    }
+
+    /// Removes the DM ext blocks of a level, returns the number of removed blocks
+    pub fn remove_ext_blocks(&mut self, level: ExtBlockLevel) -> usize {
+        self.modified = true;
+
+        match self.vdr_dm_data {
+            Some(ref mut vdr_dm_data) => vdr_dm_data.remove_ext_blocks(level),
+            None => 0,
+        }
+    }
+
+    /// Changes the target_max_pq of the L2 block targeting `old_target_pq`, keeping its trims
+    pub fn set_l2_target(&mut self, old_target_pq: u16, new_target_pq: u16) -> Result<(), String> {
+        match self.vdr_dm_data {
+            Some(ref mut vdr_dm_data) => {
+                vdr_dm_data.set_l2_target(old_target_pq, new_target_pq)?;
+                self.modified = true;
+
+                Ok(())
+            }
+            None => Err("No DM metadata".to_string()),
+        }
+    }
+
+    /// Removes the DM metadata, leaving a mapping only RPU.
+    /// The unparsed bits following the DM metadata are dropped as well.
+    pub fn strip_dm_metadata(&mut self) {
+        if self.header.vdr_dm_metadata_present_flag {
+            self.modified = true;
+
+            self.header.vdr_dm_metadata_present_flag = false;
+            self.vdr_dm_data = None;
+            self.remaining.clear();
+        }
+    }
+
+    /// Replaces the DM metadata of the frame by a template, see `VdrDmData::apply_template`.
+    /// Frames without DM metadata get the template's.
+    pub fn apply_dm_template(&mut self, template: &VdrDmData, keep_l1: bool) {
+        self.modified = true;
+
+        match self.vdr_dm_data {
+            Some(ref mut vdr_dm_data) => vdr_dm_data.apply_template(template, keep_l1),
+            None => {
+                self.header.vdr_dm_metadata_present_flag = true;
+                self.vdr_dm_data = Some(template.clone());
+            }
+        }
+
+        // The alignment bits change with the DM metadata size
+        if self.remaining.not_any() {
+            self.remaining.clear();
+        }
+    }
+
+    /// Replaces the DM metadata of the frame, `None` removing it.
+    pub fn set_dm_data(&mut self, vdr_dm_data: Option<VdrDmData>) {
+        self.header.vdr_dm_metadata_present_flag = vdr_dm_data.is_some();
+        self.vdr_dm_data = vdr_dm_data;
+        self.modified = true;
+
+        // The alignment bits change with the DM metadata size
+        if self.remaining.not_any() {
+            self.remaining.clear();
+        }
+    }
+
+    /// Sets or clears the `scene_refresh_flag` of the frame.
+    /// Frames without DM metadata are left untouched.
+    pub fn set_scene_cut(&mut self, scene_cut: bool) {
+        if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
+            if vdr_dm_data.set_scene_cut(scene_cut) {
+                self.modified = true;
+            }
+        }
+    }
+
+    /// Lowers the L1 max_pq of the frame to `max_nits`.
+    /// Frames without L1 metadata are left untouched.
+    pub fn clamp_l1_max(&mut self, max_nits: f64) {
+        let max_pq = pq::nits_to_pq(max_nits);
+
+        if let Some(block) = ExtMetadataBlockLevel1::get_mut(self) {
+            if block.clamp_max_pq(max_pq) {
+                self.modified = true;
+            }
+        }
+    }
+
+    /// Sets the L1 of the frame to fixed luminances, in nits, inserting the block when absent.
+    /// Frames without DM metadata are left untouched.
+    pub fn set_l1_fixed(&mut self, min_nits: f64, avg_nits: f64, max_nits: f64) {
+        if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
+            let changed = vdr_dm_data.set_l1(
+                pq::nits_to_pq(min_nits),
+                pq::nits_to_pq(avg_nits),
+                pq::nits_to_pq(max_nits),
+            );
+
+            if changed {
+                self.modified = true;
+            }
+        }
+    }
+
+    /// Inserts a L6 block when absent, see `VdrDmData::insert_l6`. Returns whether it was inserted.
+    /// Frames without DM metadata are left untouched.
+    pub fn insert_l6(&mut self, max_cll: u16, max_fall: u16, overwrite: bool) -> bool {
+        let inserted = self
+            .vdr_dm_data
+            .as_mut()
+            .is_some_and(|vdr_dm_data| vdr_dm_data.insert_l6(max_cll, max_fall, overwrite));
+
+        self.modified |= inserted;
+
+        inserted
+    }
+
+    /// Clamps the mastering display `source_diagonal` of the frame between `min` and `max` inches.
+    /// Frames without DM metadata are left untouched.
+    pub fn clamp_source_diagonal(&mut self, min: u16, max: u16) {
+        if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
+            if vdr_dm_data.clamp_source_diagonal(min, max) {
+                self.modified = true;
+            }
+        }
+    }
+
+    /// Makes CM v2.9 DM metadata CM v4.0, see `VdrDmData::convert_to_cmv40`.
+    /// Frames without DM metadata are left untouched.
+    pub fn convert_to_cmv40(&mut self) {
+        if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
+            if vdr_dm_data.convert_to_cmv40() {
+                self.modified = true;
+            }
+        }
+    }
+
+    /// Sets the L9 source_primary_index, see `VdrDmData::set_source_primaries`.
+    /// Frames without DM metadata are left untouched.
+    pub fn set_source_primaries(&mut self, index: u8) {
+        if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
+            if vdr_dm_data.set_source_primaries(index) {
+                self.modified = true;
+            }
+        }
+    }
+
+    /// Sets the L10 target_primary_index, removing the explicit primaries.
+    /// There's no target display to insert a block for, errors if the frame has DM metadata without L10 block
+    pub fn set_target_primaries(&mut self, index: u8) -> Result<(), String> {
+        if self.vdr_dm_data.is_none() {
+            return Ok(());
+        }
+
+        let mut count = 0;
+
+        self.map_ext_blocks(|ext| {
+            if let ExtMetadataBlock::Level10(block) = ext {
+                block.set_target_primary_index(index);
+                count += 1;
+            }
+        })?;
+
+        if count == 0 {
+            return Err("No L10 block to set the target primaries of".to_string());
         }
+
+        Ok(())
+    }
+
+    /// Scales the L2 trims of the frame around their neutral value, see `VdrDmData::scale_l2_trims`.
+    /// Frames without L2 metadata are left untouched.
+    pub fn scale_l2_trims(&mut self, factor: f64, fields: &[L2TrimField]) {
+        if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
+            if vdr_dm_data.scale_l2_trims(factor, fields) {
+                self.modified = true;
+            }
+        }
+    }
+
+    /// Rescales the mapping and NLQ coefficients to another `coefficient_log2_denom`.
+    /// The mapping curves are preserved within rounding error.
+    pub fn requantize_coefficients(&mut self, coefficient_log2_denom: u64) -> Result<(), String> {
+        let header = &mut self.header;
+
+        if !header.vdr_seq_info_present_flag || header.coefficient_data_type != 0 {
+            return Err("Only fixed point coefficients can be requantized".to_string());
+        }
+
+        if !(1..=23).contains(&coefficient_log2_denom) {
+            return Err(format!(
+                "coefficient_log2_denom should be between 1 and 23, found {}",
+                coefficient_log2_denom
+            ));
+        }
+
+        let from = header.coefficient_log2_denom;
+
+        if from == coefficient_log2_denom {
+            return Ok(());
+        }
+
+        header.coefficient_log2_denom = coefficient_log2_denom;
+        self.modified = true;
+
+        if let Some(ref mut vdr_rpu_data) = self.vdr_rpu_data {
+            vdr_rpu_data.requantize(from, coefficient_log2_denom);
+        }
+
+        if let Some(ref mut nlq_data) = self.nlq_data {
+            nlq_data.requantize(from, coefficient_log2_denom);
+        }
+
+        Ok(())
     }
 
     pub fn p5_to_p81(&mut self) {
@@ -232,12 +769,229 @@ impl DoviRpu {
         }
     }
 
-    pub fn validate(&mut self) {
-        self.dovi_profile = self.header.get_dovi_profile();
-        self.header.validate(self.dovi_profile);
+    /// Converts a profile 8.4 (HLG) RPU to profile 8.1 (PQ), the BL is unchanged.
+    ///
+    /// Exact: the DM metadata describes a PQ signal at the VDR bit depth, like profile 8.1 RPUs.
+    /// Approximated: the luma mapping is composed with the HLG to PQ conversion of the 1000 nits
+    /// reference display, with the HLG OOTF applied to the luma, and fitted with polynomials.
+    /// The chroma mappings and the L2 trims are kept as is.
+    ///
+    /// Returns `None` for the RPUs not signaling HLG, left untouched, otherwise the luma fit error.
+    pub fn p84_to_p81(&mut self) -> Result<Option<f64>, String> {
+        if self.dovi_profile != 8 {
+            return Err(format!(
+                "Attempt to convert profile 8.4: RPU is profile {}",
+                self.dovi_profile
+            ));
+        }
+
+        if self.profile8_compatibility_id() != Some(4) {
+            return Ok(None);
+        }
+
+        self.modified = true;
+
+        let hlg_to_pq =
+            |hlg| pq::nits_to_normalized_pq(pq::hlg_to_nits(hlg, pq::HLG_REFERENCE_NITS));
+
+        // The RPUs using a previous mapping have theirs converted with it
+        let fit_error = match self.vdr_rpu_data {
+            Some(ref mut vdr_rpu_data) => vdr_rpu_data.compose_luma(&mut self.header, hlg_to_pq)?,
+            None => 0.0,
+        };
+
+        let signal_bit_depth = self.header.vdr_bit_depth() as u8;
 
         if let Some(ref mut vdr_dm_data) = self.vdr_dm_data {
-            vdr_dm_data.validate(self.dovi_profile);
+            vdr_dm_data.hlg_to_pq(signal_bit_depth);
+        }
+
+        Ok(Some(fit_error))
+    }
+
+    /// `signal_bit_depth` of the DM metadata, `None` without DM metadata
+    pub fn signal_bit_depth(&self) -> Option<u8> {
+        self.vdr_dm_data.as_ref().map(VdrDmData::signal_bit_depth)
+    }
+
+    /// Profile 8 variant, as its dv_bl_signal_compatibility_id: 8.1 (HDR10), 8.2 (SDR) or 8.4 (HLG).
+    /// Detected from the DM `signal_eotf`, `None` for other profiles or without DM metadata.
+    pub fn profile8_compatibility_id(&self) -> Option<u8> {
+        if self.dovi_profile != 8 {
+            return None;
+        }
+
+        self.vdr_dm_data
+            .as_ref()
+            .and_then(VdrDmData::bl_signal_compatibility_id)
+    }
+
+    /// Profile with the profile 8 variant when detected, e.g. `8.4`
+    pub fn profile_name(&self) -> String {
+        match self.profile8_compatibility_id() {
+            Some(compat_id) => format!("{}.{}", self.dovi_profile, compat_id),
+            None => self.dovi_profile.to_string(),
         }
     }
+
+    /// Checks the constraints of a profile 8.1 RPU: single layer mapping without EL residual,
+    /// 10 bits BL mapped to 12 bits, with DM metadata.
+    /// Returns the offending fields, empty if the RPU is conformant.
+    pub fn validate_profile81(&self) -> Vec<Violation> {
+        let header = &self.header;
+        let mut violations = Vec::new();
+
+        let mut check = |field, expected: u64, found: u64| {
+            if found != expected {
+                violations.push(Violation::new(field, expected, found));
+            }
+        };
+
+        check("rpu_type", 2, header.rpu_type as u64);
+        check("rpu_format & 0x700", 0, (header.rpu_format & 0x700) as u64);
+        check("vdr_rpu_profile", 1, header.vdr_rpu_profile as u64);
+        check("bl_bit_depth_minus8", 2, header.bl_bit_depth_minus8);
+        check("vdr_bit_depth_minus_8", 4, header.vdr_bit_depth_minus_8);
+        check(
+            "el_spatial_resampling_filter_flag",
+            0,
+            header.el_spatial_resampling_filter_flag as u64,
+        );
+        check(
+            "disable_residual_flag",
+            1,
+            header.disable_residual_flag as u64,
+        );
+        check(
+            "vdr_dm_metadata_present_flag",
+            1,
+            header.vdr_dm_metadata_present_flag as u64,
+        );
+        check("num_x_partitions_minus1", 0, header.num_x_partitions_minus1);
+        check("num_y_partitions_minus1", 0, header.num_y_partitions_minus1);
+
+        if let Some(nlq_method_idc) = header.nlq_method_idc {
+            violations.push(Violation::new("nlq_method_idc", "absent", nlq_method_idc));
+        }
+
+        if self.nlq_data.is_some() {
+            violations.push(Violation::new("rpu_data_nlq", "absent", "present"));
+        }
+
+        if header.vdr_dm_metadata_present_flag && self.vdr_dm_data.is_none() {
+            violations.push(Violation::new("vdr_dm_data", "present", "absent"));
+        }
+
+        violations
+    }
+
+    /// Whether the RPU carries NLQ data, as for profile 7 FEL and MEL
+    pub fn has_nlq(&self) -> bool {
+        self.header.has_el_residual() && self.nlq_data.is_some()
+    }
+
+    /// Whether the EL residual is actually used: NLQ data that isn't the identity.
+    /// True for FEL, false for MEL and soft MEL.
+    pub fn has_residual(&self) -> bool {
+        match self.nlq_data {
+            Some(ref nlq_data) if self.has_nlq() => !nlq_data.is_identity_residual(),
+            _ => false,
+        }
+    }
+
+    /// Mapping summary of the Y, Cb and Cr components.
+    /// `None` if the RPU has no mapping, e.g. when reusing the previous RPU's.
+    pub fn component_mappings(&self) -> Option<Vec<ComponentMapping>> {
+        self.vdr_rpu_data
+            .as_ref()
+            .map(|vdr_rpu_data| vdr_rpu_data.component_mappings(&self.header))
+    }
+
+    /// Real mapping coefficients of the Y, Cb and Cr components.
+    /// Empty if the RPU has no mapping.
+    pub fn mapping_coefficients(&self) -> Vec<Vec<f64>> {
+        self.vdr_rpu_data
+            .as_ref()
+            .map(|vdr_rpu_data| vdr_rpu_data.mapping_coefficients(&self.header))
+            .unwrap_or_default()
+    }
+
+    /// Mapping curves sampled as a 1D LUT of `points` entries, see `VdrRpuData::sample_lut`.
+    pub fn mapping_lut(&self, points: usize) -> Result<Vec<[f64; 3]>, String> {
+        if points < 2 {
+            return Err(format!("A LUT needs at least 2 points, got {}", points));
+        }
+
+        let vdr_rpu_data = self
+            .vdr_rpu_data
+            .as_ref()
+            .ok_or("The RPU has no mapping, it uses the previous RPU's")?;
+
+        vdr_rpu_data
+            .sample_lut(&self.header, points)
+            .ok_or_else(|| "The mapping has predicted segments".to_string())
+    }
+
+    /// Validates the RPU against the known constraints.
+    /// In lenient mode, the failed validations are kept as warnings instead of erroring.
+    /// Fields too large to be written always error, even in lenient mode.
+    pub fn validate(&mut self, lenient: bool) -> Result<(), String> {
+        self.dovi_profile = self.header.get_dovi_profile();
+        self.warnings = self.header.validate(self.dovi_profile);
+
+        if let Some(ref vdr_rpu_data) = self.vdr_rpu_data {
+            self.warnings.extend(vdr_rpu_data.validate());
+        }
+
+        if let Some(ref vdr_dm_data) = self.vdr_dm_data {
+            self.warnings
+                .extend(vdr_dm_data.validate(self.dovi_profile));
+
+            let out_of_range = vdr_dm_data.out_of_range_fields();
+
+            if !out_of_range.is_empty() {
+                return Err(format!("Invalid RPU:\n{}", out_of_range.join("\n")));
+            }
+        }
+
+        if !lenient && !self.warnings.is_empty() {
+            return Err(format!("Invalid RPU:\n{}", self.warnings.join("\n")));
+        }
+
+        // Warnings in both modes
+        self.warnings.extend(self.header.el_flag_warnings());
+
+        if let Some(ref vdr_dm_data) = self.vdr_dm_data {
+            self.warnings.extend(vdr_dm_data.signal_format_warnings());
+        }
+
+        Ok(())
+    }
+}
+
+/// The parsing state isn't cloned
+impl Clone for DoviRpu {
+    fn clone(&self) -> Self {
+        DoviRpu {
+            dovi_profile: self.dovi_profile,
+            reader: BitVecReader::default(),
+            header: self.header.clone(),
+            vdr_rpu_data: self.vdr_rpu_data.clone(),
+            nlq_data: self.nlq_data.clone(),
+            vdr_dm_data: self.vdr_dm_data.clone(),
+            remaining: self.remaining.clone(),
+            rpu_data_crc32: self.rpu_data_crc32,
+            last_byte: self.last_byte,
+            trailing_zeroes: self.trailing_zeroes,
+            payload_bits: self.payload_bits,
+            modified: self.modified,
+            warnings: self.warnings.clone(),
+        }
+    }
+}
+
+/// Adds the position the parser stopped at to a parsing error, right after the invalid field.
+/// The bits are counted from the start of the payload, after the NAL header and without the emulation prevention bytes.
+fn at_bit_position(e: String, reader: &BitVecReader, bytes_len: usize) -> String {
+    format!("{}, at bit {}", e, 8 * bytes_len - reader.available())
 }
@@ -1,5 +1,14 @@
+use serde::{Deserialize, Serialize};
+
 use super::{BitVecReader, BitVecWriter};
-#[derive(Default, Debug)]
+
+/// Upper bound of num_pivots_minus_2, at most 9 pivots per component
+pub const MAX_PIVOTS_MINUS_2: u64 = 7;
+
+/// Upper bound of the BL bit depth, the width of the pivots
+const MAX_BL_BIT_DEPTH_MINUS8: u64 = 8;
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct RpuDataHeader {
     pub rpu_nal_prefix: u8,
     pub rpu_type: u8,
@@ -34,120 +43,269 @@ pub struct RpuDataHeader {
 }
 
 impl RpuDataHeader {
-    pub fn rpu_data_header(reader: &mut BitVecReader) -> RpuDataHeader {
-        let mut rpu_nal = RpuDataHeader::default();
+    pub fn parse(reader: &mut BitVecReader) -> Result<RpuDataHeader, String> {
+        let mut rpu_nal = RpuDataHeader {
+            rpu_nal_prefix: reader.get_n(8),
+            ..Default::default()
+        };
 
-        rpu_nal.rpu_nal_prefix = reader.get_n(8);
+        // Anything else isn't an RPU, the rest of the payload can't be parsed
+        if rpu_nal.rpu_nal_prefix != 25 {
+            return Err(format!(
+                "Invalid rpu_nal_prefix: {}, expected 25",
+                rpu_nal.rpu_nal_prefix
+            ));
+        }
 
-        if rpu_nal.rpu_nal_prefix == 25 {
-            rpu_nal.rpu_type = reader.get_n(6);
-            rpu_nal.rpu_format = reader.get_n(11);
+        rpu_nal.rpu_type = reader.get_n(6);
+        rpu_nal.rpu_format = reader.get_n(11);
 
-            if rpu_nal.rpu_type == 2 {
-                rpu_nal.vdr_rpu_profile = reader.get_n(4);
+        if rpu_nal.rpu_type == 2 {
+            rpu_nal.vdr_rpu_profile = reader.get_n(4);
 
-                rpu_nal.vdr_rpu_level = reader.get_n(4);
-                rpu_nal.vdr_seq_info_present_flag = reader.get();
+            rpu_nal.vdr_rpu_level = reader.get_n(4);
+            rpu_nal.vdr_seq_info_present_flag = reader.get();
 
-                if rpu_nal.vdr_seq_info_present_flag {
-                    rpu_nal.chroma_resampling_explicit_filter_flag = reader.get();
-                    rpu_nal.coefficient_data_type = reader.get_n(2);
+            if rpu_nal.vdr_seq_info_present_flag {
+                rpu_nal.chroma_resampling_explicit_filter_flag = reader.get();
 
-                    if rpu_nal.coefficient_data_type == 0 {
-                        rpu_nal.coefficient_log2_denom = reader.get_ue();
-                    }
+                // Unknown filter syntax, the rest is kept unparsed
+                if rpu_nal.chroma_resampling_explicit_filter_flag {
+                    return Ok(rpu_nal);
+                }
+
+                rpu_nal.coefficient_data_type = reader.get_n(2);
 
-                    rpu_nal.vdr_rpu_normalized_idc = reader.get_n(2);
-                    rpu_nal.bl_video_full_range_flag = reader.get();
-
-                    if rpu_nal.rpu_format & 0x700 == 0 {
-                        rpu_nal.bl_bit_depth_minus8 = reader.get_ue();
-                        rpu_nal.el_bit_depth_minus8 = reader.get_ue();
-                        rpu_nal.vdr_bit_depth_minus_8 = reader.get_ue();
-                        rpu_nal.spatial_resampling_filter_flag = reader.get();
-                        rpu_nal.reserved_zero_3bits = reader.get_n(3);
-                        rpu_nal.el_spatial_resampling_filter_flag = reader.get();
-                        rpu_nal.disable_residual_flag = reader.get();
+                if rpu_nal.coefficient_data_type == 0 {
+                    rpu_nal.coefficient_log2_denom = reader.get_ue();
+
+                    // Above the usual 23, only a validation warning.
+                    // The fractional parts are read in 64 bits fields, scaled by 2^denom.
+                    if rpu_nal.coefficient_log2_denom > 63 {
+                        return Err(format!(
+                            "Invalid coefficient_log2_denom: {}, coefficients are at most 63 bits",
+                            rpu_nal.coefficient_log2_denom
+                        ));
                     }
                 }
 
-                rpu_nal.vdr_dm_metadata_present_flag = reader.get();
-                rpu_nal.use_prev_vdr_rpu_flag = reader.get();
+                rpu_nal.vdr_rpu_normalized_idc = reader.get_n(2);
+                rpu_nal.bl_video_full_range_flag = reader.get();
 
-                if rpu_nal.use_prev_vdr_rpu_flag {
-                    rpu_nal.prev_vdr_rpu_id = reader.get_ue();
-                } else {
-                    rpu_nal.vdr_rpu_id = reader.get_ue();
-                    rpu_nal.mapping_color_space = reader.get_ue();
-                    rpu_nal.mapping_chroma_format_idc = reader.get_ue();
+                if rpu_nal.rpu_format & 0x700 == 0 {
+                    rpu_nal.bl_bit_depth_minus8 = reader.get_ue();
 
-                    for cmp in 0..3 {
-                        rpu_nal.num_pivots_minus_2[cmp] = reader.get_ue();
+                    // Only a validation error when 2, larger pivots can't be read
+                    if rpu_nal.bl_bit_depth_minus8 > MAX_BL_BIT_DEPTH_MINUS8 {
+                        return Err(format!(
+                            "Invalid bl_bit_depth_minus8: {}, should be at most {}",
+                            rpu_nal.bl_bit_depth_minus8, MAX_BL_BIT_DEPTH_MINUS8
+                        ));
+                    }
 
-                        let pivot_idx_count = (rpu_nal.num_pivots_minus_2[cmp] + 2) as usize;
+                    rpu_nal.el_bit_depth_minus8 = reader.get_ue();
+                    rpu_nal.vdr_bit_depth_minus_8 = reader.get_ue();
+                    rpu_nal.spatial_resampling_filter_flag = reader.get();
+                    rpu_nal.reserved_zero_3bits = reader.get_n(3);
+                    rpu_nal.el_spatial_resampling_filter_flag = reader.get();
+                    rpu_nal.disable_residual_flag = reader.get();
+                }
+            }
 
-                        rpu_nal.pred_pivot_value.push(vec![0; pivot_idx_count]);
-                        for pivot_idx in 0..pivot_idx_count {
-                            rpu_nal.pred_pivot_value[cmp][pivot_idx] =
-                                reader.get_n((rpu_nal.bl_bit_depth_minus8 + 8) as usize);
-                        }
+            rpu_nal.vdr_dm_metadata_present_flag = reader.get();
+            rpu_nal.use_prev_vdr_rpu_flag = reader.get();
+
+            if rpu_nal.use_prev_vdr_rpu_flag {
+                rpu_nal.prev_vdr_rpu_id = reader.get_ue();
+            } else {
+                rpu_nal.vdr_rpu_id = reader.get_ue();
+                rpu_nal.mapping_color_space = reader.get_ue();
+                rpu_nal.mapping_chroma_format_idc = reader.get_ue();
+
+                for cmp in 0..3 {
+                    rpu_nal.num_pivots_minus_2[cmp] = reader.get_ue();
+
+                    // The mapping vectors are sized by the pivot count
+                    if rpu_nal.num_pivots_minus_2[cmp] > MAX_PIVOTS_MINUS_2 {
+                        return Err(format!(
+                            "num_pivots_minus_2[{}] should be at most {}, found {}",
+                            cmp, MAX_PIVOTS_MINUS_2, rpu_nal.num_pivots_minus_2[cmp]
+                        ));
                     }
 
-                    // Profile 7 only
-                    if rpu_nal.rpu_format & 0x700 == 0 && !rpu_nal.disable_residual_flag {
-                        rpu_nal.nlq_method_idc = Some(reader.get_n(3));
-                        rpu_nal.nlq_num_pivots_minus2 = Some(0);
+                    let pivot_idx_count = (rpu_nal.num_pivots_minus_2[cmp] + 2) as usize;
+                    let pivot_bits = pivot_idx_count * rpu_nal.bl_bit_depth() as usize;
+
+                    if pivot_bits > reader.available() {
+                        return Err(format!(
+                            "num_pivots_minus_2[{}] is {}, the pivots need {} bits, only {} left",
+                            cmp,
+                            rpu_nal.num_pivots_minus_2[cmp],
+                            pivot_bits,
+                            reader.available()
+                        ));
                     }
 
-                    rpu_nal.num_x_partitions_minus1 = reader.get_ue();
-                    rpu_nal.num_y_partitions_minus1 = reader.get_ue();
+                    rpu_nal.pred_pivot_value.push(vec![0; pivot_idx_count]);
+                    for pivot_idx in 0..pivot_idx_count {
+                        rpu_nal.pred_pivot_value[cmp][pivot_idx] =
+                            reader.get_n(rpu_nal.bl_bit_depth() as usize);
+                    }
+                }
+
+                // Profile 7 only
+                if rpu_nal.has_el_residual() {
+                    rpu_nal.nlq_method_idc = Some(reader.get_n(3));
+                    rpu_nal.nlq_num_pivots_minus2 = Some(0);
                 }
+
+                rpu_nal.num_x_partitions_minus1 = reader.get_ue();
+                rpu_nal.num_y_partitions_minus1 = reader.get_ue();
             }
         }
 
-        rpu_nal
+        Ok(rpu_nal)
+    }
+
+    /// Bit depth of the BL, the width of the mapping pivots
+    pub fn bl_bit_depth(&self) -> u64 {
+        self.bl_bit_depth_minus8 + 8
+    }
+
+    /// Bit depth of the reconstructed VDR signal, the output of the mapping
+    pub fn vdr_bit_depth(&self) -> u64 {
+        self.vdr_bit_depth_minus_8 + 8
     }
 
-    pub fn validate(&self, profile: u8) {
-        assert_eq!(self.rpu_nal_prefix, 25);
+    /// Whether the mapping input and output are normalized to [0, 1].
+    /// With `vdr_rpu_normalized_idc` 0, the mapping coefficients apply to BL and VDR codewords.
+    pub fn is_normalized_prediction(&self) -> bool {
+        self.vdr_rpu_normalized_idc != 0
+    }
+
+    /// Whether the RPU carries the EL residual syntax (NLQ data).
+    /// `el_spatial_resampling_filter_flag` only signals the EL is upscaled,
+    /// the residual presence depends on `disable_residual_flag` alone.
+    pub fn has_el_residual(&self) -> bool {
+        self.rpu_format & 0x700 == 0 && !self.disable_residual_flag
+    }
+
+    /// Returns the list of constraints the header doesn't respect.
+    pub fn validate(&self, profile: u8) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.rpu_nal_prefix != 25 {
+            warnings.push(format!(
+                "rpu_nal_prefix should be 25, found {}",
+                self.rpu_nal_prefix
+            ));
+        }
 
         match profile {
             5 => {
-                assert_eq!(self.vdr_rpu_profile, 0);
-                assert_eq!(self.bl_video_full_range_flag, true);
-                assert_eq!(self.nlq_method_idc, None);
-                assert_eq!(self.nlq_num_pivots_minus2, None);
-            }
-            7 => {
-                assert_eq!(self.vdr_rpu_profile, 1);
+                if self.vdr_rpu_profile != 0 {
+                    warnings.push(format!(
+                        "Profile 5: vdr_rpu_profile should be 0, found {}",
+                        self.vdr_rpu_profile
+                    ));
+                }
+
+                if !self.bl_video_full_range_flag {
+                    warnings.push("Profile 5: bl_video_full_range_flag should be set".to_string());
+                }
+
+                if self.nlq_method_idc.is_some() || self.nlq_num_pivots_minus2.is_some() {
+                    warnings.push("Profile 5: NLQ data should not be present".to_string());
+                }
             }
-            8 => {
-                assert_eq!(self.vdr_rpu_profile, 1);
-                assert_eq!(self.nlq_method_idc, None);
-                assert_eq!(self.nlq_num_pivots_minus2, None);
+            7 | 8 => {
+                if self.vdr_rpu_profile != 1 {
+                    warnings.push(format!(
+                        "Profile {}: vdr_rpu_profile should be 1, found {}",
+                        profile, self.vdr_rpu_profile
+                    ));
+                }
+
+                if profile == 8
+                    && (self.nlq_method_idc.is_some() || self.nlq_num_pivots_minus2.is_some())
+                {
+                    warnings.push("Profile 8: NLQ data should not be present".to_string());
+                }
             }
             _ => (),
         };
 
-        assert_eq!(self.vdr_rpu_level, 0);
-        assert_eq!(self.bl_bit_depth_minus8, 2);
-        assert_eq!(self.el_bit_depth_minus8, 2);
-        assert!(self.vdr_bit_depth_minus_8 <= 6);
-        assert_eq!(self.mapping_color_space, 0);
-        assert_eq!(self.mapping_chroma_format_idc, 0);
-        assert!(self.coefficient_log2_denom <= 23);
+        if self.vdr_rpu_level != 0 {
+            warnings.push(format!(
+                "vdr_rpu_level should be 0, found {}",
+                self.vdr_rpu_level
+            ));
+        }
+
+        if self.bl_bit_depth_minus8 != 2 {
+            warnings.push(format!(
+                "bl_bit_depth_minus8 should be 2, found {}",
+                self.bl_bit_depth_minus8
+            ));
+        }
+
+        if self.el_bit_depth_minus8 != 2 {
+            warnings.push(format!(
+                "el_bit_depth_minus8 should be 2, found {}",
+                self.el_bit_depth_minus8
+            ));
+        }
+
+        if self.vdr_bit_depth_minus_8 > 6 {
+            warnings.push(format!(
+                "vdr_bit_depth_minus_8 should be at most 6, found {}",
+                self.vdr_bit_depth_minus_8
+            ));
+        }
+
+        if self.mapping_color_space != 0 {
+            warnings.push(format!(
+                "mapping_color_space should be 0, found {}",
+                self.mapping_color_space
+            ));
+        }
+
+        if self.mapping_chroma_format_idc != 0 {
+            warnings.push(format!(
+                "mapping_chroma_format_idc should be 0, found {}",
+                self.mapping_chroma_format_idc
+            ));
+        }
+
+        if self.coefficient_log2_denom > 23 {
+            warnings.push(format!(
+                "coefficient_log2_denom should be at most 23, found {}",
+                self.coefficient_log2_denom
+            ));
+        }
+
+        warnings
+    }
+
+    /// Returns the EL flag combinations that aren't expected, without making the RPU invalid.
+    /// An upscaled EL without residual is unusual, but the NLQ syntax only depends on `disable_residual_flag`.
+    pub fn el_flag_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.el_spatial_resampling_filter_flag && self.disable_residual_flag {
+            warnings.push(
+                "el_spatial_resampling_filter_flag should be 0 when disable_residual_flag is set"
+                    .to_string(),
+            );
+        }
+
+        warnings
     }
 
     pub fn get_dovi_profile(&self) -> u8 {
         match self.vdr_rpu_profile {
-            0 => {
-                // Profile 5 is full range
-                if self.bl_video_full_range_flag {
-                    5
-                } else {
-                    0
-                }
-            }
+            // Profile 5 is full range
+            0 if self.bl_video_full_range_flag => 5,
             1 => {
                 // 4, 7 or 8
                 if self.el_spatial_resampling_filter_flag && !self.disable_residual_flag {
@@ -178,6 +336,12 @@ impl RpuDataHeader {
 
                 if self.vdr_seq_info_present_flag {
                     writer.write(self.chroma_resampling_explicit_filter_flag);
+
+                    // The rest of the payload is in the remaining bits
+                    if self.chroma_resampling_explicit_filter_flag {
+                        return;
+                    }
+
                     writer.write_n(&self.coefficient_data_type.to_be_bytes(), 2);
 
                     if self.coefficient_data_type == 0 {
@@ -216,12 +380,12 @@ impl RpuDataHeader {
                         for pivot_idx in 0..pivot_idx_count {
                             writer.write_n(
                                 &self.pred_pivot_value[cmp][pivot_idx].to_be_bytes(),
-                                (self.bl_bit_depth_minus8 + 8) as usize,
+                                self.bl_bit_depth() as usize,
                             );
                         }
                     }
 
-                    if self.rpu_format & 0x700 == 0 && !self.disable_residual_flag {
+                    if self.has_el_residual() {
                         if let Some(nlq_method_idc) = self.nlq_method_idc {
                             writer.write_n(&nlq_method_idc.to_be_bytes(), 3);
                         }
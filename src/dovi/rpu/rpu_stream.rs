@@ -0,0 +1,490 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use super::pq::pq_to_nits;
+use super::vdr_dm_data::{
+    CmVersion, ExtBlockLevel, ExtMetadataBlockLevel1, TypedExtBlock, VdrDmData,
+};
+use super::vdr_rpu_data::MappingMethod;
+use super::{value_changes, DoviRpu};
+
+/// Frame indexed view of a parsed RPU stream, in decoding order.
+/// The `use_prev_vdr_rpu_flag` references are resolved to the RPU carrying the mapping.
+///
+/// The DM metadata isn't covered by `use_prev_vdr_rpu_flag`, a frame reusing a mapping has its own.
+/// The DM metadata of an RPU defines the set `affected_dm_metadata_id`, and the frame applies
+/// the set `current_dm_metadata_id`, which is usually the same.
+/// Frames without DM metadata keep the set applied by the previous frame.
+pub struct RpuStream<'a> {
+    rpus: &'a [DoviRpu],
+    /// Index of the RPU carrying the mapping of each frame
+    mapping_sources: Vec<Option<usize>>,
+    /// Index of the RPU carrying the active DM metadata set of each frame
+    dm_sources: Vec<Option<usize>>,
+}
+
+/// RPU of a frame, with its effective mapping
+#[derive(Debug)]
+pub struct FrameRpu<'a> {
+    pub rpu: &'a DoviRpu,
+    /// Frame index and RPU carrying the mapping, the frame's own unless it uses a previous one.
+    /// `None` when the referenced vdr_rpu_id wasn't defined before.
+    pub mapping: Option<(usize, &'a DoviRpu)>,
+    /// Frame index and DM metadata set applied, the one `current_dm_metadata_id` indicates as active.
+    /// `None` before the first frame with DM metadata.
+    pub dm_data: Option<(usize, &'a VdrDmData)>,
+}
+
+impl<'a> RpuStream<'a> {
+    pub fn new(rpus: &'a [DoviRpu]) -> RpuStream<'a> {
+        // Last RPU defining each vdr_rpu_id
+        let mut definitions: HashMap<u64, usize> = HashMap::new();
+
+        let mapping_sources = rpus
+            .iter()
+            .enumerate()
+            .map(|(index, rpu)| {
+                let header = &rpu.header;
+
+                if header.rpu_type != 2 {
+                    None
+                } else if header.use_prev_vdr_rpu_flag {
+                    definitions.get(&header.prev_vdr_rpu_id).copied()
+                } else {
+                    definitions.insert(header.vdr_rpu_id, index);
+                    Some(index)
+                }
+            })
+            .collect();
+
+        // Last RPU defining each DM metadata id
+        let mut dm_sets: HashMap<u64, usize> = HashMap::new();
+        let mut active_dm = None;
+
+        let dm_sources = rpus
+            .iter()
+            .enumerate()
+            .map(|(index, rpu)| {
+                if let Some(ref vdr_dm_data) = rpu.vdr_dm_data {
+                    let (affected, current) = vdr_dm_data.dm_metadata_ids();
+                    dm_sets.insert(affected, index);
+
+                    // A set that wasn't defined before can't be active, the frame's own applies
+                    active_dm = Some(dm_sets.get(&current).copied().unwrap_or(index));
+                }
+
+                active_dm
+            })
+            .collect();
+
+        RpuStream {
+            rpus,
+            mapping_sources,
+            dm_sources,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rpus.len()
+    }
+
+    pub fn get(&self, frame: usize) -> Option<FrameRpu<'a>> {
+        let rpu = self.rpus.get(frame)?;
+        let mapping = self.mapping_sources[frame].map(|index| (index, &self.rpus[index]));
+        let dm_data = self.dm_sources[frame]
+            .and_then(|index| Some((index, self.rpus[index].vdr_dm_data.as_ref()?)));
+
+        Some(FrameRpu {
+            rpu,
+            mapping,
+            dm_data,
+        })
+    }
+
+    /// DM metadata set active for the frame, see `FrameRpu::dm_data`
+    pub fn effective_dm_set(&self, frame: usize) -> Option<&'a VdrDmData> {
+        self.get(frame)?.dm_data.map(|(_, vdr_dm_data)| vdr_dm_data)
+    }
+
+    /// Content mapping version of the stream: CM v4.0 when a frame carries the CM v4.0 ext block list,
+    /// see `VdrDmData::cm_version`, CM v2.9 otherwise, including without DM metadata.
+    ///
+    /// A CM v4.0 stream usually has its CM v4.0 list in every frame,
+    /// a single frame is enough as the CM v2.9 list is kept in CM v4.0 metadata.
+    pub fn cm_version(&self) -> CmVersion {
+        let is_cmv4 = self
+            .rpus
+            .iter()
+            .filter_map(|rpu| rpu.vdr_dm_data.as_ref())
+            .any(|vdr_dm_data| vdr_dm_data.cm_version() == CmVersion::V40);
+
+        if is_cmv4 {
+            CmVersion::V40
+        } else {
+            CmVersion::V29
+        }
+    }
+
+    /// Frames whose effective DM metadata lacks one of the `required` ext block levels.
+    /// Frames without any DM metadata to apply lack them all.
+    pub fn find_missing_blocks(&self, required: &[ExtBlockLevel]) -> Vec<usize> {
+        (0..self.len())
+            .filter(|frame| match self.effective_dm_set(*frame) {
+                Some(dm_data) => required.iter().any(|level| {
+                    !dm_data
+                        .ext_blocks()
+                        .any(|block| block.level() == Some(*level))
+                }),
+                None => !required.is_empty(),
+            })
+            .collect()
+    }
+
+    /// Mapping methods and pivot counts of the Y, Cb and Cr components.
+    /// The frames reusing a previous mapping count it, the ones without mapping are left out.
+    pub fn mapping_summary(&self) -> Vec<MappingTypeSummary> {
+        let mut summary = vec![MappingTypeSummary::default(); 3];
+
+        let mappings = (0..self.len())
+            .filter_map(|frame| self.get(frame)?.mapping)
+            .filter_map(|(_, rpu)| rpu.component_mappings());
+
+        for component_mappings in mappings {
+            for (cmp, mapping) in summary.iter_mut().zip(component_mappings) {
+                let all = |method| mapping.methods.iter().all(|m| *m == method);
+
+                if all(MappingMethod::Polynomial) {
+                    cmp.polynomial += 1;
+                } else if all(MappingMethod::Mmr) {
+                    cmp.mmr += 1;
+                } else {
+                    cmp.mixed += 1;
+                }
+
+                cmp.frames += 1;
+                *cmp.pivot_counts.entry(mapping.num_pivots).or_insert(0) += 1;
+            }
+        }
+
+        summary
+    }
+}
+
+/// Mapping methods and pivot counts of a component, across the frames of a stream
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MappingTypeSummary {
+    /// Frames with a mapping
+    pub frames: usize,
+    /// Frames mapped with polynomials only
+    pub polynomial: usize,
+    /// Frames mapped with MMR only
+    pub mmr: usize,
+    /// Frames mixing polynomial and MMR segments
+    pub mixed: usize,
+    /// Number of frames for each pivot count
+    pub pivot_counts: BTreeMap<usize, usize>,
+}
+
+/// Frames sharing the same effective metadata
+#[derive(Debug, Serialize)]
+pub struct MetadataRun {
+    pub first_frame: usize,
+    pub last_frame: usize,
+    /// Fields changed from the previous run, empty for the first one
+    pub changes: Vec<String>,
+    /// Effective metadata of the frames, see `FrameRpu::effective_metadata`
+    pub metadata: Value,
+}
+
+impl<'a> RpuStream<'a> {
+    /// Splits the stream into runs of frames with the same effective metadata.
+    /// A run starts at every frame whose metadata differs from the previous frame's.
+    pub fn metadata_runs(&self) -> Vec<MetadataRun> {
+        let mut runs: Vec<MetadataRun> = Vec::new();
+
+        for frame in 0..self.len() {
+            let metadata = self.get(frame).unwrap().effective_metadata();
+
+            match runs.last_mut() {
+                Some(run) if run.metadata == metadata => run.last_frame = frame,
+                previous => {
+                    let changes = previous
+                        .map(|run| value_changes(&run.metadata, &metadata))
+                        .unwrap_or_default();
+
+                    runs.push(MetadataRun {
+                        first_frame: frame,
+                        last_frame: frame,
+                        changes,
+                        metadata,
+                    });
+                }
+            }
+        }
+
+        runs
+    }
+}
+
+/// Aggregate statistics of a stream, from the DM metadata set active for each frame
+#[derive(Debug, Default, Serialize)]
+pub struct StreamStats {
+    pub frames: usize,
+    /// Number of frames of each profile, e.g. `8.1`
+    pub profiles: BTreeMap<String, usize>,
+    /// Content mapping version, see `RpuStream::cm_version`
+    pub cm_version: CmVersion,
+    /// Frames with `scene_refresh_flag` set
+    pub scene_cuts: usize,
+    /// Frames without DM metadata to apply
+    pub frames_without_dm: usize,
+    /// L1 luminance over the frames with L1 metadata, `None` without any
+    pub luminance: Option<LuminanceStats>,
+    /// Distinct L2 target_max_pq values
+    pub l2_targets: Vec<u16>,
+    /// Number of frames with each ext_block_level
+    pub block_levels: BTreeMap<u8, usize>,
+    /// MaxCLL and MaxFALL in nits, see `light_level_source`
+    pub max_cll: Option<u16>,
+    pub max_fall: Option<u16>,
+    /// `L6` when signaled in the L6 metadata, `L1` when computed from the L1 luminance
+    pub light_level_source: Option<&'static str>,
+}
+
+/// L1 luminance of a stream, in nits
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct LuminanceStats {
+    /// Lowest min_pq
+    pub min_nits: f64,
+    /// Highest max_pq
+    pub max_nits: f64,
+    /// Mean of the normalized avg_pq of every frame, see `ExtMetadataBlockLevel1::normalized_avg_pq`
+    pub avg_nits: f64,
+    /// Highest normalized avg_pq
+    pub max_avg_nits: f64,
+}
+
+impl<'a> RpuStream<'a> {
+    /// Aggregates the metadata of every frame into `StreamStats`.
+    /// The MaxCLL and MaxFALL are the first non zero L6 values, or else computed from the L1 luminance.
+    pub fn stats(&self) -> StreamStats {
+        let mut stats = StreamStats {
+            frames: self.len(),
+            cm_version: self.cm_version(),
+            ..Default::default()
+        };
+
+        let mut luminance: Option<LuminanceStats> = None;
+        let mut avg_nits_sum = 0.0;
+        let mut l1_frames = 0;
+        let mut l6_light_levels = None;
+
+        for (frame, rpu) in self.rpus.iter().enumerate() {
+            *stats.profiles.entry(rpu.profile_name()).or_insert(0) += 1;
+
+            if rpu
+                .vdr_dm_data
+                .as_ref()
+                .is_some_and(|vdr_dm_data| vdr_dm_data.is_scene_cut())
+            {
+                stats.scene_cuts += 1;
+            }
+
+            let vdr_dm_data = match self.effective_dm_set(frame) {
+                Some(vdr_dm_data) => vdr_dm_data,
+                None => {
+                    stats.frames_without_dm += 1;
+                    continue;
+                }
+            };
+
+            let levels: BTreeSet<u8> = vdr_dm_data
+                .ext_blocks()
+                .map(|block| block.ext_block_level())
+                .collect();
+
+            for level in levels {
+                *stats.block_levels.entry(level).or_insert(0) += 1;
+            }
+
+            stats.l2_targets.extend(vdr_dm_data.l2_targets());
+
+            for block in vdr_dm_data.blocks() {
+                match block {
+                    TypedExtBlock::L1 { min_pq, max_pq, .. } => {
+                        let avg_nits = ExtMetadataBlockLevel1::normalized_avg_pq(
+                            vdr_dm_data,
+                            vdr_dm_data.cm_version(),
+                        )
+                        .map(pq_to_nits)
+                        .unwrap_or_default();
+
+                        let (min_nits, max_nits) = (pq_to_nits(min_pq), pq_to_nits(max_pq));
+
+                        let frame_luminance = luminance.get_or_insert(LuminanceStats {
+                            min_nits,
+                            max_nits,
+                            avg_nits: 0.0,
+                            max_avg_nits: avg_nits,
+                        });
+
+                        frame_luminance.min_nits = frame_luminance.min_nits.min(min_nits);
+                        frame_luminance.max_nits = frame_luminance.max_nits.max(max_nits);
+                        frame_luminance.max_avg_nits = frame_luminance.max_avg_nits.max(avg_nits);
+
+                        avg_nits_sum += avg_nits;
+                        l1_frames += 1;
+                    }
+                    TypedExtBlock::L6 {
+                        max_content_light_level,
+                        max_frame_average_light_level,
+                        ..
+                    } if l6_light_levels.is_none() && max_content_light_level > 0 => {
+                        l6_light_levels =
+                            Some((max_content_light_level, max_frame_average_light_level));
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        stats.l2_targets.sort_unstable();
+        stats.l2_targets.dedup();
+
+        if let Some(ref mut luminance) = luminance {
+            luminance.avg_nits = avg_nits_sum / l1_frames as f64;
+        }
+
+        if let Some((max_cll, max_fall)) = l6_light_levels {
+            stats.max_cll = Some(max_cll);
+            stats.max_fall = Some(max_fall);
+            stats.light_level_source = Some("L6");
+        } else if let Some(ref luminance) = luminance {
+            stats.max_cll = Some(luminance.max_nits.round() as u16);
+            stats.max_fall = Some(luminance.max_avg_nits.round() as u16);
+            stats.light_level_source = Some("L1");
+        }
+
+        stats.luminance = luminance;
+
+        stats
+    }
+}
+
+impl FrameRpu<'_> {
+    /// Serialized metadata applied to the frame, with the mapping of the RPU carrying it.
+    ///
+    /// The metadata ids, `use_prev_vdr_rpu_flag` and `scene_refresh_flag` are left out,
+    /// they differ between frames applying the same metadata.
+    pub fn effective_metadata(&self) -> Value {
+        let mut metadata = serde_json::to_value(self.rpu).unwrap();
+
+        if let Some((_, mapping_rpu)) = self.mapping {
+            if !std::ptr::eq(mapping_rpu, self.rpu) {
+                let mut header = serde_json::to_value(&mapping_rpu.header).unwrap();
+                header["vdr_dm_metadata_present_flag"] =
+                    self.rpu.header.vdr_dm_metadata_present_flag.into();
+
+                metadata["header"] = header;
+                metadata["rpu_data_mapping"] =
+                    serde_json::to_value(&mapping_rpu.vdr_rpu_data).unwrap();
+                metadata["rpu_data_nlq"] = serde_json::to_value(&mapping_rpu.nlq_data).unwrap();
+            }
+        }
+
+        let fields = [
+            ("header", "vdr_rpu_id"),
+            ("header", "use_prev_vdr_rpu_flag"),
+            ("header", "prev_vdr_rpu_id"),
+            ("vdr_dm_data", "affected_dm_metadata_id"),
+            ("vdr_dm_data", "current_dm_metadata_id"),
+            ("vdr_dm_data", "scene_refresh_flag"),
+        ];
+
+        for (parent, field) in &fields {
+            if let Some(Value::Object(object)) = metadata.get_mut(parent) {
+                object.remove(*field);
+            }
+        }
+
+        metadata
+    }
+}
+
+/// Largest vdr_rpu_id and DM metadata id
+const MAX_METADATA_ID: u64 = 15;
+
+/// Renumbers the vdr_rpu_id and DM metadata ids of RPU streams, in decoding order.
+///
+/// Every distinct id of a stream gets a new id, from `first_id` in order of appearance,
+/// and the `prev_vdr_rpu_id` references follow their definition.
+/// The ids of the next stream continue after the ones of the previous stream,
+/// so that spliced streams don't share ids. Past 15, they wrap around to 0.
+#[derive(Debug, Default)]
+pub struct IdRenumbering {
+    mapping_ids: HashMap<u64, u64>,
+    dm_ids: HashMap<u64, u64>,
+    next_mapping_id: u64,
+    next_dm_id: u64,
+}
+
+impl IdRenumbering {
+    pub fn new(first_id: u64) -> Result<IdRenumbering, String> {
+        if first_id > MAX_METADATA_ID {
+            return Err(format!(
+                "The first metadata id should be at most {}, found {}",
+                MAX_METADATA_ID, first_id
+            ));
+        }
+
+        Ok(IdRenumbering {
+            next_mapping_id: first_id,
+            next_dm_id: first_id,
+            ..Default::default()
+        })
+    }
+
+    /// Starts renumbering another stream, with new ids
+    pub fn next_stream(&mut self) {
+        self.mapping_ids.clear();
+        self.dm_ids.clear();
+    }
+
+    pub fn renumber(&mut self, rpu: &mut DoviRpu) {
+        let header = &mut rpu.header;
+
+        if header.rpu_type == 2 {
+            let mapping_ids = &mut self.mapping_ids;
+            let next_mapping_id = &mut self.next_mapping_id;
+
+            if header.use_prev_vdr_rpu_flag {
+                header.prev_vdr_rpu_id =
+                    new_id(mapping_ids, next_mapping_id, header.prev_vdr_rpu_id);
+            } else {
+                header.vdr_rpu_id = new_id(mapping_ids, next_mapping_id, header.vdr_rpu_id);
+            }
+        }
+
+        if let Some(ref mut vdr_dm_data) = rpu.vdr_dm_data {
+            let (affected, current) = vdr_dm_data.dm_metadata_ids();
+
+            vdr_dm_data.set_dm_metadata_ids(
+                new_id(&mut self.dm_ids, &mut self.next_dm_id, affected),
+                new_id(&mut self.dm_ids, &mut self.next_dm_id, current),
+            );
+        }
+
+        rpu.modified = true;
+    }
+}
+
+fn new_id(ids: &mut HashMap<u64, u64>, next_id: &mut u64, id: u64) -> u64 {
+    *ids.entry(id).or_insert_with(|| {
+        let new_id = *next_id;
+        *next_id = (new_id + 1) % (MAX_METADATA_ID + 1);
+
+        new_id
+    })
+}
@@ -1,8 +1,26 @@
 use std::fs::File;
 use std::{io::Read, path::PathBuf};
 
-use super::parse_dovi_rpu;
-use super::DoviRpu;
+use super::pq::{
+    hlg_to_nits, nits_to_normalized_pq, nits_to_pq, pq_to_nits, pq_to_round_nits,
+    HLG_REFERENCE_NITS,
+};
+use super::rpu_stream::{IdRenumbering, RpuStream};
+use super::vdr_dm_data::{
+    sign_extend, BlockInfo, CmVersion, ExtBlockLevel, ExtMetadataBlock, ExtMetadataBlockLevel1,
+    ExtMetadataBlockLevel5, ExtMetadataBlockLevel6, L2TrimField, TypedExtBlock, VdrDmData,
+};
+use super::vdr_rpu_data::MappingMethod;
+use super::{
+    add_start_code_emulation_prevention_3_byte, clear_start_code_emulation_prevention_3_byte,
+};
+use super::{
+    dedup_mappings, duplicate_runs, from_rpu_list_json, from_rpu_list_msgpack, l2_target_counts,
+    mapping_curves_match, merge_rpus, minify_rpus, parse_dovi_rpu, parse_vdr_dm_data,
+    profile_from_config, repair_rpu_terminator, rpu_changes, rpu_crc32, snap_scene_cuts,
+    to_rpu_list_json, to_rpu_list_msgpack,
+};
+use super::{BitVecReader, BitVecWriter, DoviProfile, DoviRpu, RpuDataHeader};
 
 pub fn _parse_file(input: PathBuf) -> (Vec<u8>, DoviRpu) {
     let mut f = File::open(input).unwrap();
@@ -11,7 +29,7 @@ pub fn _parse_file(input: PathBuf) -> (Vec<u8>, DoviRpu) {
     let mut original_data = vec![0; metadata.len() as usize];
     f.read_exact(&mut original_data).unwrap();
 
-    let dovi_rpu = parse_dovi_rpu(&original_data).unwrap();
+    let dovi_rpu = parse_dovi_rpu(&original_data, false).unwrap();
 
     (original_data, dovi_rpu)
 }
@@ -175,8 +193,2731 @@ fn sets_offsets_to_zero() {
     dovi_rpu.crop();
     let parsed_data = dovi_rpu.write_rpu_data();
 
-    let mut dovi_rpu = parse_dovi_rpu(&parsed_data).unwrap();
+    let mut dovi_rpu = parse_dovi_rpu(&parsed_data, false).unwrap();
     if let Some(block) = super::vdr_dm_data::ExtMetadataBlockLevel5::get_mut(&mut dovi_rpu) {
         assert_eq!(vec![0, 0, 0, 0], block._get_offsets());
     }
 }
+
+#[test]
+fn interpolate_l2_trims() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/data_before_crc32.bin"));
+    let vdr_dm_data = dovi_rpu.vdr_dm_data.unwrap();
+
+    // Existing target, 600 nits
+    let trims = vdr_dm_data.interpolate_l2(2851);
+    assert_eq!(trims.trim_slope, 2059);
+    assert_eq!(trims.trim_power, 1955);
+
+    // Halfway between the 100 and 600 nits targets
+    let trims = vdr_dm_data.interpolate_l2(2466);
+    assert_eq!(trims.target_max_pq, 2466);
+    assert_eq!(trims.trim_slope, 2036);
+    assert_eq!(trims.trim_offset, 2032);
+    assert_eq!(trims.trim_power, 1647);
+
+    // Below the lowest target
+    let trims = vdr_dm_data.interpolate_l2(1000);
+    assert_eq!(trims.trim_slope, 2013);
+}
+
+fn _invalid_level_rpu() -> Vec<u8> {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+
+    dovi_rpu.modified = true;
+    dovi_rpu.header.vdr_rpu_level = 1;
+
+    dovi_rpu.write_rpu_data()
+}
+
+#[test]
+fn strict_validation() {
+    let err = parse_dovi_rpu(&_invalid_level_rpu(), false).unwrap_err();
+    assert!(err.contains("vdr_rpu_level"));
+}
+
+#[test]
+fn lenient_validation() {
+    let dovi_rpu = parse_dovi_rpu(&_invalid_level_rpu(), true).unwrap();
+
+    assert_eq!(dovi_rpu.dovi_profile, 8);
+    assert_eq!(
+        dovi_rpu.warnings,
+        vec!["vdr_rpu_level should be 0, found 1".to_string()]
+    );
+}
+
+#[test]
+fn rpu_list_json_round_trip() {
+    let (original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/data_before_crc32.bin"));
+    let (eof_data, eof_rpu) = _parse_file(PathBuf::from("./assets/eof_rpu.bin"));
+
+    let json = to_rpu_list_json(&[dovi_rpu, eof_rpu]).unwrap();
+    let mut rpus = from_rpu_list_json(&json, false).unwrap();
+
+    assert_eq!(rpus.len(), 2);
+    assert_eq!(rpus[0].dovi_profile, 7);
+    assert_eq!(&original_data, &rpus[0].write_rpu_data());
+    assert_eq!(&eof_data, &rpus[1].write_rpu_data());
+}
+
+#[test]
+fn rpu_list_msgpack_round_trip() {
+    let assets = [
+        "fel_orig.bin",
+        "mel_orig.bin",
+        "profile8.bin",
+        "data_before_crc32.bin",
+        "eof_rpu.bin",
+    ];
+
+    let (original_data, original_rpus): (Vec<Vec<u8>>, Vec<DoviRpu>) = assets
+        .iter()
+        .map(|asset| _parse_file(PathBuf::from("./assets").join(asset)))
+        .unzip();
+
+    let data = to_rpu_list_msgpack(&original_rpus).unwrap();
+    let mut rpus = from_rpu_list_msgpack(&data, false).unwrap();
+
+    // Smaller than the JSON list
+    assert!(data.len() < to_rpu_list_json(&original_rpus).unwrap().len());
+
+    assert_eq!(
+        serde_json::to_value(&rpus).unwrap(),
+        serde_json::to_value(&original_rpus).unwrap()
+    );
+
+    for (rpu, original) in rpus.iter_mut().zip(original_data) {
+        assert_eq!(rpu.write_rpu_data(), original);
+    }
+
+    assert!(from_rpu_list_msgpack(&data[..data.len() / 2], false).is_err());
+}
+
+#[test]
+fn component_mappings() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let mappings = dovi_rpu.component_mappings().unwrap();
+
+    assert_eq!(mappings.len(), 3);
+
+    assert_eq!(mappings[0].num_pivots, 9);
+    assert_eq!(
+        mappings[0].pivots,
+        vec![0, 128, 256, 384, 512, 640, 768, 896, 1023]
+    );
+    assert_eq!(mappings[0].methods, vec![MappingMethod::Polynomial; 8]);
+
+    for chroma in &mappings[1..] {
+        assert_eq!(chroma.num_pivots, 2);
+        assert_eq!(chroma.pivots, vec![0, 1023]);
+        assert_eq!(chroma.methods, vec![MappingMethod::Mmr]);
+    }
+}
+
+#[test]
+fn el_residual_presence() {
+    for asset in &["./assets/fel_orig.bin", "./assets/mel_orig.bin"] {
+        let (_original_data, dovi_rpu) = _parse_file(PathBuf::from(asset));
+
+        assert!(dovi_rpu.header.el_spatial_resampling_filter_flag);
+        assert!(dovi_rpu.header.has_el_residual());
+        assert!(dovi_rpu.nlq_data.is_some());
+    }
+
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    assert!(!dovi_rpu.header.has_el_residual());
+    assert!(dovi_rpu.nlq_data.is_none());
+
+    // 8.1 conversion drops the residual syntax
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    dovi_rpu.convert_with_mode(2);
+
+    let converted = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    assert_eq!(converted.dovi_profile, 8);
+    assert!(!converted.header.has_el_residual());
+    assert!(converted.nlq_data.is_none());
+
+    // Upscaled EL without residual, warning in both modes
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    dovi_rpu.header.el_spatial_resampling_filter_flag = true;
+
+    for lenient in [false, true] {
+        assert!(dovi_rpu.validate(lenient).is_ok());
+        assert_eq!(
+            dovi_rpu.warnings,
+            vec!["el_spatial_resampling_filter_flag should be 0 when disable_residual_flag is set"]
+        );
+    }
+}
+
+#[test]
+fn nlq_and_residual() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    assert!(dovi_rpu.has_nlq());
+    assert!(dovi_rpu.has_residual());
+
+    // Converted to MEL, the NLQ data is kept but discards the residual
+    dovi_rpu.convert_with_mode(1);
+    assert!(dovi_rpu.has_nlq());
+    assert!(!dovi_rpu.has_residual());
+
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/mel_orig.bin"));
+    assert!(dovi_rpu.has_nlq());
+    assert!(!dovi_rpu.has_residual());
+
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    assert!(!dovi_rpu.has_nlq());
+    assert!(!dovi_rpu.has_residual());
+}
+
+#[test]
+fn nlq_threshold_roundtrip() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    // Distinct from linear_deadzone_slope_int, which the writer used to write in its place
+    let mut nlq = serde_json::to_value(&dovi_rpu.nlq_data).unwrap();
+    nlq["linear_deadzone_threshold_int"][0][0] = serde_json::json!(3);
+    assert_ne!(nlq["linear_deadzone_slope_int"][0][0], 3);
+
+    dovi_rpu.nlq_data = serde_json::from_value(nlq.clone()).unwrap();
+    dovi_rpu.modified = true;
+
+    let data = dovi_rpu.write_rpu_data();
+    let reparsed = parse_dovi_rpu(&data, false).unwrap();
+
+    assert_eq!(serde_json::to_value(&reparsed.nlq_data).unwrap(), nlq);
+}
+
+#[test]
+fn soft_mel() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let original_nlq = serde_json::to_value(&dovi_rpu.nlq_data).unwrap();
+    let original_mapping = serde_json::to_value(&dovi_rpu.vdr_rpu_data).unwrap();
+    let original_dm = serde_json::to_value(&dovi_rpu.vdr_dm_data).unwrap();
+
+    dovi_rpu.convert_with_mode(3);
+    let data = dovi_rpu.write_rpu_data();
+
+    let converted = parse_dovi_rpu(&data, false).unwrap();
+    assert_eq!(converted.dovi_profile, 7);
+    assert!(converted.has_nlq());
+    assert!(!converted.has_residual());
+
+    let nlq_data = converted.nlq_data.as_ref().unwrap();
+    assert!(nlq_data.is_identity_residual());
+
+    // The FEL offsets are kept, unlike MEL
+    assert!(!nlq_data.is_mel());
+
+    let nlq = serde_json::to_value(&converted.nlq_data).unwrap();
+    assert_eq!(nlq["nlq_offset"], original_nlq["nlq_offset"]);
+    assert_eq!(
+        serde_json::to_value(&converted.vdr_rpu_data).unwrap(),
+        original_mapping
+    );
+    assert_eq!(
+        serde_json::to_value(&converted.vdr_dm_data).unwrap(),
+        original_dm
+    );
+}
+
+#[test]
+fn bit_depths() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    assert_eq!(dovi_rpu.header.bl_bit_depth(), 10);
+    assert_eq!(dovi_rpu.header.vdr_bit_depth(), 12);
+    assert_eq!(dovi_rpu.signal_bit_depth(), Some(12));
+
+    let json = serde_json::to_value(&dovi_rpu).unwrap();
+    assert_eq!(json["vdr_dm_data"]["signal_bit_depth"], 12);
+
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    dovi_rpu.strip_dm_metadata();
+    assert_eq!(dovi_rpu.signal_bit_depth(), None);
+}
+
+#[test]
+fn renumber_metadata_ids() {
+    let stream = |asset: &str| {
+        let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets").join(asset));
+        let mut rpus = vec![dovi_rpu.clone(), dovi_rpu.clone(), dovi_rpu];
+
+        for rpu in &mut rpus[1..] {
+            rpu.use_prev_mapping(0);
+        }
+
+        rpus
+    };
+
+    // Both streams use the ids 0
+    let mut first = stream("fel_orig.bin");
+    let mut second = stream("mel_orig.bin");
+    assert_eq!(first[0].header.vdr_rpu_id, second[0].header.vdr_rpu_id);
+
+    let mut renumbering = IdRenumbering::new(0).unwrap();
+    first.iter_mut().for_each(|rpu| renumbering.renumber(rpu));
+
+    renumbering.next_stream();
+    second.iter_mut().for_each(|rpu| renumbering.renumber(rpu));
+
+    let rpus: Vec<DoviRpu> = first
+        .into_iter()
+        .chain(second)
+        .map(|mut rpu| parse_dovi_rpu(&rpu.write_rpu_data(), false).unwrap())
+        .collect();
+
+    let dm_ids = |rpu: &DoviRpu| rpu.vdr_dm_data.as_ref().unwrap().dm_metadata_ids();
+
+    assert_eq!(rpus[0].header.vdr_rpu_id, 0);
+    assert_eq!(dm_ids(&rpus[0]), (0, 0));
+    assert_eq!(rpus[3].header.vdr_rpu_id, 1);
+    assert_eq!(dm_ids(&rpus[3]), (1, 1));
+
+    // The references follow their stream's mapping
+    let stream = RpuStream::new(&rpus);
+
+    for (frame, source) in [(1, 0), (2, 0), (4, 3), (5, 3)] {
+        assert_eq!(rpus[frame].header.prev_vdr_rpu_id, source as u64 / 3);
+        assert_eq!(dm_ids(&rpus[frame]), (source as u64 / 3, source as u64 / 3));
+        assert_eq!(stream.get(frame).unwrap().mapping.unwrap().0, source);
+    }
+
+    assert!(IdRenumbering::new(16).is_err());
+}
+
+#[test]
+fn profile81_constraints() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    let fields: Vec<&str> = dovi_rpu
+        .validate_profile81()
+        .iter()
+        .map(|violation| violation.field)
+        .collect();
+
+    assert_eq!(
+        fields,
+        [
+            "el_spatial_resampling_filter_flag",
+            "disable_residual_flag",
+            "num_x_partitions_minus1",
+            "nlq_method_idc",
+            "rpu_data_nlq"
+        ]
+    );
+
+    dovi_rpu.convert_with_mode(2);
+    assert!(dovi_rpu.validate_profile81().is_empty());
+
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    assert!(dovi_rpu.validate_profile81().is_empty());
+
+    dovi_rpu.strip_dm_metadata();
+    let violations = dovi_rpu.validate_profile81();
+
+    assert_eq!(violations.len(), 1);
+    assert_eq!(
+        violations[0].to_string(),
+        "vdr_dm_metadata_present_flag should be 1, found 0"
+    );
+}
+
+#[test]
+fn hlg_signal_eotf() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+
+    let json = to_rpu_list_json(&[dovi_rpu]).unwrap();
+    assert!(json.contains("\"signal_eotf\":65535"));
+
+    // HLG, valid in strict mode
+    let json = json.replace("\"signal_eotf\":65535", "\"signal_eotf\":2");
+    let mut rpus = from_rpu_list_json(&json, false).unwrap();
+
+    let dovi_rpu = parse_dovi_rpu(&rpus[0].write_rpu_data(), false).unwrap();
+    let json = to_rpu_list_json(&[dovi_rpu]).unwrap();
+    assert!(json.contains("\"signal_eotf\":2"));
+
+    // Unknown value
+    let json = json.replace("\"signal_eotf\":2", "\"signal_eotf\":7");
+    assert!(from_rpu_list_json(&json, false).is_err());
+    assert!(from_rpu_list_json(&json, true).is_ok());
+}
+
+#[test]
+fn signal_color_space_and_chroma_format() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let vdr_dm_data = dovi_rpu.vdr_dm_data.as_ref().unwrap();
+
+    assert_eq!(vdr_dm_data.signal_color_space(), 0);
+    assert_eq!(vdr_dm_data.signal_chroma_format(), 0);
+    assert_eq!(vdr_dm_data.signal_format(), "YCbCr 4:2:0");
+
+    let json = to_rpu_list_json(&[dovi_rpu]).unwrap();
+    assert!(json.contains("\"signal_color_space\":0,\"signal_chroma_format\":0,"));
+
+    // IPT 4:4:4
+    let valid = json.replace(
+        "\"signal_color_space\":0,\"signal_chroma_format\":0,",
+        "\"signal_color_space\":2,\"signal_chroma_format\":2,",
+    );
+    let mut rpus = from_rpu_list_json(&valid, false).unwrap();
+    let dovi_rpu = parse_dovi_rpu(&rpus[0].write_rpu_data(), false).unwrap();
+    assert_eq!(
+        dovi_rpu.vdr_dm_data.as_ref().unwrap().signal_format(),
+        "IPT 4:4:4"
+    );
+
+    // Reserved values
+    let reserved = json.replace(
+        "\"signal_color_space\":0,\"signal_chroma_format\":0,",
+        "\"signal_color_space\":3,\"signal_chroma_format\":3,",
+    );
+    // Warnings, even when strict
+    for lenient in [false, true] {
+        let rpus = from_rpu_list_json(&reserved, lenient).unwrap();
+        assert_eq!(
+            rpus[0].warnings,
+            vec![
+                "signal_color_space should be at most 2, found 3",
+                "signal_chroma_format should be at most 2, found 3",
+            ]
+        );
+    }
+
+    let rpus = from_rpu_list_json(&reserved, false).unwrap();
+    assert_eq!(
+        rpus[0].vdr_dm_data.as_ref().unwrap().signal_format(),
+        "reserved (3) reserved (3)"
+    );
+}
+
+#[test]
+fn mapping_lut() {
+    // Identity curve: a linear ramp
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    let lut = dovi_rpu.mapping_lut(1024).unwrap();
+
+    assert_eq!(lut.len(), 1024);
+    for (i, sample) in lut.iter().enumerate() {
+        let x = i as f64 / 1023.0;
+        assert!(sample.iter().all(|value| (value - x).abs() < 1e-9));
+    }
+
+    // Linear luma pieces, MMR chroma
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let lut = dovi_rpu.mapping_lut(17).unwrap();
+
+    assert_eq!(lut[0][0], 0.0);
+    assert_eq!(lut[16][0], 1.0);
+    assert_eq!(
+        lut[8][1],
+        dovi_rpu
+            .vdr_rpu_data
+            .as_ref()
+            .unwrap()
+            .map_pixel(&dovi_rpu.header, 1, [0.5; 3])
+            .unwrap()
+    );
+
+    assert!(dovi_rpu.mapping_lut(1).is_err());
+}
+
+#[test]
+fn mapping_coefficients() {
+    // Identity curve
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    assert_eq!(dovi_rpu.mapping_coefficients(), vec![vec![0.0, 1.0]; 3]);
+
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let coefs = dovi_rpu.mapping_coefficients();
+    let denom = (1 << dovi_rpu.header.coefficient_log2_denom) as f64;
+
+    // 8 linear pieces
+    assert_eq!(coefs[0], [0.0, 1.0].repeat(8));
+
+    // MMR order 3: constant + 3 * 7 coefficients
+    assert_eq!(coefs[1].len(), 22);
+    assert_eq!(coefs[1][0], 448998.0 / denom);
+    assert_eq!(coefs[1][1], -1.0 + 7126552.0 / denom);
+    assert_eq!(coefs[2][0], 30364.0 / denom);
+}
+
+#[test]
+fn negative_ms_weight() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/data_before_crc32.bin"));
+
+    // Set through the JSON RPU list, the L2 fields aren't otherwise editable
+    let json = to_rpu_list_json(&[dovi_rpu]).unwrap();
+    let json = json.replace("\"ms_weight\":2048", "\"ms_weight\":-1");
+
+    let mut rpus = from_rpu_list_json(&json, false).unwrap();
+    let data = rpus[0].write_rpu_data();
+
+    let dovi_rpu = parse_dovi_rpu(&data, false).unwrap();
+    let vdr_dm_data = dovi_rpu.vdr_dm_data.unwrap();
+
+    assert_eq!(vdr_dm_data.interpolate_l2(2081).ms_weight, -1);
+    assert_eq!(vdr_dm_data.interpolate_l2(3079).ms_weight, -1);
+}
+
+#[test]
+fn clamp_l1_max() {
+    assert_eq!(nits_to_pq(100.0), 2081);
+    assert_eq!(nits_to_pq(1000.0), 3079);
+
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let original_json = to_rpu_list_json(std::slice::from_ref(&dovi_rpu)).unwrap();
+
+    // L1 max_pq is 2873, below 1000 nits
+    dovi_rpu.clamp_l1_max(1000.0);
+    assert!(!dovi_rpu.modified);
+
+    dovi_rpu.clamp_l1_max(100.0);
+    assert!(dovi_rpu.modified);
+
+    let dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let json = to_rpu_list_json(&[dovi_rpu]).unwrap();
+
+    // Only max_pq changed
+    assert_eq!(
+        json,
+        original_json.replace("\"max_pq\":2873", "\"max_pq\":2081")
+    );
+
+    // No L1 block
+    let mut dovi_rpu = DoviRpu::default();
+    dovi_rpu.clamp_l1_max(100.0);
+    assert!(!dovi_rpu.modified);
+}
+
+#[test]
+fn clamp_source_diagonal() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let original_json = to_rpu_list_json(std::slice::from_ref(&dovi_rpu)).unwrap();
+    assert!(original_json.contains("\"source_diagonal\":42,"));
+
+    // Within the range
+    dovi_rpu.clamp_source_diagonal(10, 100);
+    assert!(!dovi_rpu.modified);
+
+    dovi_rpu.clamp_source_diagonal(65, 65);
+    assert!(dovi_rpu.modified);
+
+    let mut dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let json = to_rpu_list_json(std::slice::from_ref(&dovi_rpu)).unwrap();
+
+    // Only source_diagonal changed
+    assert_eq!(
+        json,
+        original_json.replace("\"source_diagonal\":42,", "\"source_diagonal\":65,")
+    );
+
+    dovi_rpu.clamp_source_diagonal(0, 50);
+    let dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    assert_eq!(
+        to_rpu_list_json(&[dovi_rpu]).unwrap(),
+        original_json.replace("\"source_diagonal\":42,", "\"source_diagonal\":50,")
+    );
+
+    // No DM metadata
+    let mut dovi_rpu = DoviRpu::default();
+    dovi_rpu.clamp_source_diagonal(42, 42);
+    assert!(!dovi_rpu.modified);
+}
+
+#[test]
+fn set_l1_fixed() {
+    let (_original_data, fel_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    // Without the L1 block
+    let mut no_l1_rpu = fel_rpu.clone();
+    no_l1_rpu.remove_ext_blocks(ExtBlockLevel::L1Content);
+    let no_l1_rpu = parse_dovi_rpu(&no_l1_rpu.write_rpu_data(), false).unwrap();
+
+    let l1_of = |rpu: &DoviRpu| {
+        let l1: Vec<TypedExtBlock> = rpu
+            .vdr_dm_data
+            .as_ref()
+            .unwrap()
+            .blocks()
+            .filter(|block| matches!(block, TypedExtBlock::L1 { .. }))
+            .collect();
+        l1
+    };
+    assert!(l1_of(&no_l1_rpu).is_empty());
+
+    let fixed = TypedExtBlock::L1 {
+        min_pq: nits_to_pq(0.005),
+        max_pq: nits_to_pq(1000.0),
+        avg_pq: nits_to_pq(100.0),
+    };
+
+    for mut dovi_rpu in [fel_rpu, no_l1_rpu] {
+        let levels = dovi_rpu
+            .vdr_dm_data
+            .as_ref()
+            .unwrap()
+            .ext_metadata_blocks
+            .len();
+
+        dovi_rpu.set_l1_fixed(0.005, 100.0, 1000.0);
+        assert!(dovi_rpu.modified);
+
+        let mut dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+        let vdr_dm_data = dovi_rpu.vdr_dm_data.as_ref().unwrap();
+
+        assert_eq!(l1_of(&dovi_rpu), vec![fixed]);
+        assert_eq!(vdr_dm_data.ext_metadata_blocks[0].ext_block_level(), 1);
+        assert_eq!(vdr_dm_data.ext_metadata_blocks.len(), 5.max(levels));
+
+        // The inserted block is padded to its length, the following blocks are still parsed
+        assert!(!vdr_dm_data
+            .blocks()
+            .any(|block| matches!(block, TypedExtBlock::Reserved(_))));
+
+        // Already set
+        dovi_rpu.set_l1_fixed(0.005, 100.0, 1000.0);
+        assert!(!dovi_rpu.modified);
+    }
+
+    // No DM metadata
+    let mut dovi_rpu = DoviRpu::default();
+    dovi_rpu.set_l1_fixed(0.0, 10.0, 100.0);
+    assert!(!dovi_rpu.modified);
+}
+
+#[test]
+fn insert_l6() {
+    let (_original_data, fel_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    assert_eq!(pq_to_round_nits(3079, 1.0), 1000.0);
+    assert_eq!(pq_to_round_nits(7, 0.0001), 0.0001);
+
+    let l6_of = |rpu: &DoviRpu| {
+        let l6: Vec<TypedExtBlock> = rpu
+            .vdr_dm_data
+            .as_ref()
+            .unwrap()
+            .blocks()
+            .filter(|block| matches!(block, TypedExtBlock::L6 { .. }))
+            .collect();
+        l6
+    };
+
+    let original_l6 = TypedExtBlock::L6 {
+        max_display_mastering_luminance: 1000,
+        min_display_mastering_luminance: 1,
+        max_content_light_level: 1712,
+        max_frame_average_light_level: 175,
+    };
+    assert_eq!(l6_of(&fel_rpu), vec![original_l6]);
+
+    // Without the L6 block
+    let mut no_l6_rpu = fel_rpu.clone();
+    no_l6_rpu.remove_ext_blocks(ExtBlockLevel::L6Hdr10);
+    let mut no_l6_rpu = parse_dovi_rpu(&no_l6_rpu.write_rpu_data(), false).unwrap();
+    assert!(l6_of(&no_l6_rpu).is_empty());
+
+    // Mastering display from the source luminance
+    assert!(no_l6_rpu.insert_l6(1712, 175, false));
+    assert!(no_l6_rpu.modified);
+
+    let mut no_l6_rpu = parse_dovi_rpu(&no_l6_rpu.write_rpu_data(), false).unwrap();
+    assert_eq!(l6_of(&no_l6_rpu), vec![original_l6]);
+
+    // The inserted block is padded to its length, no block is left unparsed
+    let blocks: Vec<TypedExtBlock> = no_l6_rpu.vdr_dm_data.as_ref().unwrap().blocks().collect();
+    assert_eq!(blocks.len(), 5);
+    assert!(!blocks
+        .iter()
+        .any(|block| matches!(block, TypedExtBlock::Reserved(_))));
+
+    // Already identical, even with overwrite
+    assert!(!no_l6_rpu.insert_l6(1712, 175, true));
+    assert!(!no_l6_rpu.modified);
+
+    // Existing L6 kept
+    let mut dovi_rpu = fel_rpu;
+    assert!(!dovi_rpu.insert_l6(1000, 400, false));
+    assert!(!dovi_rpu.modified);
+    assert_eq!(l6_of(&dovi_rpu), vec![original_l6]);
+
+    // Or replaced
+    assert!(dovi_rpu.insert_l6(1000, 400, true));
+
+    let dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    assert_eq!(
+        l6_of(&dovi_rpu),
+        vec![TypedExtBlock::L6 {
+            max_display_mastering_luminance: 1000,
+            min_display_mastering_luminance: 1,
+            max_content_light_level: 1000,
+            max_frame_average_light_level: 400,
+        }]
+    );
+}
+
+#[test]
+// Writes an invalid RPU on purpose
+#[cfg_attr(feature = "debug-reparse", ignore)]
+fn unsupported_mapping_idc() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+
+    let json = to_rpu_list_json(&[dovi_rpu]).unwrap();
+    let json = json.replace("\"mapping_idc\":[[0", "\"mapping_idc\":[[2");
+
+    let mut rpus = from_rpu_list_json(&json, false).unwrap();
+    let data = rpus[0].write_rpu_data();
+
+    let err = parse_dovi_rpu(&data, false).unwrap_err();
+    assert_eq!(err, "Unsupported mapping_idc value: 2, at bit 139");
+
+    // Stopped right after the header and the 3 bits of the mapping_idc ue(v)
+    let mut writer = BitVecWriter::new();
+    rpus[0].header.write_header(&mut writer);
+
+    let header_bytes = writer.as_slice().to_vec();
+    let header_len = 8 * header_bytes.len();
+
+    let mut reader = BitVecReader::new(header_bytes);
+    RpuDataHeader::parse(&mut reader).unwrap();
+
+    assert_eq!(header_len - reader.available() + 3, 139);
+}
+
+#[test]
+fn explicit_resampling_filter() {
+    let (original_data, _) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    // Set chroma_resampling_explicit_filter_flag, the 35th bit of the payload
+    let mut bytes = clear_start_code_emulation_prevention_3_byte(&original_data[2..]);
+    assert_eq!(bytes[4] & 0x60, 0x40);
+    bytes[4] |= 0x20;
+
+    let crc32_end = bytes.len() - 1;
+    let crc32 = DoviRpu::compute_crc32(&bytes[1..crc32_end - 4]);
+    bytes[crc32_end - 4..crc32_end].copy_from_slice(&crc32.to_be_bytes());
+
+    add_start_code_emulation_prevention_3_byte(&mut bytes);
+    let data = [&[0x7C, 0x01], bytes.as_slice()].concat();
+
+    let err = parse_dovi_rpu(&data, false).unwrap_err();
+    assert_eq!(
+        err,
+        "Unsupported chroma_resampling_explicit_filter_flag: explicit resampling filters can't be parsed, --lenient keeps the RPU unparsed"
+    );
+
+    // Lenient: kept unparsed, written back bit exact
+    let mut dovi_rpu = parse_dovi_rpu(&data, true).unwrap();
+    assert!(dovi_rpu.header.chroma_resampling_explicit_filter_flag);
+    assert!(dovi_rpu.vdr_rpu_data.is_none() && dovi_rpu.vdr_dm_data.is_none());
+    assert_eq!(dovi_rpu.warnings.len(), 1);
+
+    assert_eq!(dovi_rpu.write_rpu_data(), data);
+
+    // Edited RPUs too
+    dovi_rpu.modified = true;
+    assert_eq!(dovi_rpu.write_rpu_data(), data);
+}
+
+#[test]
+fn stored_and_computed_crc32() {
+    let (original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    let (stored, computed) = rpu_crc32(&original_data[2..]).unwrap();
+    assert_eq!(stored, computed);
+    assert_eq!(stored, dovi_rpu.rpu_data_crc32);
+
+    // Altered payload, the stored CRC32 is unchanged
+    let mut altered = original_data.clone();
+    altered[10] ^= 0x01;
+
+    let (altered_stored, altered_computed) = rpu_crc32(&altered[2..]).unwrap();
+    assert_eq!(altered_stored, stored);
+    assert_ne!(altered_computed, stored);
+
+    assert!(rpu_crc32(&[0x19, 0x80]).is_err());
+}
+
+#[test]
+// Writes an invalid RPU on purpose
+#[cfg_attr(feature = "debug-reparse", ignore)]
+fn corrupted_num_ext_blocks() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let block_count = dovi_rpu
+        .vdr_dm_data
+        .as_ref()
+        .unwrap()
+        .ext_metadata_blocks
+        .len();
+
+    let declaring = |num_ext_blocks: u64| {
+        let mut value = serde_json::to_value(&dovi_rpu).unwrap();
+        value["vdr_dm_data"]["num_ext_blocks"] = num_ext_blocks.into();
+
+        let mut rpu: DoviRpu = serde_json::from_value(value).unwrap();
+        rpu.modified = true;
+
+        rpu.write_rpu_data()
+    };
+
+    let err = parse_dovi_rpu(&declaring(1_000_000_000), false).unwrap_err();
+    assert_eq!(
+        err,
+        "num_ext_blocks should be at most 255, found 1000000000, at bit 2584"
+    );
+
+    let err = parse_dovi_rpu(&declaring(200), false).unwrap_err();
+    assert_eq!(
+        err,
+        format!(
+            "num_ext_blocks is 200, only {} blocks could be parsed, at bit 2887",
+            block_count
+        )
+    );
+
+    // Lenient: truncated to the blocks in the RPU
+    for num_ext_blocks in [200, 1_000_000_000].iter() {
+        let rpu = parse_dovi_rpu(&declaring(*num_ext_blocks), true).unwrap();
+        let vdr_dm_data = rpu.vdr_dm_data.as_ref().unwrap();
+
+        assert_eq!(vdr_dm_data.ext_metadata_blocks.len(), block_count);
+        assert!(rpu.remaining.is_empty());
+        assert!(rpu.warnings.iter().any(|w| w.ends_with("truncated")));
+    }
+}
+
+#[test]
+fn clear_active_area() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let block_count = dovi_rpu
+        .vdr_dm_data
+        .as_ref()
+        .unwrap()
+        .ext_metadata_blocks
+        .len();
+
+    // A second L5 block
+    let mut value = serde_json::to_value(&dovi_rpu).unwrap();
+    let blocks = value["vdr_dm_data"]["ext_metadata_blocks"]
+        .as_array_mut()
+        .unwrap();
+    blocks.push(blocks[3].clone());
+    value["vdr_dm_data"]["num_ext_blocks"] = blocks.len().into();
+
+    let mut dovi_rpu: DoviRpu = serde_json::from_value(value).unwrap();
+    dovi_rpu.modified = true;
+
+    let l5_offsets = |rpu: &DoviRpu| -> Vec<serde_json::Value> {
+        let value = serde_json::to_value(rpu).unwrap();
+
+        value["vdr_dm_data"]["ext_metadata_blocks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|block| block.get("Level5"))
+            .flat_map(|block| {
+                ["left", "right", "top", "bottom"]
+                    .iter()
+                    .map(move |side| block[format!("active_area_{}_offset", side)].clone())
+            })
+            .collect()
+    };
+
+    assert_eq!(l5_offsets(&dovi_rpu).len(), 8);
+    assert!(l5_offsets(&dovi_rpu).iter().any(|offset| offset != 0));
+
+    dovi_rpu.crop();
+
+    let mut dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let offsets = l5_offsets(&dovi_rpu);
+
+    assert_eq!(offsets.len(), 8);
+    assert!(offsets.iter().all(|offset| offset == 0));
+
+    // Variant removing the blocks
+    assert_eq!(dovi_rpu.clear_active_area(), 2);
+
+    let dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    assert!(l5_offsets(&dovi_rpu).is_empty());
+    assert_eq!(
+        dovi_rpu.vdr_dm_data.unwrap().ext_metadata_blocks.len(),
+        block_count - 1
+    );
+}
+
+#[test]
+fn requantize_coefficients() {
+    let (original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    assert_eq!(dovi_rpu.header.coefficient_log2_denom, 23);
+
+    let original_coefs = dovi_rpu.mapping_coefficients();
+
+    dovi_rpu.requantize_coefficients(16).unwrap();
+    assert!(dovi_rpu.modified);
+
+    let mut dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    assert_eq!(dovi_rpu.header.coefficient_log2_denom, 16);
+
+    // Within half a step of the new denominator
+    let tolerance = 0.5 / (1 << 16) as f64;
+
+    for (original, requantized) in original_coefs
+        .iter()
+        .flatten()
+        .zip(dovi_rpu.mapping_coefficients().iter().flatten())
+    {
+        assert!((original - requantized).abs() <= tolerance);
+    }
+
+    // More precision is lossless
+    let requantized_coefs = dovi_rpu.mapping_coefficients();
+    dovi_rpu.requantize_coefficients(23).unwrap();
+    assert_eq!(dovi_rpu.mapping_coefficients(), requantized_coefs);
+
+    // Same denominator, untouched
+    let (_, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    dovi_rpu.requantize_coefficients(23).unwrap();
+    assert!(!dovi_rpu.modified);
+    assert_eq!(dovi_rpu.write_rpu_data(), original_data);
+
+    assert!(dovi_rpu.requantize_coefficients(24).is_err());
+}
+
+#[test]
+fn mapping_curves_comparison() {
+    let (_, original) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let (_, profile8) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+
+    let identical = mapping_curves_match(&original, &original, 0.0).unwrap();
+    assert_eq!(identical.max_errors, [0.0; 3]);
+    assert!(identical.matches);
+
+    let mut requantized = original.clone();
+    requantized.requantize_coefficients(12).unwrap();
+
+    let comparison = mapping_curves_match(&original, &requantized, 1e-3).unwrap();
+    assert!(comparison.matches);
+    assert!(comparison.max_errors.iter().any(|error| *error > 0.0));
+
+    // Rounding errors above the tolerance
+    assert!(
+        !mapping_curves_match(&original, &requantized, 1e-5)
+            .unwrap()
+            .matches
+    );
+
+    assert!(
+        !mapping_curves_match(&original, &profile8, 1e-3)
+            .unwrap()
+            .matches
+    );
+}
+
+#[test]
+fn l2_targets() {
+    assert!((pq_to_nits(2081) - 100.0).abs() < 0.5);
+    assert!((pq_to_nits(2851) - 600.0).abs() < 0.5);
+
+    let rpus: Vec<DoviRpu> = ["data_before_crc32.bin", "fel_orig.bin", "mel_orig.bin"]
+        .iter()
+        .map(|asset| _parse_file(PathBuf::from("./assets").join(asset)).1)
+        .collect();
+
+    let counts: Vec<(u16, usize)> = l2_target_counts(&rpus).into_iter().collect();
+    assert_eq!(counts, vec![(2081, 2), (2851, 1), (3079, 1)]);
+}
+
+#[test]
+fn strip_dm_metadata() {
+    let (original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    assert!(dovi_rpu.header.vdr_dm_metadata_present_flag);
+
+    let original_mapping = dovi_rpu.mapping_coefficients();
+
+    dovi_rpu.strip_dm_metadata();
+    let data = dovi_rpu.write_rpu_data();
+    assert!(data.len() < original_data.len());
+
+    let dovi_rpu = parse_dovi_rpu(&data, false).unwrap();
+
+    assert!(!dovi_rpu.header.vdr_dm_metadata_present_flag);
+    assert!(dovi_rpu.vdr_dm_data.is_none());
+    assert_eq!(dovi_rpu.mapping_coefficients(), original_mapping);
+
+    // Only the alignment bits are left
+    assert!(dovi_rpu.remaining.len() < 8 && dovi_rpu.remaining.not_any());
+}
+
+#[test]
+fn conversion_changes() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let before = serde_json::to_value(&dovi_rpu).unwrap();
+
+    assert!(rpu_changes(&before, &dovi_rpu).is_empty());
+
+    dovi_rpu.convert_with_mode(2);
+    dovi_rpu.crop();
+
+    let changes = rpu_changes(&before, &dovi_rpu);
+
+    assert!(changes.contains(&"header.disable_residual_flag: false -> true".to_string()));
+    assert!(changes.contains(&"rpu_data_nlq: removed".to_string()));
+    assert!(changes.contains(
+        &"vdr_dm_data.ext_metadata_blocks[3].Level5.active_area_top_offset: 276 -> 0".to_string()
+    ));
+}
+
+#[test]
+fn ext_block_levels() {
+    for level in [1, 2, 3, 4, 5, 6, 9, 10] {
+        assert_eq!(ExtBlockLevel::from_u8(level).unwrap().as_u8(), level);
+    }
+
+    assert_eq!(ExtBlockLevel::from_u8(0), None);
+    assert_eq!(ExtBlockLevel::from_u8(7), None);
+
+    let levels: Vec<ExtBlockLevel> = serde_json::from_str("[2, 5]").unwrap();
+    assert_eq!(levels, [ExtBlockLevel::L2Trim, ExtBlockLevel::L5ActiveArea]);
+    assert!(serde_json::from_str::<Vec<ExtBlockLevel>>("[8]").is_err());
+
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    assert_eq!(dovi_rpu.remove_ext_blocks(ExtBlockLevel::L5ActiveArea), 1);
+    assert_eq!(dovi_rpu.remove_ext_blocks(ExtBlockLevel::L5ActiveArea), 0);
+
+    let data = dovi_rpu.write_rpu_data();
+    let dovi_rpu = parse_dovi_rpu(&data, false).unwrap();
+
+    assert!(ExtMetadataBlockLevel5::get(&dovi_rpu).is_none());
+    assert_eq!(dovi_rpu.vdr_dm_data.unwrap().l2_targets().count(), 1);
+}
+
+#[test]
+fn padding() {
+    let (original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let original_json = serde_json::to_string(&dovi_rpu).unwrap();
+
+    dovi_rpu.pad(original_data.len() + 16).unwrap();
+    let padded_data = dovi_rpu.write_rpu_data();
+
+    // Zero bytes after the unchanged RPU
+    assert_eq!(padded_data.len(), original_data.len() + 16);
+    assert_eq!(&padded_data[..original_data.len()], &original_data[..]);
+    assert!(padded_data[original_data.len()..].iter().all(|b| *b == 0));
+
+    let mut dovi_rpu = parse_dovi_rpu(&padded_data, false).unwrap();
+    assert_eq!(dovi_rpu.trailing_zeroes, 16);
+
+    // last_byte only differs, from the final RPU exception
+    dovi_rpu.last_byte = 0x80;
+    assert_eq!(serde_json::to_string(&dovi_rpu).unwrap(), original_json);
+    assert_eq!(dovi_rpu.write_rpu_data(), padded_data);
+
+    assert!(dovi_rpu.pad(original_data.len() - 1).is_err());
+}
+
+#[test]
+fn dm_template() {
+    let l1 =
+        |rpu: &mut DoviRpu| serde_json::to_value(ExtMetadataBlockLevel1::get_mut(rpu)).unwrap();
+
+    let (_original_data, mut template_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    let template = template_rpu.vdr_dm_data.clone().unwrap();
+    let template_l1 = l1(&mut template_rpu);
+
+    for keep_l1 in [true, false].iter() {
+        let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+        let frame_l1 = l1(&mut dovi_rpu);
+        assert_ne!(frame_l1, template_l1);
+
+        dovi_rpu.apply_dm_template(&template, *keep_l1);
+
+        let data = dovi_rpu.write_rpu_data();
+        let mut dovi_rpu = parse_dovi_rpu(&data, false).unwrap();
+
+        let expected_l1 = if *keep_l1 { &frame_l1 } else { &template_l1 };
+        assert_eq!(&l1(&mut dovi_rpu), expected_l1);
+
+        // The other blocks are the template's
+        let blocks = |rpu: &DoviRpu| -> Vec<Option<ExtBlockLevel>> {
+            let vdr_dm_data = rpu.vdr_dm_data.as_ref().unwrap();
+            vdr_dm_data
+                .ext_metadata_blocks
+                .iter()
+                .map(|ext| ext.level())
+                .collect()
+        };
+        assert_eq!(blocks(&dovi_rpu), blocks(&template_rpu));
+    }
+}
+
+#[test]
+fn snap_scene_cuts_tolerance() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let mut rpus = vec![dovi_rpu; 12];
+
+    for frame in [1, 5, 7, 9] {
+        rpus[frame].set_scene_cut(true);
+    }
+
+    let cuts = |rpus: &[DoviRpu]| -> Vec<usize> {
+        (0..rpus.len())
+            .filter(|frame| rpus[*frame].vdr_dm_data.as_ref().unwrap().is_scene_cut())
+            .collect()
+    };
+
+    let shots = [0, 4, 10];
+
+    // Off by one, out of reach without tolerance
+    assert!(snap_scene_cuts(&mut rpus, &shots, 0).is_empty());
+
+    // The cut of frame 7 is 3 frames away from both boundaries
+    let moves = snap_scene_cuts(&mut rpus, &shots, 2);
+    assert_eq!(moves, [(1, 0), (5, 4), (9, 10)]);
+    assert_eq!(cuts(&rpus), [0, 4, 7, 10]);
+
+    let rpus: Vec<DoviRpu> = rpus
+        .iter_mut()
+        .map(|rpu| parse_dovi_rpu(&rpu.write_rpu_data(), false).unwrap())
+        .collect();
+    assert_eq!(cuts(&rpus), [0, 4, 7, 10]);
+
+    // Already on the boundaries
+    let mut rpus = rpus;
+    assert!(snap_scene_cuts(&mut rpus, &shots, 2).is_empty());
+
+    // Frame 7 is equally distant from both boundaries, the earlier wins
+    assert_eq!(
+        snap_scene_cuts(&mut rpus, &[5, 9], 2),
+        [(4, 5), (7, 5), (10, 9)]
+    );
+    assert_eq!(cuts(&rpus), [0, 5, 9]);
+}
+
+#[test]
+fn dedup_mappings_runs() {
+    let assets = [
+        "fel_orig.bin",
+        "fel_orig.bin",
+        "fel_orig.bin",
+        "profile8.bin",
+        "fel_orig.bin",
+        "fel_orig.bin",
+    ];
+
+    let mut rpus: Vec<DoviRpu> = assets
+        .iter()
+        .map(|asset| _parse_file(PathBuf::from("./assets").join(asset)).1)
+        .collect();
+
+    assert_eq!(duplicate_runs(&rpus), vec![(0, 3), (4, 2)]);
+
+    let original_sizes: Vec<usize> = rpus
+        .iter_mut()
+        .map(|rpu| rpu.write_rpu_data().len())
+        .collect();
+
+    assert_eq!(dedup_mappings(&mut rpus), 3);
+
+    for (i, rpu) in rpus.iter_mut().enumerate() {
+        let data = rpu.write_rpu_data();
+        let dovi_rpu = parse_dovi_rpu(&data, false).unwrap();
+
+        let repeated = i == 1 || i == 2 || i == 5;
+        assert_eq!(dovi_rpu.header.use_prev_vdr_rpu_flag, repeated);
+
+        if repeated {
+            assert!(dovi_rpu.vdr_rpu_data.is_none() && dovi_rpu.nlq_data.is_none());
+            assert!(dovi_rpu.vdr_dm_data.is_some());
+            assert!(data.len() < original_sizes[i]);
+        } else {
+            assert_eq!(data.len(), original_sizes[i]);
+        }
+    }
+}
+
+#[test]
+fn adjust_active_area() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    let block = ExtMetadataBlockLevel5::get_mut(&mut dovi_rpu).unwrap();
+    block.set_offsets(0, 10, 276, 8190).unwrap();
+
+    dovi_rpu.adjust_active_area(-5, 4, -6, 3);
+
+    let data = dovi_rpu.write_rpu_data();
+    let mut dovi_rpu = parse_dovi_rpu(&data, false).unwrap();
+
+    // Clamped to 0 and 8191
+    let block = ExtMetadataBlockLevel5::get_mut(&mut dovi_rpu).unwrap();
+    assert_eq!(block._get_offsets(), vec![0, 14, 270, 8191]);
+}
+
+#[test]
+fn active_area_aspect_ratio() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    let block = ExtMetadataBlockLevel5::get_mut(&mut dovi_rpu).unwrap();
+    block.set_offsets(0, 0, 276, 276).unwrap();
+
+    let ratio = dovi_rpu.active_area_aspect_ratio(3840, 2160).unwrap();
+    assert!((ratio - 3840.0 / 1608.0).abs() < 1e-9);
+
+    // The offsets leave no picture
+    let block = ExtMetadataBlockLevel5::get_mut(&mut dovi_rpu).unwrap();
+    block.set_offsets(0, 0, 1080, 1080).unwrap();
+
+    assert!(dovi_rpu.active_area_aspect_ratio(3840, 2160).is_err());
+    assert!(dovi_rpu.active_area_aspect_ratio(0, 2160).is_err());
+
+    // No L5, uncropped
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/mel_orig.bin"));
+
+    assert!(ExtMetadataBlockLevel5::get(&dovi_rpu).is_none());
+
+    let ratio = dovi_rpu.active_area_aspect_ratio(1920, 1080).unwrap();
+    assert!((ratio - 16.0 / 9.0).abs() < 1e-9);
+}
+
+/// xorshift64*, seeded for reproducible runs
+struct _Rng(u64);
+
+impl _Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Random value of up to `max_bits` significant bits
+    fn bits(&mut self, max_bits: u64) -> u64 {
+        let bits = self.next() % (max_bits + 1);
+
+        if bits == 0 {
+            0
+        } else {
+            self.next() >> (64 - bits)
+        }
+    }
+}
+
+#[derive(Debug)]
+enum _Value {
+    Bit(bool),
+    N(u64, usize),
+    Ue(u64),
+    Se(i64),
+    Signed13(i16),
+}
+
+#[test]
+fn bitvec_reader_writer_round_trip() {
+    let mut rng = _Rng(0x9E37_79B9_7F4A_7C15);
+
+    let values: Vec<_Value> = (0..20_000)
+        .map(|_| match rng.next() % 5 {
+            0 => _Value::Bit(rng.next() & 1 == 1),
+            1 => {
+                let n = (rng.next() % 64 + 1) as usize;
+                _Value::N(rng.next() >> (64 - n), n)
+            }
+            2 => _Value::Ue(rng.bits(32)),
+            3 => {
+                let value = rng.bits(31) as i64;
+                _Value::Se(if rng.next() & 1 == 1 { -value } else { value })
+            }
+            _ => _Value::Signed13((rng.next() % 8192) as i16 - 4096),
+        })
+        .collect();
+
+    let mut writer = BitVecWriter::new();
+
+    for value in &values {
+        match *value {
+            _Value::Bit(v) => writer.write(v),
+            _Value::N(v, n) => writer.write_n(&v.to_be_bytes(), n),
+            _Value::Ue(v) => writer.write_ue(v),
+            _Value::Se(v) => writer.write_se(v),
+            _Value::Signed13(v) => writer.write_n(&v.to_be_bytes(), 13),
+        }
+    }
+
+    let mut reader = BitVecReader::new(writer.as_slice().to_vec());
+
+    for (index, value) in values.iter().enumerate() {
+        let matches = match *value {
+            _Value::Bit(v) => reader.get() == v,
+            _Value::N(v, n) => reader.get_n::<u64>(n) == v,
+            _Value::Ue(v) => reader.get_ue() == v,
+            _Value::Se(v) => reader.get_se() == v,
+            _Value::Signed13(v) => sign_extend(reader.get_n(13), 13) == v,
+        };
+
+        assert!(matches, "value {}: {:?}", index, value);
+    }
+
+    assert!(reader.available() < 8);
+}
+
+#[test]
+fn rpu_stream_lookup() {
+    let assets = [
+        "fel_orig.bin",
+        "fel_orig.bin",
+        "profile8.bin",
+        "profile8.bin",
+    ];
+
+    let mut rpus: Vec<DoviRpu> = assets
+        .iter()
+        .map(|asset| _parse_file(PathBuf::from("./assets").join(asset)).1)
+        .collect();
+
+    let vdr_rpu_id = rpus[0].header.vdr_rpu_id;
+    dedup_mappings(&mut rpus);
+
+    // Unknown reference
+    rpus[2].use_prev_mapping(vdr_rpu_id + 1);
+
+    let rpus: Vec<DoviRpu> = rpus
+        .iter_mut()
+        .map(|rpu| parse_dovi_rpu(&rpu.write_rpu_data(), false).unwrap())
+        .collect();
+
+    let stream = RpuStream::new(&rpus);
+    assert_eq!(stream.len(), 4);
+
+    let sources: Vec<Option<usize>> = (0..stream.len())
+        .map(|frame| stream.get(frame).unwrap().mapping.map(|(index, _)| index))
+        .collect();
+
+    // Frame 3 referenced frame 2, which doesn't define a mapping anymore
+    assert_eq!(sources, vec![Some(0), Some(0), None, Some(0)]);
+
+    let frame = stream.get(1).unwrap();
+    let (_, mapping_rpu) = frame.mapping.unwrap();
+    assert!(frame.rpu.vdr_rpu_data.is_none());
+    assert_eq!(
+        mapping_rpu.mapping_coefficients(),
+        rpus[0].mapping_coefficients()
+    );
+
+    assert!(stream.get(4).is_none());
+}
+
+#[test]
+fn minimal_use_prev() {
+    let (original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    assert!(!dovi_rpu.minimize_use_prev());
+
+    dovi_rpu.use_prev_mapping(0);
+    assert!(dovi_rpu.minimize_use_prev());
+
+    let data = dovi_rpu.write_rpu_data();
+    assert!(data.len() < original_data.len());
+
+    // Header, rpu_alignment_zero_bits, CRC32 and terminator byte
+    let mut writer = BitVecWriter::new();
+    dovi_rpu.header.write_header(&mut writer);
+
+    while !writer.is_aligned() {
+        writer.write(false);
+    }
+
+    let crc32 = DoviRpu::compute_crc32(&writer.as_slice()[1..]);
+    writer.write_n(&crc32.to_be_bytes(), 32);
+    writer.write_n(&[0x80], 8);
+
+    let mut expected = vec![0x7C, 0x01];
+    expected.extend_from_slice(writer.as_slice());
+
+    assert_eq!(data, expected);
+
+    let dovi_rpu = parse_dovi_rpu(&data, false).unwrap();
+    assert!(dovi_rpu.header.use_prev_vdr_rpu_flag);
+    assert!(dovi_rpu.vdr_rpu_data.is_none() && dovi_rpu.nlq_data.is_none());
+    assert!(dovi_rpu.vdr_dm_data.is_none());
+
+    // Only the alignment bits are left
+    assert!(dovi_rpu.remaining.len() < 8 && dovi_rpu.remaining.not_any());
+}
+
+#[test]
+fn out_of_range_fields() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    assert!(dovi_rpu.validate(false).is_ok());
+
+    // Left unchanged
+    let block = ExtMetadataBlockLevel5::get_mut(&mut dovi_rpu).unwrap();
+    let offsets = block._get_offsets();
+
+    assert_eq!(
+        block.set_offsets(0, 0, 8192, 0),
+        Err("L5 active_area_top_offset should be at most 8191, found 8192".to_string())
+    );
+    assert_eq!(block._get_offsets(), offsets);
+
+    let mut l1 = serde_json::to_value(ExtMetadataBlockLevel1::get(&dovi_rpu).unwrap()).unwrap();
+    l1["max_pq"] = 5000.into();
+    *ExtMetadataBlockLevel1::get_mut(&mut dovi_rpu).unwrap() = serde_json::from_value(l1).unwrap();
+
+    // Rejected even in lenient mode
+    let error = dovi_rpu.validate(true).unwrap_err();
+    assert!(error.contains("L1 max_pq should be at most 4095, found 5000"));
+
+    let json = to_rpu_list_json(&[dovi_rpu]).unwrap();
+    assert!(from_rpu_list_json(&json, true).is_err());
+}
+
+#[test]
+fn normalized_l1_avg_pq() {
+    let (_, cmv29_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let (_, cmv4_rpu) = _parse_file(PathBuf::from("./assets/data_before_crc32.bin"));
+
+    let cmv29_dm_data = cmv29_rpu.vdr_dm_data.as_ref().unwrap();
+    let cmv4_dm_data = cmv4_rpu.vdr_dm_data.as_ref().unwrap();
+
+    assert_eq!(cmv29_dm_data.cm_version(), CmVersion::V29);
+    assert_eq!(cmv4_dm_data.cm_version(), CmVersion::V40);
+
+    let l1_avg_pq = |vdr_dm_data: &VdrDmData| {
+        vdr_dm_data.blocks().find_map(|block| match block {
+            TypedExtBlock::L1 { avg_pq, .. } => Some(avg_pq),
+            _ => None,
+        })
+    };
+
+    // CM v2.9, the L1 avg_pq as is
+    assert_eq!(
+        ExtMetadataBlockLevel1::normalized_avg_pq(cmv29_dm_data, CmVersion::V29),
+        l1_avg_pq(cmv29_dm_data)
+    );
+
+    // CM v4.0, L1 avg_pq 1229 and L3 avg_pq_offset 1434, 614 below the neutral 2048
+    assert_eq!(l1_avg_pq(cmv4_dm_data), Some(1229));
+    assert_eq!(
+        ExtMetadataBlockLevel1::normalized_avg_pq(cmv4_dm_data, CmVersion::V40),
+        Some(615)
+    );
+
+    // Read as CM v2.9, the offset is ignored
+    assert_eq!(
+        ExtMetadataBlockLevel1::normalized_avg_pq(cmv4_dm_data, CmVersion::V29),
+        Some(1229)
+    );
+
+    // Same content in CM v2.9: without the CM v4.0 blocks, the offset applied to the L1 avg_pq
+    let mut cmv29_equivalent = cmv4_dm_data.clone();
+    cmv29_equivalent.cmv40_ext_blocks = None;
+    cmv29_equivalent.set_l1(0, 615, 2081);
+
+    assert_eq!(cmv29_equivalent.cm_version(), CmVersion::V29);
+    assert_eq!(
+        ExtMetadataBlockLevel1::normalized_avg_nits(&cmv29_equivalent, CmVersion::V29),
+        ExtMetadataBlockLevel1::normalized_avg_nits(cmv4_dm_data, CmVersion::V40)
+    );
+}
+
+#[test]
+fn payload_bit_size() {
+    for asset in &[
+        "fel_orig.bin",
+        "mel_orig.bin",
+        "profile5.bin",
+        "profile8.bin",
+    ] {
+        let (original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets").join(asset));
+        let bits = dovi_rpu.payload_bits.unwrap();
+        let payload = clear_start_code_emulation_prevention_3_byte(&original_data[2..]);
+
+        // rpu_nal_prefix to the CRC32, the CRC32 and terminator excluded
+        assert_eq!(
+            bits.total,
+            8 * (payload.len() - 5 - dovi_rpu.trailing_zeroes)
+        );
+        assert!(bits.leftover() < 8, "{}", asset);
+        assert!(!dovi_rpu.has_payload_desync(), "{}", asset);
+    }
+
+    // The CM v4.0 ext blocks before the CRC32 are parsed
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/data_before_crc32.bin"));
+    let bits = dovi_rpu.payload_bits.unwrap();
+
+    assert_eq!((bits.parsed, bits.total), (1747, 1752));
+    assert!(!dovi_rpu.has_payload_desync());
+
+    let levels: Vec<u8> = dovi_rpu
+        .vdr_dm_data
+        .as_ref()
+        .unwrap()
+        .ext_blocks()
+        .map(ExtMetadataBlock::ext_block_level)
+        .collect();
+    assert_eq!(levels, vec![1, 2, 2, 2, 4, 5, 6, 3, 9, 254]);
+}
+
+#[test]
+fn set_l2_target() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let original_json = serde_json::to_value(&dovi_rpu).unwrap();
+    let original_len = dovi_rpu.write_rpu_data().len();
+
+    assert_eq!(
+        dovi_rpu.set_l2_target(3079, 2851),
+        Err("No L2 block with target_max_pq 3079".to_string())
+    );
+    assert!(dovi_rpu.set_l2_target(2081, 4096).is_err());
+
+    dovi_rpu.set_l2_target(2081, 2851).unwrap();
+
+    let data = dovi_rpu.write_rpu_data();
+    assert_eq!(data.len(), original_len);
+
+    let dovi_rpu = parse_dovi_rpu(&data, false).unwrap();
+    let targets: Vec<u16> = dovi_rpu
+        .vdr_dm_data
+        .as_ref()
+        .unwrap()
+        .l2_targets()
+        .collect();
+    assert_eq!(targets, vec![2851]);
+
+    assert_eq!(
+        rpu_changes(&original_json, &dovi_rpu),
+        vec!["vdr_dm_data.ext_metadata_blocks[1].Level2.target_max_pq: 2081 -> 2851"]
+    );
+}
+
+#[test]
+fn metadata_runs() {
+    let assets = [
+        "fel_orig.bin",
+        "fel_orig.bin",
+        "fel_orig.bin",
+        "profile8.bin",
+        "profile8.bin",
+    ];
+
+    let mut rpus: Vec<DoviRpu> = assets
+        .iter()
+        .map(|asset| _parse_file(PathBuf::from("./assets").join(asset)).1)
+        .collect();
+
+    // The repeated mappings are references, the scene cut isn't a metadata change
+    assert_eq!(dedup_mappings(&mut rpus), 3);
+    rpus[2].set_scene_cut(true);
+
+    let rpus: Vec<DoviRpu> = rpus
+        .iter_mut()
+        .map(|rpu| parse_dovi_rpu(&rpu.write_rpu_data(), false).unwrap())
+        .collect();
+
+    let stream = RpuStream::new(&rpus);
+    let runs = stream.metadata_runs();
+
+    let ranges: Vec<(usize, usize)> = runs
+        .iter()
+        .map(|run| (run.first_frame, run.last_frame))
+        .collect();
+    assert_eq!(ranges, vec![(0, 2), (3, 4)]);
+
+    assert!(runs[0].changes.is_empty());
+    assert!(!runs[1].changes.is_empty());
+
+    // The mapping of the referencing frames is the one they use
+    assert_eq!(
+        stream.get(4).unwrap().effective_metadata(),
+        stream.get(3).unwrap().effective_metadata()
+    );
+    assert_eq!(
+        runs[0].metadata["rpu_data_mapping"],
+        serde_json::to_value(&rpus[0].vdr_rpu_data).unwrap()
+    );
+}
+
+#[test]
+fn non_normalized_coefficients() {
+    let (_original_data, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    assert!(dovi_rpu.header.is_normalized_prediction());
+
+    let normalized = dovi_rpu.mapping_coefficients();
+
+    dovi_rpu.header.vdr_rpu_normalized_idc = 0;
+    let coefs = dovi_rpu.mapping_coefficients();
+
+    // 10 bits BL codewords to 12 bits VDR codewords
+    let bl_max = 1023.0;
+    let vdr_max = 4095.0;
+
+    // Linear pieces: constant, then the slope applied to a BL value
+    assert_eq!(coefs[0][0], 0.0);
+    assert_eq!(coefs[0][1], bl_max / vdr_max);
+
+    // MMR: constant, then the order 1 terms Y, Cb, Cr, Y*Cb, Y*Cr, Cb*Cr and Y*Cb*Cr
+    assert_eq!(coefs[1][0], normalized[1][0] / vdr_max);
+    assert_eq!(coefs[1][1], normalized[1][1] * bl_max / vdr_max);
+    assert_eq!(coefs[1][4], normalized[1][4] * bl_max.powi(2) / vdr_max);
+    assert_eq!(coefs[1][7], normalized[1][7] * bl_max.powi(3) / vdr_max);
+
+    // Order 2 terms are squared
+    let approx_eq = |a: f64, b: f64| (a - b).abs() <= b.abs() * 1e-12;
+    assert!(approx_eq(
+        coefs[1][8],
+        normalized[1][8] * bl_max.powi(2) / vdr_max
+    ));
+    assert!(approx_eq(
+        coefs[1][14],
+        normalized[1][14] * bl_max.powi(6) / vdr_max
+    ));
+}
+
+#[test]
+fn minify() {
+    let assets = [
+        "fel_orig.bin",
+        "fel_orig.bin",
+        "mel_orig.bin",
+        "profile8.bin",
+    ];
+
+    let mut rpus: Vec<DoviRpu> = assets
+        .iter()
+        .map(|asset| _parse_file(PathBuf::from("./assets").join(asset)).1)
+        .collect();
+
+    // Full frame active area on the first frame, cropped on the second
+    ExtMetadataBlockLevel5::get_mut(&mut rpus[0])
+        .unwrap()
+        .set_offsets(0, 0, 0, 0)
+        .unwrap();
+    ExtMetadataBlockLevel5::get_mut(&mut rpus[1])
+        .unwrap()
+        .set_offsets(0, 0, 280, 280)
+        .unwrap();
+    rpus[1].trailing_zeroes = 4;
+
+    let original: Vec<DoviRpu> = rpus
+        .iter_mut()
+        .map(|rpu| {
+            rpu.modified = true;
+            parse_dovi_rpu(&rpu.write_rpu_data(), false).unwrap()
+        })
+        .collect();
+
+    let (original_size, new_size) = minify_rpus(&mut rpus);
+    assert!(new_size < original_size);
+
+    let minified: Vec<DoviRpu> = rpus
+        .iter_mut()
+        .map(|rpu| parse_dovi_rpu(&rpu.write_rpu_data(), false).unwrap())
+        .collect();
+
+    assert!(ExtMetadataBlockLevel5::get(&minified[0]).is_none());
+    assert!(ExtMetadataBlockLevel5::get(&minified[1]).is_some());
+    assert_eq!(minified[1].trailing_zeroes, 0);
+
+    // The second frame reuses the first mapping, the others have their own
+    let use_prev: Vec<bool> = minified
+        .iter()
+        .map(|rpu| rpu.header.use_prev_vdr_rpu_flag)
+        .collect();
+    assert_eq!(use_prev, vec![false, true, false, false]);
+
+    // Same mapping and DM metadata for every frame
+    let original_stream = RpuStream::new(&original);
+    let minified_stream = RpuStream::new(&minified);
+
+    for frame in 0..original.len() {
+        let (_, original_mapping) = original_stream.get(frame).unwrap().mapping.unwrap();
+        let (_, minified_mapping) = minified_stream.get(frame).unwrap().mapping.unwrap();
+
+        assert_eq!(
+            original_mapping.mapping_coefficients(),
+            minified_mapping.mapping_coefficients()
+        );
+
+        let mut original_dm = original[frame].vdr_dm_data.clone().unwrap();
+        original_dm.remove_no_op_blocks();
+
+        assert_eq!(
+            serde_json::to_value(&original_dm).unwrap(),
+            serde_json::to_value(minified[frame].vdr_dm_data.as_ref().unwrap()).unwrap()
+        );
+    }
+}
+
+#[test]
+fn profile8_variants() {
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    assert_eq!(dovi_rpu.profile8_compatibility_id(), Some(1));
+    assert_eq!(dovi_rpu.profile_name(), "8.1");
+
+    let json = to_rpu_list_json(&[dovi_rpu]).unwrap();
+    assert!(json.contains("\"signal_eotf\":65535"));
+
+    // BT.1886 for SDR, HLG
+    for (signal_eotf, profile) in &[(0, "8.2"), (2, "8.4")] {
+        let json = json.replace(
+            "\"signal_eotf\":65535",
+            &format!("\"signal_eotf\":{}", signal_eotf),
+        );
+
+        let mut rpus = from_rpu_list_json(&json, false).unwrap();
+        let dovi_rpu = parse_dovi_rpu(&rpus[0].write_rpu_data(), false).unwrap();
+
+        assert_eq!(&dovi_rpu.profile_name(), profile);
+        assert!(dovi_rpu.warnings.is_empty());
+        assert!(dovi_rpu.validate_profile81().is_empty());
+    }
+
+    // Not a profile 8 variant
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    assert_eq!(dovi_rpu.profile8_compatibility_id(), None);
+    assert_eq!(dovi_rpu.profile_name(), "7");
+}
+
+#[test]
+fn mapping_summary() {
+    let assets = [
+        "fel_orig.bin",
+        "fel_orig.bin",
+        "fel_orig.bin",
+        "profile8.bin",
+    ];
+
+    let mut rpus: Vec<DoviRpu> = assets
+        .iter()
+        .map(|asset| _parse_file(PathBuf::from("./assets").join(asset)).1)
+        .collect();
+
+    // The referencing frames count the mapping they use
+    assert_eq!(dedup_mappings(&mut rpus), 2);
+
+    let summary = RpuStream::new(&rpus).mapping_summary();
+    assert_eq!(summary.len(), 3);
+
+    let luma = &summary[0];
+    assert_eq!(
+        (luma.frames, luma.polynomial, luma.mmr, luma.mixed),
+        (4, 4, 0, 0)
+    );
+    assert_eq!(
+        luma.pivot_counts.iter().collect::<Vec<_>>(),
+        vec![(&2, &1), (&9, &3)]
+    );
+
+    for chroma in &summary[1..] {
+        assert_eq!(
+            (chroma.frames, chroma.polynomial, chroma.mmr, chroma.mixed),
+            (4, 1, 3, 0)
+        );
+        assert_eq!(chroma.pivot_counts.get(&2), Some(&4));
+    }
+}
+
+#[test]
+fn invalid_nal_prefix() {
+    let (mut data, _) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    assert_eq!(data[2], 25);
+
+    // The CRC32 doesn't cover rpu_nal_prefix, the payload is otherwise valid
+    data[2] = 24;
+
+    for lenient in [false, true] {
+        assert_eq!(
+            parse_dovi_rpu(&data, lenient).unwrap_err(),
+            "Invalid rpu_nal_prefix: 24, expected 25, at bit 8"
+        );
+    }
+}
+
+#[test]
+fn large_coefficient_log2_denom() {
+    let (_, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    assert_eq!(dovi_rpu.header.coefficient_log2_denom, 23);
+
+    // The coefficients are written on 24 bits
+    dovi_rpu.header.coefficient_log2_denom = 24;
+    dovi_rpu.modified = true;
+    let data = dovi_rpu.write_rpu_data();
+
+    assert_eq!(
+        parse_dovi_rpu(&data, false).unwrap_err(),
+        "Invalid RPU:\ncoefficient_log2_denom should be at most 23, found 24"
+    );
+
+    let mut parsed = parse_dovi_rpu(&data, true).unwrap();
+    assert_eq!(parsed.header.coefficient_log2_denom, 24);
+    assert_eq!(
+        parsed.warnings,
+        vec!["coefficient_log2_denom should be at most 23, found 24".to_string()]
+    );
+
+    // Same coefficient widths when writing it back
+    parsed.modified = true;
+    assert_eq!(parsed.write_rpu_data(), data);
+}
+
+#[test]
+fn typed_ext_blocks() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let vdr_dm_data = dovi_rpu.vdr_dm_data.as_ref().unwrap();
+
+    let blocks: Vec<TypedExtBlock> = vdr_dm_data.blocks().collect();
+    assert_eq!(blocks.len(), 5);
+
+    assert_eq!(
+        blocks[0],
+        TypedExtBlock::L1 {
+            min_pq: 0,
+            max_pq: 2873,
+            avg_pq: 1060
+        }
+    );
+    assert!(
+        matches!(blocks[1], TypedExtBlock::L2(trim) if trim.target_max_pq == 2081 && trim.trim_slope == 2271)
+    );
+    assert_eq!(
+        blocks[2],
+        TypedExtBlock::L4 {
+            anchor_pq: 1354,
+            anchor_power: 577
+        }
+    );
+    assert_eq!(
+        blocks[3],
+        TypedExtBlock::L5 {
+            left_offset: 0,
+            right_offset: 0,
+            top_offset: 276,
+            bottom_offset: 277
+        }
+    );
+    assert_eq!(
+        blocks[4],
+        TypedExtBlock::L6 {
+            max_display_mastering_luminance: 1000,
+            min_display_mastering_luminance: 1,
+            max_content_light_level: 1712,
+            max_frame_average_light_level: 175
+        }
+    );
+
+    // Same order as the raw blocks
+    for (block, ext) in blocks.iter().zip(&vdr_dm_data.ext_metadata_blocks) {
+        assert_eq!(*block, ext.typed());
+    }
+}
+
+#[test]
+fn l3_ext_block_length() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    // 36 bits of offsets in a 5 bytes block
+    let mut value = serde_json::to_value(&dovi_rpu).unwrap();
+    let blocks = value["vdr_dm_data"]["ext_metadata_blocks"]
+        .as_array_mut()
+        .unwrap();
+
+    blocks.insert(
+        2,
+        serde_json::json!({
+            "Level3": {
+                "block_info": { "ext_block_length": 5, "ext_block_level": 3, "remaining": "0000" },
+                "min_pq_offset": 2048,
+                "max_pq_offset": 2048,
+                "avg_pq_offset": 2048,
+            }
+        }),
+    );
+    value["vdr_dm_data"]["num_ext_blocks"] = blocks.len().into();
+
+    let mut dovi_rpu: DoviRpu = serde_json::from_value(value).unwrap();
+    dovi_rpu.modified = true;
+
+    let reparsed = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let blocks: Vec<TypedExtBlock> = reparsed.vdr_dm_data.as_ref().unwrap().blocks().collect();
+
+    assert_eq!(ExtBlockLevel::L3ContentOffsets.ext_block_length(), 5);
+    assert_eq!(
+        blocks[2],
+        TypedExtBlock::L3 {
+            min_pq_offset: 2048,
+            max_pq_offset: 2048,
+            avg_pq_offset: 2048
+        }
+    );
+}
+
+#[test]
+fn p84_to_p81() {
+    // BT.2408 HDR reference white, 75% HLG
+    assert!((hlg_to_nits(0.75, HLG_REFERENCE_NITS) - 203.0).abs() < 0.5);
+    assert!((hlg_to_nits(1.0, HLG_REFERENCE_NITS) - 1000.0).abs() < 0.01);
+
+    let (_original_data, dovi_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+    let json = to_rpu_list_json(&[dovi_rpu])
+        .unwrap()
+        .replace("\"signal_eotf\":65535", "\"signal_eotf\":2")
+        .replace("\"signal_bit_depth\":12", "\"signal_bit_depth\":10");
+
+    let mut rpus = from_rpu_list_json(&json, false).unwrap();
+    let hlg_rpu = parse_dovi_rpu(&rpus[0].write_rpu_data(), false).unwrap();
+    assert_eq!(hlg_rpu.profile_name(), "8.4");
+
+    let map = |rpu: &DoviRpu, cmp: usize, x: f64| {
+        let vdr_rpu_data = rpu.vdr_rpu_data.as_ref().unwrap();
+        vdr_rpu_data.map_pixel(&rpu.header, cmp, [x, x, x]).unwrap()
+    };
+
+    let mut converted = hlg_rpu.clone();
+    let fit_error = converted.p84_to_p81().unwrap().unwrap();
+    assert!(fit_error < 0.002, "fit error {}", fit_error);
+
+    let converted = parse_dovi_rpu(&converted.write_rpu_data(), false).unwrap();
+    assert_eq!(converted.profile_name(), "8.1");
+    assert_eq!(converted.signal_bit_depth(), Some(12));
+    assert!(converted.validate_profile81().is_empty());
+    assert_eq!(converted.component_mappings().unwrap()[0].num_pivots, 9);
+
+    for codeword in (0..=1023).step_by(31) {
+        let x = codeword as f64 / 1023.0;
+        let expected = nits_to_normalized_pq(hlg_to_nits(map(&hlg_rpu, 0, x), HLG_REFERENCE_NITS));
+
+        assert!((map(&converted, 0, x) - expected).abs() < 0.002);
+
+        // Chroma mappings kept
+        assert_eq!(map(&converted, 1, x), map(&hlg_rpu, 1, x));
+        assert_eq!(map(&converted, 2, x), map(&hlg_rpu, 2, x));
+    }
+
+    // Already PQ, left untouched
+    let mut converted = converted;
+    assert_eq!(converted.p84_to_p81(), Ok(None));
+
+    let (_original_data, mut fel_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    assert!(fel_rpu.p84_to_p81().is_err());
+}
+
+fn _dm_data_bytes(dovi_rpu: &DoviRpu) -> Vec<u8> {
+    let mut writer = BitVecWriter::new();
+    dovi_rpu.vdr_dm_data.as_ref().unwrap().write(&mut writer);
+
+    writer.as_slice().to_vec()
+}
+
+#[test]
+fn parse_dm_data_l1() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    let vdr_dm_data = parse_vdr_dm_data(&_dm_data_bytes(&dovi_rpu)).unwrap();
+    assert_eq!(vdr_dm_data.ext_metadata_blocks.len(), 5);
+
+    assert_eq!(
+        vdr_dm_data.blocks().next(),
+        Some(TypedExtBlock::L1 {
+            min_pq: 0,
+            max_pq: 2873,
+            avg_pq: 1060
+        })
+    );
+
+    let original: Vec<TypedExtBlock> = dovi_rpu.vdr_dm_data.as_ref().unwrap().blocks().collect();
+    assert!(vdr_dm_data.blocks().eq(original));
+}
+
+#[test]
+fn parse_dm_data_l5() {
+    let (_, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    ExtMetadataBlockLevel5::get_mut(&mut dovi_rpu)
+        .unwrap()
+        .set_offsets(8, 16, 140, 142)
+        .unwrap();
+
+    let vdr_dm_data = parse_vdr_dm_data(&_dm_data_bytes(&dovi_rpu)).unwrap();
+
+    let l5 = vdr_dm_data
+        .blocks()
+        .find(|block| matches!(block, TypedExtBlock::L5 { .. }));
+
+    assert_eq!(
+        l5,
+        Some(TypedExtBlock::L5 {
+            left_offset: 8,
+            right_offset: 16,
+            top_offset: 140,
+            bottom_offset: 142
+        })
+    );
+}
+
+#[test]
+fn scale_l2_trims() {
+    let (_, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let original = dovi_rpu.clone();
+
+    let l2 = |rpu: &DoviRpu| {
+        rpu.vdr_dm_data
+            .as_ref()
+            .unwrap()
+            .blocks()
+            .find_map(|block| match block {
+                TypedExtBlock::L2(trim) => Some(trim),
+                _ => None,
+            })
+            .unwrap()
+    };
+    let trim = l2(&original);
+
+    dovi_rpu.scale_l2_trims(2.0, &[L2TrimField::Slope, L2TrimField::Offset]);
+    assert!(dovi_rpu.modified);
+
+    let scaled = l2(&dovi_rpu);
+    assert_eq!(scaled.trim_slope, 2048 + 2 * (trim.trim_slope - 2048));
+    assert_eq!(
+        scaled.trim_offset as i32,
+        2048 + 2 * (trim.trim_offset as i32 - 2048)
+    );
+    assert_eq!(scaled.trim_power, trim.trim_power);
+    assert_eq!(scaled.target_max_pq, trim.target_max_pq);
+    assert_eq!(scaled.trim_chroma_weight, trim.trim_chroma_weight);
+
+    // The other blocks are untouched
+    let other_blocks = |rpu: &DoviRpu| -> Vec<TypedExtBlock> {
+        rpu.vdr_dm_data
+            .as_ref()
+            .unwrap()
+            .blocks()
+            .filter(|block| !matches!(block, TypedExtBlock::L2(_)))
+            .collect()
+    };
+    assert_eq!(other_blocks(&dovi_rpu), other_blocks(&original));
+
+    // Clamped to 12 bits, and to 0 below the neutral value
+    dovi_rpu.scale_l2_trims(100.0, &[L2TrimField::Slope]);
+    assert_eq!(l2(&dovi_rpu).trim_slope, 4095);
+
+    dovi_rpu.scale_l2_trims(-100.0, &[L2TrimField::Slope]);
+    assert_eq!(l2(&dovi_rpu).trim_slope, 0);
+
+    // Neutralized
+    dovi_rpu.scale_l2_trims(0.0, &[L2TrimField::Slope, L2TrimField::Power]);
+    assert_eq!(l2(&dovi_rpu).trim_slope, 2048);
+    assert_eq!(l2(&dovi_rpu).trim_power, 2048);
+
+    let data = dovi_rpu.write_rpu_data();
+    let reparsed = parse_dovi_rpu(&data, false).unwrap();
+    assert_eq!(l2(&reparsed), l2(&dovi_rpu));
+}
+
+#[test]
+fn find_missing_blocks() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let mut rpus = vec![dovi_rpu; 6];
+
+    // No DM metadata to apply before the first frame carrying some
+    rpus[0].strip_dm_metadata();
+    rpus[2].remove_ext_blocks(ExtBlockLevel::L5ActiveArea);
+    rpus[3].remove_ext_blocks(ExtBlockLevel::L5ActiveArea);
+    rpus[3].remove_ext_blocks(ExtBlockLevel::L2Trim);
+    // Keeps the DM metadata of frame 3
+    rpus[4].strip_dm_metadata();
+
+    let stream = RpuStream::new(&rpus);
+
+    assert_eq!(
+        stream.find_missing_blocks(&[ExtBlockLevel::L5ActiveArea]),
+        vec![0, 2, 3, 4]
+    );
+    assert_eq!(
+        stream.find_missing_blocks(&[ExtBlockLevel::L2Trim]),
+        vec![0, 3, 4]
+    );
+    assert_eq!(
+        stream.find_missing_blocks(&[ExtBlockLevel::L1Content, ExtBlockLevel::L6Hdr10]),
+        vec![0]
+    );
+    assert!(stream.find_missing_blocks(&[]).is_empty());
+
+    assert_eq!(stream.get(4).unwrap().dm_data.unwrap().0, 3);
+}
+
+#[test]
+fn effective_dm_set() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    // (affected_dm_metadata_id, current_dm_metadata_id) of each frame
+    let ids = [(0, 0), (1, 0), (1, 1), (0, 1), (2, 5)];
+
+    let rpus: Vec<DoviRpu> = ids
+        .iter()
+        .enumerate()
+        .map(|(frame, &(affected, current))| {
+            let mut rpu = dovi_rpu.clone();
+            let vdr_dm_data = rpu.vdr_dm_data.as_mut().unwrap();
+            vdr_dm_data.set_dm_metadata_ids(affected, current);
+
+            // Tells the sets apart
+            ExtMetadataBlockLevel1::get_mut(&mut rpu)
+                .unwrap()
+                .clamp_max_pq(1000 + frame as u16);
+
+            rpu
+        })
+        .collect();
+
+    let stream = RpuStream::new(&rpus);
+    let active_max_pq = |frame: usize| {
+        let vdr_dm_data = stream.effective_dm_set(frame).unwrap();
+
+        match vdr_dm_data.blocks().next() {
+            Some(TypedExtBlock::L1 { max_pq, .. }) => max_pq,
+            _ => panic!("No L1 block"),
+        }
+    };
+
+    // Single set, the frame's own
+    assert_eq!(active_max_pq(0), 1000);
+    // Frame 1 updates the set 1, the set 0 of frame 0 stays active
+    assert_eq!(active_max_pq(1), 1000);
+    assert_eq!(stream.get(1).unwrap().dm_data.unwrap().0, 0);
+    assert_eq!(active_max_pq(2), 1002);
+    assert_eq!(active_max_pq(3), 1002);
+    // The set 5 was never defined
+    assert_eq!(active_max_pq(4), 1004);
+    assert!(stream.effective_dm_set(5).is_none());
+}
+
+#[test]
+fn stream_cm_version() {
+    let (_, cmv29_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let (_, cmv4_rpu) = _parse_file(PathBuf::from("./assets/data_before_crc32.bin"));
+
+    // L1, L2, L4, L5 and L6 only
+    let cmv29_rpus = vec![cmv29_rpu; 3];
+    assert_eq!(RpuStream::new(&cmv29_rpus).cm_version(), CmVersion::V29);
+    assert_eq!(
+        RpuStream::new(&cmv29_rpus).stats().cm_version,
+        CmVersion::V29
+    );
+
+    // A single CM v4.0 frame, with L3, L9 and L254 blocks
+    let mut rpus = cmv29_rpus;
+    rpus.push(cmv4_rpu.clone());
+    rpus[0].strip_dm_metadata();
+
+    assert_eq!(RpuStream::new(&rpus).cm_version(), CmVersion::V40);
+    assert_eq!(RpuStream::new(&rpus).stats().cm_version, CmVersion::V40);
+
+    // Still CM v4.0 without the L3 and L9 blocks, the L254 block is left in the list
+    let mut edited = cmv4_rpu;
+    assert_eq!(edited.remove_ext_blocks(ExtBlockLevel::L3ContentOffsets), 1);
+    assert_eq!(
+        edited.remove_ext_blocks(ExtBlockLevel::L9SourcePrimaries),
+        1
+    );
+
+    let edited = parse_dovi_rpu(&edited.write_rpu_data(), false).unwrap();
+    assert_eq!(RpuStream::new(&[edited]).cm_version(), CmVersion::V40);
+
+    // No DM metadata at all
+    assert_eq!(RpuStream::new(&rpus[..1]).cm_version(), CmVersion::V29);
+}
+
+#[test]
+fn stream_stats() {
+    let mut rpus: Vec<DoviRpu> = [
+        "fel_orig.bin",
+        "mel_orig.bin",
+        "profile8.bin",
+        "profile8.bin",
+    ]
+    .iter()
+    .map(|asset| _parse_file(PathBuf::from("./assets").join(asset)).1)
+    .collect();
+
+    // Applies the DM metadata of frame 2
+    rpus[3].strip_dm_metadata();
+
+    let stats = RpuStream::new(&rpus).stats();
+
+    assert_eq!(stats.frames, 4);
+    assert_eq!(
+        stats.profiles.into_iter().collect::<Vec<_>>(),
+        [
+            ("7".to_string(), 2),
+            // Without DM metadata, the base layer compatibility is unknown
+            ("8".to_string(), 1),
+            ("8.1".to_string(), 1)
+        ]
+    );
+    assert_eq!(stats.scene_cuts, 2);
+    assert_eq!(stats.frames_without_dm, 0);
+
+    let luminance = stats.luminance.unwrap();
+    assert_eq!(luminance.min_nits, 0.0);
+    assert_eq!(format!("{:.4}", luminance.max_nits), "1976.3601");
+    assert_eq!(format!("{:.4}", luminance.max_avg_nits), "26.7048");
+    // (5.8305 + 26.7048 + 2.4293 * 2) / 4, frame 2 counted twice
+    assert_eq!(format!("{:.4}", luminance.avg_nits), "9.3484");
+
+    assert_eq!(stats.l2_targets, [2081]);
+    // No L2 and L5 metadata in the MEL sample
+    assert_eq!(
+        stats.block_levels.into_iter().collect::<Vec<_>>(),
+        [(1, 4), (2, 3), (4, 4), (5, 3), (6, 4)]
+    );
+    assert_eq!((stats.max_cll, stats.max_fall), (Some(1712), Some(175)));
+    assert_eq!(stats.light_level_source, Some("L6"));
+
+    // Without L6 metadata, computed from L1
+    for rpu in rpus.iter_mut() {
+        rpu.remove_ext_blocks(ExtBlockLevel::L6Hdr10);
+    }
+
+    let stats = RpuStream::new(&rpus).stats();
+
+    assert_eq!(stats.block_levels.get(&6), None);
+    assert_eq!((stats.max_cll, stats.max_fall), (Some(1976), Some(27)));
+    assert_eq!(stats.light_level_source, Some("L1"));
+
+    let stats = RpuStream::new(&[]).stats();
+    assert!(stats.luminance.is_none() && stats.max_cll.is_none());
+}
+
+#[test]
+fn source_and_target_primaries() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    // A L10 block with explicit primaries, in the CM v4.0 list
+    let mut value = serde_json::to_value(&dovi_rpu).unwrap();
+
+    let mut block_info =
+        serde_json::to_value(BlockInfo::new(ExtBlockLevel::L10TargetDisplay)).unwrap();
+    block_info["ext_block_length"] = 21.into();
+
+    value["vdr_dm_data"]["cmv40_ext_blocks"] = serde_json::json!({
+        "num_ext_blocks": 1,
+        "ext_metadata_blocks": [{
+            "Level10": {
+                "block_info": block_info,
+                "target_display_index": 1,
+                "target_max_pq": 3079,
+                "target_min_pq": 62,
+                "target_primary_index": 255,
+                "target_primaries": [34000, 16000, 13250, 34500, 7500, 3000, 15635, 16450],
+            }
+        }]
+    });
+
+    let mut dovi_rpu: DoviRpu = serde_json::from_value(value).unwrap();
+    dovi_rpu.modified = true;
+
+    let mut dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let original = dovi_rpu.clone();
+
+    let l10_primaries = |rpu: &DoviRpu| {
+        serde_json::to_value(rpu).unwrap()["vdr_dm_data"]["cmv40_ext_blocks"]["ext_metadata_blocks"]
+            [0]["Level10"]["target_primaries"]
+            .clone()
+    };
+    assert_eq!(l10_primaries(&dovi_rpu)[7], 16450);
+
+    let mut no_l10 = _parse_file(PathBuf::from("./assets/fel_orig.bin")).1;
+    assert!(no_l10.set_target_primaries(2).is_err());
+
+    dovi_rpu.set_source_primaries(2);
+    dovi_rpu.set_target_primaries(2).unwrap();
+
+    let edited = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let blocks: Vec<TypedExtBlock> = edited.vdr_dm_data.as_ref().unwrap().blocks().collect();
+    let original_blocks: Vec<TypedExtBlock> =
+        original.vdr_dm_data.as_ref().unwrap().blocks().collect();
+
+    // L9 inserted before the L10 block, the other blocks are untouched
+    assert_eq!(blocks.len(), 7);
+    assert_eq!(blocks[..5], original_blocks[..5]);
+    assert_eq!(
+        blocks[5],
+        TypedExtBlock::L9 {
+            source_primary_index: 2
+        }
+    );
+    assert_eq!(
+        blocks[6],
+        TypedExtBlock::L10 {
+            target_display_index: 1,
+            target_max_pq: 3079,
+            target_min_pq: 62,
+            target_primary_index: 2
+        }
+    );
+    assert_eq!(
+        edited.vdr_dm_data.as_ref().unwrap().cm_version(),
+        CmVersion::V40
+    );
+
+    // Explicit primaries removed with the index
+    let value = serde_json::to_value(&edited).unwrap();
+    let l10 = &value["vdr_dm_data"]["cmv40_ext_blocks"]["ext_metadata_blocks"][1]["Level10"];
+    assert_eq!(l10["block_info"]["ext_block_length"], 5);
+    assert!(l10.get("target_primaries").is_none());
+
+    // Same DM metadata besides the blocks
+    let dm_fields = |rpu: &DoviRpu| {
+        let mut value = serde_json::to_value(rpu.vdr_dm_data.as_ref().unwrap()).unwrap();
+        let object = value.as_object_mut().unwrap();
+        object.remove("ext_metadata_blocks");
+        object.remove("num_ext_blocks");
+        object.remove("cmv40_ext_blocks");
+
+        value
+    };
+    assert_eq!(dm_fields(&edited), dm_fields(&original));
+
+    // Setting the same values again changes nothing
+    let mut unchanged = edited.clone();
+    unchanged.set_source_primaries(2);
+    assert!(!unchanged.modified);
+}
+
+/// Whether the escaped data contains a start code prefix or a 0x000000 sequence
+fn _has_start_code_emulation(data: &[u8]) -> bool {
+    data.windows(3)
+        .any(|window| window[0] == 0 && window[1] == 0 && window[2] <= 2)
+}
+
+#[test]
+fn emulation_prevention_sequences() {
+    let cases: [(&[u8], &[u8]); 8] = [
+        (&[0, 0, 0], &[0, 0, 3, 0]),
+        (&[0, 0, 1], &[0, 0, 3, 1]),
+        (&[0, 0, 2], &[0, 0, 3, 2]),
+        (&[0, 0, 3], &[0, 0, 3, 3]),
+        (&[0, 0, 4], &[0, 0, 4]),
+        (&[0, 0, 0, 0, 0], &[0, 0, 3, 0, 0, 3, 0]),
+        // Before the terminator byte, e.g. the end of the CRC32
+        (&[25, 0x12, 0, 0, 1, 0x80], &[25, 0x12, 0, 0, 3, 1, 0x80]),
+        (&[25, 0, 0, 3, 0, 0], &[25, 0, 0, 3, 3, 0, 0]),
+    ];
+
+    for (raw, escaped) in cases.iter() {
+        let mut data = raw.to_vec();
+        add_start_code_emulation_prevention_3_byte(&mut data);
+
+        assert_eq!(&data, escaped);
+        assert_eq!(&clear_start_code_emulation_prevention_3_byte(&data), raw);
+    }
+}
+
+#[test]
+fn emulation_prevention_round_trip() {
+    let mut rng = _Rng(0x2545_F491_4F6C_DD1D);
+
+    for _ in 0..5_000 {
+        let len = (rng.next() % 64) as usize;
+
+        // Mostly bytes up to 3, to hit the escaped sequences often
+        let raw: Vec<u8> = (0..len)
+            .map(|_| match rng.next() % 4 {
+                0 | 1 => 0,
+                2 => (rng.next() % 4) as u8,
+                _ => rng.next() as u8,
+            })
+            .collect();
+
+        let mut escaped = raw.clone();
+        add_start_code_emulation_prevention_3_byte(&mut escaped);
+
+        assert!(!_has_start_code_emulation(&escaped), "{:02X?}", raw);
+        assert_eq!(
+            clear_start_code_emulation_prevention_3_byte(&escaped),
+            raw,
+            "{:02X?}",
+            raw
+        );
+
+        // Escaping is reversible on already escaped data as well
+        let mut twice = escaped.clone();
+        add_start_code_emulation_prevention_3_byte(&mut twice);
+        assert_eq!(
+            clear_start_code_emulation_prevention_3_byte(&twice),
+            escaped
+        );
+    }
+}
+
+#[test]
+fn convert_to_cmv40() {
+    let (_, mut dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let orig = dovi_rpu.clone();
+
+    dovi_rpu.convert_to_cmv40();
+    assert!(dovi_rpu.modified);
+
+    let converted = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let vdr_dm_data = converted.vdr_dm_data.as_ref().unwrap();
+    let orig_dm_data = orig.vdr_dm_data.as_ref().unwrap();
+
+    assert_eq!(vdr_dm_data.cm_version(), CmVersion::V40);
+
+    let levels: Vec<u8> = vdr_dm_data
+        .ext_blocks()
+        .map(|ext| ext.ext_block_level())
+        .collect();
+    assert_eq!(levels, vec![1, 2, 4, 5, 6, 3, 9, 11, 254]);
+
+    // The inserted blocks are padded, only the levels without known fields are reserved
+    let reserved: Vec<u8> = vdr_dm_data
+        .blocks()
+        .filter_map(|block| match block {
+            TypedExtBlock::Reserved(level) => Some(level),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(reserved, vec![11, 254]);
+
+    // The CM v2.9 blocks are untouched
+    assert_eq!(
+        serde_json::to_value(&vdr_dm_data.ext_metadata_blocks).unwrap(),
+        serde_json::to_value(&orig_dm_data.ext_metadata_blocks).unwrap()
+    );
+
+    // The CM v4.0 list, in level order like in real CM v4.0 RPUs
+    let (_, cmv4_rpu) = _parse_file(PathBuf::from("./assets/data_before_crc32.bin"));
+    let list_levels = |vdr_dm_data: &VdrDmData| -> Vec<u8> {
+        let list = vdr_dm_data.cmv40_ext_blocks.as_ref().unwrap();
+        list.ext_metadata_blocks
+            .iter()
+            .map(|ext| ext.ext_block_level())
+            .collect()
+    };
+
+    assert_eq!(list_levels(vdr_dm_data), vec![3, 9, 11, 254]);
+    assert_eq!(
+        list_levels(cmv4_rpu.vdr_dm_data.as_ref().unwrap()),
+        vec![3, 9, 254]
+    );
+
+    let cmv40_blocks = &vdr_dm_data
+        .cmv40_ext_blocks
+        .as_ref()
+        .unwrap()
+        .ext_metadata_blocks;
+    let l3 = serde_json::to_value(&cmv40_blocks[0]).unwrap();
+    assert_eq!(l3["Level3"]["min_pq_offset"], 2048);
+    assert_eq!(l3["Level3"]["max_pq_offset"], 2048);
+    assert_eq!(l3["Level3"]["avg_pq_offset"], 2048);
+
+    // Already CM v4.0
+    let mut converted = converted;
+    converted.convert_to_cmv40();
+    assert!(!converted.modified);
+}
+
+#[test]
+fn corrupted_num_pivots() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    let header_bytes = |header: &RpuDataHeader| {
+        let mut writer = BitVecWriter::new();
+        header.write_header(&mut writer);
+
+        writer.as_slice().to_vec()
+    };
+
+    // A huge pivot count is rejected before allocating the pivots
+    let mut header = dovi_rpu.header.clone();
+    header.num_pivots_minus_2[0] = 1_000_000;
+    header.pred_pivot_value[0] = vec![0; 1_000_002];
+
+    let mut bytes = header_bytes(&header);
+    bytes.truncate(32);
+
+    let err = RpuDataHeader::parse(&mut BitVecReader::new(bytes)).unwrap_err();
+    assert_eq!(
+        err,
+        "num_pivots_minus_2[0] should be at most 7, found 1000000"
+    );
+
+    // A valid count, with the pivots missing from the payload
+    let mut header = dovi_rpu.header.clone();
+    header.num_pivots_minus_2[2] = 7;
+    header.pred_pivot_value[2] = vec![0; 9];
+
+    let mut bytes = header_bytes(&header);
+    bytes.truncate(bytes.len() - 8);
+
+    let err = RpuDataHeader::parse(&mut BitVecReader::new(bytes)).unwrap_err();
+    assert!(err.starts_with("num_pivots_minus_2[2] is 7, the pivots need 90 bits, only "));
+
+    // Unchanged header
+    let bytes = header_bytes(&dovi_rpu.header);
+    assert!(RpuDataHeader::parse(&mut BitVecReader::new(bytes)).is_ok());
+}
+
+#[test]
+fn poly_order_warning() {
+    let (_, dovi_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+
+    // A third order polynomial, with its 4 coefficients
+    let mut value = serde_json::to_value(&dovi_rpu).unwrap();
+    let mapping = &mut value["rpu_data_mapping"];
+    assert_eq!(mapping["mapping_idc"][0][0], 0);
+
+    mapping["poly_order_minus1"][0][0] = 2.into();
+    for coefs in ["poly_coef_int", "poly_coef"] {
+        mapping[coefs][0][0]
+            .as_array_mut()
+            .unwrap()
+            .resize(4, 0.into());
+    }
+
+    let mut dovi_rpu: DoviRpu = serde_json::from_value(value).unwrap();
+    dovi_rpu.modified = true;
+    let data = dovi_rpu.write_rpu_data();
+
+    let err = parse_dovi_rpu(&data, false).unwrap_err();
+    assert!(err.contains("poly_order_minus1[0][0] should be at most 1, found 2"));
+
+    let dovi_rpu = parse_dovi_rpu(&data, true).unwrap();
+    assert_eq!(
+        dovi_rpu.warnings,
+        vec!["poly_order_minus1[0][0] should be at most 1, found 2"]
+    );
+}
+
+#[test]
+fn merge_rpus_mapping_and_dm() {
+    let (_, fel_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let (_, p8_rpu) = _parse_file(PathBuf::from("./assets/profile8.bin"));
+
+    let mut no_dm_rpu = p8_rpu.clone();
+    no_dm_rpu.strip_dm_metadata();
+
+    let mapping_source = vec![fel_rpu.clone(), fel_rpu.clone()];
+    let dm_source = vec![p8_rpu.clone(), no_dm_rpu];
+
+    let mut merged = merge_rpus(&mapping_source, &dm_source).unwrap();
+    let merged: Vec<DoviRpu> = merged
+        .iter_mut()
+        .map(|rpu| parse_dovi_rpu(&rpu.write_rpu_data(), false).unwrap())
+        .collect();
+
+    for rpu in &merged {
+        // Mapping from the FEL RPU
+        assert_eq!(rpu.dovi_profile, 7);
+        assert!(rpu.has_residual());
+        assert_eq!(rpu.mapping_coefficients(), fel_rpu.mapping_coefficients());
+        assert_eq!(
+            serde_json::to_value(&rpu.nlq_data).unwrap(),
+            serde_json::to_value(&fel_rpu.nlq_data).unwrap()
+        );
+    }
+
+    // DM metadata from the profile 8 RPU
+    assert_eq!(
+        serde_json::to_value(&merged[0].vdr_dm_data).unwrap(),
+        serde_json::to_value(&p8_rpu.vdr_dm_data).unwrap()
+    );
+    assert!(merged[1].vdr_dm_data.is_none());
+    assert!(!merged[1].header.vdr_dm_metadata_present_flag);
+
+    let err = merge_rpus(&mapping_source, &dm_source[..1]).unwrap_err();
+    assert_eq!(
+        err,
+        "Frame counts differ: 2 frames for the mapping, 1 for the DM metadata"
+    );
+
+    // Unparsed bits after the DM metadata
+    let mut unparsed_rpu = p8_rpu.clone();
+    unparsed_rpu.remaining.extend([true; 8].iter());
+
+    let merged = merge_rpus(&[unparsed_rpu.clone()], &[p8_rpu]).unwrap();
+    assert!(merged[0].remaining.is_empty());
+
+    // The alignment bit and the 8 extra bits
+    let err = merge_rpus(&[fel_rpu], &[unparsed_rpu]).unwrap_err();
+    assert_eq!(
+        err,
+        "Frame 0: the DM metadata is followed by 9 unparsed bits, they can't be merged"
+    );
+}
+
+#[test]
+fn repair_terminator() {
+    let (original_data, _) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let payload = &original_data[2..];
+    let unterminated = &payload[..payload.len() - 1];
+
+    assert_eq!(repair_rpu_terminator(payload), Ok(None));
+
+    assert_eq!(
+        parse_dovi_rpu(&original_data[..original_data.len() - 1], false).unwrap_err(),
+        "Invalid RPU: no 0x80 terminator byte, ends with 0x82"
+    );
+
+    // Missing
+    assert_eq!(
+        repair_rpu_terminator(unterminated),
+        Ok(Some(payload.to_vec()))
+    );
+
+    // Missing, the zero bytes after the CRC32 are kept after the terminator
+    let padded = [unterminated, &[0, 0]].concat();
+    let repaired = repair_rpu_terminator(&padded).unwrap().unwrap();
+    assert_eq!(repaired, [payload, &[0, 0]].concat());
+
+    let dovi_rpu = parse_dovi_rpu(&[&original_data[..2], &repaired].concat(), false).unwrap();
+    assert_eq!(dovi_rpu.trailing_zeroes, 2);
+
+    // Corrupted
+    let corrupted = [unterminated, &[0x81]].concat();
+    assert_eq!(
+        repair_rpu_terminator(&corrupted),
+        Ok(Some(payload.to_vec()))
+    );
+
+    // Zeroed, can't be told from padding
+    let zeroed = [unterminated, &[0]].concat();
+    assert_eq!(
+        repair_rpu_terminator(&zeroed),
+        Ok(Some([payload, &[0]].concat()))
+    );
+
+    // The CRC32 can't be found
+    let mut corrupted = unterminated.to_vec();
+    corrupted[10] ^= 1;
+
+    assert!(repair_rpu_terminator(&corrupted).is_err());
+}
+
+#[test]
+fn map_ext_blocks() {
+    let (_original_data, fel_rpu) = _parse_file(PathBuf::from("./assets/fel_orig.bin"));
+    let original_blocks: Vec<TypedExtBlock> =
+        fel_rpu.vdr_dm_data.as_ref().unwrap().blocks().collect();
+
+    // Zeroed L5 offsets
+    let mut dovi_rpu = fel_rpu.clone();
+    dovi_rpu
+        .map_ext_blocks(|ext| {
+            if let ExtMetadataBlock::Level5(block) = ext {
+                block.set_offsets(0, 0, 0, 0).unwrap();
+            }
+        })
+        .unwrap();
+    assert!(dovi_rpu.modified);
+
+    // Parsed with the CRC32 checked
+    let dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let blocks: Vec<TypedExtBlock> = dovi_rpu.vdr_dm_data.as_ref().unwrap().blocks().collect();
+
+    assert_eq!(blocks.len(), original_blocks.len());
+
+    for (block, original) in blocks.iter().zip(&original_blocks) {
+        match block {
+            TypedExtBlock::L5 {
+                left_offset,
+                right_offset,
+                top_offset,
+                bottom_offset,
+            } => assert_eq!(
+                [*left_offset, *right_offset, *top_offset, *bottom_offset],
+                [0; 4]
+            ),
+            _ => assert_eq!(block, original),
+        }
+    }
+
+    // Replaced blocks get a consistent ext_block_length and padding
+    let mut dovi_rpu = fel_rpu.clone();
+    dovi_rpu
+        .map_ext_blocks(|ext| {
+            if let ExtMetadataBlock::Level6(_) = ext {
+                *ext = ExtMetadataBlock::Level6(ExtMetadataBlockLevel6::default());
+            }
+        })
+        .unwrap();
+
+    let dovi_rpu = parse_dovi_rpu(&dovi_rpu.write_rpu_data(), false).unwrap();
+    let blocks: Vec<TypedExtBlock> = dovi_rpu.vdr_dm_data.as_ref().unwrap().blocks().collect();
+
+    assert_eq!(&blocks[..4], &original_blocks[..4]);
+    assert_eq!(
+        blocks[4],
+        TypedExtBlock::L6 {
+            max_display_mastering_luminance: 0,
+            min_display_mastering_luminance: 0,
+            max_content_light_level: 0,
+            max_frame_average_light_level: 0,
+        }
+    );
+
+    // Fields too large to be written
+    let mut dovi_rpu = fel_rpu;
+    let result = dovi_rpu.map_ext_blocks(|ext| {
+        if let ExtMetadataBlock::Level1(_) = ext {
+            *ext = serde_json::from_value(serde_json::json!({
+                "Level1": {
+                    "block_info": { "ext_block_length": 0, "ext_block_level": 0, "remaining": "" },
+                    "min_pq": 0,
+                    "max_pq": 5000,
+                    "avg_pq": 0
+                }
+            }))
+            .unwrap();
+        }
+    });
+    assert_eq!(
+        result.unwrap_err(),
+        "L1 max_pq should be at most 4095, found 5000"
+    );
+}
+
+#[test]
+fn profile_from_config_mappings() {
+    assert_eq!(profile_from_config(5, 6, Some(0)), DoviProfile::Profile5);
+    assert_eq!(profile_from_config(7, 6, Some(6)), DoviProfile::Profile7);
+    assert_eq!(profile_from_config(7, 6, None), DoviProfile::Profile7);
+    assert_eq!(profile_from_config(8, 6, Some(1)), DoviProfile::Profile81);
+    assert_eq!(profile_from_config(8, 6, Some(2)), DoviProfile::Profile82);
+    assert_eq!(profile_from_config(8, 6, Some(4)), DoviProfile::Profile84);
+    assert_eq!(profile_from_config(4, 3, Some(2)), DoviProfile::Profile4);
+    assert_eq!(profile_from_config(9, 5, Some(2)), DoviProfile::Profile9);
+    assert_eq!(profile_from_config(10, 6, Some(0)), DoviProfile::Profile10);
+    assert_eq!(profile_from_config(10, 6, Some(1)), DoviProfile::Profile101);
+    assert_eq!(profile_from_config(10, 6, Some(2)), DoviProfile::Profile102);
+    assert_eq!(profile_from_config(10, 6, Some(4)), DoviProfile::Profile104);
+
+    // Profile 8 without a valid compatibility id, invalid level
+    for (profile, level, bl_compat_id) in [
+        (8, 6, None),
+        (8, 6, Some(0)),
+        (8, 0, Some(1)),
+        (9, 5, Some(1)),
+        (10, 6, None),
+        (10, 6, Some(3)),
+    ] {
+        assert_eq!(
+            profile_from_config(profile, level, bl_compat_id),
+            DoviProfile::Unknown {
+                profile,
+                bl_compat_id
+            }
+        );
+    }
+
+    assert_eq!(profile_from_config(8, 6, Some(4)).to_string(), "8.4");
+    assert_eq!(profile_from_config(10, 6, Some(1)).to_string(), "10.1");
+    assert_eq!(
+        profile_from_config(6, 6, Some(1)).to_string(),
+        "6 (unknown)"
+    );
+}
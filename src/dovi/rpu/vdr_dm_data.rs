@@ -1,6 +1,35 @@
-use super::{prelude::*, BitVecReader, BitVecWriter, DoviRpu};
+use std::convert::TryFrom;
 
-#[derive(Debug, Default)]
+use serde::{Deserialize, Serialize};
+
+use super::pq::{pq_to_nits, pq_to_round_nits};
+use super::rpu_data::MAX_EXT_BLOCKS;
+use super::{bitvec_serde, prelude::*, BitVecReader, BitVecWriter, DoviRpu};
+
+/// Known signal_eotf values: BT.1886 gamma, PQ, HLG (profile 8.4),
+/// and 65535, used for PQ by most encoders
+const SIGNAL_EOTFS: [u16; 4] = [0, 1, 2, 65535];
+
+/// signal_color_space values, 3 is reserved
+const SIGNAL_COLOR_SPACES: [&str; 3] = ["YCbCr", "RGB", "IPT"];
+/// signal_chroma_format values, 3 is reserved
+const SIGNAL_CHROMA_FORMATS: [&str; 3] = ["4:2:0", "4:2:2", "4:4:4"];
+
+/// Largest value of the 12 bits PQ and trim fields
+const MAX_12_BITS: u16 = 0xFFF;
+/// Largest L5 active area offset, 13 bits
+const MAX_ACTIVE_AREA_OFFSET: u16 = 0x1FFF;
+
+/// Bytes of the explicit primaries of L9 and L10 blocks: 8 16 bits chromaticity coordinates
+const PRIMARIES_LENGTH: u64 = 16;
+
+/// Value of the L2 trims leaving the image unchanged
+const NEUTRAL_TRIM: u16 = 2048;
+
+/// Ext block levels of the CM v4.0 ext block list
+const CMV4_LEVELS: [u8; 6] = [3, 8, 9, 10, 11, 254];
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct VdrDmData {
     affected_dm_metadata_id: u64,
     current_dm_metadata_id: u64,
@@ -39,9 +68,19 @@ pub struct VdrDmData {
     source_diagonal: u16,
     num_ext_blocks: u64,
     pub(crate) ext_metadata_blocks: Vec<ExtMetadataBlock>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cmv40_ext_blocks: Option<CmV40ExtBlocks>,
 }
 
-#[derive(Debug)]
+/// Ext blocks of the CM v4.0 levels, in a second list after the CM v2.9 ones.
+/// Only present in CM v4.0 metadata.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CmV40ExtBlocks {
+    num_ext_blocks: u64,
+    pub(crate) ext_metadata_blocks: Vec<ExtMetadataBlock>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ExtMetadataBlock {
     Level1(ExtMetadataBlockLevel1),
     Level2(ExtMetadataBlockLevel2),
@@ -49,17 +88,34 @@ pub enum ExtMetadataBlock {
     Level4(ExtMetadataBlockLevel4),
     Level5(ExtMetadataBlockLevel5),
     Level6(ExtMetadataBlockLevel6),
+    Level9(ExtMetadataBlockLevel9),
+    Level10(ExtMetadataBlockLevel10),
     Reserved(ReservedExtMetadataBlock),
 }
 
-#[derive(Debug, Default)]
+/// Known ext_block_level values, other levels are reserved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum ExtBlockLevel {
+    L1Content = 1,
+    L2Trim = 2,
+    L3ContentOffsets = 3,
+    L4Temporal = 4,
+    L5ActiveArea = 5,
+    L6Hdr10 = 6,
+    L9SourcePrimaries = 9,
+    L10TargetDisplay = 10,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct BlockInfo {
     ext_block_length: u64,
     ext_block_level: u8,
+    #[serde(with = "bitvec_serde")]
     remaining: BitVec<Msb0, u8>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ExtMetadataBlockLevel1 {
     block_info: BlockInfo,
     min_pq: u16,
@@ -67,7 +123,7 @@ pub struct ExtMetadataBlockLevel1 {
     avg_pq: u16,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ExtMetadataBlockLevel2 {
     block_info: BlockInfo,
     target_max_pq: u16,
@@ -79,7 +135,7 @@ pub struct ExtMetadataBlockLevel2 {
     ms_weight: i16,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ExtMetadataBlockLevel3 {
     block_info: BlockInfo,
     min_pq_offset: u16,
@@ -87,14 +143,14 @@ pub struct ExtMetadataBlockLevel3 {
     avg_pq_offset: u16,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ExtMetadataBlockLevel4 {
     block_info: BlockInfo,
     anchor_pq: u16,
     anchor_power: u16,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ExtMetadataBlockLevel5 {
     block_info: BlockInfo,
     active_area_left_offset: u16,
@@ -103,7 +159,7 @@ pub struct ExtMetadataBlockLevel5 {
     active_area_bottom_offset: u16,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ExtMetadataBlockLevel6 {
     block_info: BlockInfo,
     max_display_mastering_luminance: u16,
@@ -112,17 +168,138 @@ pub struct ExtMetadataBlockLevel6 {
     max_frame_average_light_level: u16,
 }
 
-#[derive(Debug, Default)]
+/// Primaries of the source, CM v4.0
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtMetadataBlockLevel9 {
+    block_info: BlockInfo,
+    source_primary_index: u8,
+    /// Red, green, blue and white x and y, when ext_block_length is 17
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_primaries: Option<[u16; 8]>,
+}
+
+/// Target display of the L2 or L8 trims, CM v4.0
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtMetadataBlockLevel10 {
+    block_info: BlockInfo,
+    target_display_index: u8,
+    target_max_pq: u16,
+    target_min_pq: u16,
+    target_primary_index: u8,
+    /// Red, green, blue and white x and y, when ext_block_length is 21
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_primaries: Option<[u16; 8]>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ReservedExtMetadataBlock {
     block_info: BlockInfo,
 }
 
+/// Fields of an ext metadata block relevant to its level, without the block info
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypedExtBlock {
+    L1 {
+        min_pq: u16,
+        max_pq: u16,
+        avg_pq: u16,
+    },
+    L2(TrimValues),
+    L3 {
+        min_pq_offset: u16,
+        max_pq_offset: u16,
+        avg_pq_offset: u16,
+    },
+    L4 {
+        anchor_pq: u16,
+        anchor_power: u16,
+    },
+    L5 {
+        left_offset: u16,
+        right_offset: u16,
+        top_offset: u16,
+        bottom_offset: u16,
+    },
+    L6 {
+        max_display_mastering_luminance: u16,
+        min_display_mastering_luminance: u16,
+        max_content_light_level: u16,
+        max_frame_average_light_level: u16,
+    },
+    L9 {
+        source_primary_index: u8,
+    },
+    L10 {
+        target_display_index: u8,
+        target_max_pq: u16,
+        target_min_pq: u16,
+        target_primary_index: u8,
+    },
+    /// Level of a block the parser doesn't know the fields of
+    Reserved(u8),
+}
+
+/// L2 trims scaled by `ExtMetadataBlockLevel2::scale_trims`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum L2TrimField {
+    Slope,
+    Offset,
+    Power,
+}
+
+impl std::str::FromStr for L2TrimField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "slope" => Ok(L2TrimField::Slope),
+            "offset" => Ok(L2TrimField::Offset),
+            "power" => Ok(L2TrimField::Power),
+            _ => Err(format!("Unknown L2 trim: {}", s)),
+        }
+    }
+}
+
+/// Content mapping version of the DM metadata
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CmVersion {
+    #[default]
+    V29,
+    V40,
+}
+
+/// L2 trim values for a given target display.
+/// 2048 is the neutral (no trim) value for every trim field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrimValues {
+    pub target_max_pq: u16,
+    pub trim_slope: u16,
+    pub trim_offset: u16,
+    pub trim_power: u16,
+    pub trim_chroma_weight: u16,
+    pub trim_saturation_gain: u16,
+    pub ms_weight: i16,
+}
+
 impl VdrDmData {
-    pub fn vdr_dm_data_payload(reader: &mut BitVecReader) -> VdrDmData {
-        let mut data = VdrDmData::default();
-        data.affected_dm_metadata_id = reader.get_ue();
-        data.current_dm_metadata_id = reader.get_ue();
-        data.scene_refresh_flag = reader.get_ue();
+    /// Parses the DM metadata, `final_len` being the bits after the payload: CRC32, terminator and padding.
+    ///
+    /// The ext blocks are parsed as long as they fit before `final_len`, up to `max_ext_blocks`.
+    /// A `num_ext_blocks` above either is an error, unless lenient: the blocks are then
+    /// truncated to the ones that fit, with a warning in `warnings`, and the rest of the payload is dropped.
+    pub fn vdr_dm_data_payload(
+        reader: &mut BitVecReader,
+        final_len: usize,
+        max_ext_blocks: u64,
+        lenient: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<VdrDmData, String> {
+        let mut data = VdrDmData {
+            affected_dm_metadata_id: reader.get_ue(),
+            current_dm_metadata_id: reader.get_ue(),
+            scene_refresh_flag: reader.get_ue(),
+            ..Default::default()
+        };
 
         data.ycc_to_rgb_coef0 = reader.get_n::<u16>(16) as i16;
         data.ycc_to_rgb_coef1 = reader.get_n::<u16>(16) as i16;
@@ -158,29 +335,251 @@ impl VdrDmData {
         data.source_min_pq = reader.get_n(12);
         data.source_max_pq = reader.get_n(12);
         data.source_diagonal = reader.get_n(10);
-        data.num_ext_blocks = reader.get_ue();
 
-        if data.num_ext_blocks > 0 {
-            while !reader.is_aligned() {
-                assert_eq!(reader.get(), false);
-            }
+        let (num_ext_blocks, ext_metadata_blocks) =
+            parse_ext_blocks(reader, final_len, max_ext_blocks, lenient, warnings)?;
+        data.num_ext_blocks = num_ext_blocks;
+        data.ext_metadata_blocks = ext_metadata_blocks;
+
+        Ok(data)
+    }
+
+    /// Parses the CM v4.0 ext block list from `bits`, the bits left after the CM v2.9 list, before the CRC32.
+    /// `offset` is the position of the first bit in its byte, the alignment bits depend on it.
+    /// Returns the bits left after the list.
+    pub fn parse_cmv40_ext_blocks(
+        &mut self,
+        bits: &BitSlice<Msb0, u8>,
+        offset: usize,
+        lenient: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<BitVec<Msb0, u8>, String> {
+        let mut data = BitVec::<Msb0, u8>::repeat(false, offset);
+        data.extend(bits.iter());
+
+        let final_len = (8 - data.len() % 8) % 8;
+        data.resize(data.len() + final_len, false);
+
+        let mut reader = BitVecReader::new(data.into_vec());
+        reader.skip_n(offset);
+
+        let (num_ext_blocks, ext_metadata_blocks) =
+            parse_ext_blocks(&mut reader, final_len, MAX_EXT_BLOCKS, lenient, warnings)?;
+
+        self.cmv40_ext_blocks = Some(CmV40ExtBlocks {
+            num_ext_blocks,
+            ext_metadata_blocks,
+        });
+
+        let mut remaining = BitVec::new();
+
+        while reader.available() > final_len {
+            remaining.push(reader.get());
+        }
+
+        Ok(remaining)
+    }
+
+    /// Ext blocks of both lists, the CM v2.9 ones first
+    pub fn ext_blocks(&self) -> impl Iterator<Item = &ExtMetadataBlock> {
+        let cmv40_blocks = self
+            .cmv40_ext_blocks
+            .iter()
+            .flat_map(|list| list.ext_metadata_blocks.iter());
+
+        self.ext_metadata_blocks.iter().chain(cmv40_blocks)
+    }
+
+    fn ext_blocks_mut(&mut self) -> impl Iterator<Item = &mut ExtMetadataBlock> {
+        let cmv40_blocks = self
+            .cmv40_ext_blocks
+            .iter_mut()
+            .flat_map(|list| list.ext_metadata_blocks.iter_mut());
+
+        self.ext_metadata_blocks.iter_mut().chain(cmv40_blocks)
+    }
+
+    /// Sets the num_ext_blocks of both lists to their number of blocks
+    fn update_num_ext_blocks(&mut self) {
+        self.num_ext_blocks = self.ext_metadata_blocks.len() as u64;
+
+        if let Some(ref mut list) = self.cmv40_ext_blocks {
+            list.num_ext_blocks = list.ext_metadata_blocks.len() as u64;
+        }
+    }
+
+    /// Keeps the ext blocks of both lists matching `f`, returns the number of removed blocks.
+    /// The CM v4.0 list is removed once empty, the metadata is then CM v2.9.
+    fn retain_ext_blocks(&mut self, f: impl Fn(&ExtMetadataBlock) -> bool) -> usize {
+        let count = self.ext_blocks().count();
 
-            for _ in 0..data.num_ext_blocks {
-                let ext_metadata_block = ExtMetadataBlock::parse(reader);
-                data.ext_metadata_blocks.push(ext_metadata_block);
+        self.ext_metadata_blocks.retain(&f);
+
+        if let Some(ref mut list) = self.cmv40_ext_blocks {
+            list.ext_metadata_blocks.retain(&f);
+
+            if list.ext_metadata_blocks.is_empty() {
+                self.cmv40_ext_blocks = None;
             }
         }
 
-        data
+        self.update_num_ext_blocks();
+
+        count - self.ext_blocks().count()
+    }
+
+    pub fn is_scene_cut(&self) -> bool {
+        self.scene_refresh_flag == 1
+    }
+
+    /// Sets or clears `scene_refresh_flag`, returns whether it was changed
+    pub fn set_scene_cut(&mut self, scene_cut: bool) -> bool {
+        let changed = self.is_scene_cut() != scene_cut;
+        self.scene_refresh_flag = scene_cut as u64;
+
+        changed
+    }
+
+    /// Clamps `source_diagonal` between `min` and `max`, in inches.
+    /// Returns whether it was changed
+    pub fn clamp_source_diagonal(&mut self, min: u16, max: u16) -> bool {
+        let source_diagonal = self.source_diagonal.clamp(min, max);
+        let changed = self.source_diagonal != source_diagonal;
+        self.source_diagonal = source_diagonal;
+
+        changed
+    }
+
+    /// `affected_dm_metadata_id` and `current_dm_metadata_id`
+    pub fn dm_metadata_ids(&self) -> (u64, u64) {
+        (self.affected_dm_metadata_id, self.current_dm_metadata_id)
+    }
+
+    pub fn set_dm_metadata_ids(&mut self, affected: u64, current: u64) {
+        self.affected_dm_metadata_id = affected;
+        self.current_dm_metadata_id = current;
+    }
+
+    /// CM v4.0 when the CM v4.0 ext block list is present, CM v2.9 otherwise
+    pub fn cm_version(&self) -> CmVersion {
+        if self.cmv40_ext_blocks.is_some() {
+            CmVersion::V40
+        } else {
+            CmVersion::V29
+        }
+    }
+
+    /// dv_bl_signal_compatibility_id of a profile 8 stream with this `signal_eotf`:
+    /// 1 for PQ (HDR10), 2 for BT.1886 (SDR) and 4 for HLG. `None` for other values.
+    pub fn bl_signal_compatibility_id(&self) -> Option<u8> {
+        match self.signal_eotf {
+            1 | 65535 => Some(1),
+            0 => Some(2),
+            2 => Some(4),
+            _ => None,
+        }
+    }
+
+    /// Describes a full range PQ signal instead of HLG, for profile 8.4 to 8.1.
+    /// The source display and the L1 to L6 blocks are PQ values already, they are kept.
+    pub fn hlg_to_pq(&mut self, signal_bit_depth: u8) {
+        self.signal_eotf = 65535;
+        self.signal_eotf_param0 = 0;
+        self.signal_eotf_param1 = 0;
+        self.signal_eotf_param2 = 0;
+        self.signal_bit_depth = signal_bit_depth;
+        self.signal_full_range_flag = 1;
+    }
+
+    /// Bit depth of the signal the DM metadata describes, in its `signal_eotf`.
+    /// Unrelated to `bl_bit_depth_minus8`: the BL is usually 10 bits, the signal 12 bits
+    /// like the reconstructed VDR, and 8 to 10 bits for SDR compatible profiles.
+    pub fn signal_bit_depth(&self) -> u8 {
+        self.signal_bit_depth
+    }
+
+    /// Color space of the signal the DM metadata describes: 0 YCbCr, 1 RGB, 2 IPT
+    pub fn signal_color_space(&self) -> u8 {
+        self.signal_color_space
+    }
+
+    /// Chroma format of the signal the DM metadata describes: 0 4:2:0, 1 4:2:2, 2 4:4:4
+    pub fn signal_chroma_format(&self) -> u8 {
+        self.signal_chroma_format
+    }
+
+    /// Color space and chroma format of the signal, e.g. "YCbCr 4:2:0".
+    /// The reserved values are printed as numbers.
+    pub fn signal_format(&self) -> String {
+        let name = |names: &[&str], value: u8| match names.get(value as usize) {
+            Some(name) => name.to_string(),
+            None => format!("reserved ({})", value),
+        };
+
+        format!(
+            "{} {}",
+            name(&SIGNAL_COLOR_SPACES, self.signal_color_space()),
+            name(&SIGNAL_CHROMA_FORMATS, self.signal_chroma_format())
+        )
+    }
+
+    /// Returns the list of constraints the DM data doesn't respect.
+    pub fn validate(&self, profile: u8) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.affected_dm_metadata_id > 15 {
+            warnings.push(format!(
+                "affected_dm_metadata_id should be at most 15, found {}",
+                self.affected_dm_metadata_id
+            ));
+        }
+
+        if !(8..=16).contains(&self.signal_bit_depth) {
+            warnings.push(format!(
+                "signal_bit_depth should be between 8 and 16, found {}",
+                self.signal_bit_depth
+            ));
+        }
+
+        if profile > 4 && !SIGNAL_EOTFS.contains(&self.signal_eotf) {
+            warnings.push(format!(
+                "signal_eotf should be one of {:?}, found {}",
+                SIGNAL_EOTFS, self.signal_eotf
+            ));
+        }
+
+        warnings
     }
 
-    pub fn validate(&self, profile: u8) {
-        assert!(self.affected_dm_metadata_id <= 15);
-        assert!(self.signal_bit_depth >= 8 && self.signal_bit_depth <= 16);
+    /// Returns the reserved signal_color_space and signal_chroma_format values.
+    /// They only describe the signal, the RPU is valid either way.
+    pub fn signal_format_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.signal_color_space as usize >= SIGNAL_COLOR_SPACES.len() {
+            warnings.push(format!(
+                "signal_color_space should be at most {}, found {}",
+                SIGNAL_COLOR_SPACES.len() - 1,
+                self.signal_color_space
+            ));
+        }
 
-        if profile > 4 {
-            assert_eq!(self.signal_eotf, 65535);
+        if self.signal_chroma_format as usize >= SIGNAL_CHROMA_FORMATS.len() {
+            warnings.push(format!(
+                "signal_chroma_format should be at most {}, found {}",
+                SIGNAL_CHROMA_FORMATS.len() - 1,
+                self.signal_chroma_format
+            ));
         }
+
+        warnings
+    }
+
+    /// Ext block fields too large for their bit width, which would be truncated when written
+    pub fn out_of_range_fields(&self) -> Vec<String> {
+        self.ext_blocks()
+            .flat_map(ExtMetadataBlock::out_of_range_fields)
+            .collect()
     }
 
     pub fn write(&self, writer: &mut BitVecWriter) {
@@ -225,16 +624,342 @@ impl VdrDmData {
         writer.write_n(&self.source_min_pq.to_be_bytes(), 12);
         writer.write_n(&self.source_max_pq.to_be_bytes(), 12);
         writer.write_n(&self.source_diagonal.to_be_bytes(), 10);
-        writer.write_ue(self.num_ext_blocks);
 
-        if self.num_ext_blocks > 0 {
-            while !writer.is_aligned() {
-                writer.write(false);
+        write_ext_blocks(writer, self.num_ext_blocks, &self.ext_metadata_blocks);
+
+        if let Some(ref list) = self.cmv40_ext_blocks {
+            write_ext_blocks(writer, list.num_ext_blocks, &list.ext_metadata_blocks);
+        }
+    }
+
+    /// Replaces the DM metadata by the template's, keeping the metadata ids and scene_refresh_flag.
+    /// With `keep_l1`, the L1 block of the frame replaces the template's one.
+    pub fn apply_template(&mut self, template: &VdrDmData, keep_l1: bool) {
+        let frame_l1 = if keep_l1 {
+            self.ext_metadata_blocks
+                .iter()
+                .find(|ext| ext.level() == Some(ExtBlockLevel::L1Content))
+                .cloned()
+        } else {
+            None
+        };
+
+        let mut data = template.clone();
+        data.affected_dm_metadata_id = self.affected_dm_metadata_id;
+        data.current_dm_metadata_id = self.current_dm_metadata_id;
+        data.scene_refresh_flag = self.scene_refresh_flag;
+
+        if let Some(frame_l1) = frame_l1 {
+            data.remove_ext_blocks(ExtBlockLevel::L1Content);
+            data.ext_metadata_blocks.insert(0, frame_l1);
+            data.update_num_ext_blocks();
+        }
+
+        *self = data;
+    }
+
+    /// Removes the ext blocks of a level, returns the number of removed blocks
+    pub fn remove_ext_blocks(&mut self, level: ExtBlockLevel) -> usize {
+        self.retain_ext_blocks(|ext| ext.level() != Some(level))
+    }
+
+    /// Removes the blocks signaling nothing more than their absence, see `ExtMetadataBlock::is_no_op`.
+    /// Returns the number of removed blocks.
+    pub fn remove_no_op_blocks(&mut self) -> usize {
+        self.retain_ext_blocks(|ext| !ext.is_no_op())
+    }
+
+    /// Applies `f` to every ext block, the primitive for arbitrary edits: clamping, scaling or replacing blocks.
+    /// The block info and `num_ext_blocks` are updated afterwards, the blocks are then checked
+    /// like when parsed. The edits are kept when the check errors, the RPU can't be written as is.
+    pub fn map_blocks(&mut self, f: impl FnMut(&mut ExtMetadataBlock)) -> Result<(), String> {
+        self.ext_blocks_mut().for_each(f);
+        self.ext_blocks_mut()
+            .for_each(ExtMetadataBlock::update_block_info);
+
+        self.update_num_ext_blocks();
+
+        let cmv40_num_ext_blocks = self
+            .cmv40_ext_blocks
+            .as_ref()
+            .map(|list| list.num_ext_blocks);
+
+        for num_ext_blocks in std::iter::once(self.num_ext_blocks).chain(cmv40_num_ext_blocks) {
+            if num_ext_blocks > MAX_EXT_BLOCKS {
+                return Err(format!(
+                    "num_ext_blocks should be at most {}, found {}",
+                    MAX_EXT_BLOCKS, num_ext_blocks
+                ));
+            }
+        }
+
+        let out_of_range = self.out_of_range_fields();
+
+        if !out_of_range.is_empty() {
+            return Err(out_of_range.join("\n"));
+        }
+
+        Ok(())
+    }
+
+    /// Fields of the ext blocks, in order, the CM v2.9 ones first
+    pub fn blocks(&self) -> impl Iterator<Item = TypedExtBlock> + '_ {
+        self.ext_blocks().map(ExtMetadataBlock::typed)
+    }
+
+    /// target_max_pq of every L2 block
+    pub fn l2_targets(&self) -> impl Iterator<Item = u16> + '_ {
+        self.blocks().filter_map(|block| match block {
+            TypedExtBlock::L2(trim) => Some(trim.target_max_pq),
+            _ => None,
+        })
+    }
+
+    /// Sets the PQ values of the L1 blocks, inserting one when absent.
+    /// Returns whether the metadata was changed
+    pub fn set_l1(&mut self, min_pq: u16, avg_pq: u16, max_pq: u16) -> bool {
+        let mut found = false;
+        let mut changed = false;
+
+        for ext in self.ext_metadata_blocks.iter_mut() {
+            if let ExtMetadataBlock::Level1(block) = ext {
+                found = true;
+
+                if (block.min_pq, block.avg_pq, block.max_pq) != (min_pq, avg_pq, max_pq) {
+                    block.min_pq = min_pq;
+                    block.avg_pq = avg_pq;
+                    block.max_pq = max_pq;
+                    changed = true;
+                }
+            }
+        }
+
+        if !found {
+            let block = ExtMetadataBlock::Level1(ExtMetadataBlockLevel1 {
+                block_info: BlockInfo::new(ExtBlockLevel::L1Content),
+                min_pq,
+                max_pq,
+                avg_pq,
+            });
+
+            self.insert_ext_block(block);
+            changed = true;
+        }
+
+        changed
+    }
+
+    /// Inserts a L6 block with the MaxCLL and MaxFALL, in nits, when absent. With `overwrite`, the existing L6 blocks are replaced.
+    /// The mastering display luminances are the DM `source_max_pq` and `source_min_pq`, e.g. 1000 and 0.0001 nits.
+    /// Returns whether the metadata was changed
+    pub fn insert_l6(&mut self, max_cll: u16, max_fall: u16, overwrite: bool) -> bool {
+        let block = ExtMetadataBlockLevel6 {
+            block_info: BlockInfo::new(ExtBlockLevel::L6Hdr10),
+            max_display_mastering_luminance: pq_to_round_nits(self.source_max_pq, 1.0) as u16,
+            // In 0.0001 nits
+            min_display_mastering_luminance: (pq_to_round_nits(self.source_min_pq, 0.0001)
+                * 10000.0)
+                .round() as u16,
+            max_content_light_level: max_cll,
+            max_frame_average_light_level: max_fall,
+        };
+
+        let existing: Vec<&ExtMetadataBlockLevel6> = self
+            .ext_metadata_blocks
+            .iter()
+            .filter_map(|ext| match ext {
+                ExtMetadataBlock::Level6(existing) => Some(existing),
+                _ => None,
+            })
+            .collect();
+
+        let unchanged = existing.len() == 1 && existing[0].light_levels() == block.light_levels();
+
+        if (!existing.is_empty() && !overwrite) || unchanged {
+            return false;
+        }
+
+        self.remove_ext_blocks(ExtBlockLevel::L6Hdr10);
+        self.insert_ext_block(ExtMetadataBlock::Level6(block));
+
+        true
+    }
+
+    /// Sets the source_primary_index of the L9 blocks, inserting one when absent.
+    /// Explicit primaries are removed. Returns whether the metadata was changed
+    pub fn set_source_primaries(&mut self, index: u8) -> bool {
+        let mut found = false;
+        let mut changed = false;
+
+        for ext in self.ext_blocks_mut() {
+            if let ExtMetadataBlock::Level9(block) = ext {
+                found = true;
+
+                if block.source_primary_index != index || block.source_primaries.is_some() {
+                    block.source_primary_index = index;
+                    block.source_primaries = None;
+                    block.block_info.ext_block_length =
+                        ExtBlockLevel::L9SourcePrimaries.ext_block_length();
+                    changed = true;
+                }
+            }
+        }
+
+        if !found {
+            let block = ExtMetadataBlock::Level9(ExtMetadataBlockLevel9 {
+                block_info: BlockInfo::new(ExtBlockLevel::L9SourcePrimaries),
+                source_primary_index: index,
+                source_primaries: None,
+            });
+
+            self.insert_ext_block(block);
+            changed = true;
+        }
+
+        changed
+    }
+
+    /// Makes CM v2.9 metadata CM v4.0, keeping the CM v2.9 ext block list as it is.
+    /// Returns whether blocks were added, CM v4.0 metadata is left untouched.
+    ///
+    /// The blocks are added to the CM v4.0 ext block list, with default values
+    /// that have no effect on the CM v2.9 metadata:
+    /// - L3 with offsets of 2048, the neutral value
+    /// - L9 with source_primary_index 0, DCI-P3 D65
+    /// - L11 with content type, white point and reference mode 0, unspecified
+    /// - L254 with dm_mode 0 and dm_version_index 2, CM v4.0
+    ///
+    /// No L10 block is added, the L2 blocks describe their target displays.
+    pub fn convert_to_cmv40(&mut self) -> bool {
+        if self.cm_version() == CmVersion::V40 {
+            return false;
+        }
+
+        self.insert_ext_block(ExtMetadataBlock::Level3(ExtMetadataBlockLevel3 {
+            block_info: BlockInfo::new(ExtBlockLevel::L3ContentOffsets),
+            min_pq_offset: NEUTRAL_TRIM,
+            max_pq_offset: NEUTRAL_TRIM,
+            avg_pq_offset: NEUTRAL_TRIM,
+        }));
+
+        self.set_source_primaries(0);
+
+        self.insert_ext_block(ReservedExtMetadataBlock::with_data(11, &[0; 4]));
+        self.insert_ext_block(ReservedExtMetadataBlock::with_data(254, &[0, 2]));
+
+        true
+    }
+
+    /// Inserts a block after the blocks of lower or equal level.
+    /// The CM v4.0 levels go to the CM v4.0 list, added when absent.
+    fn insert_ext_block(&mut self, block: ExtMetadataBlock) {
+        let level = block.block_info().ext_block_level;
+
+        let blocks = if CMV4_LEVELS.contains(&level) {
+            &mut self
+                .cmv40_ext_blocks
+                .get_or_insert_with(CmV40ExtBlocks::default)
+                .ext_metadata_blocks
+        } else {
+            &mut self.ext_metadata_blocks
+        };
+
+        let position = blocks
+            .iter()
+            .position(|ext| ext.block_info().ext_block_level > level)
+            .unwrap_or(blocks.len());
+
+        blocks.insert(position, block);
+        self.update_num_ext_blocks();
+    }
+
+    /// Changes the target_max_pq of the L2 block targeting `old_target_pq`, keeping its trims
+    pub fn set_l2_target(&mut self, old_target_pq: u16, new_target_pq: u16) -> Result<(), String> {
+        if new_target_pq > MAX_12_BITS {
+            return Err(format!(
+                "L2 target_max_pq should be at most {}, found {}",
+                MAX_12_BITS, new_target_pq
+            ));
+        }
+
+        if new_target_pq != old_target_pq && self.l2_targets().any(|t| t == new_target_pq) {
+            return Err(format!(
+                "L2 block with target_max_pq {} already present",
+                new_target_pq
+            ));
+        }
+
+        let block = self
+            .ext_metadata_blocks
+            .iter_mut()
+            .find_map(|ext| match ext {
+                ExtMetadataBlock::Level2(block) if block.target_max_pq == old_target_pq => {
+                    Some(block)
+                }
+                _ => None,
+            });
+
+        match block {
+            Some(block) => {
+                block.target_max_pq = new_target_pq;
+                Ok(())
+            }
+            None => Err(format!("No L2 block with target_max_pq {}", old_target_pq)),
+        }
+    }
+
+    /// Scales the trims of every L2 block, see `ExtMetadataBlockLevel2::scale_trims`.
+    /// Returns whether a trim was changed
+    pub fn scale_l2_trims(&mut self, factor: f64, fields: &[L2TrimField]) -> bool {
+        let mut changed = false;
+
+        for ext in self.ext_metadata_blocks.iter_mut() {
+            if let ExtMetadataBlock::Level2(block) = ext {
+                changed |= block.scale_trims(factor, fields);
             }
+        }
+
+        changed
+    }
+
+    /// Computes the L2 trims for an arbitrary target display.
+    ///
+    /// Interpolates linearly in PQ between the nearest L2 targets.
+    /// Above the highest trim, the trims fade out towards the source display,
+    /// and below the lowest trim, the lowest trim is used as is.
+    pub fn interpolate_l2(&self, target_pq: u16) -> TrimValues {
+        let mut trims: Vec<TrimValues> = self
+            .blocks()
+            .filter_map(|block| match block {
+                TypedExtBlock::L2(trim) => Some(trim),
+                _ => None,
+            })
+            .collect();
+
+        // Untrimmed source display
+        if trims.iter().all(|t| t.target_max_pq < self.source_max_pq) {
+            trims.push(TrimValues::neutral(self.source_max_pq));
+        }
+
+        trims.sort_by_key(|t| t.target_max_pq);
 
-            for ext_metadata_block in &self.ext_metadata_blocks {
-                ext_metadata_block.write(writer);
+        let lower = trims.iter().rev().find(|t| t.target_max_pq <= target_pq);
+        let upper = trims.iter().find(|t| t.target_max_pq >= target_pq);
+
+        let trim = match (lower, upper) {
+            (Some(lower), Some(upper)) if lower.target_max_pq != upper.target_max_pq => {
+                let weight = (target_pq - lower.target_max_pq) as f64
+                    / (upper.target_max_pq - lower.target_max_pq) as f64;
+
+                lower.interpolate(upper, weight)
             }
+            (Some(trim), _) | (None, Some(trim)) => *trim,
+            (None, None) => TrimValues::neutral(target_pq),
+        };
+
+        TrimValues {
+            target_max_pq: target_pq,
+            ..trim
         }
     }
 
@@ -266,95 +991,265 @@ impl VdrDmData {
     }
 }
 
+impl ExtBlockLevel {
+    pub fn from_u8(level: u8) -> Option<ExtBlockLevel> {
+        match level {
+            1 => Some(ExtBlockLevel::L1Content),
+            2 => Some(ExtBlockLevel::L2Trim),
+            3 => Some(ExtBlockLevel::L3ContentOffsets),
+            4 => Some(ExtBlockLevel::L4Temporal),
+            5 => Some(ExtBlockLevel::L5ActiveArea),
+            6 => Some(ExtBlockLevel::L6Hdr10),
+            9 => Some(ExtBlockLevel::L9SourcePrimaries),
+            10 => Some(ExtBlockLevel::L10TargetDisplay),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// ext_block_length of the level, in bytes.
+    /// L9 and L10 blocks with explicit primaries are `PRIMARIES_LENGTH` longer.
+    pub fn ext_block_length(self) -> u64 {
+        match self {
+            ExtBlockLevel::L1Content => 5,
+            ExtBlockLevel::L2Trim => 11,
+            ExtBlockLevel::L3ContentOffsets => 5,
+            ExtBlockLevel::L4Temporal => 3,
+            ExtBlockLevel::L5ActiveArea => 7,
+            ExtBlockLevel::L6Hdr10 => 8,
+            ExtBlockLevel::L9SourcePrimaries => 1,
+            ExtBlockLevel::L10TargetDisplay => 5,
+        }
+    }
+
+    /// Size of the fields of the level in bits, without explicit primaries
+    pub fn fields_bits(self) -> u64 {
+        match self {
+            ExtBlockLevel::L1Content => 36,
+            ExtBlockLevel::L2Trim => 85,
+            ExtBlockLevel::L3ContentOffsets => 36,
+            ExtBlockLevel::L4Temporal => 24,
+            ExtBlockLevel::L5ActiveArea => 52,
+            ExtBlockLevel::L6Hdr10 => 64,
+            ExtBlockLevel::L9SourcePrimaries => 8,
+            ExtBlockLevel::L10TargetDisplay => 40,
+        }
+    }
+
+    /// Whether the level can carry explicit primaries after its fields
+    pub fn has_primaries(self) -> bool {
+        matches!(
+            self,
+            ExtBlockLevel::L9SourcePrimaries | ExtBlockLevel::L10TargetDisplay
+        )
+    }
+}
+
+impl TryFrom<u8> for ExtBlockLevel {
+    type Error = String;
+
+    fn try_from(level: u8) -> Result<Self, Self::Error> {
+        ExtBlockLevel::from_u8(level).ok_or_else(|| format!("Unknown ext_block_level: {}", level))
+    }
+}
+
+impl From<ExtBlockLevel> for u8 {
+    fn from(level: ExtBlockLevel) -> u8 {
+        level.as_u8()
+    }
+}
+
+impl BlockInfo {
+    /// Block info of a level without explicit primaries, zero padded to its ext_block_length
+    pub fn new(level: ExtBlockLevel) -> BlockInfo {
+        let ext_block_length = level.ext_block_length();
+        let padding = 8 * ext_block_length - level.fields_bits();
+
+        BlockInfo {
+            ext_block_length,
+            ext_block_level: level.as_u8(),
+            remaining: BitVec::repeat(false, padding as usize),
+        }
+    }
+}
+
+impl ReservedExtMetadataBlock {
+    /// Block of a level without known fields, `data` being its payload
+    fn with_data(level: u8, data: &[u8]) -> ExtMetadataBlock {
+        let mut remaining = BitVec::<Msb0, u8>::new();
+        remaining.extend(
+            data.iter()
+                .flat_map(|byte| (0..8).rev().map(move |bit| byte >> bit & 1 == 1)),
+        );
+
+        ExtMetadataBlock::Reserved(ReservedExtMetadataBlock {
+            block_info: BlockInfo {
+                ext_block_length: data.len() as u64,
+                ext_block_level: level,
+                remaining,
+            },
+        })
+    }
+}
+
 impl ExtMetadataBlock {
-    pub fn parse(reader: &mut BitVecReader) -> ExtMetadataBlock {
-        let mut block_info = BlockInfo::default();
+    /// Parses a block, erroring if it doesn't fit before the last `final_len` bits
+    pub fn parse(reader: &mut BitVecReader, final_len: usize) -> Result<ExtMetadataBlock, String> {
+        let ext_block_length = reader.get_ue();
+        let available = reader.available().saturating_sub(final_len) as u64;
+
+        if ext_block_length.saturating_add(1).saturating_mul(8) > available {
+            return Err(format!(
+                "ext_block_length of {} bytes exceeds the RPU payload",
+                ext_block_length
+            ));
+        }
+
+        let mut block_info = BlockInfo {
+            ext_block_length,
+            ext_block_level: reader.get_n(8),
+            ..Default::default()
+        };
+
+        let level = ExtBlockLevel::from_u8(block_info.ext_block_level);
+
+        // With explicit primaries
+        let mut primaries_present = false;
 
-        block_info.ext_block_length = reader.get_ue();
-        block_info.ext_block_level = reader.get_n(8);
+        if let Some(level) = level {
+            let expected_length = level.ext_block_length();
+
+            if level.has_primaries() {
+                primaries_present =
+                    block_info.ext_block_length == expected_length + PRIMARIES_LENGTH;
+
+                if !primaries_present && block_info.ext_block_length != expected_length {
+                    return Err(format!(
+                        "L{} ext_block_length should be {} or {}, found {}",
+                        block_info.ext_block_level,
+                        expected_length,
+                        expected_length + PRIMARIES_LENGTH,
+                        block_info.ext_block_length
+                    ));
+                }
+            } else if block_info.ext_block_length != expected_length {
+                return Err(format!(
+                    "L{} ext_block_length should be {}, found {}",
+                    block_info.ext_block_level, expected_length, block_info.ext_block_length
+                ));
+            }
+        }
 
         let ext_block_len_bits = 8 * block_info.ext_block_length;
         let mut ext_block_use_bits = 0;
 
-        let mut ext_metadata_block = match block_info.ext_block_level {
-            1 => {
-                assert_eq!(block_info.ext_block_length, 5);
-
-                let mut block = ExtMetadataBlockLevel1::default();
-                block.min_pq = reader.get_n(12);
-                block.max_pq = reader.get_n(12);
-                block.avg_pq = reader.get_n(12);
+        let mut ext_metadata_block = match level {
+            Some(ExtBlockLevel::L1Content) => {
+                let block = ExtMetadataBlockLevel1 {
+                    min_pq: reader.get_n(12),
+                    max_pq: reader.get_n(12),
+                    avg_pq: reader.get_n(12),
+                    ..Default::default()
+                };
 
                 ext_block_use_bits += 36;
 
                 ExtMetadataBlock::Level1(block)
             }
-            2 => {
-                assert_eq!(block_info.ext_block_length, 11);
-
-                let mut block = ExtMetadataBlockLevel2::default();
-                block.target_max_pq = reader.get_n(12);
-                block.trim_slope = reader.get_n(12);
-                block.trim_offset = reader.get_n(12);
-                block.trim_power = reader.get_n(12);
-                block.trim_chroma_weight = reader.get_n(12);
-                block.trim_saturation_gain = reader.get_n(12);
-                block.ms_weight = reader.get_n::<u16>(13) as i16;
+            Some(ExtBlockLevel::L2Trim) => {
+                let block = ExtMetadataBlockLevel2 {
+                    target_max_pq: reader.get_n(12),
+                    trim_slope: reader.get_n(12),
+                    trim_offset: reader.get_n(12),
+                    trim_power: reader.get_n(12),
+                    trim_chroma_weight: reader.get_n(12),
+                    trim_saturation_gain: reader.get_n(12),
+                    ms_weight: sign_extend(reader.get_n(13), 13),
+                    ..Default::default()
+                };
 
                 ext_block_use_bits += 85;
 
                 ExtMetadataBlock::Level2(block)
             }
-            3 => {
-                assert_eq!(block_info.ext_block_length, 2);
-
-                let mut block = ExtMetadataBlockLevel3::default();
-                block.min_pq_offset = reader.get_n(12);
-                block.max_pq_offset = reader.get_n(12);
-                block.avg_pq_offset = reader.get_n(12);
+            Some(ExtBlockLevel::L3ContentOffsets) => {
+                let block = ExtMetadataBlockLevel3 {
+                    min_pq_offset: reader.get_n(12),
+                    max_pq_offset: reader.get_n(12),
+                    avg_pq_offset: reader.get_n(12),
+                    ..Default::default()
+                };
 
                 ext_block_use_bits += 36;
 
                 ExtMetadataBlock::Level3(block)
             }
-            4 => {
-                assert_eq!(block_info.ext_block_length, 3);
-
-                let mut block = ExtMetadataBlockLevel4::default();
-                block.anchor_pq = reader.get_n(12);
-                block.anchor_power = reader.get_n(12);
+            Some(ExtBlockLevel::L4Temporal) => {
+                let block = ExtMetadataBlockLevel4 {
+                    anchor_pq: reader.get_n(12),
+                    anchor_power: reader.get_n(12),
+                    ..Default::default()
+                };
 
                 ext_block_use_bits += 24;
 
                 ExtMetadataBlock::Level4(block)
             }
-            5 => {
-                assert_eq!(block_info.ext_block_length, 7);
-
-                let mut block = ExtMetadataBlockLevel5::default();
-                block.active_area_left_offset = reader.get_n(13);
-                block.active_area_right_offset = reader.get_n(13);
-                block.active_area_top_offset = reader.get_n(13);
-                block.active_area_bottom_offset = reader.get_n(13);
+            Some(ExtBlockLevel::L5ActiveArea) => {
+                let block = ExtMetadataBlockLevel5 {
+                    active_area_left_offset: reader.get_n(13),
+                    active_area_right_offset: reader.get_n(13),
+                    active_area_top_offset: reader.get_n(13),
+                    active_area_bottom_offset: reader.get_n(13),
+                    ..Default::default()
+                };
 
                 ext_block_use_bits += 52;
 
                 ExtMetadataBlock::Level5(block)
             }
-            6 => {
-                assert_eq!(block_info.ext_block_length, 8);
-                let mut block = ExtMetadataBlockLevel6::default();
-
-                block.max_display_mastering_luminance = reader.get_n(16);
-                block.min_display_mastering_luminance = reader.get_n(16);
-                block.max_content_light_level = reader.get_n(16);
-                block.max_frame_average_light_level = reader.get_n(16);
+            Some(ExtBlockLevel::L6Hdr10) => {
+                let block = ExtMetadataBlockLevel6 {
+                    max_display_mastering_luminance: reader.get_n(16),
+                    min_display_mastering_luminance: reader.get_n(16),
+                    max_content_light_level: reader.get_n(16),
+                    max_frame_average_light_level: reader.get_n(16),
+                    ..Default::default()
+                };
 
                 ext_block_use_bits += 64;
 
                 ExtMetadataBlock::Level6(block)
             }
-            _ => {
+            Some(ExtBlockLevel::L9SourcePrimaries) => {
+                let block = ExtMetadataBlockLevel9 {
+                    source_primary_index: reader.get_n(8),
+                    source_primaries: read_primaries(reader, primaries_present),
+                    ..Default::default()
+                };
+
+                ext_block_use_bits += 8 + 128 * primaries_present as u64;
+
+                ExtMetadataBlock::Level9(block)
+            }
+            Some(ExtBlockLevel::L10TargetDisplay) => {
+                let block = ExtMetadataBlockLevel10 {
+                    target_display_index: reader.get_n(8),
+                    target_max_pq: reader.get_n(12),
+                    target_min_pq: reader.get_n(12),
+                    target_primary_index: reader.get_n(8),
+                    target_primaries: read_primaries(reader, primaries_present),
+                    ..Default::default()
+                };
+
+                ext_block_use_bits += 40 + 128 * primaries_present as u64;
+
+                ExtMetadataBlock::Level10(block)
+            }
+            None => {
                 let block = ReservedExtMetadataBlock::default();
                 ExtMetadataBlock::Reserved(block)
             }
@@ -372,23 +1267,233 @@ impl ExtMetadataBlock {
             ExtMetadataBlock::Level4(ref mut b) => b.block_info = block_info,
             ExtMetadataBlock::Level5(ref mut b) => b.block_info = block_info,
             ExtMetadataBlock::Level6(ref mut b) => b.block_info = block_info,
+            ExtMetadataBlock::Level9(ref mut b) => b.block_info = block_info,
+            ExtMetadataBlock::Level10(ref mut b) => b.block_info = block_info,
             ExtMetadataBlock::Reserved(ref mut b) => b.block_info = block_info,
         }
 
-        ext_metadata_block
+        Ok(ext_metadata_block)
     }
 
-    pub fn write(&self, writer: &mut BitVecWriter) {
-        let block_info = match self {
+    fn block_info(&self) -> &BlockInfo {
+        match self {
             ExtMetadataBlock::Level1(b) => &b.block_info,
             ExtMetadataBlock::Level2(b) => &b.block_info,
             ExtMetadataBlock::Level3(b) => &b.block_info,
             ExtMetadataBlock::Level4(b) => &b.block_info,
             ExtMetadataBlock::Level5(b) => &b.block_info,
             ExtMetadataBlock::Level6(b) => &b.block_info,
+            ExtMetadataBlock::Level9(b) => &b.block_info,
+            ExtMetadataBlock::Level10(b) => &b.block_info,
             ExtMetadataBlock::Reserved(b) => &b.block_info,
+        }
+    }
+
+    fn block_info_mut(&mut self) -> &mut BlockInfo {
+        match self {
+            ExtMetadataBlock::Level1(b) => &mut b.block_info,
+            ExtMetadataBlock::Level2(b) => &mut b.block_info,
+            ExtMetadataBlock::Level3(b) => &mut b.block_info,
+            ExtMetadataBlock::Level4(b) => &mut b.block_info,
+            ExtMetadataBlock::Level5(b) => &mut b.block_info,
+            ExtMetadataBlock::Level6(b) => &mut b.block_info,
+            ExtMetadataBlock::Level9(b) => &mut b.block_info,
+            ExtMetadataBlock::Level10(b) => &mut b.block_info,
+            ExtMetadataBlock::Reserved(b) => &mut b.block_info,
+        }
+    }
+
+    /// Makes the block info match the fields: the level, ext_block_length and the zero padding after the fields.
+    /// Reserved blocks keep their level, their data is padded to whole bytes.
+    fn update_block_info(&mut self) {
+        let level = self.level();
+        let explicit_primaries = match self {
+            ExtMetadataBlock::Level9(b) => b.source_primaries.is_some(),
+            ExtMetadataBlock::Level10(b) => b.target_primaries.is_some(),
+            _ => false,
         };
 
+        let block_info = self.block_info_mut();
+
+        match level {
+            Some(level) => {
+                let length =
+                    level.ext_block_length() + PRIMARIES_LENGTH * explicit_primaries as u64;
+                let fields_bits =
+                    level.fields_bits() + 8 * PRIMARIES_LENGTH * explicit_primaries as u64;
+
+                block_info.ext_block_level = level.as_u8();
+                block_info.ext_block_length = length;
+                block_info
+                    .remaining
+                    .resize((8 * length - fields_bits) as usize, false);
+            }
+            None => {
+                let length = (block_info.remaining.len() as u64).div_ceil(8);
+
+                block_info.ext_block_length = length;
+                block_info.remaining.resize(8 * length as usize, false);
+            }
+        }
+    }
+
+    /// ext_block_level of the block, reserved levels included
+    pub fn ext_block_level(&self) -> u8 {
+        self.block_info().ext_block_level
+    }
+
+    /// Level of the block, `None` for reserved levels
+    pub fn level(&self) -> Option<ExtBlockLevel> {
+        match self {
+            ExtMetadataBlock::Level1(_) => Some(ExtBlockLevel::L1Content),
+            ExtMetadataBlock::Level2(_) => Some(ExtBlockLevel::L2Trim),
+            ExtMetadataBlock::Level3(_) => Some(ExtBlockLevel::L3ContentOffsets),
+            ExtMetadataBlock::Level4(_) => Some(ExtBlockLevel::L4Temporal),
+            ExtMetadataBlock::Level5(_) => Some(ExtBlockLevel::L5ActiveArea),
+            ExtMetadataBlock::Level6(_) => Some(ExtBlockLevel::L6Hdr10),
+            ExtMetadataBlock::Level9(_) => Some(ExtBlockLevel::L9SourcePrimaries),
+            ExtMetadataBlock::Level10(_) => Some(ExtBlockLevel::L10TargetDisplay),
+            ExtMetadataBlock::Reserved(_) => None,
+        }
+    }
+
+    pub fn typed(&self) -> TypedExtBlock {
+        match self {
+            ExtMetadataBlock::Level1(block) => TypedExtBlock::L1 {
+                min_pq: block.min_pq,
+                max_pq: block.max_pq,
+                avg_pq: block.avg_pq,
+            },
+            ExtMetadataBlock::Level2(block) => TypedExtBlock::L2(block.trim_values()),
+            ExtMetadataBlock::Level3(block) => TypedExtBlock::L3 {
+                min_pq_offset: block.min_pq_offset,
+                max_pq_offset: block.max_pq_offset,
+                avg_pq_offset: block.avg_pq_offset,
+            },
+            ExtMetadataBlock::Level4(block) => TypedExtBlock::L4 {
+                anchor_pq: block.anchor_pq,
+                anchor_power: block.anchor_power,
+            },
+            ExtMetadataBlock::Level5(block) => TypedExtBlock::L5 {
+                left_offset: block.active_area_left_offset,
+                right_offset: block.active_area_right_offset,
+                top_offset: block.active_area_top_offset,
+                bottom_offset: block.active_area_bottom_offset,
+            },
+            ExtMetadataBlock::Level6(block) => TypedExtBlock::L6 {
+                max_display_mastering_luminance: block.max_display_mastering_luminance,
+                min_display_mastering_luminance: block.min_display_mastering_luminance,
+                max_content_light_level: block.max_content_light_level,
+                max_frame_average_light_level: block.max_frame_average_light_level,
+            },
+            ExtMetadataBlock::Level9(block) => TypedExtBlock::L9 {
+                source_primary_index: block.source_primary_index,
+            },
+            ExtMetadataBlock::Level10(block) => TypedExtBlock::L10 {
+                target_display_index: block.target_display_index,
+                target_max_pq: block.target_max_pq,
+                target_min_pq: block.target_min_pq,
+                target_primary_index: block.target_primary_index,
+            },
+            ExtMetadataBlock::Reserved(block) => {
+                TypedExtBlock::Reserved(block.block_info.ext_block_level)
+            }
+        }
+    }
+
+    /// Whether the block signals nothing more than its absence:
+    /// a L5 block without offsets, the full frame, or a L6 block with unknown HDR10 metadata
+    pub fn is_no_op(&self) -> bool {
+        if self.block_info().remaining.any() {
+            return false;
+        }
+
+        match self {
+            ExtMetadataBlock::Level5(block) => block.named_offsets().iter().all(|(_, o)| *o == 0),
+            ExtMetadataBlock::Level6(block) => {
+                block.max_display_mastering_luminance == 0
+                    && block.min_display_mastering_luminance == 0
+                    && block.max_content_light_level == 0
+                    && block.max_frame_average_light_level == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Fields too large for their bit width, named with the block level
+    pub fn out_of_range_fields(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        let mut check = |level: u8, field: &str, value: u16, max: u16| {
+            if value > max {
+                errors.push(format!(
+                    "L{} {} should be at most {}, found {}",
+                    level, field, max, value
+                ));
+            }
+        };
+
+        match self {
+            ExtMetadataBlock::Level1(block) => {
+                check(1, "min_pq", block.min_pq, MAX_12_BITS);
+                check(1, "max_pq", block.max_pq, MAX_12_BITS);
+                check(1, "avg_pq", block.avg_pq, MAX_12_BITS);
+            }
+            ExtMetadataBlock::Level2(block) => {
+                check(2, "target_max_pq", block.target_max_pq, MAX_12_BITS);
+                check(2, "trim_slope", block.trim_slope, MAX_12_BITS);
+                check(2, "trim_offset", block.trim_offset, MAX_12_BITS);
+                check(2, "trim_power", block.trim_power, MAX_12_BITS);
+                check(
+                    2,
+                    "trim_chroma_weight",
+                    block.trim_chroma_weight,
+                    MAX_12_BITS,
+                );
+                check(
+                    2,
+                    "trim_saturation_gain",
+                    block.trim_saturation_gain,
+                    MAX_12_BITS,
+                );
+
+                // 13 bits signed
+                if !(-4096..=4095).contains(&block.ms_weight) {
+                    errors.push(format!(
+                        "L2 ms_weight should be between -4096 and 4095, found {}",
+                        block.ms_weight
+                    ));
+                }
+            }
+            ExtMetadataBlock::Level3(block) => {
+                check(3, "min_pq_offset", block.min_pq_offset, MAX_12_BITS);
+                check(3, "max_pq_offset", block.max_pq_offset, MAX_12_BITS);
+                check(3, "avg_pq_offset", block.avg_pq_offset, MAX_12_BITS);
+            }
+            ExtMetadataBlock::Level4(block) => {
+                check(4, "anchor_pq", block.anchor_pq, MAX_12_BITS);
+                check(4, "anchor_power", block.anchor_power, MAX_12_BITS);
+            }
+            ExtMetadataBlock::Level5(block) => {
+                for (field, offset) in block.named_offsets() {
+                    check(5, field, offset, MAX_ACTIVE_AREA_OFFSET);
+                }
+            }
+            ExtMetadataBlock::Level10(block) => {
+                check(10, "target_max_pq", block.target_max_pq, MAX_12_BITS);
+                check(10, "target_min_pq", block.target_min_pq, MAX_12_BITS);
+            }
+            ExtMetadataBlock::Level6(_)
+            | ExtMetadataBlock::Level9(_)
+            | ExtMetadataBlock::Reserved(_) => (),
+        }
+
+        errors
+    }
+
+    pub fn write(&self, writer: &mut BitVecWriter) {
+        let block_info = self.block_info();
+
         writer.write_ue(block_info.ext_block_length);
         writer.write_n(&block_info.ext_block_level.to_be_bytes(), 8);
 
@@ -406,6 +1511,7 @@ impl ExtMetadataBlock {
                 writer.write_n(&block.trim_chroma_weight.to_be_bytes(), 12);
                 writer.write_n(&block.trim_saturation_gain.to_be_bytes(), 12);
 
+                // Two's complement, the low 13 bits
                 writer.write_n(&block.ms_weight.to_be_bytes(), 13);
             }
             ExtMetadataBlock::Level3(block) => {
@@ -429,6 +1535,17 @@ impl ExtMetadataBlock {
                 writer.write_n(&block.max_content_light_level.to_be_bytes(), 16);
                 writer.write_n(&block.max_frame_average_light_level.to_be_bytes(), 16);
             }
+            ExtMetadataBlock::Level9(block) => {
+                writer.write_n(&block.source_primary_index.to_be_bytes(), 8);
+                write_primaries(writer, &block.source_primaries);
+            }
+            ExtMetadataBlock::Level10(block) => {
+                writer.write_n(&block.target_display_index.to_be_bytes(), 8);
+                writer.write_n(&block.target_max_pq.to_be_bytes(), 12);
+                writer.write_n(&block.target_min_pq.to_be_bytes(), 12);
+                writer.write_n(&block.target_primary_index.to_be_bytes(), 8);
+                write_primaries(writer, &block.target_primaries);
+            }
             ExtMetadataBlock::Reserved(_) => {
                 // Copy the data
                 block_info.remaining.iter().for_each(|b| writer.write(*b));
@@ -446,6 +1563,251 @@ impl ExtMetadataBlock {
     }
 }
 
+/// Parses an ext block list: its num_ext_blocks, the alignment bits and the blocks.
+/// Returns num_ext_blocks, lowered to the parsed blocks when truncated, and the blocks.
+fn parse_ext_blocks(
+    reader: &mut BitVecReader,
+    final_len: usize,
+    max_ext_blocks: u64,
+    lenient: bool,
+    warnings: &mut Vec<String>,
+) -> Result<(u64, Vec<ExtMetadataBlock>), String> {
+    let mut num_ext_blocks = reader.get_ue();
+    let mut ext_metadata_blocks = Vec::new();
+
+    if num_ext_blocks > 0 {
+        while !reader.is_aligned() {
+            if reader.get() {
+                return Err("ext_dm_alignment_zero_bit should be 0".to_string());
+            }
+        }
+
+        if num_ext_blocks > max_ext_blocks && !lenient {
+            return Err(format!(
+                "num_ext_blocks should be at most {}, found {}",
+                max_ext_blocks, num_ext_blocks
+            ));
+        }
+
+        for _ in 0..num_ext_blocks.min(max_ext_blocks) {
+            // Smallest block: 1 bit ext_block_length and the ext_block_level byte
+            if reader.available() < final_len + 9 {
+                break;
+            }
+
+            ext_metadata_blocks.push(ExtMetadataBlock::parse(reader, final_len)?);
+        }
+
+        let parsed_blocks = ext_metadata_blocks.len() as u64;
+
+        if parsed_blocks < num_ext_blocks {
+            let message = format!(
+                "num_ext_blocks is {}, only {} blocks could be parsed",
+                num_ext_blocks, parsed_blocks
+            );
+
+            if !lenient {
+                return Err(message);
+            }
+
+            warnings.push(format!("{}, truncated", message));
+            num_ext_blocks = parsed_blocks;
+
+            // The rest can't be trusted
+            reader.skip_n(reader.available() - final_len);
+        }
+    }
+
+    Ok((num_ext_blocks, ext_metadata_blocks))
+}
+
+fn write_ext_blocks(writer: &mut BitVecWriter, num_ext_blocks: u64, blocks: &[ExtMetadataBlock]) {
+    writer.write_ue(num_ext_blocks);
+
+    if num_ext_blocks > 0 {
+        while !writer.is_aligned() {
+            writer.write(false);
+        }
+
+        for ext_metadata_block in blocks {
+            ext_metadata_block.write(writer);
+        }
+    }
+}
+
+fn read_primaries(reader: &mut BitVecReader, present: bool) -> Option<[u16; 8]> {
+    if present {
+        let mut primaries = [0; 8];
+        primaries.iter_mut().for_each(|p| *p = reader.get_n(16));
+
+        Some(primaries)
+    } else {
+        None
+    }
+}
+
+fn write_primaries(writer: &mut BitVecWriter, primaries: &Option<[u16; 8]>) {
+    if let Some(primaries) = primaries {
+        primaries
+            .iter()
+            .for_each(|p| writer.write_n(&p.to_be_bytes(), 16));
+    }
+}
+
+impl ExtMetadataBlockLevel2 {
+    /// Multiplies the distance of the trims to the neutral 2048 by `factor`,
+    /// rounded and clamped between 0 and 4095. Returns whether a trim was changed
+    pub fn scale_trims(&mut self, factor: f64, fields: &[L2TrimField]) -> bool {
+        let mut changed = false;
+
+        for field in fields {
+            let trim = match field {
+                L2TrimField::Slope => &mut self.trim_slope,
+                L2TrimField::Offset => &mut self.trim_offset,
+                L2TrimField::Power => &mut self.trim_power,
+            };
+
+            let deviation = (*trim as f64 - NEUTRAL_TRIM as f64) * factor;
+            let scaled = (NEUTRAL_TRIM as f64 + deviation)
+                .round()
+                .clamp(0.0, MAX_12_BITS as f64) as u16;
+
+            if scaled != *trim {
+                *trim = scaled;
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    pub fn trim_values(&self) -> TrimValues {
+        TrimValues {
+            target_max_pq: self.target_max_pq,
+            trim_slope: self.trim_slope,
+            trim_offset: self.trim_offset,
+            trim_power: self.trim_power,
+            trim_chroma_weight: self.trim_chroma_weight,
+            trim_saturation_gain: self.trim_saturation_gain,
+            ms_weight: self.ms_weight,
+        }
+    }
+}
+
+impl TrimValues {
+    pub fn neutral(target_max_pq: u16) -> TrimValues {
+        TrimValues {
+            target_max_pq,
+            trim_slope: NEUTRAL_TRIM,
+            trim_offset: NEUTRAL_TRIM,
+            trim_power: NEUTRAL_TRIM,
+            trim_chroma_weight: NEUTRAL_TRIM,
+            trim_saturation_gain: NEUTRAL_TRIM,
+            ms_weight: NEUTRAL_TRIM as i16,
+        }
+    }
+
+    fn interpolate(&self, other: &TrimValues, weight: f64) -> TrimValues {
+        let lerp = |a: f64, b: f64| (a + (b - a) * weight).round();
+
+        TrimValues {
+            target_max_pq: lerp(self.target_max_pq as f64, other.target_max_pq as f64) as u16,
+            trim_slope: lerp(self.trim_slope as f64, other.trim_slope as f64) as u16,
+            trim_offset: lerp(self.trim_offset as f64, other.trim_offset as f64) as u16,
+            trim_power: lerp(self.trim_power as f64, other.trim_power as f64) as u16,
+            trim_chroma_weight: lerp(
+                self.trim_chroma_weight as f64,
+                other.trim_chroma_weight as f64,
+            ) as u16,
+            trim_saturation_gain: lerp(
+                self.trim_saturation_gain as f64,
+                other.trim_saturation_gain as f64,
+            ) as u16,
+            ms_weight: lerp(self.ms_weight as f64, other.ms_weight as f64) as i16,
+        }
+    }
+}
+
+impl ExtMetadataBlockLevel1 {
+    /// Lowers max_pq to `max_pq`, returns whether it was changed
+    pub fn clamp_max_pq(&mut self, max_pq: u16) -> bool {
+        if self.max_pq > max_pq {
+            self.max_pq = max_pq;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Frame peak luminance, in nits
+    pub fn max_nits(&self) -> f64 {
+        pq_to_nits(self.max_pq)
+    }
+
+    /// L1 avg_pq of the DM metadata, interpreted the same way whatever the CM version.
+    /// CM v4.0 adjusts the L1 avg_pq with the L3 avg_pq_offset, centered on 2048, which is applied.
+    pub fn normalized_avg_pq(vdr_dm_data: &VdrDmData, cm_version: CmVersion) -> Option<u16> {
+        let avg_pq = vdr_dm_data.blocks().find_map(|block| match block {
+            TypedExtBlock::L1 { avg_pq, .. } => Some(avg_pq),
+            _ => None,
+        })?;
+
+        let offset = match cm_version {
+            CmVersion::V29 => None,
+            CmVersion::V40 => vdr_dm_data.blocks().find_map(|block| match block {
+                TypedExtBlock::L3 { avg_pq_offset, .. } => Some(avg_pq_offset as i32 - 2048),
+                _ => None,
+            }),
+        };
+
+        let avg_pq = (avg_pq as i32 + offset.unwrap_or(0)).clamp(0, MAX_12_BITS as i32);
+
+        Some(avg_pq as u16)
+    }
+
+    /// Frame average luminance, in nits, from the normalized avg_pq
+    pub fn normalized_avg_nits(vdr_dm_data: &VdrDmData, cm_version: CmVersion) -> Option<f64> {
+        ExtMetadataBlockLevel1::normalized_avg_pq(vdr_dm_data, cm_version).map(pq_to_nits)
+    }
+
+    #[cfg(test)]
+    pub fn get(rpu: &DoviRpu) -> Option<&ExtMetadataBlockLevel1> {
+        if let Some(ref vdr_dm_data) = rpu.vdr_dm_data {
+            for ext in vdr_dm_data.ext_metadata_blocks.iter() {
+                if let ExtMetadataBlock::Level1(block) = ext {
+                    return Some(block);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn get_mut(rpu: &mut DoviRpu) -> Option<&mut ExtMetadataBlockLevel1> {
+        if let Some(ref mut vdr_dm_data) = rpu.vdr_dm_data {
+            for ext in vdr_dm_data.ext_metadata_blocks.iter_mut() {
+                if let ExtMetadataBlock::Level1(block) = ext {
+                    return Some(block);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl ExtMetadataBlockLevel6 {
+    /// Mastering display max and min, MaxCLL and MaxFALL
+    fn light_levels(&self) -> [u16; 4] {
+        [
+            self.max_display_mastering_luminance,
+            self.min_display_mastering_luminance,
+            self.max_content_light_level,
+            self.max_frame_average_light_level,
+        ]
+    }
+}
+
 impl ExtMetadataBlockLevel5 {
     pub fn _get_offsets(&self) -> Vec<u16> {
         vec![
@@ -456,11 +1818,75 @@ impl ExtMetadataBlockLevel5 {
         ]
     }
 
-    pub fn set_offsets(&mut self, left: u16, right: u16, top: u16, bottom: u16) {
+    fn named_offsets(&self) -> [(&'static str, u16); 4] {
+        [
+            ("active_area_left_offset", self.active_area_left_offset),
+            ("active_area_right_offset", self.active_area_right_offset),
+            ("active_area_top_offset", self.active_area_top_offset),
+            ("active_area_bottom_offset", self.active_area_bottom_offset),
+        ]
+    }
+
+    /// Sets the offsets, left unchanged if any is above 8191
+    pub fn set_offsets(
+        &mut self,
+        left: u16,
+        right: u16,
+        top: u16,
+        bottom: u16,
+    ) -> Result<(), String> {
+        let offsets = ExtMetadataBlockLevel5 {
+            block_info: BlockInfo::default(),
+            active_area_left_offset: left,
+            active_area_right_offset: right,
+            active_area_top_offset: top,
+            active_area_bottom_offset: bottom,
+        };
+
+        for (field, offset) in offsets.named_offsets() {
+            if offset > MAX_ACTIVE_AREA_OFFSET {
+                return Err(format!(
+                    "L5 {} should be at most {}, found {}",
+                    field, MAX_ACTIVE_AREA_OFFSET, offset
+                ));
+            }
+        }
+
         self.active_area_left_offset = left;
         self.active_area_right_offset = right;
         self.active_area_top_offset = top;
         self.active_area_bottom_offset = bottom;
+
+        Ok(())
+    }
+
+    /// Shifts the offsets by signed deltas, clamped to the 13 bits range
+    pub fn adjust_offsets(&mut self, left: i32, right: i32, top: i32, bottom: i32) {
+        let adjust = |offset: u16, delta: i32| {
+            (offset as i32 + delta).clamp(0, MAX_ACTIVE_AREA_OFFSET as i32) as u16
+        };
+
+        self.active_area_left_offset = adjust(self.active_area_left_offset, left);
+        self.active_area_right_offset = adjust(self.active_area_right_offset, right);
+        self.active_area_top_offset = adjust(self.active_area_top_offset, top);
+        self.active_area_bottom_offset = adjust(self.active_area_bottom_offset, bottom);
+    }
+
+    /// Aspect ratio of the active area of a `width`x`height` frame
+    pub fn aspect_ratio(&self, width: u16, height: u16) -> Result<f64, String> {
+        let horizontal = self.active_area_left_offset as u32 + self.active_area_right_offset as u32;
+        let vertical = self.active_area_top_offset as u32 + self.active_area_bottom_offset as u32;
+
+        if horizontal >= width as u32 || vertical >= height as u32 {
+            return Err(format!(
+                "Active area offsets {:?} leave no picture in a {}x{} frame",
+                self._get_offsets(),
+                width,
+                height
+            ));
+        }
+
+        Ok((width as u32 - horizontal) as f64 / (height as u32 - vertical) as f64)
     }
 
     pub fn crop(&mut self) {
@@ -470,6 +1896,18 @@ impl ExtMetadataBlockLevel5 {
         self.active_area_bottom_offset = 0;
     }
 
+    pub fn get(rpu: &DoviRpu) -> Option<&ExtMetadataBlockLevel5> {
+        if let Some(ref vdr_dm_data) = rpu.vdr_dm_data {
+            for ext in vdr_dm_data.ext_metadata_blocks.iter() {
+                if let ExtMetadataBlock::Level5(block) = ext {
+                    return Some(block);
+                }
+            }
+        }
+
+        None
+    }
+
     pub fn get_mut(rpu: &mut DoviRpu) -> Option<&mut ExtMetadataBlockLevel5> {
         if let Some(ref mut vdr_dm_data) = rpu.vdr_dm_data {
             for ext in vdr_dm_data.ext_metadata_blocks.iter_mut() {
@@ -482,3 +1920,18 @@ impl ExtMetadataBlockLevel5 {
         None
     }
 }
+
+impl ExtMetadataBlockLevel10 {
+    /// Sets the target_primary_index, removing the explicit primaries
+    pub fn set_target_primary_index(&mut self, index: u8) {
+        self.target_primary_index = index;
+        self.target_primaries = None;
+    }
+}
+
+/// Interprets the low `bits` bits of `value` as a two's complement signed value
+pub(super) fn sign_extend(value: u16, bits: u32) -> i16 {
+    let shift = 16 - bits;
+
+    ((value << shift) as i16) >> shift
+}
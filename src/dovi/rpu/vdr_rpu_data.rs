@@ -1,7 +1,18 @@
+use serde::{Deserialize, Serialize};
+
 use super::RpuDataHeader;
 use super::{BitVecReader, BitVecWriter};
 
-#[derive(Debug, Default)]
+/// Number of Y, Cb and Cr values multiplied in each MMR term, at order 1
+const MMR_TERM_DEGREES: [u32; 7] = [1, 1, 1, 2, 2, 2, 3];
+
+/// Segments of the BL range the composed luma mapping is fitted on
+const FITTED_SEGMENTS: usize = 8;
+
+/// Upper bound of poly_order_minus1, polynomials are at most of order 2
+const MAX_POLY_ORDER_MINUS1: u64 = 1;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct VdrRpuData {
     mapping_idc: Vec<Vec<u64>>,
     mapping_param_pred_flag: Vec<Vec<bool>>,
@@ -20,7 +31,7 @@ pub struct VdrRpuData {
     mmr_coef: Vec<Vec<Vec<Vec<u64>>>>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct NlqData {
     num_nlq_param_predictors: Vec<Vec<u64>>,
     nlq_param_pred_flag: Vec<Vec<bool>>,
@@ -34,22 +45,43 @@ pub struct NlqData {
     linear_deadzone_threshold: Vec<Vec<u64>>,
 }
 
+/// Mapping method of a pivot segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MappingMethod {
+    Polynomial,
+    Mmr,
+    Unknown(u64),
+}
+
+/// Summary of the mapping of a single component
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComponentMapping {
+    pub num_pivots: usize,
+    /// Pivot values, in BL codewords
+    pub pivots: Vec<u64>,
+    /// Mapping method of each segment between two pivots
+    pub methods: Vec<MappingMethod>,
+}
+
 impl VdrRpuData {
     pub fn vdr_rpu_data_payload(
         reader: &mut BitVecReader,
         header: &mut RpuDataHeader,
-    ) -> (Option<VdrRpuData>, Option<NlqData>) {
-        let vdr_rpu_data = VdrRpuData::rpu_data_mapping(reader, header);
+    ) -> Result<(Option<VdrRpuData>, Option<NlqData>), String> {
+        let vdr_rpu_data = VdrRpuData::rpu_data_mapping(reader, header)?;
 
         if header.nlq_method_idc.is_some() {
-            let nlq_data = NlqData::rpu_data_nlq(reader, header);
-            (Some(vdr_rpu_data), Some(nlq_data))
+            let nlq_data = NlqData::rpu_data_nlq(reader, header)?;
+            Ok((Some(vdr_rpu_data), Some(nlq_data)))
         } else {
-            (Some(vdr_rpu_data), None)
+            Ok((Some(vdr_rpu_data), None))
         }
     }
 
-    pub fn rpu_data_mapping(reader: &mut BitVecReader, header: &mut RpuDataHeader) -> VdrRpuData {
+    pub fn rpu_data_mapping(
+        reader: &mut BitVecReader,
+        header: &mut RpuDataHeader,
+    ) -> Result<VdrRpuData, String> {
         let num_cmps = 3;
 
         let mut data = VdrRpuData::default();
@@ -59,7 +91,10 @@ impl VdrRpuData {
         } else if header.coefficient_data_type == 1 {
             32
         } else {
-            panic!("Invalid coefficient_data_type value!");
+            return Err(format!(
+                "Invalid coefficient_data_type value: {}",
+                header.coefficient_data_type
+            ));
         };
 
         // rpu_data_mapping_param
@@ -83,9 +118,11 @@ impl VdrRpuData {
             data.poly_coef_int.push(vec![vec![]; pivot_idx_count]);
             data.poly_coef.push(vec![vec![]; pivot_idx_count]);
 
+            // The last segment can also code the value of the last pivot
             data.pred_linear_interp_value_int
-                .push(vec![0; pivot_idx_count]);
-            data.pred_linear_interp_value.push(vec![0; pivot_idx_count]);
+                .push(vec![0; pivot_idx_count + 1]);
+            data.pred_linear_interp_value
+                .push(vec![0; pivot_idx_count + 1]);
             data.mmr_order_minus1.push(vec![0; pivot_idx_count]);
             data.mmr_constant_int.push(vec![0; pivot_idx_count]);
             data.mmr_constant.push(vec![0; pivot_idx_count]);
@@ -96,6 +133,14 @@ impl VdrRpuData {
             for pivot_idx in 0..pivot_idx_count {
                 data.mapping_idc[cmp][pivot_idx] = reader.get_ue();
 
+                // Only polynomial and MMR mappings are defined, the parameters can't be skipped
+                if data.mapping_idc[cmp][pivot_idx] > 1 {
+                    return Err(format!(
+                        "Unsupported mapping_idc value: {}",
+                        data.mapping_idc[cmp][pivot_idx]
+                    ));
+                }
+
                 // Dolby pls. Guessing this is what they mean by "new parameters"
                 if pivot_idx > 0
                     && data.mapping_idc[cmp][pivot_idx] != data.mapping_idc[cmp][pivot_idx - 1]
@@ -120,6 +165,19 @@ impl VdrRpuData {
                     if data.mapping_idc[cmp][pivot_idx] == 0 {
                         data.poly_order_minus1[cmp][pivot_idx] = reader.get_ue();
 
+                        // The coefficient vectors are sized by the order, a coefficient takes at least a bit
+                        let poly_coef_count =
+                            data.poly_order_minus1[cmp][pivot_idx].saturating_add(2);
+
+                        if poly_coef_count > reader.available() as u64 {
+                            return Err(format!(
+                                "poly_order_minus1 is {}, the coefficients need at least {} bits, only {} left",
+                                data.poly_order_minus1[cmp][pivot_idx],
+                                poly_coef_count,
+                                reader.available()
+                            ));
+                        }
+
                         if data.poly_order_minus1[cmp][pivot_idx] == 0 {
                             data.linear_interp_flag[cmp][pivot_idx] = reader.get();
                         }
@@ -164,7 +222,12 @@ impl VdrRpuData {
                         // MAPPING_MMR
                         data.mmr_order_minus1[cmp][pivot_idx] = reader.get_n(2);
 
-                        assert!(data.mmr_order_minus1[cmp][pivot_idx] <= 2);
+                        if data.mmr_order_minus1[cmp][pivot_idx] > 2 {
+                            return Err(format!(
+                                "Invalid mmr_order_minus1 value: {}",
+                                data.mmr_order_minus1[cmp][pivot_idx]
+                            ));
+                        }
 
                         data.mmr_coef[cmp][pivot_idx] =
                             vec![vec![0; 7]; data.mmr_order_minus1[cmp][pivot_idx] as usize + 2];
@@ -195,12 +258,425 @@ impl VdrRpuData {
             }
         }
 
-        data.validate();
+        Ok(data)
+    }
+
+    /// Checks the values allowed by the parser but not by the spec, returning the warnings
+    pub fn validate(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        for (cmp, orders) in self.poly_order_minus1.iter().enumerate() {
+            for (pivot_idx, order) in orders.iter().enumerate() {
+                if *order > MAX_POLY_ORDER_MINUS1 {
+                    warnings.push(format!(
+                        "poly_order_minus1[{}][{}] should be at most {}, found {}",
+                        cmp, pivot_idx, MAX_POLY_ORDER_MINUS1, order
+                    ));
+                }
+            }
+        }
 
-        data
+        warnings
     }
 
-    pub fn validate(&self) {}
+    /// Returns the pivots and the mapping method of every segment, per component.
+    pub fn component_mappings(&self, header: &RpuDataHeader) -> Vec<ComponentMapping> {
+        self.mapping_idc
+            .iter()
+            .enumerate()
+            .map(|(cmp, mapping_idc)| {
+                // Pivots are coded as deltas from the previous pivot
+                let pivots = header.pred_pivot_value[cmp]
+                    .iter()
+                    .scan(0, |pivot, delta| {
+                        *pivot += delta;
+                        Some(*pivot)
+                    })
+                    .collect();
+
+                let methods = mapping_idc
+                    .iter()
+                    .map(|idc| match idc {
+                        0 => MappingMethod::Polynomial,
+                        1 => MappingMethod::Mmr,
+                        _ => MappingMethod::Unknown(*idc),
+                    })
+                    .collect();
+
+                ComponentMapping {
+                    num_pivots: (header.num_pivots_minus_2[cmp] + 2) as usize,
+                    pivots,
+                    methods,
+                }
+            })
+            .collect()
+    }
+
+    /// Reconstructs the real mapping coefficients, per component.
+    /// The segments are in pivot order, segments predicted from another one are skipped.
+    /// Polynomial: coefficients from the lowest order, or the linear interpolation values.
+    /// MMR: constant, then the 7 coefficients of each order.
+    pub fn mapping_coefficients(&self, header: &RpuDataHeader) -> Vec<Vec<f64>> {
+        let value = |int: i64, frac: u64, degree: u32| {
+            coefficient_value(header, int, frac) * normalization_scale(header, degree)
+        };
+
+        self.mapping_idc
+            .iter()
+            .enumerate()
+            .map(|(cmp, mapping_idc)| {
+                let mut coefs = Vec::new();
+
+                for (pivot_idx, idc) in mapping_idc.iter().enumerate() {
+                    if self.mapping_param_pred_flag[cmp][pivot_idx] {
+                        continue;
+                    }
+
+                    match idc {
+                        0 if self.poly_order_minus1[cmp][pivot_idx] == 0
+                            && self.linear_interp_flag[cmp][pivot_idx] =>
+                        {
+                            let last = pivot_idx as u64 == header.num_pivots_minus_2[cmp];
+                            let end = if last { pivot_idx + 1 } else { pivot_idx };
+
+                            for i in pivot_idx..=end {
+                                coefs.push(value(
+                                    self.pred_linear_interp_value_int[cmp][i] as i64,
+                                    self.pred_linear_interp_value[cmp][i],
+                                    0,
+                                ));
+                            }
+                        }
+                        0 => coefs.extend(
+                            self.poly_coef_int[cmp][pivot_idx]
+                                .iter()
+                                .zip(&self.poly_coef[cmp][pivot_idx])
+                                .enumerate()
+                                .map(|(order, (int, frac))| value(*int, *frac, order as u32)),
+                        ),
+                        1 => {
+                            coefs.push(value(
+                                self.mmr_constant_int[cmp][pivot_idx],
+                                self.mmr_constant[cmp][pivot_idx],
+                                0,
+                            ));
+
+                            // Order 0 is unused
+                            for (order, (ints, fracs)) in self.mmr_coef_int[cmp][pivot_idx]
+                                .iter()
+                                .zip(&self.mmr_coef[cmp][pivot_idx])
+                                .enumerate()
+                                .skip(1)
+                            {
+                                coefs.extend(ints.iter().zip(fracs).zip(&MMR_TERM_DEGREES).map(
+                                    |((int, frac), degree)| {
+                                        value(*int, *frac, degree * order as u32)
+                                    },
+                                ));
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+
+                coefs
+            })
+            .collect()
+    }
+
+    /// Maps a BL pixel to the value of the `cmp` component, both normalized to [0, 1].
+    /// `None` if the pixel's segment is predicted from another segment.
+    pub fn map_pixel(&self, header: &RpuDataHeader, cmp: usize, pixel: [f64; 3]) -> Option<f64> {
+        let value = |int: i64, frac: u64, degree: u32| {
+            coefficient_value(header, int, frac) * normalization_scale(header, degree)
+        };
+
+        let max_codeword = ((1_u64 << header.bl_bit_depth()) - 1) as f64;
+        let pivots: Vec<f64> = self.component_mappings(header)[cmp]
+            .pivots
+            .iter()
+            .map(|pivot| *pivot as f64 / max_codeword)
+            .collect();
+
+        let x = pixel[cmp].max(pivots[0]).min(pivots[pivots.len() - 1]);
+
+        // Segment between the two pivots around the input
+        let pivot_idx = pivots[1..pivots.len() - 1]
+            .iter()
+            .filter(|pivot| x >= **pivot)
+            .count();
+
+        if self.mapping_param_pred_flag[cmp][pivot_idx] {
+            return None;
+        }
+
+        match self.mapping_idc[cmp][pivot_idx] {
+            0 if self.poly_order_minus1[cmp][pivot_idx] == 0
+                && self.linear_interp_flag[cmp][pivot_idx] =>
+            {
+                let start = value(
+                    self.pred_linear_interp_value_int[cmp][pivot_idx] as i64,
+                    self.pred_linear_interp_value[cmp][pivot_idx],
+                    0,
+                );
+                let end = value(
+                    self.pred_linear_interp_value_int[cmp][pivot_idx + 1] as i64,
+                    self.pred_linear_interp_value[cmp][pivot_idx + 1],
+                    0,
+                );
+
+                let width = pivots[pivot_idx + 1] - pivots[pivot_idx];
+                let t = if width > 0.0 {
+                    (x - pivots[pivot_idx]) / width
+                } else {
+                    0.0
+                };
+
+                Some(start + (end - start) * t)
+            }
+            0 => Some(
+                self.poly_coef_int[cmp][pivot_idx]
+                    .iter()
+                    .zip(&self.poly_coef[cmp][pivot_idx])
+                    .enumerate()
+                    .map(|(order, (int, frac))| {
+                        value(*int, *frac, order as u32) * x.powi(order as i32)
+                    })
+                    .sum(),
+            ),
+            1 => {
+                let [y, cb, cr] = pixel;
+                let terms = [y, cb, cr, y * cb, y * cr, cb * cr, y * cb * cr];
+
+                let constant = value(
+                    self.mmr_constant_int[cmp][pivot_idx],
+                    self.mmr_constant[cmp][pivot_idx],
+                    0,
+                );
+
+                // Order 0 is unused
+                let sum: f64 = self.mmr_coef_int[cmp][pivot_idx]
+                    .iter()
+                    .zip(&self.mmr_coef[cmp][pivot_idx])
+                    .enumerate()
+                    .skip(1)
+                    .flat_map(|(order, (ints, fracs))| {
+                        ints.iter()
+                            .zip(fracs)
+                            .zip(terms.iter().zip(&MMR_TERM_DEGREES))
+                            .map(move |((int, frac), (term, degree))| {
+                                value(*int, *frac, degree * order as u32) * term.powi(order as i32)
+                            })
+                    })
+                    .sum();
+
+                Some(constant + sum)
+            }
+            _ => None,
+        }
+    }
+
+    /// Samples the mapping curves at `points` inputs evenly spaced over [0, 1], as Y, Cb and Cr outputs.
+    /// Each component is sampled on its own, the other inputs at the middle of the range.
+    /// `None` if a curve has predicted segments.
+    pub fn sample_lut(&self, header: &RpuDataHeader, points: usize) -> Option<Vec<[f64; 3]>> {
+        let last = points.saturating_sub(1).max(1) as f64;
+
+        (0..points)
+            .map(|i| {
+                let x = i as f64 / last;
+                let mut sample = [0.0; 3];
+
+                for (cmp, value) in sample.iter_mut().enumerate() {
+                    let mut pixel = [0.5; 3];
+                    pixel[cmp] = x;
+
+                    *value = self.map_pixel(header, cmp, pixel)?;
+                }
+
+                Some(sample)
+            })
+            .collect()
+    }
+
+    /// Composes the luma mapping with `transfer`, a function of the normalized mapped value.
+    ///
+    /// The result is fitted with second order polynomials over up to 8 segments of the pivots range,
+    /// the segment fitting the worst split in two. The luma pivots and mapping are replaced. The chroma mappings are left untouched.
+    /// Returns the largest fitting error, normalized.
+    pub fn compose_luma<F: Fn(f64) -> f64>(
+        &mut self,
+        header: &mut RpuDataHeader,
+        transfer: F,
+    ) -> Result<f64, String> {
+        if self.mapping_idc[0].iter().any(|idc| *idc != 0) {
+            return Err("Only polynomial luma mappings can be composed".to_string());
+        }
+
+        let max_codeword = ((1_u64 << header.bl_bit_depth()) - 1) as f64;
+        let pivots = &self.component_mappings(header)[0].pivots;
+        let (first, last) = (pivots[0], pivots[pivots.len() - 1]);
+
+        // At least 3 codewords per segment
+        if last < first + 2 {
+            return Err(format!(
+                "Luma pivots range too small to fit: {} to {}",
+                first, last
+            ));
+        }
+
+        let samples = (first..=last)
+            .map(|codeword| {
+                let x = codeword as f64 / max_codeword;
+
+                self.map_pixel(header, 0, [x, 0.5, 0.5])
+                    .map(|y| (x, transfer(y)))
+            })
+            .collect::<Option<Vec<(f64, f64)>>>()
+            .ok_or("The luma mapping has predicted segments")?;
+
+        // Fit and error of the segment between two pivots
+        let fit = |start: u64, end: u64| {
+            let samples = &samples[(start - first) as usize..=(end - first) as usize];
+            let c = fit_quadratic(samples);
+
+            let error = samples
+                .iter()
+                .map(|(x, y)| (c[0] + c[1] * x + c[2] * x * x - y).abs())
+                .fold(0.0, f64::max);
+
+            (c, error)
+        };
+
+        // The segment with the largest error is split in two until there are enough
+        let mut segments = vec![(first, last, fit(first, last))];
+
+        while segments.len() < FITTED_SEGMENTS {
+            let worst = (0..segments.len())
+                .filter(|i| segments[*i].1 - segments[*i].0 >= 4)
+                .max_by(|a, b| (segments[*a].2).1.total_cmp(&(segments[*b].2).1));
+
+            let worst = match worst {
+                Some(worst) => worst,
+                None => break,
+            };
+
+            let (start, end, _) = segments[worst];
+            let middle = (start + end) / 2;
+
+            segments[worst] = (start, middle, fit(start, middle));
+            segments.insert(worst + 1, (middle, end, fit(middle, end)));
+        }
+
+        let max_error = segments
+            .iter()
+            .map(|segment| (segment.2).1)
+            .fold(0.0, f64::max);
+        let coefficients: Vec<[f64; 3]> = segments.iter().map(|segment| (segment.2).0).collect();
+
+        let new_pivots: Vec<u64> = segments
+            .iter()
+            .map(|segment| segment.0)
+            .chain(std::iter::once(last))
+            .collect();
+
+        let count = segments.len();
+        header.num_pivots_minus_2[0] = count as u64 - 1;
+        // Coded as deltas from the previous pivot
+        header.pred_pivot_value[0] = new_pivots
+            .iter()
+            .scan(0, |previous, pivot| {
+                let delta = pivot - *previous;
+                *previous = *pivot;
+
+                Some(delta)
+            })
+            .collect();
+
+        let segments = count;
+
+        self.mapping_idc[0] = vec![0; segments];
+        self.mapping_param_pred_flag[0] = vec![false; segments];
+        self.num_mapping_param_predictors[0] = vec![0; segments];
+        self.diff_pred_part_idx_mapping_minus1[0] = vec![0; segments];
+        self.poly_order_minus1[0] = vec![1; segments];
+        self.linear_interp_flag[0] = vec![false; segments];
+        self.pred_linear_interp_value_int[0] = vec![0; segments + 1];
+        self.pred_linear_interp_value[0] = vec![0; segments + 1];
+        self.mmr_order_minus1[0] = vec![0; segments];
+        self.mmr_constant_int[0] = vec![0; segments];
+        self.mmr_constant[0] = vec![0; segments];
+        self.mmr_coef_int[0] = vec![vec![]; segments];
+        self.mmr_coef[0] = vec![vec![]; segments];
+
+        let coded: Vec<Vec<(i64, u64)>> = coefficients
+            .iter()
+            .map(|c| {
+                c.iter()
+                    .enumerate()
+                    .map(|(degree, value)| {
+                        coefficient_fields(
+                            header,
+                            value / normalization_scale(header, degree as u32),
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+
+        self.poly_coef_int[0] = coded
+            .iter()
+            .map(|c| c.iter().map(|f| f.0).collect())
+            .collect();
+        self.poly_coef[0] = coded
+            .iter()
+            .map(|c| c.iter().map(|f| f.1).collect())
+            .collect();
+
+        Ok(max_error)
+    }
+
+    /// Rescales the coefficients from a `from` bits fraction to a `to` bits fraction
+    pub fn requantize(&mut self, from: u64, to: u64) {
+        for (int, frac) in self
+            .pred_linear_interp_value_int
+            .iter_mut()
+            .flatten()
+            .zip(self.pred_linear_interp_value.iter_mut().flatten())
+        {
+            requantize_unsigned(int, frac, from, to);
+        }
+
+        for (int, frac) in self
+            .poly_coef_int
+            .iter_mut()
+            .flatten()
+            .flatten()
+            .zip(self.poly_coef.iter_mut().flatten().flatten())
+        {
+            requantize(int, frac, from, to);
+        }
+
+        for (int, frac) in self
+            .mmr_constant_int
+            .iter_mut()
+            .flatten()
+            .zip(self.mmr_constant.iter_mut().flatten())
+        {
+            requantize(int, frac, from, to);
+        }
+
+        for (int, frac) in self
+            .mmr_coef_int
+            .iter_mut()
+            .flatten()
+            .flatten()
+            .flatten()
+            .zip(self.mmr_coef.iter_mut().flatten().flatten().flatten())
+        {
+            requantize(int, frac, from, to);
+        }
+    }
 
     pub fn write(&self, writer: &mut BitVecWriter, header: &RpuDataHeader) {
         let coefficient_log2_denom_length = if header.coefficient_data_type == 0 {
@@ -409,12 +885,15 @@ impl VdrRpuData {
 }
 
 impl NlqData {
-    pub fn rpu_data_nlq(reader: &mut BitVecReader, header: &mut RpuDataHeader) -> NlqData {
+    pub fn rpu_data_nlq(
+        reader: &mut BitVecReader,
+        header: &mut RpuDataHeader,
+    ) -> Result<NlqData, String> {
         let num_cmps = 3;
         let pivot_idx_count = if let Some(nlq_num_pivots_minus2) = header.nlq_num_pivots_minus2 {
             nlq_num_pivots_minus2 as usize + 1
         } else {
-            panic!("Shouldn't be in NLQ if not profile 7!");
+            return Err("Shouldn't be in NLQ if not profile 7!".to_string());
         };
 
         let mut data = NlqData::default();
@@ -424,7 +903,10 @@ impl NlqData {
         } else if header.coefficient_data_type == 1 {
             32
         } else {
-            panic!("Invalid coefficient_data_type value!");
+            return Err(format!(
+                "Invalid coefficient_data_type value: {}",
+                header.coefficient_data_type
+            ));
         };
 
         for pivot_idx in 0..pivot_idx_count {
@@ -500,17 +982,63 @@ impl NlqData {
 
         data.validate();
 
-        data
+        Ok(data)
     }
 
     pub fn validate(&self) {}
 
+    /// Rescales the coefficients from a `from` bits fraction to a `to` bits fraction
+    pub fn requantize(&mut self, from: u64, to: u64) {
+        let pairs = [
+            (&mut self.vdr_in_max_int, &mut self.vdr_in_max),
+            (
+                &mut self.linear_deadzone_slope_int,
+                &mut self.linear_deadzone_slope,
+            ),
+            (
+                &mut self.linear_deadzone_threshold_int,
+                &mut self.linear_deadzone_threshold,
+            ),
+        ];
+
+        for (ints, fracs) in pairs {
+            for (int, frac) in ints.iter_mut().flatten().zip(fracs.iter_mut().flatten()) {
+                requantize_unsigned(int, frac, from, to);
+            }
+        }
+    }
+
+    /// Whether the NLQ parameters discard the EL residual, as set by `convert_to_mel`
+    pub fn is_mel(&self) -> bool {
+        self.nlq_offset.iter().flatten().all(|value| *value == 0) && self.is_identity_residual()
+    }
+
+    /// Whether the residual decodes to nothing, whatever the `nlq_offset`:
+    /// `vdr_in_max` of 1.0, zero deadzone slope and threshold.
+    pub fn is_identity_residual(&self) -> bool {
+        let all = |values: &Vec<Vec<u64>>, expected: u64| {
+            values.iter().flatten().all(|value| *value == expected)
+        };
+
+        all(&self.vdr_in_max_int, 1)
+            && all(&self.vdr_in_max, 0)
+            && all(&self.linear_deadzone_slope_int, 0)
+            && all(&self.linear_deadzone_slope, 0)
+            && all(&self.linear_deadzone_threshold_int, 0)
+            && all(&self.linear_deadzone_threshold, 0)
+    }
+
     pub fn convert_to_mel(&mut self) {
         // Set to 0
         self.nlq_offset.iter_mut().for_each(|v| {
             v.iter_mut().for_each(|v2| *v2 = 0);
         });
 
+        self.neutralize_residual();
+    }
+
+    /// Sets the residual to identity, keeping the `nlq_offset` values
+    pub fn neutralize_residual(&mut self) {
         // Set to 1
         self.vdr_in_max_int.iter_mut().for_each(|v| {
             v.iter_mut().for_each(|v2| *v2 = 1);
@@ -589,7 +1117,7 @@ impl NlqData {
                             );
 
                             if header.coefficient_data_type == 0 {
-                                writer.write_ue(self.linear_deadzone_slope_int[pivot_idx][cmp]);
+                                writer.write_ue(self.linear_deadzone_threshold_int[pivot_idx][cmp]);
                             }
 
                             writer.write_n(
@@ -605,3 +1133,110 @@ impl NlqData {
         }
     }
 }
+
+/// Real value of a coefficient, fixed point or 32 bits float
+fn coefficient_value(header: &RpuDataHeader, int: i64, frac: u64) -> f64 {
+    if header.coefficient_data_type == 0 {
+        int as f64 + frac as f64 / (1_u64 << header.coefficient_log2_denom) as f64
+    } else {
+        f32::from_bits(frac as u32) as f64
+    }
+}
+
+/// Coded integer and fractional fields of a real coefficient, the inverse of `coefficient_value`
+fn coefficient_fields(header: &RpuDataHeader, value: f64) -> (i64, u64) {
+    if header.coefficient_data_type == 0 {
+        let one = (1_u64 << header.coefficient_log2_denom) as f64;
+        let fixed = (value * one).round();
+
+        let int = (fixed / one).floor();
+
+        (int as i64, (fixed - int * one) as u64)
+    } else {
+        (0, (value as f32).to_bits() as u64)
+    }
+}
+
+/// Least squares second order polynomial of the `(x, y)` samples, lowest order first
+fn fit_quadratic(samples: &[(f64, f64)]) -> [f64; 3] {
+    // Normal equations, sums of x^(row + col) and x^row * y
+    let mut matrix = [[0.0; 4]; 3];
+
+    for (x, y) in samples {
+        for (row, values) in matrix.iter_mut().enumerate() {
+            for (col, value) in values.iter_mut().take(3).enumerate() {
+                *value += x.powi((row + col) as i32);
+            }
+
+            values[3] += x.powi(row as i32) * y;
+        }
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..3 {
+        let pivot = (col..3)
+            .max_by(|a, b| matrix[*a][col].abs().total_cmp(&matrix[*b][col].abs()))
+            .unwrap();
+        matrix.swap(col, pivot);
+
+        let pivot_row = matrix[col];
+
+        for values in matrix.iter_mut().skip(col + 1) {
+            let factor = values[col] / pivot_row[col];
+
+            for (value, pivot_value) in values.iter_mut().zip(&pivot_row).skip(col) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+
+    let mut coefficients = [0.0; 3];
+
+    for row in (0..3).rev() {
+        let sum: f64 = (row + 1..3).map(|k| matrix[row][k] * coefficients[k]).sum();
+        coefficients[row] = (matrix[row][3] - sum) / matrix[row][row];
+    }
+
+    coefficients
+}
+
+/// Scale of a coefficient to the normalized [0, 1] input and output.
+///
+/// With `vdr_rpu_normalized_idc` 0, the prediction maps BL codewords to VDR codewords,
+/// a coefficient applied to a term of `degree` BL values is scaled by `bl_max^degree / vdr_max`.
+/// The other values are normalized prediction, the coefficients are used as is.
+fn normalization_scale(header: &RpuDataHeader, degree: u32) -> f64 {
+    if header.is_normalized_prediction() {
+        return 1.0;
+    }
+
+    let bl_max = ((1_u64 << header.bl_bit_depth()) - 1) as f64;
+    let vdr_max = ((1_u64 << header.vdr_bit_depth()) - 1) as f64;
+
+    bl_max.powi(degree as i32) / vdr_max
+}
+
+/// Rescales the fixed point value `int + frac / 2^from` to a `to` bits fraction,
+/// rounding to the nearest value.
+fn requantize(int: &mut i64, frac: &mut u64, from: u64, to: u64) {
+    let fixed = ((*int as i128) << from) + *frac as i128;
+
+    let scaled = if to >= from {
+        fixed << (to - from)
+    } else {
+        let shift = from - to;
+        (fixed + (1 << (shift - 1))) >> shift
+    };
+
+    let one = 1_i128 << to;
+
+    *int = scaled.div_euclid(one) as i64;
+    *frac = scaled.rem_euclid(one) as u64;
+}
+
+fn requantize_unsigned(int: &mut u64, frac: &mut u64, from: u64, to: u64) {
+    let mut signed_int = *int as i64;
+    requantize(&mut signed_int, frac, from, to);
+
+    *int = signed_int as u64;
+}
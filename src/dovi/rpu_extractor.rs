@@ -1,22 +1,45 @@
 use std::path::PathBuf;
 
-use super::{input_format, io, Format, RpuOptions};
+use super::{input_format, io, Format, RpuOptions, RpuPacking};
 use indicatif::ProgressBar;
 
 use io::{DoviReader, DoviWriter};
 
+#[cfg(feature = "async")]
+use std::{future::poll_fn, io::Write, path::Path, pin::Pin};
+
+#[cfg(feature = "async")]
+use ansi_term::Colour::Red;
+#[cfg(feature = "async")]
+use futures_core::Stream;
+#[cfg(feature = "async")]
+use tokio::io::AsyncRead;
+
+#[cfg(feature = "async")]
+use super::async_extractor::AsyncRpuExtractor;
+
 pub struct RpuExtractor {
     format: Format,
     input: PathBuf,
     rpu_out: PathBuf,
+    packing: RpuPacking,
+    frame: Option<usize>,
 }
 
 impl RpuExtractor {
-    pub fn new(format: Format, input: PathBuf, rpu_out: PathBuf) -> Self {
+    pub fn new(
+        format: Format,
+        input: PathBuf,
+        rpu_out: PathBuf,
+        packing: RpuPacking,
+        frame: Option<usize>,
+    ) -> Self {
         Self {
             format,
             input,
             rpu_out,
+            packing,
+            frame,
         }
     }
 
@@ -24,15 +47,11 @@ impl RpuExtractor {
         input: Option<PathBuf>,
         stdin: Option<PathBuf>,
         rpu_out: Option<PathBuf>,
+        packing: RpuPacking,
+        frame: Option<usize>,
         options: RpuOptions,
     ) {
-        let input = match input {
-            Some(input) => input,
-            None => match stdin {
-                Some(stdin) => stdin,
-                None => PathBuf::new(),
-            },
-        };
+        let input = input_path(input, stdin);
 
         match input_format(&input) {
             Ok(format) => {
@@ -41,13 +60,59 @@ impl RpuExtractor {
                     None => PathBuf::from("RPU.bin"),
                 };
 
-                let parser = RpuExtractor::new(format, input, rpu_out);
+                let parser = RpuExtractor::new(format, input, rpu_out, packing, frame);
                 parser.process_input(options);
             }
             Err(msg) => println!("{}", msg),
         }
     }
 
+    /// Writes the RPUs of a raw HEVC input as they are read by `AsyncRpuExtractor`, in decoding order.
+    /// The RPUs are written as parsed, the edit options don't apply.
+    #[cfg(feature = "async")]
+    pub fn stream_rpus(
+        input: Option<PathBuf>,
+        stdin: Option<PathBuf>,
+        rpu_out: Option<PathBuf>,
+        packing: RpuPacking,
+        lenient: bool,
+    ) {
+        let input = input_path(input, stdin);
+        let rpu_out = match rpu_out {
+            Some(path) => path,
+            None => PathBuf::from("RPU.bin"),
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("Can't start the async runtime");
+
+        let result = match input_format(&input) {
+            Ok(Format::Raw) => runtime.block_on(async {
+                let file = tokio::fs::File::open(&input).await?;
+                write_streamed_rpus(file, &rpu_out, packing, lenient).await
+            }),
+            Ok(Format::RawStdin) => runtime.block_on(write_streamed_rpus(
+                tokio::io::stdin(),
+                &rpu_out,
+                packing,
+                lenient,
+            )),
+            Ok(format) => {
+                println!("Streaming is unsupported for {} input", format);
+                return;
+            }
+            Err(msg) => {
+                println!("{}", msg);
+                return;
+            }
+        };
+
+        if let Err(e) = result {
+            panic!("{}", e);
+        }
+    }
+
     fn process_input(&self, options: RpuOptions) {
         let pb = super::initialize_progress_bar(&self.format, &self.input);
 
@@ -59,7 +124,13 @@ impl RpuExtractor {
 
     fn extract_rpu_from_el(&self, pb: Option<&ProgressBar>, options: RpuOptions) {
         let mut dovi_reader = DoviReader::new(options);
+
+        if let Some(frame) = self.frame {
+            dovi_reader.set_frame(frame);
+        }
+
         let mut dovi_writer = DoviWriter::new(None, None, Some(&self.rpu_out), None);
+        dovi_writer.set_rpu_packing(self.packing);
 
         match dovi_reader.read_write_from_io(&self.format, &self.input, pb, &mut dovi_writer) {
             Ok(_) => (),
@@ -67,3 +138,38 @@ impl RpuExtractor {
         }
     }
 }
+
+fn input_path(input: Option<PathBuf>, stdin: Option<PathBuf>) -> PathBuf {
+    match input {
+        Some(input) => input,
+        None => match stdin {
+            Some(stdin) => stdin,
+            None => PathBuf::new(),
+        },
+    }
+}
+
+#[cfg(feature = "async")]
+async fn write_streamed_rpus<R: AsyncRead + Unpin>(
+    reader: R,
+    rpu_out: &Path,
+    packing: RpuPacking,
+    lenient: bool,
+) -> Result<(), std::io::Error> {
+    let mut writer = std::io::BufWriter::with_capacity(100_000, std::fs::File::create(rpu_out)?);
+    let mut extractor = AsyncRpuExtractor::new(reader, lenient);
+
+    while let Some(item) = poll_fn(|cx| Pin::new(&mut extractor).poll_next(cx)).await {
+        let (frame, rpu) = item?;
+
+        match rpu {
+            Ok(mut rpu) => packing.write(&mut writer, &rpu.write_rpu_data()[2..])?,
+            Err(e) => println!(
+                "{}",
+                Red.paint(format!("Skipping RPU of frame {}: {}", frame, e))
+            ),
+        }
+    }
+
+    writer.flush()
+}
@@ -1,6 +1,11 @@
-use std::path::PathBuf;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 
-use super::{parse_rpu_file, rpu::DoviRpu};
+use super::rpu::{
+    duplicate_runs, l2_target_counts, pq::pq_to_nits, rpu_stream::RpuStream,
+    vdr_dm_data::ExtBlockLevel, DoviRpu,
+};
+use super::{parse_rpu_file, unterminated_rpus};
 
 pub struct RpuInfo {
     input: PathBuf,
@@ -8,22 +13,356 @@ pub struct RpuInfo {
     rpus: Option<Vec<DoviRpu>>,
 }
 
+/// Additional info to print
+#[derive(Debug, Default)]
+pub struct InfoOptions {
+    pub interpolate_l2: Option<u16>,
+    pub mapping: bool,
+    pub coefficients: bool,
+    /// Number of points the mapping curves are sampled at
+    pub lut: Option<usize>,
+    pub l2_targets: bool,
+    pub duplicates: bool,
+    /// Bits consumed by the parser against the CRC32 position
+    pub bit_size: bool,
+    /// Mapping methods and pivot counts of each component, for the whole stream
+    pub mapping_summary: bool,
+    /// Frame width and height
+    pub aspect_ratio: Option<(u16, u16)>,
+    /// Ext block levels expected in every frame
+    pub missing_blocks: Vec<u8>,
+    /// Check the 0x80 terminator byte of every RPU
+    pub terminators: bool,
+}
+
 impl RpuInfo {
-    pub fn info(input: PathBuf, frame: Option<usize>) {
+    pub fn info(input: PathBuf, frame: Option<usize>, options: InfoOptions, lenient: bool) {
         let mut info = RpuInfo {
             input,
             frame,
             rpus: None,
         };
 
-        info.rpus = parse_rpu_file(&info.input);
+        // The RPUs lacking the terminator can't be parsed
+        if options.terminators && !check_terminators(&info.input) {
+            return;
+        }
+
+        info.rpus = parse_rpu_file(&info.input, lenient);
 
         if let Some(ref rpus) = info.rpus {
             if let Some(f) = info.frame {
-                assert!(f < rpus.len());
+                let stream = RpuStream::new(rpus);
+                let frame_rpu = match stream.get(f) {
+                    Some(frame_rpu) => frame_rpu,
+                    None => panic!("Frame {} out of range, {} RPUs", f, stream.len()),
+                };
 
                 println!("{:#?}", rpus[f]);
+
+                // The mapping is printed from the RPU carrying it
+                let mapping_rpu = if rpus[f].header.use_prev_vdr_rpu_flag {
+                    match frame_rpu.mapping {
+                        Some((index, mapping_rpu)) => {
+                            println!("Mapping of frame {}", index);
+                            mapping_rpu
+                        }
+                        None => {
+                            println!(
+                                "Mapping of prev_vdr_rpu_id {} not found",
+                                rpus[f].header.prev_vdr_rpu_id
+                            );
+                            frame_rpu.rpu
+                        }
+                    }
+                } else {
+                    frame_rpu.rpu
+                };
+
+                if let Some(compat_id) = rpus[f].profile8_compatibility_id() {
+                    let compatibility = match compat_id {
+                        1 => "HDR10",
+                        2 => "SDR",
+                        _ => "HLG",
+                    };
+                    println!(
+                        "Profile {} ({} compatible)",
+                        rpus[f].profile_name(),
+                        compatibility
+                    );
+                }
+
+                if rpus[f].has_nlq() {
+                    let el_type = if rpus[f].has_residual() {
+                        "FEL"
+                    } else if rpus[f].nlq_data.as_ref().is_some_and(|nlq| nlq.is_mel()) {
+                        "MEL"
+                    } else {
+                        "soft MEL"
+                    };
+                    println!("Profile 7 {}", el_type);
+                }
+
+                let header = &rpus[f].header;
+                let signal_bit_depth = match rpus[f].signal_bit_depth() {
+                    Some(bit_depth) => format!(", DM signal {}", bit_depth),
+                    None => String::new(),
+                };
+
+                println!(
+                    "Bit depths: BL {}, VDR {}{}",
+                    header.bl_bit_depth(),
+                    header.vdr_bit_depth(),
+                    signal_bit_depth
+                );
+
+                if let Some(ref vdr_dm_data) = rpus[f].vdr_dm_data {
+                    println!("DM signal format: {}", vdr_dm_data.signal_format());
+                }
+
+                if let Some(target_pq) = options.interpolate_l2 {
+                    if let Some(ref vdr_dm_data) = rpus[f].vdr_dm_data {
+                        println!("{:#?}", vdr_dm_data.interpolate_l2(target_pq));
+                    }
+                }
+
+                if options.mapping {
+                    if let Some(component_mappings) = mapping_rpu.component_mappings() {
+                        println!("{:#?}", component_mappings);
+                    }
+                }
+
+                if options.coefficients {
+                    println!(
+                        "coefficient_log2_denom: {}, vdr_rpu_normalized_idc: {}",
+                        mapping_rpu.header.coefficient_log2_denom,
+                        mapping_rpu.header.vdr_rpu_normalized_idc
+                    );
+                    println!("{:#?}", mapping_rpu.mapping_coefficients());
+                }
+
+                if let Some(points) = options.lut {
+                    match mapping_rpu.mapping_lut(points) {
+                        Ok(lut) => print_lut(&lut),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+
+                if options.bit_size {
+                    println!("Payload: {}", rpus[f].payload_bits_report());
+                }
+
+                if let Some((width, height)) = options.aspect_ratio {
+                    match rpus[f].active_area_aspect_ratio(width, height) {
+                        Ok(ratio) => println!("Active area aspect ratio: {:.2}:1", ratio),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+            } else if let Some((width, height)) = options.aspect_ratio {
+                print_aspect_ratio_ranges(rpus, width, height);
+            }
+
+            if options.bit_size && info.frame.is_none() {
+                print_payload_desyncs(rpus);
+            }
+
+            if options.mapping_summary {
+                print_mapping_summary(rpus);
             }
+
+            if !options.missing_blocks.is_empty() {
+                print_missing_blocks(rpus, &options.missing_blocks);
+            }
+
+            if options.l2_targets {
+                println!("L2 targets:");
+
+                for (target_max_pq, frames) in l2_target_counts(rpus) {
+                    println!(
+                        "  target_max_pq {} ({:.0} nits): {} frames",
+                        target_max_pq,
+                        pq_to_nits(target_max_pq),
+                        frames
+                    );
+                }
+            }
+
+            if options.duplicates {
+                let runs = duplicate_runs(rpus);
+
+                for (start, len) in &runs {
+                    println!(
+                        "Frames {}-{}: {} identical RPUs",
+                        start,
+                        start + len - 1,
+                        len
+                    );
+                }
+
+                let repeated: usize = runs.iter().map(|(_, len)| len - 1).sum();
+                println!(
+                    "{} runs, {} repeated RPUs out of {}",
+                    runs.len(),
+                    repeated,
+                    rpus.len()
+                );
+            }
+        }
+    }
+}
+
+/// Prints the RPUs with more than the alignment padding left before the CRC32
+fn print_payload_desyncs(rpus: &[DoviRpu]) {
+    let mut count = 0;
+
+    for (index, rpu) in rpus.iter().enumerate() {
+        if rpu.has_payload_desync() {
+            println!("Frame {}: {}", index, rpu.payload_bits_report());
+            count += 1;
+        }
+    }
+
+    println!(
+        "{} RPUs out of {} with bits left past the alignment padding",
+        count,
+        rpus.len()
+    );
+}
+
+/// Prints the sampled mapping curves as CSV, one line per input value
+fn print_lut(lut: &[[f64; 3]]) {
+    let last = (lut.len() - 1) as f64;
+
+    println!("input,y,cb,cr");
+
+    for (i, [y, cb, cr]) in lut.iter().enumerate() {
+        println!("{:.6},{:.6},{:.6},{:.6}", i as f64 / last, y, cb, cr);
+    }
+}
+
+/// Prints the share of polynomial and MMR mapped frames and the pivot counts, per component
+fn print_mapping_summary(rpus: &[DoviRpu]) {
+    let summary = RpuStream::new(rpus).mapping_summary();
+    let share = |count: usize, frames: usize| {
+        let percent = if frames > 0 {
+            count as f64 * 100.0 / frames as f64
+        } else {
+            0.0
+        };
+
+        format!("{} ({:.1}%)", count, percent)
+    };
+
+    println!(
+        "{:<10}{:>8}  {:<18}{:<18}{:<18}Pivots (count: frames)",
+        "Component", "Frames", "Polynomial", "MMR", "Mixed"
+    );
+
+    for (name, cmp) in ["Y", "Cb", "Cr"].iter().zip(&summary) {
+        let pivots: Vec<String> = cmp
+            .pivot_counts
+            .iter()
+            .map(|(pivots, frames)| format!("{}: {}", pivots, frames))
+            .collect();
+
+        println!(
+            "{:<10}{:>8}  {:<18}{:<18}{:<18}{}",
+            name,
+            cmp.frames,
+            share(cmp.polynomial, cmp.frames),
+            share(cmp.mmr, cmp.frames),
+            share(cmp.mixed, cmp.frames),
+            pivots.join(", ")
+        );
+    }
+}
+
+/// Lists the RPUs lacking the 0x80 terminator byte, returns whether all of them have it
+fn check_terminators(input: &Path) -> bool {
+    let data = match std::fs::read(input) {
+        Ok(data) => data,
+        Err(e) => panic!("Can't read {}: {}", input.display(), e),
+    };
+
+    let unterminated = unterminated_rpus(&data);
+
+    for (frame, error) in &unterminated {
+        match error {
+            Some(e) => println!("Frame {}: no 0x80 terminator, {}", frame, e),
+            None => println!("Frame {}: no 0x80 terminator, repairable", frame),
+        }
+    }
+
+    if unterminated.is_empty() {
+        println!("Every RPU ends with the 0x80 terminator");
+    } else {
+        println!(
+            "{} RPUs lacking the 0x80 terminator, --repair-terminator repairs them when converting or extracting",
+            unterminated.len()
+        );
+    }
+
+    unterminated.is_empty()
+}
+
+/// Prints the frames lacking each of the ext block levels, as ranges
+fn print_missing_blocks(rpus: &[DoviRpu], levels: &[u8]) {
+    let stream = RpuStream::new(rpus);
+
+    for &level in levels {
+        let ext_block_level = match ExtBlockLevel::try_from(level) {
+            Ok(ext_block_level) => ext_block_level,
+            Err(e) => panic!("{}", e),
+        };
+
+        let frames = stream.find_missing_blocks(&[ext_block_level]);
+
+        if frames.is_empty() {
+            println!("L{}: present in every frame", level);
+            continue;
+        }
+
+        let mut ranges = Vec::new();
+        let mut start = 0;
+
+        for end in 1..=frames.len() {
+            if end == frames.len() || frames[end] != frames[end - 1] + 1 {
+                ranges.push(if end - 1 == start {
+                    frames[start].to_string()
+                } else {
+                    format!("{}-{}", frames[start], frames[end - 1])
+                });
+
+                start = end;
+            }
+        }
+
+        println!(
+            "L{}: missing in {} frames out of {}: {}",
+            level,
+            frames.len(),
+            rpus.len(),
+            ranges.join(", ")
+        );
+    }
+}
+
+/// Prints the frame ranges sharing the same displayed aspect ratio
+fn print_aspect_ratio_ranges(rpus: &[DoviRpu], width: u16, height: u16) {
+    let ratios: Vec<String> = rpus
+        .iter()
+        .map(|rpu| match rpu.active_area_aspect_ratio(width, height) {
+            Ok(ratio) => format!("{:.2}:1", ratio),
+            Err(e) => e,
+        })
+        .collect();
+
+    let mut start = 0;
+
+    for end in 1..=ratios.len() {
+        if end == ratios.len() || ratios[end] != ratios[start] {
+            println!("Frames {}-{}: {}", start, end - 1, ratios[start]);
+            start = end;
         }
     }
 }
@@ -2,23 +2,78 @@ use std::fs::File;
 use std::io::{stdout, BufReader, BufWriter, Read, Write};
 use std::path::PathBuf;
 
-use crate::dovi::get_aud;
-
-use super::{input_format, parse_rpu_file, DoviRpu, Format, OUT_NAL_HEADER};
+use super::{
+    get_aud, input_format, parse_rpu_file, print_warnings, DoviRpu, Format, OUT_NAL_HEADER,
+};
 
 use hevc_parser::hevc::*;
 use hevc_parser::HevcParser;
 
+/// Handling of the AUD and parameter set NALs of the input
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum HeaderNals {
+    /// Copied from the input, in order
+    #[default]
+    Preserve,
+    /// An AUD generated for every access unit, replacing the input's.
+    /// IRAP frames without parameter sets get the latest VPS, SPS and PPS repeated.
+    Regenerate,
+}
+
+impl std::str::FromStr for HeaderNals {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(HeaderNals::Preserve),
+            "regenerate" => Ok(HeaderNals::Regenerate),
+            _ => Err(format!("Unknown header NALs handling: {}", s)),
+        }
+    }
+}
+
+/// Handling of an input and RPU file with different frame counts
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum FrameCountMismatch {
+    /// Nothing is written
+    #[default]
+    Error,
+    /// Only the frames having both a picture and an RPU are written.
+    /// The input is cut at the last access unit keeping the decoded frames complete.
+    Truncate,
+}
+
+impl std::str::FromStr for FrameCountMismatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(FrameCountMismatch::Error),
+            "truncate" => Ok(FrameCountMismatch::Truncate),
+            _ => Err(format!("Unknown frame count mismatch handling: {}", s)),
+        }
+    }
+}
+
 pub struct RpuInjector {
     input: PathBuf,
     rpu_in: PathBuf,
     output: PathBuf,
+    headers: HeaderNals,
+    mismatch: FrameCountMismatch,
 
     rpus: Option<Vec<DoviRpu>>,
 }
 
 impl RpuInjector {
-    pub fn inject_rpu(input: PathBuf, rpu_in: PathBuf, output: Option<PathBuf>) {
+    pub fn inject_rpu(
+        input: PathBuf,
+        rpu_in: PathBuf,
+        output: Option<PathBuf>,
+        headers: HeaderNals,
+        mismatch: FrameCountMismatch,
+        lenient: bool,
+    ) {
         match input_format(&input) {
             Ok(format) => {
                 if let Format::Raw = format {
@@ -27,16 +82,11 @@ impl RpuInjector {
                         None => PathBuf::from("injected_output.hevc"),
                     };
 
-                    let mut injector = RpuInjector::new(input, rpu_in, output);
-                    let mut parser = HevcParser::default();
+                    let mut injector = RpuInjector::new(input, rpu_in, output, lenient);
+                    injector.set_headers(headers);
+                    injector.set_mismatch(mismatch);
 
-                    injector.process_input(&mut parser, format);
-                    parser.finish();
-
-                    let frames = parser.ordered_frames();
-                    let nals = parser.get_nals();
-
-                    match injector.interleave_rpu_nals(&nals, frames) {
+                    match injector.inject() {
                         Ok(_) => (),
                         Err(e) => panic!("{}", e),
                     }
@@ -48,6 +98,31 @@ impl RpuInjector {
         }
     }
 
+    pub fn set_headers(&mut self, headers: HeaderNals) {
+        self.headers = headers;
+    }
+
+    pub fn set_mismatch(&mut self, mismatch: FrameCountMismatch) {
+        self.mismatch = mismatch;
+    }
+
+    /// Writes the output, with the RPUs interleaved after the last NAL of their frame
+    pub fn inject(&mut self) -> Result<(), String> {
+        let mut parser = HevcParser::default();
+
+        self.process_input(&mut parser, Format::Raw);
+        parser.finish();
+
+        let frames = parser.ordered_frames();
+        let nals = parser.get_nals();
+
+        let rpu_count = self.rpus.as_ref().map_or(0, Vec::len);
+        let frame_count = aligned_frame_count(frames, rpu_count, self.mismatch)?;
+
+        self.interleave_rpu_nals(nals, frames, frame_count)
+            .map_err(|e| e.to_string())
+    }
+
     fn process_input(&self, parser: &mut HevcParser, format: Format) {
         println!("Processing input video for frame order info...");
         stdout().flush().ok();
@@ -117,15 +192,17 @@ impl RpuInjector {
         pb.finish_and_clear();
     }
 
-    pub fn new(input: PathBuf, rpu_in: PathBuf, output: PathBuf) -> RpuInjector {
+    pub fn new(input: PathBuf, rpu_in: PathBuf, output: PathBuf, lenient: bool) -> RpuInjector {
         let mut injector = RpuInjector {
             input,
             rpu_in,
             output,
+            headers: HeaderNals::default(),
+            mismatch: FrameCountMismatch::default(),
             rpus: None,
         };
 
-        injector.rpus = parse_rpu_file(&injector.rpu_in);
+        injector.rpus = parse_rpu_file(&injector.rpu_in, lenient);
 
         injector
     }
@@ -134,11 +211,13 @@ impl RpuInjector {
         &mut self,
         nals: &[NALUnit],
         frames: &[Frame],
+        frame_count: usize,
     ) -> Result<(), std::io::Error> {
         if let Some(ref mut rpus) = self.rpus {
-            if frames.len() != rpus.len() {
-                panic!("Number of frames of input and RPU file are different!");
-            }
+            // Frames past the count aren't written
+            let truncated = frame_count < frames.len();
+            let frames = &frames[..frame_count];
+            rpus.truncate(frame_count);
 
             println!("Computing frame indices..");
             stdout().flush().ok();
@@ -176,9 +255,22 @@ impl RpuInjector {
 
             let mut nals_parsed = 0;
 
-            // AUDs
-            //let first_decoded_index = frames.iter().position(|f| f.decoded_number == 0).unwrap();
-            //writer.write_all(&get_aud(&frames[first_decoded_index]))?;
+            let regenerate = self.headers == HeaderNals::Regenerate;
+
+            // The access units are written in decoding order
+            let mut decoded_frames: Vec<&Frame> = frames.iter().collect();
+            decoded_frames.sort_by_key(|f| f.decoded_number);
+
+            // Latest VPS, SPS and PPS
+            let mut parameter_sets: [Option<Vec<u8>>; 3] = Default::default();
+            let mut access_unit = AccessUnit::default();
+            let mut generated_auds = 0;
+            let mut repeated_parameter_sets = 0;
+
+            if regenerate {
+                writer.write_all(&get_aud(decoded_frames[0]))?;
+                generated_auds += 1;
+            }
 
             while let Ok(n) = reader.read(&mut main_buf) {
                 let read_bytes = n;
@@ -212,13 +304,35 @@ impl RpuInjector {
                 let nals = parser.split_nals(&chunk, &offsets, last, true);
 
                 for (cur_index, nal) in nals.iter().enumerate() {
-                    // AUDs
-                    //if nal.nal_type == NAL_AUD {
-                    //    continue;
-                    //}
+                    let data = &chunk[nal.start..nal.end];
+
+                    match nal.nal_type {
+                        NAL_VPS | NAL_SPS | NAL_PPS => {
+                            parameter_sets[(nal.nal_type - NAL_VPS) as usize] = Some(data.to_vec());
+                            access_unit.has_parameter_sets = true;
+                        }
+                        // VCL NALs
+                        0..=31 if !access_unit.vcl_found => {
+                            access_unit.vcl_found = true;
+
+                            let is_irap = (NAL_BLA_W_LP..=NAL_IRAP_VCL23).contains(&nal.nal_type);
+
+                            if regenerate && is_irap && !access_unit.has_parameter_sets {
+                                for parameter_set in parameter_sets.iter().flatten() {
+                                    writer.write_all(OUT_NAL_HEADER)?;
+                                    writer.write_all(parameter_set)?;
+                                }
+
+                                repeated_parameter_sets += 1;
+                            }
+                        }
+                        _ => (),
+                    }
 
-                    writer.write_all(OUT_NAL_HEADER)?;
-                    writer.write_all(&chunk[nal.start..nal.end])?;
+                    if !(regenerate && nal.nal_type == NAL_AUD) {
+                        writer.write_all(OUT_NAL_HEADER)?;
+                        writer.write_all(data)?;
+                    }
 
                     let global_index = nals_parsed + cur_index;
 
@@ -234,15 +348,31 @@ impl RpuInjector {
                         writer.write_all(OUT_NAL_HEADER)?;
                         writer.write_all(&data)?;
 
-                        // AUDs
-                        //if rpu_index < rpus.len() - 1 {
-                        //    writer.write_all(&get_aud(&frames[rpu_index]))?;
-                        //}
+                        // The next access unit starts after the RPU
+                        access_unit = AccessUnit {
+                            decoded_index: access_unit.decoded_index + 1,
+                            ..Default::default()
+                        };
+
+                        if truncated && access_unit.decoded_index == frame_count {
+                            break;
+                        }
+
+                        if regenerate {
+                            if let Some(frame) = decoded_frames.get(access_unit.decoded_index) {
+                                writer.write_all(&get_aud(frame))?;
+                                generated_auds += 1;
+                            }
+                        }
                     }
                 }
 
                 nals_parsed += nals.len();
 
+                if truncated && access_unit.decoded_index == frame_count {
+                    break;
+                }
+
                 chunk.clear();
 
                 if !end.is_empty() {
@@ -262,12 +392,82 @@ impl RpuInjector {
             writer.flush()?;
 
             pb.finish_and_clear();
+
+            if regenerate {
+                println!(
+                    "Generated {} AUDs, repeated the parameter sets before {} IRAP frames",
+                    generated_auds, repeated_parameter_sets
+                );
+            }
         }
 
         Ok(())
     }
 }
 
+/// Number of frames injected, erroring on a mismatch unless truncating.
+/// When truncating the input, the frames kept are the longest run in decoding order
+/// whose display order is complete, so the output doesn't reference dropped frames.
+fn aligned_frame_count(
+    frames: &[Frame],
+    rpu_count: usize,
+    mismatch: FrameCountMismatch,
+) -> Result<usize, String> {
+    if frames.len() == rpu_count {
+        return Ok(rpu_count);
+    }
+
+    let first_unaligned = frames.len().min(rpu_count);
+    let message = format!(
+        "Input has {} frames and the RPU file {} RPUs, frame {} is the first without {}",
+        frames.len(),
+        rpu_count,
+        first_unaligned,
+        if rpu_count < frames.len() {
+            "an RPU"
+        } else {
+            "a picture"
+        }
+    );
+
+    if mismatch == FrameCountMismatch::Error {
+        return Err(message);
+    }
+
+    let mut decoded_frames: Vec<&Frame> = frames.iter().collect();
+    decoded_frames.sort_by_key(|f| f.decoded_number);
+
+    let mut max_presentation = 0;
+    let mut frame_count = 0;
+
+    for (index, frame) in decoded_frames.iter().take(first_unaligned).enumerate() {
+        max_presentation = max_presentation.max(frame.presentation_number as usize);
+
+        if max_presentation == index {
+            frame_count = index + 1;
+        }
+    }
+
+    if frame_count == 0 {
+        return Err(format!("{}, no frame can be kept", message));
+    }
+
+    print_warnings(std::iter::once(&format!(
+        "{}, truncated to {} frames",
+        message, frame_count
+    )));
+
+    Ok(frame_count)
+}
+
+/// State of the access unit being written
+#[derive(Default)]
+struct AccessUnit {
+    decoded_index: usize,
+    has_parameter_sets: bool,
+    vcl_found: bool,
+}
+
 fn find_last_slice_nal_index(nals: &[NALUnit], frame: &Frame) -> usize {
     let slice_nals = frame.nals.iter().enumerate().filter(|(_idx, nal)| {
         matches!(
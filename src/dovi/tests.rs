@@ -0,0 +1,2184 @@
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use hevc_parser::hevc::{
+    NAL_AUD, NAL_IDR_W_RADL, NAL_PPS, NAL_SPS, NAL_TRAIL_R, NAL_UNSPEC62, NAL_VPS,
+};
+use hevc_parser::HevcParser;
+
+use super::batch::{Batch, BatchAction};
+use super::concatenator::concat_rpus;
+use super::editor::Editor;
+use super::exporter::{measurements_text, ExportKind, Exporter};
+use super::io::{DoviReader, DoviWriter, ProcessingSummary};
+use super::mp4_injector::{parse_boxes, Mp4Box, Mp4Injector};
+use super::rpu::{
+    add_start_code_emulation_prevention_3_byte, parse_dovi_rpu, rpu_changes,
+    vdr_dm_data::ExtMetadataBlockLevel5, DoviProfile, DoviRpu,
+};
+use super::rpu_injector::{FrameCountMismatch, HeaderNals, RpuInjector};
+use super::timecode::Timecode;
+use super::ts::{DoviConfig, TsReader};
+use super::BitVecWriter;
+use super::{
+    input_format, iter_rpus, nal_unit_type, par_parse_rpu_nals, parse_hex_lines, parse_rpu_file,
+    split_nals, split_rpu_file, unterminated_rpus, write_rpu_file, Format, RpuOptions, RpuPacking,
+    OUT_NAL_HEADER,
+};
+
+fn _read_asset(name: &str) -> Vec<u8> {
+    let mut f = File::open(PathBuf::from("./assets").join(name)).unwrap();
+    let mut data = Vec::new();
+    f.read_to_end(&mut data).unwrap();
+
+    data
+}
+
+fn _demux_el(input: &Path, el_out: &Path, mode: u8) -> ProcessingSummary {
+    let options = RpuOptions {
+        mode: Some(mode),
+        ..Default::default()
+    };
+
+    let mut dovi_reader = DoviReader::new(options);
+    let mut dovi_writer = DoviWriter::new(None, Some(el_out), None, None);
+
+    dovi_reader
+        .read_write_from_io(&Format::Raw, input, None, &mut dovi_writer)
+        .unwrap()
+}
+
+#[test]
+fn corrupted_rpu_in_stream() {
+    let first = _read_asset("fel_orig.bin");
+    let last = _read_asset("fel_rpu.bin");
+
+    let mut corrupted = _read_asset("mel_orig.bin");
+    let middle = corrupted.len() / 2;
+    corrupted[middle] ^= 0xFF;
+
+    assert!(parse_dovi_rpu(&corrupted, false).is_err());
+
+    let mut stream = Vec::new();
+    for rpu in &[&first, &corrupted, &last] {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(rpu);
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_corrupted_rpu.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_corrupted_rpu_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let summary = _demux_el(&input, &el_out, 2);
+
+    assert_eq!(summary.frames, 3);
+    assert_eq!(summary.converted, 2);
+    assert_eq!(summary.invalid, 1);
+
+    // The valid RPUs are converted, the corrupted one is copied untouched
+    let mut expected = Vec::new();
+    for rpu in &[&first, &corrupted, &last] {
+        expected.extend_from_slice(OUT_NAL_HEADER);
+
+        match parse_dovi_rpu(rpu, false) {
+            Ok(mut dovi_rpu) => {
+                dovi_rpu.convert_with_mode(2);
+                expected.extend_from_slice(&dovi_rpu.write_rpu_data());
+            }
+            Err(_) => expected.extend_from_slice(rpu),
+        }
+    }
+
+    let output = fs::read(&el_out).unwrap();
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&el_out).ok();
+
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn skip_leading_bytes() {
+    let mut stream = Vec::new();
+    for asset in &["fel_orig.bin", "mel_orig.bin"] {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&_read_asset(asset));
+    }
+
+    // Capture header, containing a start code
+    let mut prefixed = vec![0xFF, 0, 0, 1, 0x7C, 0x01, 0xAB];
+    prefixed.extend_from_slice(&stream);
+
+    let input = std::env::temp_dir().join("dovi_tool_skip_bytes.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_skip_bytes_EL.hevc");
+    fs::write(&input, &prefixed).unwrap();
+
+    let options = RpuOptions {
+        skip_bytes: 7,
+        ..Default::default()
+    };
+
+    let mut dovi_reader = DoviReader::new(options);
+    let mut dovi_writer = DoviWriter::new(None, Some(&el_out), None, None);
+
+    let summary = dovi_reader
+        .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+        .unwrap();
+    drop(dovi_writer);
+
+    let output = fs::read(&el_out).unwrap();
+
+    // Skipping past the end of the input
+    let options = RpuOptions {
+        skip_bytes: prefixed.len() + 1,
+        ..Default::default()
+    };
+
+    let mut dovi_reader = DoviReader::new(options);
+    let mut dovi_writer = DoviWriter::new(None, Some(&el_out), None, None);
+
+    let result = dovi_reader.read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer);
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&el_out).ok();
+
+    assert_eq!(summary.frames, 2);
+    assert_eq!(output, stream);
+    assert!(result.is_err());
+}
+
+#[test]
+fn rpu_spanning_chunks() {
+    // TRAIL_R slice NAL header, dummy payload
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34];
+    let el_slice = [0x7E, 0x01, 0x02, 0x01, 0xAF, 0x56, 0x78];
+
+    let mut stream = Vec::new();
+    for asset in &["fel_orig.bin", "fel_orig.bin", "mel_orig.bin"] {
+        for nal in [&slice[..], &el_slice, &_read_asset(asset)] {
+            stream.extend_from_slice(OUT_NAL_HEADER);
+            stream.extend_from_slice(nal);
+        }
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_chunks.hevc");
+    let bl_out = std::env::temp_dir().join("dovi_tool_chunks_BL.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_chunks_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let mut outputs = Vec::new();
+
+    // The RPUs span dozens of chunks with the smallest sizes, start codes are split too
+    for chunk_size in [100_000, 1, 2, 3, 5, 64, 250] {
+        let options = RpuOptions {
+            mode: Some(0),
+            ..Default::default()
+        };
+
+        let mut dovi_reader = DoviReader::new(options);
+        dovi_reader.set_chunk_size(chunk_size);
+
+        let mut dovi_writer = DoviWriter::new(Some(&bl_out), Some(&el_out), None, None);
+
+        let summary = dovi_reader
+            .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+            .unwrap();
+        drop(dovi_writer);
+
+        assert_eq!(summary.frames, 3, "chunk size {}", chunk_size);
+        assert_eq!(summary.converted, 3, "chunk size {}", chunk_size);
+        assert_eq!(summary.invalid, 0, "chunk size {}", chunk_size);
+
+        outputs.push((
+            chunk_size,
+            fs::read(&bl_out).unwrap(),
+            fs::read(&el_out).unwrap(),
+        ));
+    }
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&bl_out).ok();
+    fs::remove_file(&el_out).ok();
+
+    let (_, bl, el) = &outputs[0];
+    assert_eq!(bl.len(), 3 * (OUT_NAL_HEADER.len() + slice.len()));
+
+    for (chunk_size, other_bl, other_el) in &outputs[1..] {
+        assert_eq!(other_bl, bl, "chunk size {}", chunk_size);
+        assert_eq!(other_el, el, "chunk size {}", chunk_size);
+    }
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped_input_identical() {
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34, 0x56, 0x78, 0x9A];
+    let el_slice = [0x7E, 0x01, 0x02, 0x01, 0xAF, 0x56, 0x78];
+
+    // Capture header, then frames with runs of BL NALs spanning windows
+    let mut stream = vec![0xFF, 0, 0, 1, 0x7C, 0x01, 0xAB];
+    for asset in &["fel_orig.bin", "mel_orig.bin", "fel_orig.bin"] {
+        for _ in 0..6 {
+            stream.extend_from_slice(OUT_NAL_HEADER);
+            stream.extend_from_slice(&slice);
+        }
+
+        for nal in [&el_slice[..], &_read_asset(asset)] {
+            stream.extend_from_slice(OUT_NAL_HEADER);
+            stream.extend_from_slice(nal);
+        }
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_mapped.hevc");
+    let bl_out = std::env::temp_dir().join("dovi_tool_mapped_BL.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_mapped_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let demux = |mapped: bool, chunk_size: usize| {
+        let options = RpuOptions {
+            mode: Some(2),
+            skip_bytes: 7,
+            ..Default::default()
+        };
+
+        let mut dovi_reader = DoviReader::new(options);
+        dovi_reader.set_mapped(mapped);
+        dovi_reader.set_chunk_size(chunk_size);
+
+        let mut dovi_writer = DoviWriter::new(Some(&bl_out), Some(&el_out), None, None);
+
+        let summary = dovi_reader
+            .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+            .unwrap();
+        drop(dovi_writer);
+
+        assert_eq!(summary.frames, 3, "chunk size {}", chunk_size);
+        assert_eq!(summary.converted, 3, "chunk size {}", chunk_size);
+
+        (fs::read(&bl_out).unwrap(), fs::read(&el_out).unwrap())
+    };
+
+    let (bl, el) = demux(false, 100_000);
+    assert_eq!(bl.len(), 3 * 6 * (OUT_NAL_HEADER.len() + slice.len()));
+
+    // The RPUs and the BL runs span several windows with the small sizes
+    for chunk_size in [100_000, 1, 7, 64, 250] {
+        assert_eq!(
+            demux(true, chunk_size),
+            (bl.clone(), el.clone()),
+            "chunk size {}",
+            chunk_size
+        );
+    }
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&bl_out).ok();
+    fs::remove_file(&el_out).ok();
+}
+
+#[test]
+fn repair_rpu_terminators() {
+    // TRAIL_R slice NAL header, dummy payload
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34];
+    let el_slice = [0x7E, 0x01, 0x02, 0x01, 0xAF, 0x56, 0x78];
+
+    let fel = _read_asset("fel_orig.bin");
+    let unterminated = &fel[..fel.len() - 1];
+
+    let mut stream = Vec::new();
+    for rpu in [unterminated, &fel] {
+        for nal in [&slice[..], &el_slice, rpu] {
+            stream.extend_from_slice(OUT_NAL_HEADER);
+            stream.extend_from_slice(nal);
+        }
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_terminators.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_terminators_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    // Only the unterminated RPU is reported
+    let mut rpu_file = Vec::new();
+    for rpu in [unterminated, &fel, unterminated] {
+        rpu_file.extend_from_slice(OUT_NAL_HEADER);
+        rpu_file.extend_from_slice(rpu);
+    }
+    assert_eq!(unterminated_rpus(&rpu_file), vec![(0, None), (2, None)]);
+
+    for repair_terminator in [false, true] {
+        let options = RpuOptions {
+            mode: Some(0),
+            repair_terminator,
+            ..Default::default()
+        };
+
+        let mut dovi_reader = DoviReader::new(options);
+        let mut dovi_writer = DoviWriter::new(None, Some(&el_out), None, None);
+
+        let summary = dovi_reader
+            .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+            .unwrap();
+        drop(dovi_writer);
+
+        let el = fs::read(&el_out).unwrap();
+        let terminated = el
+            .windows(fel.len())
+            .filter(|window| *window == fel)
+            .count();
+
+        if repair_terminator {
+            // Written back with the terminator
+            assert_eq!(
+                (summary.converted, summary.invalid, summary.repaired),
+                (2, 0, 1)
+            );
+            assert_eq!(terminated, 2);
+        } else {
+            // Copied untouched
+            assert_eq!(
+                (summary.converted, summary.invalid, summary.repaired),
+                (1, 1, 0)
+            );
+            assert_eq!(terminated, 1);
+        }
+    }
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&el_out).ok();
+}
+
+fn _corpus_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            _corpus_files(&path, files);
+        } else if let Some("rpu") | Some("bin") = path.extension().and_then(|ext| ext.to_str()) {
+            files.push(path);
+        }
+    }
+}
+
+/// Round trips every RPU file of the directory set in `DOVI_RPU_CORPUS`.
+/// Skipped when the variable isn't set.
+#[test]
+fn regression_corpus() {
+    let corpus = match std::env::var_os("DOVI_RPU_CORPUS") {
+        Some(corpus) => PathBuf::from(corpus),
+        None => return,
+    };
+
+    let mut files = Vec::new();
+    _corpus_files(&corpus, &mut files);
+    files.sort();
+
+    let mut failures = 0;
+
+    for file in &files {
+        let data = fs::read(file).unwrap();
+
+        for (index, nal) in split_rpu_file(&data).iter().enumerate() {
+            let result = std::panic::catch_unwind(|| {
+                let mut dovi_rpu = parse_dovi_rpu(nal, true)?;
+                dovi_rpu.convert_with_mode(0);
+
+                Ok(dovi_rpu.write_rpu_data())
+            });
+
+            let error = match result {
+                Ok(Ok(written)) if written[2..] == nal[2..] => continue,
+                Ok(Ok(_)) => "written RPU differs".to_string(),
+                Ok(Err(e)) => e,
+                Err(_) => "panicked".to_string(),
+            };
+
+            println!("{}, frame {}: {}", file.display(), index, error);
+            failures += 1;
+        }
+    }
+
+    assert_eq!(failures, 0, "{} RPUs failed to round trip", failures);
+}
+
+#[test]
+fn profile5_demux() {
+    let rpu = _read_asset("profile5.bin");
+
+    // TRAIL_R slice NAL header, dummy payload
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34];
+
+    let mut stream = Vec::new();
+    for _ in 0..3 {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&slice);
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&rpu);
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_profile5.hevc");
+    let bl_out = std::env::temp_dir().join("dovi_tool_profile5_BL.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_profile5_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let options = RpuOptions::default();
+
+    let mut dovi_reader = DoviReader::new(options);
+    let mut dovi_writer = DoviWriter::new(Some(&bl_out), Some(&el_out), None, None);
+
+    dovi_reader
+        .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+        .unwrap();
+
+    let bl = fs::read(&bl_out).unwrap();
+    let el = fs::read(&el_out).unwrap();
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&bl_out).ok();
+    fs::remove_file(&el_out).ok();
+
+    // Single layer: BL untouched, no EL
+    assert_eq!(bl, stream);
+    assert!(el.is_empty());
+}
+
+#[test]
+fn interleaved_sei() {
+    let rpu = _read_asset("fel_orig.bin");
+
+    // TRAIL_R slice NAL header, dummy payload
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34];
+    let el_slice = [0x02, 0x01, 0xAF, 0x56, 0x78];
+
+    // Prefix and suffix SEI NAL headers, dummy payloads
+    let prefix_sei = [0x4E, 0x01, 0x89, 0x02, 0x11, 0x80];
+    let suffix_sei = [0x50, 0x01, 0x84, 0x01, 0x22, 0x80];
+
+    let mut stream = Vec::new();
+    let mut expected_bl = Vec::new();
+    let mut expected_el = Vec::new();
+    let mut expected_sei = Vec::new();
+
+    for _ in 0..3 {
+        for nal in [&prefix_sei[..], &slice, &suffix_sei] {
+            expected_bl.extend_from_slice(OUT_NAL_HEADER);
+            expected_bl.extend_from_slice(nal);
+        }
+
+        for nal in [&prefix_sei[..], &suffix_sei] {
+            expected_sei.extend_from_slice(OUT_NAL_HEADER);
+            expected_sei.extend_from_slice(nal);
+        }
+
+        expected_el.extend_from_slice(OUT_NAL_HEADER);
+        expected_el.extend_from_slice(&el_slice);
+        expected_el.extend_from_slice(OUT_NAL_HEADER);
+        expected_el.extend_from_slice(&rpu);
+
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&prefix_sei);
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&slice);
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&[0x7E, 0x01]);
+        stream.extend_from_slice(&el_slice);
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&rpu);
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&suffix_sei);
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_sei.hevc");
+    let bl_out = std::env::temp_dir().join("dovi_tool_sei_BL.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_sei_EL.hevc");
+    let sei_out = std::env::temp_dir().join("dovi_tool_sei_SEI.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let mut dovi_reader = DoviReader::new(RpuOptions::default());
+    let mut dovi_writer = DoviWriter::new(Some(&bl_out), Some(&el_out), None, None);
+    dovi_writer.set_sei_out(&sei_out).unwrap();
+
+    dovi_reader
+        .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+        .unwrap();
+
+    let bl = fs::read(&bl_out).unwrap();
+    let el = fs::read(&el_out).unwrap();
+    let sei = fs::read(&sei_out).unwrap();
+
+    for path in [&input, &bl_out, &el_out, &sei_out] {
+        fs::remove_file(path).ok();
+    }
+
+    // The SEI NALs stay in the BL, copied to the SEI output
+    assert_eq!(bl, expected_bl);
+    assert_eq!(el, expected_el);
+    assert_eq!(sei, expected_sei);
+}
+
+#[test]
+fn rpu_packings() {
+    let rpu = _read_asset("fel_orig.bin");
+    let payload = &rpu[2..];
+
+    let packed = |packing: RpuPacking| {
+        let mut data = Vec::new();
+        packing.write(&mut data, payload).unwrap();
+
+        data
+    };
+
+    let annexb = packed(RpuPacking::AnnexB);
+    assert_eq!(annexb.len(), 4 + payload.len());
+    assert_eq!(&annexb[..4], OUT_NAL_HEADER);
+    assert_eq!(&annexb[4..], payload);
+
+    let nal = packed(RpuPacking::Nal);
+    assert_eq!(nal.len(), 4 + rpu.len());
+    assert_eq!(&nal[..6], &[0, 0, 0, 1, 0x7C, 0x01]);
+    assert_eq!(&nal[4..], &rpu[..]);
+
+    assert_eq!(packed(RpuPacking::Raw), payload);
+
+    // The RPU files can be read back
+    for packing in [RpuPacking::AnnexB, RpuPacking::Nal] {
+        let data = [packed(packing), packed(packing)].concat();
+        let nals = split_rpu_file(&data);
+
+        assert_eq!(nals.len(), 2);
+        assert!(nals.iter().all(|nal| nal[2..] == *payload));
+    }
+
+    assert_eq!("nal".parse::<RpuPacking>(), Ok(RpuPacking::Nal));
+
+    // Named by their use
+    let x265 = packed("x265".parse().unwrap());
+    assert_eq!(&x265[..6], &[0, 0, 0, 1, rpu[2], rpu[3]]);
+    assert_eq!(x265, annexb);
+
+    let standalone = packed("standalone".parse().unwrap());
+    assert_eq!(&standalone[..6], &[0, 0, 0, 1, 0x7C, 0x01]);
+    assert_eq!(standalone, nal);
+
+    assert!("hevc".parse::<RpuPacking>().is_err());
+}
+
+#[test]
+fn extract_single_frame() {
+    // TRAIL_R slice NAL header, dummy payload
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34];
+    let rpus = [
+        _read_asset("fel_orig.bin"),
+        _read_asset("mel_orig.bin"),
+        _read_asset("profile8.bin"),
+    ];
+
+    let mut stream = Vec::new();
+    for rpu in &rpus {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&slice);
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(rpu);
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_frame.hevc");
+    let rpu_out = std::env::temp_dir().join("dovi_tool_frame_RPU.bin");
+    fs::write(&input, &stream).unwrap();
+
+    let extract = |frame: usize| {
+        let mut dovi_reader = DoviReader::new(RpuOptions::default());
+        dovi_reader.set_chunk_size(64);
+        dovi_reader.set_frame(frame);
+
+        let mut dovi_writer = DoviWriter::new(None, None, Some(&rpu_out), None);
+
+        dovi_reader
+            .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+            .map(|summary| (summary, fs::read(&rpu_out).unwrap()))
+    };
+
+    let (summary, output) = extract(1).unwrap();
+    let out_of_range = extract(3);
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&rpu_out).ok();
+
+    // Stopped after the frame's RPU
+    assert_eq!(summary.frames, 2);
+    assert_eq!(output, [OUT_NAL_HEADER, &rpus[1][2..]].concat());
+
+    let error = out_of_range.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(error.to_string(), "Frame 3 out of range, 3 RPUs");
+}
+
+#[test]
+fn timecodes() {
+    let tc = Timecode::new(24.0, false).unwrap();
+    assert_eq!(tc.format(0), "00:00:00:00");
+    assert_eq!(tc.format(25), "00:00:01:01");
+
+    // Non-drop, counts 24 frames per timecode second
+    let tc = Timecode::new(23.976, false).unwrap();
+    assert_eq!(tc.format(86400), "01:00:00:00");
+
+    let tc = Timecode::new(29.97, true).unwrap();
+    assert_eq!(tc.format(1799), "00:00:59;29");
+    assert_eq!(tc.format(1800), "00:01:00;02");
+    assert_eq!(tc.format(17982), "00:10:00;00");
+    assert_eq!(tc.format(107892), "01:00:00;00");
+
+    let tc = Timecode::new(59.94, true).unwrap();
+    assert_eq!(tc.format(3600), "00:01:00;04");
+
+    assert!(Timecode::new(23.976, true).is_err());
+    assert!(Timecode::new(25.0, true).is_err());
+}
+
+fn _to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+#[test]
+fn hex_lines_input() {
+    let text = format!(
+        "{}\n\n{}\n",
+        _to_hex(&_read_asset("profile8.bin")),
+        _to_hex(&_read_asset("fel_orig.bin"))
+    );
+
+    let nals = parse_hex_lines(&text).unwrap();
+    assert_eq!(nals.len(), 2);
+    assert_eq!(nals[0], _read_asset("profile8.bin"));
+
+    let input = std::env::temp_dir().join("dovi_tool_hex_lines.txt");
+    fs::write(&input, &text).unwrap();
+
+    let rpus = parse_rpu_file(&input, false).unwrap();
+    fs::remove_file(&input).ok();
+
+    assert_eq!(rpus.len(), 2);
+    assert_eq!(rpus[0].dovi_profile, 8);
+    assert_eq!(rpus[1].dovi_profile, 7);
+
+    assert!(parse_hex_lines("7C0").is_err());
+    assert!(parse_hex_lines("7C0G").is_err());
+}
+
+#[test]
+fn standalone_el() {
+    let rpu = _read_asset("fel_orig.bin");
+
+    // TRAIL_R slice NAL header, dummy payload
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34];
+
+    // EL VPS, SPS, PPS and slice, dummy payloads
+    let el_nals: [&[u8]; 4] = [
+        &[0x40, 0x01, 0x0C],
+        &[0x42, 0x01, 0x01],
+        &[0x44, 0x01, 0xC1],
+        &[0x02, 0x01, 0xAF, 0x56, 0x78],
+    ];
+
+    let mut stream = Vec::new();
+    let mut expected_el = Vec::new();
+
+    for _ in 0..3 {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&slice);
+
+        for el_nal in &el_nals {
+            stream.extend_from_slice(OUT_NAL_HEADER);
+            stream.extend_from_slice(&[0x7E, 0x01]);
+            stream.extend_from_slice(el_nal);
+
+            expected_el.extend_from_slice(OUT_NAL_HEADER);
+            expected_el.extend_from_slice(el_nal);
+        }
+
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&rpu);
+
+        expected_el.extend_from_slice(OUT_NAL_HEADER);
+        expected_el.extend_from_slice(&rpu);
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_standalone_el.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_standalone_el_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let mut dovi_reader = DoviReader::new(RpuOptions::default());
+    let mut dovi_writer = DoviWriter::new(None, Some(&el_out), None, None);
+
+    dovi_reader
+        .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+        .unwrap();
+
+    let el = fs::read(&el_out).unwrap();
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&el_out).ok();
+
+    // EL NALs without the 0x7E01 header, the RPUs untouched
+    assert_eq!(el, expected_el);
+}
+
+/// Packetizes a PES packet or PSI section, stuffing the last packet with an adaptation field
+fn _ts_packets(pid: u16, payload: &[u8], continuity_counter: &mut u8) -> Vec<u8> {
+    let mut packets = Vec::new();
+
+    for (i, chunk) in payload.chunks(184).enumerate() {
+        let payload_unit_start = if i == 0 { 0x40 } else { 0 };
+
+        packets.extend_from_slice(&[0x47, payload_unit_start | (pid >> 8) as u8, pid as u8]);
+
+        let stuffing = 184 - chunk.len();
+
+        if stuffing > 0 {
+            packets.extend_from_slice(&[0x30 | *continuity_counter, (stuffing - 1) as u8]);
+
+            if stuffing > 1 {
+                packets.push(0);
+                packets.resize(packets.len() + stuffing - 2, 0xFF);
+            }
+        } else {
+            packets.push(0x10 | *continuity_counter);
+        }
+
+        packets.extend_from_slice(chunk);
+        *continuity_counter = (*continuity_counter + 1) & 0xF;
+    }
+
+    packets
+}
+
+/// PSI section with a pointer_field and a dummy CRC32
+fn _psi_section(table_id: u8, header: &[u8], data: &[u8]) -> Vec<u8> {
+    let section_length = header.len() + data.len() + 4;
+
+    let mut section = vec![
+        0,
+        table_id,
+        0xB0 | (section_length >> 8) as u8,
+        section_length as u8,
+    ];
+    section.extend_from_slice(header);
+    section.extend_from_slice(data);
+    section.extend_from_slice(&[0; 4]);
+
+    section
+}
+
+#[test]
+fn transport_stream_input() {
+    // TRAIL_R slice NAL header, dummy payload
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34];
+
+    let programs = [
+        (0x100, 0x101, "fel_orig.bin"),
+        (0x200, 0x201, "profile8.bin"),
+    ];
+
+    let pat = _psi_section(
+        0,
+        &[0x00, 0x01, 0xC1, 0x00, 0x00],
+        &[0x00, 0x01, 0xE1, 0x00, 0x00, 0x02, 0xE2, 0x00],
+    );
+
+    let mut counters = [0_u8; 5];
+    let mut stream = _ts_packets(0, &pat, &mut counters[0]);
+
+    // DOVI_video_stream_descriptor of the first stream: profile 8, level 6, BL+RPU, compatibility id 1
+    let dovi_descriptor = [0xB0, 0x05, 0x01, 0x00, 0x10, 0x35, 0x10];
+
+    for (i, (pmt_pid, hevc_pid, _)) in programs.iter().enumerate() {
+        let pid_bytes = [0xE0 | (hevc_pid >> 8) as u8, *hevc_pid as u8];
+
+        let mut es_info = vec![0x24, pid_bytes[0], pid_bytes[1], 0xF0, 0x00];
+
+        if i == 0 {
+            es_info[4] = dovi_descriptor.len() as u8;
+            es_info.extend_from_slice(&dovi_descriptor);
+        }
+
+        let pmt = _psi_section(
+            2,
+            &[
+                0x00,
+                i as u8 + 1,
+                0xC1,
+                0x00,
+                0x00,
+                pid_bytes[0],
+                pid_bytes[1],
+                0xF0,
+                0x00,
+            ],
+            &es_info,
+        );
+
+        stream.extend(_ts_packets(*pmt_pid, &pmt, &mut counters[1 + i]));
+    }
+
+    let mut expected_els = vec![Vec::new(), Vec::new()];
+
+    // One PES packet per access unit, the programs interleaved
+    for _ in 0..3 {
+        for (i, (_, hevc_pid, asset)) in programs.iter().enumerate() {
+            let rpu = _read_asset(asset);
+
+            let mut pes = vec![
+                0, 0, 1, 0xE0, 0, 0, 0x80, 0x80, 0x05, 0x21, 0, 0x01, 0, 0x01,
+            ];
+            pes.extend_from_slice(OUT_NAL_HEADER);
+            pes.extend_from_slice(&slice);
+            pes.extend_from_slice(OUT_NAL_HEADER);
+            pes.extend_from_slice(&rpu);
+
+            stream.extend(_ts_packets(*hevc_pid, &pes, &mut counters[3 + i]));
+
+            expected_els[i].extend_from_slice(OUT_NAL_HEADER);
+            expected_els[i].extend_from_slice(&rpu);
+        }
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_transport_stream.ts");
+    let el_out = std::env::temp_dir().join("dovi_tool_transport_stream_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    // First HEVC stream by default, then the second by PID
+    for (pid, expected_el) in [None, Some(0x201)].iter().zip(&expected_els) {
+        let options = RpuOptions {
+            pid: *pid,
+            ..Default::default()
+        };
+
+        let mut dovi_reader = DoviReader::new(options);
+        let mut dovi_writer = DoviWriter::new(None, Some(&el_out), None, None);
+
+        let summary = dovi_reader
+            .read_write_from_io(&Format::TransportStream, &input, None, &mut dovi_writer)
+            .unwrap();
+
+        assert_eq!(summary.frames, 3);
+        assert_eq!(&fs::read(&el_out).unwrap(), expected_el);
+    }
+
+    // The configuration of the first stream, claiming profile 8 for profile 7 RPUs
+    let mut ts_reader = TsReader::new(File::open(&input).unwrap(), None);
+    ts_reader.read_to_end(&mut Vec::new()).unwrap();
+
+    let config = ts_reader.dovi_config().get().unwrap();
+    assert_eq!(
+        config,
+        DoviConfig {
+            profile: 8,
+            level: 6,
+            rpu_present: true,
+            el_present: false,
+            bl_present: true,
+            bl_compat_id: Some(1),
+        }
+    );
+
+    assert_eq!(config.dovi_profile(), DoviProfile::Profile81);
+    assert_eq!(
+        config.to_string(),
+        "profile 8.1, level 6, dv_bl_signal_compatibility_id 1, BL+RPU"
+    );
+
+    assert!(config.check_rpu_profile(8, Some(1)).is_none());
+    assert!(config.check_rpu_profile(8, None).is_none());
+    assert_eq!(
+        config.check_rpu_profile(7, None).unwrap(),
+        "Dolby Vision configuration claims profile 8, the RPUs are profile 7"
+    );
+    assert_eq!(
+        config.check_rpu_profile(8, Some(4)).unwrap(),
+        "Dolby Vision configuration claims profile 8.1, the RPUs are profile 8.4"
+    );
+
+    let mut ts_reader = TsReader::new(File::open(&input).unwrap(), Some(0x201));
+    ts_reader.read_to_end(&mut Vec::new()).unwrap();
+    assert!(ts_reader.dovi_config().get().is_none());
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&el_out).ok();
+}
+
+#[test]
+fn duplicate_ts_packets() {
+    let pat = _psi_section(
+        0,
+        &[0x00, 0x01, 0xC1, 0x00, 0x00],
+        &[0x00, 0x01, 0xE1, 0x00],
+    );
+    let pmt = _psi_section(
+        2,
+        &[0x00, 0x01, 0xC1, 0x00, 0x00, 0xE1, 0x01, 0xF0, 0x00],
+        &[0x24, 0xE1, 0x01, 0xF0, 0x00],
+    );
+
+    let mut counters = [0_u8; 3];
+    let mut stream = _ts_packets(0, &pat, &mut counters[0]);
+    stream.extend(_ts_packets(0x100, &pmt, &mut counters[1]));
+
+    let mut expected = Vec::new();
+
+    for asset in &["fel_orig.bin", "mel_orig.bin"] {
+        let mut es = OUT_NAL_HEADER.to_vec();
+        es.extend_from_slice(&_read_asset(asset));
+
+        let mut pes = vec![0, 0, 1, 0xE0, 0, 0, 0x80, 0x00, 0x00];
+        pes.extend_from_slice(&es);
+        expected.extend_from_slice(&es);
+
+        // Every packet sent twice, with the same continuity_counter
+        for packet in _ts_packets(0x101, &pes, &mut counters[2]).chunks(188) {
+            stream.extend_from_slice(packet);
+            stream.extend_from_slice(packet);
+        }
+    }
+
+    let mut ts_reader = TsReader::new(stream.as_slice(), None);
+    let mut es = Vec::new();
+    ts_reader.read_to_end(&mut es).unwrap();
+
+    assert_eq!(es, expected);
+}
+
+#[test]
+fn transport_stream_extensions() {
+    let dir = std::env::temp_dir().join("dovi_tool_input_formats");
+    fs::create_dir_all(&dir).unwrap();
+
+    let formats = [
+        ("movie.ts", Ok(Format::TransportStream)),
+        ("movie.m2ts", Ok(Format::TransportStream)),
+        ("movie.hevc.ts", Ok(Format::TransportStream)),
+        ("movie.hevc", Ok(Format::Raw)),
+        ("movie.hevc.parts", Ok(Format::Raw)),
+        ("movie.mkv", Ok(Format::Matroska)),
+        ("movie.its", Err("Invalid input file type.")),
+    ];
+
+    for (name, format) in formats {
+        let input = dir.join(name);
+        fs::write(&input, []).unwrap();
+
+        assert_eq!(input_format(&input), format, "{}", name);
+    }
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn batch_demux() {
+    let mut stream = Vec::new();
+    for name in &["fel_orig.bin", "fel_rpu.bin"] {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&_read_asset(name));
+    }
+
+    let dir = std::env::temp_dir().join("dovi_tool_batch");
+    let input = std::env::temp_dir().join("dovi_tool_batch_input.hevc");
+    let missing = std::env::temp_dir().join("dovi_tool_batch_missing.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let inputs = vec![missing, input.clone()];
+    let results = Batch::process(
+        inputs,
+        BatchAction::Demux,
+        Some(dir.clone()),
+        RpuOptions::default(),
+    );
+
+    // The missing input doesn't stop the batch
+    assert_eq!(results.len(), 2);
+    assert!(results[0].1.is_err());
+    assert_eq!(results[1].1.as_ref().unwrap().frames, 2);
+
+    let el_out = dir.join("dovi_tool_batch_input_EL.hevc");
+    assert_eq!(fs::read(&el_out).unwrap(), stream);
+    assert!(dir.join("dovi_tool_batch_input_BL.hevc").is_file());
+
+    fs::remove_file(&input).ok();
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn measurements_export() {
+    let mut rpus: Vec<_> = [
+        "fel_orig.bin",
+        "mel_orig.bin",
+        "profile8.bin",
+        "profile8.bin",
+    ]
+    .iter()
+    .map(|asset| parse_dovi_rpu(&_read_asset(asset), false).unwrap())
+    .collect();
+
+    // No L1, the previous frame's values are repeated
+    rpus[3].strip_dm_metadata();
+
+    let expected = fs::read_to_string("./assets/measurements.txt").unwrap();
+    assert_eq!(measurements_text(&rpus), expected);
+
+    // Same output from the export command
+    let input = std::env::temp_dir().join("dovi_tool_measurements.bin");
+    let output = std::env::temp_dir().join("dovi_tool_measurements.txt");
+    write_rpu_file(&input, &mut rpus).unwrap();
+
+    Exporter::export(
+        input.clone(),
+        Some(output.clone()),
+        None,
+        false,
+        ExportKind::Measurements,
+        false,
+    );
+    assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+
+    fs::remove_file(&input).ok();
+    fs::remove_file(&output).ok();
+}
+
+#[test]
+fn concat_rpu_files() {
+    let parts = [
+        vec!["fel_orig.bin", "mel_orig.bin"],
+        vec!["fel_to_mel.bin", "fel_to_81.bin", "profile8.bin"],
+        vec!["profile5.bin"],
+    ];
+
+    let inputs: Vec<PathBuf> = parts
+        .iter()
+        .enumerate()
+        .map(|(index, assets)| {
+            let mut rpus: Vec<_> = assets
+                .iter()
+                .map(|asset| parse_dovi_rpu(&_read_asset(asset), false).unwrap())
+                .collect();
+
+            let input = std::env::temp_dir().join(format!("dovi_tool_concat_{}.bin", index));
+            write_rpu_file(&input, &mut rpus).unwrap();
+
+            input
+        })
+        .collect();
+
+    let rpu_out = std::env::temp_dir().join("dovi_tool_concat_RPU.bin");
+
+    for renumber_ids in [None, Some(0)] {
+        // The profile 5 input after profile 7 ones only warns
+        let count = concat_rpus(&inputs, &rpu_out, renumber_ids, false).unwrap();
+        assert_eq!(count, parts.iter().map(Vec::len).sum::<usize>());
+
+        let rpus = parse_rpu_file(&rpu_out, false).unwrap();
+        assert_eq!(rpus.len(), count);
+        assert_eq!(rpus[count - 1].dovi_profile, 5);
+    }
+
+    assert!(concat_rpus(&inputs, &rpu_out, Some(16), false).is_err());
+}
+
+#[test]
+fn bl_runs_copied_identically() {
+    let rpu = _read_asset("fel_orig.bin");
+
+    // VPS, TRAIL_R slices, the second one with trailing zero bytes
+    let vps = [0x40, 0x01, 0x0C, 0x01];
+    let slice = [0x02, 0x01, 0xAF, 0x12, 0x34];
+    let padded_slice = [0x02, 0x01, 0xAF, 0x56, 0x78, 0x00, 0x00];
+    let prefix_sei = [0x4E, 0x01, 0x89, 0x02, 0x11, 0x80];
+
+    let mut stream = Vec::new();
+
+    for frame in 0..4 {
+        let start_code: &[u8] = if frame % 2 == 0 {
+            OUT_NAL_HEADER
+        } else {
+            &[0, 0, 1]
+        };
+
+        for nal in [&vps[..], &slice, &padded_slice, &prefix_sei, &slice] {
+            stream.extend_from_slice(start_code);
+            stream.extend_from_slice(nal);
+        }
+
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&rpu);
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_bl_runs.hevc");
+    let bl_out = std::env::temp_dir().join("dovi_tool_bl_runs_BL.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_bl_runs_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let demux_bl = |verbose: bool, chunk_size: usize| {
+        let options = RpuOptions {
+            verbose,
+            ..Default::default()
+        };
+
+        let mut dovi_reader = DoviReader::new(options);
+        dovi_reader.set_chunk_size(chunk_size);
+
+        let mut dovi_writer = DoviWriter::new(Some(&bl_out), Some(&el_out), None, None);
+        let summary = dovi_reader
+            .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+            .unwrap();
+
+        assert_eq!(summary.frames, 4);
+
+        fs::read(&bl_out).unwrap()
+    };
+
+    // The NALs are written one at a time when verbose
+    let expected_bl = demux_bl(true, 100_000);
+    assert_eq!(
+        expected_bl
+            .windows(4)
+            .filter(|w| w == &OUT_NAL_HEADER)
+            .count(),
+        20
+    );
+
+    for chunk_size in [100_000, 7, 16] {
+        assert_eq!(demux_bl(false, chunk_size), expected_bl);
+    }
+}
+
+/// Large single layer stream: each frame made of slices and an RPU
+fn _large_single_layer_stream(frames: usize, slices: usize, slice_size: usize) -> Vec<u8> {
+    let rpu = _read_asset("profile8.bin");
+
+    let mut slice = vec![0x02, 0x01, 0xAF];
+    slice.resize(slice_size, 0x5A);
+
+    let mut stream = Vec::with_capacity(frames * (slices * (slice_size + 4) + rpu.len() + 4));
+
+    for _ in 0..frames {
+        for _ in 0..slices {
+            stream.extend_from_slice(OUT_NAL_HEADER);
+            stream.extend_from_slice(&slice);
+        }
+
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&rpu);
+    }
+
+    stream
+}
+
+/// Best of 3 runs of the demuxing of `input` by the reader from `new_reader`, in seconds
+fn _time_demux(input: &Path, new_reader: impl Fn() -> DoviReader) -> f64 {
+    let bl_out = std::env::temp_dir().join("dovi_tool_bench_BL.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_bench_EL.hevc");
+
+    let time = (0..3)
+        .map(|_| {
+            let start = std::time::Instant::now();
+
+            let mut dovi_reader = new_reader();
+            let mut dovi_writer = DoviWriter::new(Some(&bl_out), Some(&el_out), None, None);
+            dovi_reader
+                .read_write_from_io(&Format::Raw, input, None, &mut dovi_writer)
+                .unwrap();
+            drop(dovi_writer);
+
+            start.elapsed().as_secs_f64()
+        })
+        .fold(f64::INFINITY, f64::min);
+
+    fs::remove_file(&bl_out).ok();
+    fs::remove_file(&el_out).ok();
+
+    time
+}
+
+/// Compares copying the runs of BL NALs at once to writing them one at a time.
+/// `cargo test --release bench_bl_runs -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_bl_runs() {
+    let input = std::env::temp_dir().join("dovi_tool_bench_bl_runs.hevc");
+
+    // Small slices, where the writes per NAL cost the most
+    for (slices, slice_size) in [(16, 256), (4, 4096)] {
+        let stream = _large_single_layer_stream(50_000, slices, slice_size);
+        fs::write(&input, &stream).unwrap();
+
+        let megabytes = stream.len() as f64 / 1e6;
+        let demux = |verbose| {
+            _time_demux(&input, || {
+                DoviReader::new(RpuOptions {
+                    verbose,
+                    ..Default::default()
+                })
+            })
+        };
+
+        let per_nal = demux(true);
+        let runs = demux(false);
+
+        println!(
+            "{} MB, {} slices of {} bytes per frame: per NAL {:.0} MB/s, runs {:.0} MB/s, {:.2}x",
+            megabytes.round(),
+            slices,
+            slice_size,
+            megabytes / per_nal,
+            megabytes / runs,
+            per_nal / runs
+        );
+    }
+
+    fs::remove_file(&input).ok();
+}
+
+/// Compares the memory mapped input to the buffered one.
+/// `cargo test --release --features mmap bench_mapped_input -- --ignored --nocapture`
+#[cfg(feature = "mmap")]
+#[test]
+#[ignore]
+fn bench_mapped_input() {
+    let input = std::env::temp_dir().join("dovi_tool_bench_mapped.hevc");
+
+    let stream = _large_single_layer_stream(50_000, 4, 4096);
+    fs::write(&input, &stream).unwrap();
+
+    let demux = |mapped| {
+        _time_demux(&input, || {
+            let mut dovi_reader = DoviReader::new(RpuOptions::default());
+            dovi_reader.set_mapped(mapped);
+
+            dovi_reader
+        })
+    };
+
+    let megabytes = stream.len() as f64 / 1e6;
+    let buffered = demux(false);
+    let mapped = demux(true);
+
+    println!(
+        "{} MB: buffered {:.0} MB/s, mapped {:.0} MB/s, {:.2}x",
+        megabytes.round(),
+        megabytes / buffered,
+        megabytes / mapped,
+        buffered / mapped
+    );
+
+    fs::remove_file(&input).ok();
+}
+
+/// NAL with its start code, from the RBSP bits written by `write_rbsp`
+fn _hevc_nal(nal_type: u8, write_rbsp: impl Fn(&mut BitVecWriter)) -> Vec<u8> {
+    let mut writer = BitVecWriter::new();
+
+    // forbidden_zero_bit, nal_unit_type, nuh_layer_id, nuh_temporal_id_plus1
+    writer.write(false);
+    writer.write_n(&nal_type.to_be_bytes(), 6);
+    writer.write_n(&0_u8.to_be_bytes(), 6);
+    writer.write_n(&1_u8.to_be_bytes(), 3);
+
+    write_rbsp(&mut writer);
+
+    // rbsp_trailing_bits
+    writer.write(true);
+    while !writer.is_aligned() {
+        writer.write(false);
+    }
+
+    let mut data = writer.as_slice().to_vec();
+    add_start_code_emulation_prevention_3_byte(&mut data);
+
+    [OUT_NAL_HEADER, &data].concat()
+}
+
+/// Main 10 profile_tier_level, level 5.1
+fn _write_profile_tier_level(writer: &mut BitVecWriter) {
+    writer.write_n(&2_u8.to_be_bytes(), 8);
+    writer.write_n(&0x2000_0000_u32.to_be_bytes(), 32);
+    writer.write_n(&0xB_u8.to_be_bytes(), 4);
+    writer.write_n(&0_u64.to_be_bytes(), 44);
+    writer.write_n(&153_u8.to_be_bytes(), 8);
+}
+
+/// Minimal 64x64 HEVC stream, parseable by the HEVC parser.
+/// Every frame is a single slice, `true` for an IDR frame with parameter sets, a P frame otherwise.
+fn _hevc_stream(frames: &[bool], with_auds: bool) -> Vec<u8> {
+    let vps = _hevc_nal(NAL_VPS, |w| {
+        // vps_video_parameter_set_id, vps_reserved_three_2bits, vps_max_layers_minus1,
+        // vps_max_sub_layers_minus1, vps_temporal_id_nesting_flag
+        w.write_n(&0x0C01_u16.to_be_bytes(), 16);
+        w.write_n(&0xFFFF_u16.to_be_bytes(), 16);
+        _write_profile_tier_level(w);
+
+        // vps_sub_layer_ordering_info_present_flag, ordering info
+        w.write(true);
+        w.write_ue(4);
+        w.write_ue(0);
+        w.write_ue(0);
+
+        // vps_max_layer_id, vps_num_layer_sets_minus1
+        w.write_n(&0_u8.to_be_bytes(), 6);
+        w.write_ue(0);
+
+        // vps_timing_info_present_flag, vps_extension_flag
+        w.write(false);
+        w.write(false);
+    });
+
+    let sps = _hevc_nal(NAL_SPS, |w| {
+        // sps_video_parameter_set_id, sps_max_sub_layers_minus1, sps_temporal_id_nesting_flag
+        w.write_n(&1_u8.to_be_bytes(), 8);
+        _write_profile_tier_level(w);
+
+        // sps_seq_parameter_set_id, chroma_format_idc, width, height
+        for value in [0, 1, 64, 64] {
+            w.write_ue(value);
+        }
+
+        // conformance_window_flag
+        w.write(false);
+
+        // Bit depths, log2_max_pic_order_cnt_lsb_minus4
+        for value in [2, 2, 4] {
+            w.write_ue(value);
+        }
+
+        // sps_sub_layer_ordering_info_present_flag, ordering info
+        w.write(true);
+        for value in [4, 0, 0] {
+            w.write_ue(value);
+        }
+
+        // Coding and transform block sizes, transform hierarchy depths
+        for value in [0, 3, 0, 3, 0, 0] {
+            w.write_ue(value);
+        }
+
+        // scaling_list_enabled_flag, amp_enabled_flag, sample_adaptive_offset_enabled_flag, pcm_enabled_flag
+        for _ in 0..4 {
+            w.write(false);
+        }
+
+        // num_short_term_ref_pic_sets
+        w.write_ue(0);
+
+        // long_term_ref_pics_present_flag, sps_temporal_mvp_enabled_flag,
+        // strong_intra_smoothing_enabled_flag, vui_parameters_present_flag, sps_extension_present_flag
+        for _ in 0..5 {
+            w.write(false);
+        }
+    });
+
+    let pps = _hevc_nal(NAL_PPS, |w| {
+        // pps_pic_parameter_set_id, pps_seq_parameter_set_id
+        w.write_ue(0);
+        w.write_ue(0);
+
+        // dependent_slice_segments_enabled_flag, output_flag_present_flag, num_extra_slice_header_bits,
+        // sign_data_hiding_enabled_flag, cabac_init_present_flag
+        w.write_n(&0_u8.to_be_bytes(), 7);
+
+        // num_ref_idx_l0/l1_default_active_minus1, init_qp_minus26
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_se(0);
+
+        // constrained_intra_pred_flag, transform_skip_enabled_flag, cu_qp_delta_enabled_flag
+        w.write_n(&0_u8.to_be_bytes(), 3);
+
+        // diff_cu_qp_delta_depth, pps_cb_qp_offset, pps_cr_qp_offset
+        w.write_ue(0);
+        w.write_se(0);
+        w.write_se(0);
+
+        // Chroma QP offsets, weighted prediction, transquant bypass, tiles, entropy coding sync,
+        // loop filter across slices, deblocking control, scaling list, lists modification flags
+        w.write_n(&0_u16.to_be_bytes(), 10);
+
+        // log2_parallel_merge_level_minus2
+        w.write_ue(0);
+
+        // slice_segment_header_extension_present_flag, pps_extension_present_flag
+        w.write(false);
+        w.write(false);
+    });
+
+    let mut stream = Vec::new();
+
+    for (index, &idr) in frames.iter().enumerate() {
+        if with_auds {
+            let pic_type: u8 = if idr { 0 } else { 1 };
+            stream.extend(_hevc_nal(NAL_AUD, |w| {
+                w.write_n(&pic_type.to_be_bytes(), 3)
+            }));
+        }
+
+        let nal_type = if idr {
+            stream.extend_from_slice(&vps);
+            stream.extend_from_slice(&sps);
+            stream.extend_from_slice(&pps);
+
+            NAL_IDR_W_RADL
+        } else {
+            NAL_TRAIL_R
+        };
+
+        stream.extend(_hevc_nal(nal_type, |w| {
+            // first_slice_segment_in_pic_flag
+            w.write(true);
+
+            if idr {
+                // no_output_of_prior_pics_flag
+                w.write(false);
+            }
+
+            // slice_pic_parameter_set_id, slice_type I or P
+            w.write_ue(0);
+            w.write_ue(if idr { 2 } else { 1 });
+
+            if !idr {
+                // slice_pic_order_cnt_lsb
+                w.write_n(&(index as u8).to_be_bytes(), 8);
+            }
+
+            // Dummy slice data
+            w.write_n(&0xA5A5_u16.to_be_bytes(), 16);
+        }));
+    }
+
+    stream
+}
+
+/// NALs of an Annex B stream, without their start code
+fn _split_nals(data: &[u8]) -> Vec<&[u8]> {
+    let mut parser = HevcParser::default();
+    let mut offsets = Vec::new();
+
+    parser.get_offsets(data, &mut offsets);
+
+    let last = *offsets.last().unwrap();
+
+    parser
+        .split_nals(data, &offsets, last, false)
+        .iter()
+        .map(|nal| &data[nal.start..nal.end])
+        .collect()
+}
+
+/// Injects the RPU assets in a stream, one per frame, returning the output
+fn _inject_rpus(name: &str, stream: &[u8], headers: HeaderNals) -> Vec<u8> {
+    let frames = _split_nals(stream)
+        .iter()
+        .filter(|nal| nal[0] >> 1 < 32)
+        .count();
+
+    _inject_rpu_count(name, stream, headers, frames, FrameCountMismatch::Error).unwrap()
+}
+
+fn _inject_rpu_count(
+    name: &str,
+    stream: &[u8],
+    headers: HeaderNals,
+    rpu_count: usize,
+    mismatch: FrameCountMismatch,
+) -> Result<Vec<u8>, String> {
+    let mut rpus: Vec<_> = (0..rpu_count)
+        .map(|_| parse_dovi_rpu(&_read_asset("profile8.bin"), false).unwrap())
+        .collect();
+
+    let input = std::env::temp_dir().join(format!("dovi_tool_{}.hevc", name));
+    let rpu_in = std::env::temp_dir().join(format!("dovi_tool_{}_RPU.bin", name));
+    let output = std::env::temp_dir().join(format!("dovi_tool_{}_injected.hevc", name));
+
+    fs::write(&input, stream).unwrap();
+    write_rpu_file(&rpu_in, &mut rpus).unwrap();
+
+    let mut injector = RpuInjector::new(input, rpu_in, output.clone(), false);
+    injector.set_headers(headers);
+    injector.set_mismatch(mismatch);
+    injector.inject()?;
+
+    Ok(fs::read(&output).unwrap())
+}
+
+#[test]
+fn inject_preserves_parameter_sets() {
+    let header_nals = |data: &[u8]| -> Vec<Vec<u8>> {
+        _split_nals(data)
+            .into_iter()
+            .filter(|nal| (NAL_VPS..=NAL_AUD).contains(&(nal[0] >> 1)))
+            .map(<[u8]>::to_vec)
+            .collect()
+    };
+
+    let stream = _hevc_stream(&[true, false, false, true, false], true);
+    let output = _inject_rpus("preserve_headers", &stream, HeaderNals::Preserve);
+
+    let nals = _split_nals(&output);
+    assert_eq!(
+        nals.iter().filter(|nal| nal[..2] == [0x7C, 0x01]).count(),
+        5
+    );
+
+    // AUDs, VPS, SPS and PPS in the order of the source
+    assert_eq!(header_nals(&output), header_nals(&stream));
+
+    // Every access unit starts with its AUD, after the previous RPU
+    for (index, nal) in nals.iter().enumerate().skip(1) {
+        if nal[0] >> 1 == NAL_AUD {
+            assert_eq!(nals[index - 1][0] >> 1, NAL_UNSPEC62);
+        }
+    }
+}
+
+#[test]
+fn inject_regenerates_headers() {
+    // Parameter sets only before the first IDR frame
+    let mut stream = _hevc_stream(&[true, false, false], false);
+    let second_gop = _hevc_stream(&[true, false], false);
+
+    for nal in &_split_nals(&second_gop)[3..] {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(nal);
+    }
+
+    let source_nals = _split_nals(&stream);
+    let output = _inject_rpus("regenerate_headers", &stream, HeaderNals::Regenerate);
+    let nals = _split_nals(&output);
+
+    let types: Vec<u8> = nals.iter().map(|nal| nal[0] >> 1).collect();
+    let (aud, vps, sps, pps) = (NAL_AUD, NAL_VPS, NAL_SPS, NAL_PPS);
+    let (idr, p, rpu) = (NAL_IDR_W_RADL, NAL_TRAIL_R, NAL_UNSPEC62);
+
+    #[rustfmt::skip]
+    assert_eq!(
+        types,
+        vec![
+            aud, vps, sps, pps, idr, rpu,
+            aud, p, rpu,
+            aud, p, rpu,
+            aud, vps, sps, pps, idr, rpu,
+            aud, p, rpu,
+        ]
+    );
+
+    // Repeated from the first access unit
+    assert_eq!(nals[13..16], source_nals[..3]);
+
+    // I and P frames
+    assert_eq!(nals[0], [0x46, 0x01, 0x10]);
+    assert_eq!(nals[6], [0x46, 0x01, 0x30]);
+}
+
+#[test]
+fn inject_frame_count_mismatch() {
+    let stream = _hevc_stream(&[true, false, false, true, false], true);
+    let rpu_count = |data: &[u8]| {
+        _split_nals(data)
+            .iter()
+            .filter(|nal| nal[..2] == [0x7C, 0x01])
+            .count()
+    };
+
+    let error = _inject_rpu_count(
+        "mismatch_error",
+        &stream,
+        HeaderNals::Preserve,
+        4,
+        FrameCountMismatch::Error,
+    );
+    assert_eq!(
+        error,
+        Err(
+            "Input has 5 frames and the RPU file 4 RPUs, frame 4 is the first without an RPU"
+                .to_string()
+        )
+    );
+
+    // Fewer RPUs: the input is cut after the 3rd frame
+    let output = _inject_rpu_count(
+        "mismatch_short_rpus",
+        &stream,
+        HeaderNals::Preserve,
+        3,
+        FrameCountMismatch::Truncate,
+    )
+    .unwrap();
+
+    let nals = _split_nals(&output);
+    assert_eq!(rpu_count(&output), 3);
+    assert_eq!(nals.iter().filter(|nal| nal[0] >> 1 < 32).count(), 3);
+    assert_eq!(nals.last().unwrap()[0] >> 1, NAL_UNSPEC62);
+
+    // More RPUs: the extra ones are dropped
+    let output = _inject_rpu_count(
+        "mismatch_long_rpus",
+        &stream,
+        HeaderNals::Preserve,
+        7,
+        FrameCountMismatch::Truncate,
+    )
+    .unwrap();
+
+    assert_eq!(rpu_count(&output), 5);
+    assert_eq!(
+        _split_nals(&output)
+            .iter()
+            .filter(|nal| nal[..2] != [0x7C, 0x01])
+            .count(),
+        _split_nals(&stream).len()
+    );
+}
+
+#[test]
+fn first_scene_cut() {
+    let rpus = [
+        _read_asset("fel_orig.bin"),
+        _read_asset("mel_orig.bin"),
+        _read_asset("fel_rpu.bin"),
+        _read_asset("profile8.bin"),
+    ];
+
+    let mut stream = Vec::new();
+    for rpu in &rpus {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(rpu);
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_first_scene_cut.hevc");
+    let el_out = std::env::temp_dir().join("dovi_tool_first_scene_cut_EL.hevc");
+    fs::write(&input, &stream).unwrap();
+
+    let demux_el = |clear_scene_cuts: bool| {
+        let options = RpuOptions {
+            mode: Some(0),
+            first_scene_cut: true,
+            clear_scene_cuts,
+            ..Default::default()
+        };
+
+        let mut dovi_reader = DoviReader::new(options);
+        let mut dovi_writer = DoviWriter::new(None, Some(&el_out), None, None);
+
+        dovi_reader
+            .read_write_from_io(&Format::Raw, &input, None, &mut dovi_writer)
+            .unwrap();
+
+        let el = fs::read(&el_out).unwrap();
+
+        let nals = _split_nals(&el);
+        let scene_cuts: Vec<bool> = nals
+            .iter()
+            .map(|nal| {
+                let rpu = parse_dovi_rpu(nal, false).unwrap();
+                rpu.vdr_dm_data.unwrap().is_scene_cut()
+            })
+            .collect();
+
+        (
+            scene_cuts,
+            nals.iter().map(|nal| nal.to_vec()).collect::<Vec<_>>(),
+        )
+    };
+
+    // The first frame has no scene cut, the others are untouched
+    let (flags, nals) = demux_el(false);
+    assert_eq!(flags, vec![true, true, false, true]);
+    assert_ne!(nals[0], rpus[0]);
+    assert_eq!(nals[1..], rpus[1..]);
+
+    let (flags, _) = demux_el(true);
+    assert_eq!(flags, vec![true, false, false, false]);
+}
+
+#[test]
+fn active_area_presets() {
+    let input = std::env::temp_dir().join("dovi_tool_active_area.bin");
+    let config = std::env::temp_dir().join("dovi_tool_active_area.json");
+    let rpu_out = std::env::temp_dir().join("dovi_tool_active_area_modified.bin");
+
+    let mut rpus = vec![parse_dovi_rpu(&_read_asset("profile8.bin"), false).unwrap()];
+    write_rpu_file(&input, &mut rpus).unwrap();
+
+    fs::write(
+        &config,
+        r#"{
+            "active_area": {
+                "presets": [{ "id": 0, "left": 0, "right": 0, "top": 280, "bottom": 280 }],
+                "edits": { "0-0": 0 }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    Editor::edit(input.clone(), config.clone(), Some(rpu_out.clone()), false);
+
+    let mut rpus = parse_rpu_file(&rpu_out, false).unwrap();
+    let block = ExtMetadataBlockLevel5::get_mut(&mut rpus[0]).unwrap();
+    assert_eq!(block._get_offsets(), vec![0, 0, 280, 280]);
+
+    fs::remove_file(input).unwrap();
+    fs::remove_file(config).unwrap();
+    fs::remove_file(rpu_out).unwrap();
+}
+
+#[test]
+fn edit_rpus_keeping_raw_bytes() {
+    let assets = [
+        "fel_orig.bin",
+        "mel_orig.bin",
+        "fel_rpu.bin",
+        "profile8.bin",
+    ];
+
+    let mut data = Vec::new();
+    for asset in assets {
+        RpuPacking::AnnexB
+            .write(&mut data, &_read_asset(asset)[2..])
+            .unwrap();
+    }
+
+    // Only the L5 of the frames with an active area is changed
+    let mut output = Vec::new();
+    let mut edited = Vec::new();
+
+    for (frame, rpu, raw) in iter_rpus(&data, false) {
+        let mut rpu = rpu.unwrap();
+        assert_eq!(raw, &_read_asset(assets[frame])[2..]);
+
+        let before = serde_json::to_value(&rpu).unwrap();
+
+        if let Some(block) = ExtMetadataBlockLevel5::get_mut(&mut rpu) {
+            block.set_offsets(0, 0, 280, 280).unwrap();
+            rpu.modified = true;
+            edited.push(frame);
+
+            let changes = rpu_changes(&before, &rpu);
+            assert!(changes.iter().all(|change| change.contains("active_area")));
+
+            RpuPacking::AnnexB
+                .write(&mut output, &rpu.write_rpu_data()[2..])
+                .unwrap();
+        } else {
+            RpuPacking::AnnexB.write(&mut output, raw).unwrap();
+        }
+    }
+
+    assert_eq!(edited, vec![0, 2, 3]);
+
+    let nals = split_rpu_file(&output);
+    assert_eq!(nals.len(), assets.len());
+
+    for (frame, nal) in nals.iter().enumerate() {
+        let original = _read_asset(assets[frame]);
+
+        if edited.contains(&frame) {
+            let mut rpu = parse_dovi_rpu(nal, false).unwrap();
+            let block = ExtMetadataBlockLevel5::get_mut(&mut rpu).unwrap();
+            assert_eq!(block._get_offsets(), vec![0, 0, 280, 280]);
+        } else {
+            assert_eq!(nal[2..], original[2..]);
+        }
+    }
+}
+
+/// In-memory async reader returning a few bytes per read, pending every other read
+#[cfg(feature = "async")]
+struct _SlowReader<'a> {
+    data: &'a [u8],
+    pending: bool,
+}
+
+#[cfg(feature = "async")]
+impl tokio::io::AsyncRead for _SlowReader<'_> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.pending = !self.pending;
+
+        if self.pending {
+            cx.waker().wake_by_ref();
+            return std::task::Poll::Pending;
+        }
+
+        let count = self.data.len().min(buf.remaining()).min(7);
+        buf.put_slice(&self.data[..count]);
+        self.data = &self.data[count..];
+
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_extract_rpus() {
+    use futures_core::Stream;
+    use std::task::{Context, Poll, Waker};
+
+    use super::async_extractor::AsyncRpuExtractor;
+
+    let assets = ["fel_orig.bin", "mel_orig.bin", "profile8.bin"];
+    let mut stream = Vec::new();
+
+    for asset in &assets {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&[0x02, 0x01, 0xAF, 0x12, 0x34]);
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&_read_asset(asset));
+    }
+
+    let reader = _SlowReader {
+        data: &stream,
+        pending: false,
+    };
+
+    let mut extractor = AsyncRpuExtractor::new(reader, false);
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut rpus = Vec::new();
+
+    loop {
+        match std::pin::Pin::new(&mut extractor).poll_next(&mut cx) {
+            Poll::Ready(Some(item)) => rpus.push(item.unwrap()),
+            Poll::Ready(None) => break,
+            Poll::Pending => (),
+        }
+    }
+
+    assert_eq!(rpus.len(), assets.len());
+
+    for ((frame, rpu), (index, asset)) in rpus.into_iter().zip(assets.iter().enumerate()) {
+        let mut expected = parse_dovi_rpu(&_read_asset(asset), false).unwrap();
+
+        assert_eq!(frame, index);
+        assert_eq!(rpu.unwrap().write_rpu_data(), expected.write_rpu_data());
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn stream_extracted_rpus() {
+    use super::rpu_extractor::RpuExtractor;
+
+    let assets = ["fel_orig.bin", "mel_orig.bin", "profile8.bin"];
+    let mut stream = Vec::new();
+
+    for asset in &assets {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&[0x02, 0x01, 0xAF, 0x12, 0x34]);
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&_read_asset(asset));
+    }
+
+    let input = std::env::temp_dir().join("dovi_tool_stream.hevc");
+    let rpu_out = std::env::temp_dir().join("dovi_tool_stream_RPU.bin");
+    fs::write(&input, &stream).unwrap();
+
+    RpuExtractor::stream_rpus(
+        Some(input.clone()),
+        None,
+        Some(rpu_out.clone()),
+        RpuPacking::AnnexB,
+        false,
+    );
+
+    let data = fs::read(&rpu_out).unwrap();
+    let nals = split_rpu_file(&data);
+    assert_eq!(nals.len(), assets.len());
+
+    for (nal, asset) in nals.iter().zip(&assets) {
+        assert_eq!(nal[2..], _read_asset(asset)[2..]);
+    }
+
+    fs::remove_file(input).unwrap();
+    fs::remove_file(rpu_out).unwrap();
+}
+
+fn _mp4_box(kind: &[u8; 4], data: Vec<u8>, children: Vec<Mp4Box>) -> Mp4Box {
+    Mp4Box {
+        kind: *kind,
+        data,
+        children,
+    }
+}
+
+/// Full box with the entry count followed by the entries
+fn _mp4_table(kind: &[u8; 4], fields: &[u32], entries: &[&[u32]]) -> Mp4Box {
+    let mut data = vec![0; 4];
+    let mut values = fields.to_vec();
+    values.push(entries.len() as u32);
+    values.extend(entries.iter().flat_map(|e| e.iter()));
+
+    values
+        .iter()
+        .for_each(|v| data.extend_from_slice(&v.to_be_bytes()));
+
+    _mp4_box(kind, data, Vec::new())
+}
+
+fn _mp4_trak(sample_entry: Mp4Box, tables: Vec<Mp4Box>) -> Mp4Box {
+    // Timescale 24000
+    let mut mdhd = vec![0; 12];
+    mdhd.extend_from_slice(&24000_u32.to_be_bytes());
+    mdhd.extend_from_slice(&[0; 8]);
+
+    let stsd = _mp4_box(b"stsd", vec![0, 0, 0, 0, 0, 0, 0, 1], vec![sample_entry]);
+    let stbl = _mp4_box(b"stbl", Vec::new(), [vec![stsd], tables].concat());
+
+    let minf = _mp4_box(b"minf", Vec::new(), vec![stbl]);
+    let mdia = _mp4_box(
+        b"mdia",
+        Vec::new(),
+        vec![_mp4_box(b"mdhd", mdhd, Vec::new()), minf],
+    );
+
+    _mp4_box(b"trak", Vec::new(), vec![mdia])
+}
+
+#[test]
+fn inject_mp4() {
+    // I, P, B decoding order, displayed I, B, P
+    let video_samples: [&[u8]; 3] = [
+        &[0, 0, 0, 3, 0x26, 0x01, 0xAF, 0, 0, 0, 3, 0x7C, 0x01, 0xFF],
+        &[0, 0, 0, 3, 0x02, 0x01, 0xD0],
+        &[0, 0, 0, 4, 0x02, 0x01, 0xE0, 0x10],
+    ];
+    let audio_samples: [&[u8]; 2] = [&[0xA0; 6], &[0xA1; 5]];
+
+    let mut hvc1 = vec![0; 78];
+    hvc1[24..26].copy_from_slice(&1920_u16.to_be_bytes());
+    hvc1[26..28].copy_from_slice(&1080_u16.to_be_bytes());
+
+    // lengthSizeMinusOne 3
+    let mut hvcc = vec![0; 23];
+    hvcc[21] = 0x03;
+
+    let ftyp = _mp4_box(b"ftyp", b"isom\0\0\0\0isomhvc1".to_vec(), Vec::new());
+    let mdat_offset = 8 + 16 + 8;
+
+    let mut moov = _mp4_box(b"moov", Vec::new(), Vec::new());
+    let video_size: usize = video_samples.iter().map(|s| s.len()).sum();
+
+    for _ in 0..2 {
+        let moov_size = moov.size() as u32;
+        let video_offset = mdat_offset + moov_size;
+        let audio_offset = video_offset + video_size as u32;
+
+        let video = _mp4_trak(
+            _mp4_box(
+                b"hvc1",
+                hvc1.clone(),
+                vec![_mp4_box(b"hvcC", hvcc.clone(), Vec::new())],
+            ),
+            vec![
+                _mp4_table(b"stts", &[], &[&[3, 1001]]),
+                _mp4_table(b"ctts", &[], &[&[1, 1001], &[1, 2002], &[1, 0]]),
+                _mp4_table(b"stsc", &[], &[&[1, 3, 1]]),
+                _mp4_table(b"stsz", &[0], &[&[14], &[7], &[8]]),
+                _mp4_table(b"stco", &[], &[&[video_offset]]),
+            ],
+        );
+        let audio = _mp4_trak(
+            _mp4_box(b"mp4a", vec![0; 28], Vec::new()),
+            vec![
+                _mp4_table(b"stts", &[], &[&[2, 1024]]),
+                _mp4_table(b"stsc", &[], &[&[1, 2, 1]]),
+                _mp4_table(b"stsz", &[0], &[&[6], &[5]]),
+                _mp4_table(b"stco", &[], &[&[audio_offset]]),
+            ],
+        );
+
+        moov.children = vec![video, audio];
+    }
+
+    let mut data = Vec::new();
+    ftyp.write(&mut data);
+    moov.write(&mut data);
+
+    let mdat: Vec<u8> = video_samples
+        .concat()
+        .into_iter()
+        .chain(audio_samples.concat())
+        .collect();
+    data.extend_from_slice(&(mdat.len() as u32 + 8).to_be_bytes());
+    data.extend_from_slice(b"mdat");
+    data.extend_from_slice(&mdat);
+
+    // User data after the media data
+    let udta = _mp4_box(b"udta", b"\0\0\0\x0ccprtdovi".to_vec(), Vec::new());
+    udta.write(&mut data);
+
+    let input = std::env::temp_dir().join("dovi_tool_inject.mp4");
+    let output = std::env::temp_dir().join("dovi_tool_inject_DV.mp4");
+    fs::write(&input, &data).unwrap();
+
+    // vdr_rpu_id set to the display index
+    let mut rpus: Vec<DoviRpu> = (0..3)
+        .map(|index| {
+            let mut rpu = parse_dovi_rpu(&_read_asset("profile8.bin"), false).unwrap();
+            rpu.header.vdr_rpu_id = index;
+            rpu.modified = true;
+
+            rpu
+        })
+        .collect();
+
+    Mp4Injector::new(input, output.clone())
+        .inject(&mut rpus)
+        .unwrap();
+
+    let injected = fs::read(&output).unwrap();
+    let boxes = parse_boxes(&injected).unwrap();
+    let moov = boxes.iter().find(|b| &b.kind == b"moov").unwrap();
+
+    // Other top level boxes copied before the new mdat
+    let kinds: Vec<&[u8; 4]> = boxes.iter().map(|b| &b.kind).collect();
+    assert_eq!(kinds, [b"ftyp", b"moov", b"udta", b"mdat"]);
+    assert_eq!(boxes[2].data, udta.data);
+
+    let stbl = |track: usize| {
+        moov.children[track]
+            .find(&[b"mdia", b"minf", b"stbl"])
+            .unwrap()
+    };
+    let u32_at = |data: &[u8], offset: usize| {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+    };
+
+    // dvvC: profile 8, level 3 (1080p24), RPU and BL present, 8.1
+    let entry = &stbl(0).child(b"stsd").unwrap().children[0];
+    let dvvc = entry.child(b"dvvC").unwrap();
+    let flags = u16::from_be_bytes(dvvc.data[2..4].try_into().unwrap());
+
+    assert_eq!(dvvc.data.len(), 24);
+    assert_eq!(&dvvc.data[..2], &[1, 0]);
+    assert_eq!(flags >> 9, 8);
+    assert_eq!((flags >> 3) & 0x3F, 3);
+    assert_eq!(flags & 0x7, 0b101);
+    assert_eq!(dvvc.data[4] >> 4, 1);
+
+    let video_offset = u32_at(&stbl(0).child(b"stco").unwrap().data, 8);
+    let sizes: Vec<usize> = (0..3)
+        .map(|i| u32_at(&stbl(0).child(b"stsz").unwrap().data, 12 + i * 4))
+        .collect();
+
+    let mut offset = video_offset;
+    for (sample, (size, display_index)) in sizes.iter().zip([0, 2, 1]).enumerate() {
+        let sample_data = &injected[offset..offset + size];
+
+        // Slice NAL kept, previous RPU replaced
+        let slice_size = video_samples[sample][..4]
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | b as usize)
+            + 4;
+        assert_eq!(
+            &sample_data[..slice_size],
+            &video_samples[sample][..slice_size]
+        );
+
+        let rpu_nal = &sample_data[slice_size + 4..];
+        assert_eq!(u32_at(sample_data, slice_size), rpu_nal.len());
+        assert_eq!(&rpu_nal[..2], &[0x7C, 0x01]);
+
+        let rpu = parse_dovi_rpu(rpu_nal, false).unwrap();
+        assert_eq!(rpu.dovi_profile, 8);
+        assert_eq!(rpu.header.vdr_rpu_id, display_index);
+
+        offset += size;
+    }
+
+    // Audio track copied after the video chunk
+    let audio_offset = u32_at(&stbl(1).child(b"stco").unwrap().data, 8);
+    assert_eq!(audio_offset, offset);
+    assert_eq!(
+        &injected[audio_offset..audio_offset + 11],
+        &audio_samples.concat()[..]
+    );
+}
+
+#[test]
+fn nal_unit_type_forbidden_bit() {
+    assert_eq!(nal_unit_type(0x7C), NAL_UNSPEC62);
+    assert_eq!(nal_unit_type(0x40), NAL_VPS);
+    // forbidden_zero_bit set
+    assert_eq!(nal_unit_type(0xFC), NAL_UNSPEC62);
+    assert_eq!(nal_unit_type(0xC0), NAL_VPS);
+    // First bit of nuh_layer_id set
+    assert_eq!(nal_unit_type(0x7D), NAL_UNSPEC62);
+
+    let mut stream = Vec::new();
+    for header in [[0x40, 0x01], [0xFC, 0x01], [0x7C, 0x01]] {
+        stream.extend_from_slice(OUT_NAL_HEADER);
+        stream.extend_from_slice(&header);
+        stream.extend_from_slice(&[0x12, 0x34]);
+    }
+
+    let mut parser = HevcParser::default();
+    let mut offsets = Vec::new();
+    parser.get_offsets(&stream, &mut offsets);
+
+    let last = *offsets.last().unwrap();
+    let types: Vec<u8> = split_nals(&mut parser, &stream, &offsets, last, false)
+        .iter()
+        .map(|nal| nal.nal_type)
+        .collect();
+
+    assert_eq!(types, [NAL_VPS, NAL_UNSPEC62, NAL_UNSPEC62]);
+}
+
+#[test]
+fn parallel_parsing_order() {
+    let assets = [
+        "fel_orig.bin",
+        "mel_orig.bin",
+        "profile8.bin",
+        "profile5.bin",
+    ];
+    let mut rng_state = 0x9E37_79B9_7F4A_7C15_u64;
+
+    // Several batches per thread, with invalid RPUs in between
+    let mut data = Vec::new();
+    for frame in 0..1200 {
+        rng_state ^= rng_state << 13;
+        rng_state ^= rng_state >> 7;
+        rng_state ^= rng_state << 17;
+
+        let mut payload = _read_asset(assets[rng_state as usize % assets.len()])[2..].to_vec();
+
+        if frame % 301 == 0 {
+            // Corrupts the CRC32
+            let crc_index = payload.len() - 2;
+            payload[crc_index] ^= 0xFF;
+        }
+
+        RpuPacking::AnnexB.write(&mut data, &payload).unwrap();
+    }
+
+    let serialize =
+        |parsed: Vec<(usize, Result<DoviRpu, String>)>| -> Vec<(usize, Result<Vec<u8>, String>)> {
+            parsed
+                .into_iter()
+                .map(|(frame, rpu)| (frame, rpu.map(|mut rpu| rpu.write_rpu_data())))
+                .collect()
+        };
+
+    let serial = serialize(
+        iter_rpus(&data, false)
+            .map(|(frame, rpu, _)| (frame, rpu))
+            .collect(),
+    );
+
+    assert_eq!(serial.len(), 1200);
+    assert_eq!(serial.iter().filter(|(_, rpu)| rpu.is_err()).count(), 4);
+
+    for threads in [1, 2, 7] {
+        let parallel = serialize(par_parse_rpu_nals(split_rpu_file(&data), false, threads));
+        assert!(parallel == serial, "{} threads", threads);
+    }
+}
@@ -0,0 +1,72 @@
+/// SMPTE timecode formatting of frame indices
+#[derive(Debug)]
+pub struct Timecode {
+    /// Frames per timecode second, the frame rate rounded
+    timebase: usize,
+    /// Frame numbers skipped every minute, except every tenth minute
+    dropped_frames: usize,
+}
+
+impl Timecode {
+    /// `drop_frame` is only valid for 29.97 and 59.94 fps.
+    /// Other non-integer rates, like 23.976, use non-drop timecodes.
+    pub fn new(frame_rate: f64, drop_frame: bool) -> Result<Timecode, String> {
+        if !frame_rate.is_finite() || frame_rate <= 0.0 {
+            return Err(format!("Invalid frame rate: {}", frame_rate));
+        }
+
+        let timebase = frame_rate.round() as usize;
+
+        let dropped_frames = if drop_frame {
+            let ntsc_rate = timebase as f64 * 1000.0 / 1001.0;
+
+            if !(timebase == 30 || timebase == 60) || (frame_rate - ntsc_rate).abs() > 0.01 {
+                return Err(format!(
+                    "Drop frame timecodes require a 29.97 or 59.94 frame rate, found {}",
+                    frame_rate
+                ));
+            }
+
+            timebase / 15
+        } else {
+            0
+        };
+
+        Ok(Timecode {
+            timebase,
+            dropped_frames,
+        })
+    }
+
+    /// Formats as HH:MM:SS:FF, or HH:MM:SS;FF for drop frame timecodes
+    pub fn format(&self, frame: usize) -> String {
+        let mut frame_number = frame;
+
+        if self.dropped_frames > 0 {
+            let frames_per_minute = self.timebase * 60 - self.dropped_frames;
+            let frames_per_10_minutes = frames_per_minute * 10 + self.dropped_frames;
+
+            let tens = frame / frames_per_10_minutes;
+            let remainder = frame % frames_per_10_minutes;
+
+            frame_number += self.dropped_frames * 9 * tens;
+
+            if remainder > self.dropped_frames {
+                frame_number +=
+                    self.dropped_frames * ((remainder - self.dropped_frames) / frames_per_minute);
+            }
+        }
+
+        let frames = frame_number % self.timebase;
+        let seconds = (frame_number / self.timebase) % 60;
+        let minutes = (frame_number / (self.timebase * 60)) % 60;
+        let hours = frame_number / (self.timebase * 3600);
+
+        let separator = if self.dropped_frames > 0 { ';' } else { ':' };
+
+        format!(
+            "{:02}:{:02}:{:02}{}{:02}",
+            hours, minutes, seconds, separator, frames
+        )
+    }
+}
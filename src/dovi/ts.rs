@@ -0,0 +1,406 @@
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+use ansi_term::Colour::Yellow;
+
+use super::rpu::{profile_from_config, DoviProfile};
+
+const TS_PACKET_SIZE: usize = 188;
+/// M2TS packets are prefixed with a 4 bytes timestamp
+const M2TS_PACKET_SIZE: usize = 192;
+
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+const STREAM_TYPE_HEVC: u8 = 0x24;
+const DOVI_VIDEO_STREAM_DESCRIPTOR: u8 = 0xB0;
+
+/// Dolby Vision configuration signaled by the container
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DoviConfig {
+    pub profile: u8,
+    pub level: u8,
+    pub rpu_present: bool,
+    pub el_present: bool,
+    pub bl_present: bool,
+    pub bl_compat_id: Option<u8>,
+}
+
+/// Dolby Vision configuration of the selected stream, known once its PMT is parsed
+pub type SharedDoviConfig = Rc<Cell<Option<DoviConfig>>>;
+
+/// Reads the HEVC elementary stream of an MPEG transport stream.
+///
+/// The HEVC PID is the first one found in the PMTs, unless set.
+/// Only the PES payloads are kept, the NALs are split by the HEVC parser as usual.
+pub struct TsReader<R: Read> {
+    reader: R,
+    packet_size: usize,
+    packet: Vec<u8>,
+
+    pmt_pids: Vec<u16>,
+    hevc_pid: Option<u16>,
+    /// PMT of the HEVC stream parsed
+    pmt_parsed: bool,
+    dovi_config: SharedDoviConfig,
+
+    continuity_counter: Option<u8>,
+    pes_started: bool,
+
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> TsReader<R> {
+    pub fn new(reader: R, pid: Option<u16>) -> TsReader<R> {
+        TsReader {
+            reader,
+            packet_size: 0,
+            packet: vec![0; M2TS_PACKET_SIZE],
+            pmt_pids: Vec::new(),
+            hevc_pid: pid,
+            pmt_parsed: false,
+            dovi_config: SharedDoviConfig::default(),
+            continuity_counter: None,
+            pes_started: false,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Handle to the Dolby Vision configuration of the HEVC stream, from the PMT
+    pub fn dovi_config(&self) -> SharedDoviConfig {
+        Rc::clone(&self.dovi_config)
+    }
+
+    /// Reads the next packet, returns false at the end of the stream
+    fn read_packet(&mut self) -> io::Result<bool> {
+        // The packet size is detected from the first sync byte
+        if self.packet_size == 0 {
+            if !self.read_exact_or_eof(0, TS_PACKET_SIZE)? {
+                return Ok(false);
+            }
+
+            self.packet_size = if self.packet[0] == SYNC_BYTE {
+                TS_PACKET_SIZE
+            } else if self.packet[4] == SYNC_BYTE {
+                if !self.read_exact_or_eof(TS_PACKET_SIZE, M2TS_PACKET_SIZE)? {
+                    return Ok(false);
+                }
+
+                M2TS_PACKET_SIZE
+            } else {
+                return Err(invalid_data("Not a transport stream: no sync byte found"));
+            };
+        } else if !self.read_exact_or_eof(0, self.packet_size)? {
+            return Ok(false);
+        }
+
+        let start = self.packet_size - TS_PACKET_SIZE;
+        let packet = std::mem::take(&mut self.packet);
+
+        let result = self.parse_packet(&packet[start..start + TS_PACKET_SIZE]);
+        self.packet = packet;
+
+        result.map(|_| true)
+    }
+
+    fn read_exact_or_eof(&mut self, from: usize, to: usize) -> io::Result<bool> {
+        match self.reader.read_exact(&mut self.packet[from..to]) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        if packet[0] != SYNC_BYTE {
+            return Err(invalid_data("Lost transport stream sync"));
+        }
+
+        let payload_unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1F, packet[2]]);
+        let adaptation_field_control = (packet[3] >> 4) & 0x3;
+        let continuity_counter = packet[3] & 0xF;
+
+        let has_adaptation_field = adaptation_field_control & 0x2 != 0;
+        let has_payload = adaptation_field_control & 0x1 != 0;
+
+        if !has_payload {
+            return Ok(());
+        }
+
+        let (payload_start, discontinuity_indicator) = if has_adaptation_field {
+            let length = packet[4] as usize;
+            (5 + length, length > 0 && packet[5] & 0x80 != 0)
+        } else {
+            (4, false)
+        };
+
+        if payload_start >= TS_PACKET_SIZE {
+            return Ok(());
+        }
+
+        let payload = &packet[payload_start..];
+
+        if Some(pid) == self.hevc_pid {
+            // Duplicate packets are discarded, their payload was already read
+            if !self.check_continuity(pid, continuity_counter, discontinuity_indicator) {
+                self.read_pes_payload(payload, payload_unit_start);
+            }
+        } else if !self.pmt_parsed && payload_unit_start {
+            if pid == PAT_PID {
+                self.parse_pat(section(payload));
+            } else if self.pmt_pids.contains(&pid) {
+                self.parse_pmt(section(payload));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_pat(&mut self, section: &[u8]) {
+        // Program number and PMT PID pairs, after the 8 bytes header
+        for entry in section_data(section, 8).chunks_exact(4) {
+            let program_number = u16::from_be_bytes([entry[0], entry[1]]);
+            let pid = u16::from_be_bytes([entry[2] & 0x1F, entry[3]]);
+
+            // Program 0 is the network PID
+            if program_number != 0 && !self.pmt_pids.contains(&pid) {
+                self.pmt_pids.push(pid);
+            }
+        }
+    }
+
+    fn parse_pmt(&mut self, section: &[u8]) {
+        if section.len() < 12 {
+            return;
+        }
+
+        let program_info_length = u16::from_be_bytes([section[10] & 0xF, section[11]]) as usize;
+        let mut streams = section_data(section, 12 + program_info_length);
+
+        while streams.len() >= 5 {
+            let stream_type = streams[0];
+            let pid = u16::from_be_bytes([streams[1] & 0x1F, streams[2]]);
+            let es_info_length = u16::from_be_bytes([streams[3] & 0xF, streams[4]]) as usize;
+
+            let descriptors = streams.get(5..5 + es_info_length).unwrap_or_default();
+
+            let selected = match self.hevc_pid {
+                Some(hevc_pid) => pid == hevc_pid,
+                None => stream_type == STREAM_TYPE_HEVC,
+            };
+
+            if selected {
+                if self.hevc_pid.is_none() {
+                    println!("Using the HEVC stream of PID {:#x}", pid);
+                }
+
+                self.hevc_pid = Some(pid);
+                self.pmt_parsed = true;
+                self.dovi_config.set(find_dovi_config(descriptors));
+
+                if let Some(config) = self.dovi_config.get() {
+                    println!("Dolby Vision configuration: {}", config);
+                }
+
+                return;
+            }
+
+            streams = streams.get(5 + es_info_length..).unwrap_or_default();
+        }
+    }
+
+    /// Warns about discontinuities, returning whether the packet is a duplicate of the previous one
+    fn check_continuity(&mut self, pid: u16, continuity_counter: u8, discontinuity: bool) -> bool {
+        let mut duplicate = false;
+
+        if let Some(previous) = self.continuity_counter {
+            let expected = (previous + 1) & 0xF;
+
+            // A duplicate packet repeats the counter
+            duplicate = !discontinuity && continuity_counter == previous;
+
+            if !discontinuity && !duplicate && continuity_counter != expected {
+                println!(
+                    "{}",
+                    Yellow.paint(format!(
+                        "Warning: discontinuity in PID {:#x}, expected counter {}, found {}",
+                        pid, expected, continuity_counter
+                    ))
+                );
+            }
+        }
+
+        self.continuity_counter = Some(continuity_counter);
+
+        duplicate
+    }
+
+    fn read_pes_payload(&mut self, payload: &[u8], payload_unit_start: bool) {
+        if payload_unit_start {
+            // packet_start_code_prefix, stream_id, PES_packet_length, flags, PES_header_data_length
+            if payload.len() < 9 || payload[..3] != [0, 0, 1] {
+                self.pes_started = false;
+                return;
+            }
+
+            let data_start = 9 + payload[8] as usize;
+
+            self.pes_started = true;
+            self.buffer
+                .extend_from_slice(payload.get(data_start..).unwrap_or_default());
+        } else if self.pes_started {
+            self.buffer.extend_from_slice(payload);
+        }
+    }
+}
+
+impl<R: Read> Read for TsReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+
+        // Fill the whole buffer, like a file, a partial read is the end of the stream
+        while read < buf.len() {
+            if self.position == self.buffer.len() {
+                self.buffer.clear();
+                self.position = 0;
+
+                if !self.read_packet()? {
+                    if self.hevc_pid.is_none() {
+                        return Err(invalid_data("No HEVC stream found in the transport stream"));
+                    }
+
+                    break;
+                }
+
+                continue;
+            }
+
+            let count = (buf.len() - read).min(self.buffer.len() - self.position);
+
+            buf[read..read + count]
+                .copy_from_slice(&self.buffer[self.position..self.position + count]);
+
+            read += count;
+            self.position += count;
+        }
+
+        Ok(read)
+    }
+}
+
+impl DoviConfig {
+    /// Parses the body of a DOVI_video_stream_descriptor, or of a dvcC configuration record
+    pub fn parse(data: &[u8]) -> Option<DoviConfig> {
+        if data.len() < 4 {
+            return None;
+        }
+
+        // dv_version_major, dv_version_minor, then dv_profile (7 bits), dv_level (6 bits) and the flags
+        let mut config = DoviConfig {
+            profile: data[2] >> 1,
+            level: ((data[2] & 0x1) << 5) | (data[3] >> 3),
+            rpu_present: data[3] & 0x4 != 0,
+            el_present: data[3] & 0x2 != 0,
+            bl_present: data[3] & 0x1 != 0,
+            bl_compat_id: None,
+        };
+
+        // dependency_pid when the BL is in another stream
+        let compat_index = if config.bl_present { 4 } else { 6 };
+        config.bl_compat_id = data.get(compat_index).map(|b| b >> 4);
+
+        Some(config)
+    }
+
+    /// Named profile of the configuration, see `profile_from_config`
+    pub fn dovi_profile(&self) -> DoviProfile {
+        profile_from_config(self.profile, self.level, self.bl_compat_id)
+    }
+
+    /// Warning when the RPU profile isn't the profile of the configuration.
+    /// For profile 8, the variant detected from the RPU is checked against the compatibility id too.
+    pub fn check_rpu_profile(&self, rpu_profile: u8, rpu_compat_id: Option<u8>) -> Option<String> {
+        if self.profile != rpu_profile {
+            return Some(format!(
+                "Dolby Vision configuration claims profile {}, the RPUs are profile {}",
+                self.profile, rpu_profile
+            ));
+        }
+
+        match (self.bl_compat_id, rpu_compat_id) {
+            (Some(bl_compat_id), Some(rpu_compat_id))
+                if self.profile == 8 && bl_compat_id != rpu_compat_id =>
+            {
+                Some(format!(
+                    "Dolby Vision configuration claims profile 8.{}, the RPUs are profile 8.{}",
+                    bl_compat_id, rpu_compat_id
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for DoviConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "profile {}, level {}", self.dovi_profile(), self.level)?;
+
+        if let Some(bl_compat_id) = self.bl_compat_id {
+            write!(f, ", dv_bl_signal_compatibility_id {}", bl_compat_id)?;
+        }
+
+        let layers: Vec<&str> = [
+            (self.bl_present, "BL"),
+            (self.el_present, "EL"),
+            (self.rpu_present, "RPU"),
+        ]
+        .iter()
+        .filter(|(present, _)| *present)
+        .map(|(_, layer)| *layer)
+        .collect();
+
+        write!(f, ", {}", layers.join("+"))
+    }
+}
+
+/// DOVI_video_stream_descriptor among the ES descriptors
+fn find_dovi_config(mut descriptors: &[u8]) -> Option<DoviConfig> {
+    while descriptors.len() >= 2 {
+        let (tag, length) = (descriptors[0], descriptors[1] as usize);
+        let data = descriptors.get(2..2 + length)?;
+
+        if tag == DOVI_VIDEO_STREAM_DESCRIPTOR {
+            return DoviConfig::parse(data);
+        }
+
+        descriptors = &descriptors[2 + length..];
+    }
+
+    None
+}
+
+/// PSI section of a payload starting with a pointer_field
+fn section(payload: &[u8]) -> &[u8] {
+    let pointer_field = payload[0] as usize;
+
+    payload.get(1 + pointer_field..).unwrap_or_default()
+}
+
+/// Section data from `start`, without the CRC32
+fn section_data(section: &[u8], start: usize) -> &[u8] {
+    if section.len() < 3 {
+        return &[];
+    }
+
+    let section_length = u16::from_be_bytes([section[1] & 0xF, section[2]]) as usize;
+    let end = (3 + section_length).saturating_sub(4).min(section.len());
+
+    section.get(start..end).unwrap_or_default()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
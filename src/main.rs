@@ -1,5 +1,5 @@
 use regex::Regex;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 use bitvec_helpers::{bitvec_reader, bitvec_writer};
@@ -9,8 +9,19 @@ use commands::Command;
 
 mod dovi;
 use dovi::{
-    converter::Converter, demuxer::Demuxer, editor::Editor, rpu_extractor::RpuExtractor,
-    rpu_info::RpuInfo, rpu_injector::RpuInjector, Format, RpuOptions,
+    batch::Batch,
+    concatenator::Concatenator,
+    converter::Converter,
+    demuxer::Demuxer,
+    editor::Editor,
+    exporter::{ExportKind, Exporter},
+    importer::Importer,
+    merger::Merger,
+    mp4_injector::Mp4Injector,
+    rpu_extractor::RpuExtractor,
+    rpu_info::{InfoOptions, RpuInfo},
+    rpu_injector::RpuInjector,
+    Format, L2TrimField, RpuOptions,
 };
 
 #[derive(StructOpt, Debug)]
@@ -20,7 +31,7 @@ struct Opt {
         short = "m",
         long,
         help = "Sets the mode for RPU processing. --help for more info",
-        long_help = "Sets the mode for RPU processing.\nMode 1: Converts the RPU to be MEL compatible\nMode 2: Converts the RPU to be profile 8.1 compatible"
+        long_help = "Sets the mode for RPU processing.\nMode 1: Converts the RPU to be MEL compatible\nMode 2: Converts the RPU to be profile 8.1 compatible\nMode 3: Sets the EL residual to identity, keeping the NLQ offsets (soft MEL)"
     )]
     mode: Option<u8>,
 
@@ -31,6 +42,178 @@ struct Opt {
     )]
     crop: bool,
 
+    #[structopt(
+        long,
+        conflicts_with_all = &["crop", "adjust-active-area"],
+        help = "Removes the L5 active area metadata, the whole frame is then active"
+    )]
+    clear_active_area: bool,
+
+    #[structopt(
+        long,
+        allow_hyphen_values = true,
+        parse(try_from_str = parse_active_area_deltas),
+        help = "Shifts the active area offsets by signed deltas, as left,right,top,bottom"
+    )]
+    adjust_active_area: Option<[i32; 4]>,
+
+    #[structopt(
+        long,
+        help = "Keeps parsing RPUs that fail validation, printing the failures as warnings"
+    )]
+    lenient: bool,
+
+    #[structopt(long, help = "Removes the DM metadata, leaving mapping only RPUs")]
+    strip_dm: bool,
+
+    #[structopt(
+        long,
+        conflicts_with = "strip-dm",
+        help = "Makes CM v2.9 DM metadata CM v4.0, adding L3, L9, L11 and L254 blocks with default values"
+    )]
+    cmv40: bool,
+
+    #[structopt(
+        long,
+        help = "Lowers the L1 max_pq of every frame above this luminance, in nits"
+    )]
+    clamp_l1_max: Option<f64>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_l1_fixed),
+        help = "Sets the L1 of every frame to fixed min,avg,max luminances, in nits, inserting the block when absent"
+    )]
+    set_l1_fixed: Option<(f64, f64, f64)>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_light_levels),
+        help = "Inserts a L6 block with these MaxCLL,MaxFALL values, in nits, in the frames lacking one"
+    )]
+    insert_l6: Option<(u16, u16)>,
+
+    #[structopt(
+        long,
+        requires = "insert-l6",
+        help = "Replaces the existing L6 blocks too"
+    )]
+    overwrite_l6: bool,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_source_diagonal),
+        help = "Sets the source_diagonal of every frame, in inches, or clamps it to a min,max range"
+    )]
+    source_diagonal: Option<(u16, u16)>,
+
+    #[structopt(
+        long,
+        help = "Sets the L9 source_primary_index of every frame, inserting the block when absent"
+    )]
+    source_primaries: Option<u8>,
+
+    #[structopt(long, help = "Sets the L10 target_primary_index of every frame")]
+    target_primaries: Option<u8>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_scale_factor),
+        help = "Scales the L2 trims of every frame around their neutral value by this factor"
+    )]
+    scale_l2_trims: Option<f64>,
+
+    #[structopt(
+        long,
+        requires = "scale-l2-trims",
+        require_delimiter = true,
+        possible_values = &["slope", "offset", "power"],
+        help = "L2 trims scaled by --scale-l2-trims, comma separated: slope, offset, power. Defaults to all three"
+    )]
+    scale_l2_fields: Vec<L2TrimField>,
+
+    #[structopt(
+        long,
+        help = "Sets scene_refresh_flag on the first frame, for RPUs without an initial scene cut"
+    )]
+    first_scene_cut: bool,
+
+    #[structopt(long, help = "Clears scene_refresh_flag on every frame but the first")]
+    clear_scene_cuts: bool,
+
+    #[structopt(
+        long,
+        possible_values = &["1", "2", "4"],
+        help = "Intended dv_bl_signal_compatibility_id of the profile 8 output. --help for more info",
+        long_help = "Intended dv_bl_signal_compatibility_id of the profile 8 output, for the Dolby Vision configuration record when muxing.\n1: HDR10 compatible base layer (8.1)\n2: SDR compatible base layer (8.2)\n4: HLG compatible base layer (8.4)"
+    )]
+    bl_compat_id: Option<u8>,
+
+    #[structopt(
+        long,
+        help = "Rescales the mapping and NLQ coefficients to this coefficient_log2_denom"
+    )]
+    coefficient_log2_denom: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Renumbers the vdr_rpu_id and DM metadata ids from this id, in order of appearance"
+    )]
+    renumber_ids: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Pads every RPU NAL to this length in bytes, with zero bytes after the RPU"
+    )]
+    pad_rpu: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Repairs the RPUs lacking the 0x80 terminator byte after the CRC32, instead of skipping them"
+    )]
+    repair_terminator: bool,
+
+    #[structopt(
+        long,
+        help = "Replaces the DM metadata of every frame by a JSON vdr_dm_data template, keeping the L1 metadata",
+        parse(from_os_str)
+    )]
+    dm_template: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        requires = "dm-template",
+        help = "Replaces the L1 metadata by the template's too"
+    )]
+    dm_template_l1: bool,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_pid),
+        help = "HEVC PID of a transport stream input, in decimal or 0x hexadecimal. Defaults to the first HEVC stream"
+    )]
+    pid: Option<u16>,
+
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Ignores this many bytes at the start of the input, e.g. a capture header before the HEVC stream"
+    )]
+    skip_bytes: usize,
+
+    #[structopt(
+        long,
+        help = "Prints the RPU fields changed by the mode and edits, for each range of frames"
+    )]
+    change_log: bool,
+
+    #[structopt(
+        short = "v",
+        long,
+        help = "Logs the type, offset, size and destination of every NAL, at the debug level"
+    )]
+    verbose: bool,
+
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -38,10 +221,75 @@ struct Opt {
 fn main() {
     let opt = Opt::from_args();
 
+    // RUST_LOG sets the level of the other modules
+    let mut logger = env_logger::Builder::from_default_env();
+    if opt.verbose {
+        logger.filter_module("dovi_tool", log::LevelFilter::Debug);
+    }
+    logger.format_timestamp(None).init();
+
+    // Editing the RPUs requires parsing them
+    let mode = if opt.crop
+        || opt.clear_active_area
+        || opt.adjust_active_area.is_some()
+        || opt.strip_dm
+        || opt.cmv40
+        || opt.clamp_l1_max.is_some()
+        || opt.set_l1_fixed.is_some()
+        || opt.insert_l6.is_some()
+        || opt.source_diagonal.is_some()
+        || opt.scale_l2_trims.is_some()
+        || opt.source_primaries.is_some()
+        || opt.target_primaries.is_some()
+        || opt.first_scene_cut
+        || opt.clear_scene_cuts
+        || opt.bl_compat_id.is_some()
+        || opt.coefficient_log2_denom.is_some()
+        || opt.renumber_ids.is_some()
+        || opt.pad_rpu.is_some()
+        || opt.repair_terminator
+        || opt.dm_template.is_some()
+    {
+        opt.mode.or(Some(0))
+    } else {
+        opt.mode
+    };
+
     let mut rpu_options = RpuOptions {
-        mode: opt.mode,
+        mode,
         crop: opt.crop,
+        clear_active_area: opt.clear_active_area,
+        strip_dm: opt.strip_dm,
+        cmv40: opt.cmv40,
+        active_area_deltas: opt.adjust_active_area,
+        clamp_l1_max: opt.clamp_l1_max,
+        l1_fixed: opt.set_l1_fixed,
+        insert_l6: opt.insert_l6,
+        overwrite_l6: opt.overwrite_l6,
+        source_diagonal: opt.source_diagonal,
+        source_primaries: opt.source_primaries,
+        target_primaries: opt.target_primaries,
+        scale_l2_trims: opt.scale_l2_trims,
+        scale_l2_fields: if opt.scale_l2_fields.is_empty() {
+            vec![L2TrimField::Slope, L2TrimField::Offset, L2TrimField::Power]
+        } else {
+            opt.scale_l2_fields
+        },
+        first_scene_cut: opt.first_scene_cut,
+        clear_scene_cuts: opt.clear_scene_cuts,
+        bl_compat_id: opt.bl_compat_id,
+        coefficient_log2_denom: opt.coefficient_log2_denom,
+        renumber_ids: opt.renumber_ids,
+        pad_rpu: opt.pad_rpu,
+        repair_terminator: opt.repair_terminator,
+        dm_template: opt.dm_template,
+        dm_template_l1: opt.dm_template_l1,
+        pid: opt.pid,
+        skip_bytes: opt.skip_bytes,
         discard_el: false,
+        lenient: opt.lenient,
+        change_log: opt.change_log,
+        verbose: opt.verbose,
     };
 
     match opt.cmd {
@@ -50,12 +298,13 @@ fn main() {
             stdin,
             bl_out,
             el_out,
-        } => Demuxer::demux(input, stdin, bl_out, el_out, rpu_options),
+            sei_out,
+        } => Demuxer::demux(input, stdin, bl_out, el_out, sei_out, rpu_options),
         Command::Editor {
             input,
             json_file,
             rpu_out,
-        } => Editor::edit(input, json_file, rpu_out),
+        } => Editor::edit(input, json_file, rpu_out, opt.lenient),
         Command::Convert {
             input,
             stdin,
@@ -65,17 +314,108 @@ fn main() {
             rpu_options.discard_el = discard;
             Converter::convert(input, stdin, output, rpu_options)
         }
+        #[cfg(feature = "async")]
+        Command::ExtractRpu {
+            input,
+            stdin,
+            rpu_out,
+            packing,
+            stream: true,
+            ..
+        } => RpuExtractor::stream_rpus(input, stdin, rpu_out, packing, opt.lenient),
         Command::ExtractRpu {
             input,
             stdin,
             rpu_out,
-        } => RpuExtractor::extract_rpu(input, stdin, rpu_out, rpu_options),
+            packing,
+            frame,
+            ..
+        } => RpuExtractor::extract_rpu(input, stdin, rpu_out, packing, frame, rpu_options),
+        Command::Batch {
+            inputs,
+            action,
+            output_dir,
+            discard,
+        } => {
+            rpu_options.discard_el = discard;
+            Batch::process(inputs, action, output_dir, rpu_options);
+        }
         Command::InjectRpu {
             input,
             rpu_in,
             output,
-        } => RpuInjector::inject_rpu(input, rpu_in, output),
-        Command::Info { input, frame } => RpuInfo::info(input, frame),
+            headers,
+            mismatch,
+        } => RpuInjector::inject_rpu(input, rpu_in, output, headers, mismatch, opt.lenient),
+        Command::InjectMp4 {
+            input,
+            rpu_in,
+            output,
+        } => Mp4Injector::inject_mp4(input, rpu_in, output, opt.lenient),
+        Command::Info {
+            input,
+            frame,
+            interpolate_l2,
+            mapping,
+            coefficients,
+            lut,
+            l2_targets,
+            duplicates,
+            mapping_summary,
+            bit_size,
+            aspect_ratio,
+            missing_blocks,
+            terminators,
+        } => {
+            let info_options = InfoOptions {
+                interpolate_l2,
+                mapping,
+                coefficients,
+                lut,
+                l2_targets,
+                duplicates,
+                mapping_summary,
+                bit_size,
+                aspect_ratio,
+                missing_blocks,
+                terminators,
+            };
+
+            RpuInfo::info(input, frame, info_options, opt.lenient)
+        }
+        Command::Export {
+            input,
+            output,
+            fps,
+            drop_frame,
+            measurements,
+            msgpack,
+            changes_only,
+            stats,
+        } => {
+            let kind = if measurements {
+                ExportKind::Measurements
+            } else if msgpack {
+                ExportKind::Msgpack
+            } else if changes_only {
+                ExportKind::Changes
+            } else if stats {
+                ExportKind::Stats
+            } else {
+                ExportKind::Json
+            };
+
+            Exporter::export(input, output, fps, drop_frame, kind, opt.lenient)
+        }
+        Command::Import { input, rpu_out } => Importer::import(input, rpu_out, opt.lenient),
+        Command::Concat { inputs, rpu_out } => {
+            Concatenator::concat(inputs, rpu_out, opt.renumber_ids, opt.lenient)
+        }
+        Command::Merge {
+            mapping,
+            dm,
+            rpu_out,
+        } => Merger::merge(mapping, dm, rpu_out, opt.lenient),
     }
 }
 
@@ -86,10 +426,17 @@ pub fn input_format(input: &Path) -> Result<Format, &str> {
         None => "",
     };
 
+    let transport_stream = matches!(
+        input.extension().and_then(|extension| extension.to_str()),
+        Some("ts") | Some("m2ts")
+    );
+
     if file_name == "-" {
         Ok(Format::RawStdin)
-    } else if regex.is_match(file_name) && input.is_file() {
-        if file_name.contains("mkv") {
+    } else if (regex.is_match(file_name) || transport_stream) && input.is_file() {
+        if transport_stream {
+            Ok(Format::TransportStream)
+        } else if file_name.contains("mkv") {
             Ok(Format::Matroska)
         } else {
             Ok(Format::Raw)
@@ -102,3 +449,115 @@ pub fn input_format(input: &Path) -> Result<Format, &str> {
         Err("Invalid input file type.")
     }
 }
+
+fn parse_pid(pid: &str) -> Result<u16, String> {
+    let result = match pid.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => pid.parse(),
+    };
+
+    match result {
+        Ok(pid) if pid <= 0x1FFF => Ok(pid),
+        _ => Err(format!("Invalid PID: {}", pid)),
+    }
+}
+
+fn parse_resolution(resolution: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("Invalid resolution, expected WIDTHxHEIGHT: {}", resolution);
+
+    let (width, height) = resolution.split_once(['x', 'X']).ok_or_else(invalid)?;
+
+    match (width.trim().parse(), height.trim().parse()) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_scale_factor(factor: &str) -> Result<f64, String> {
+    match factor.parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor >= 0.0 => Ok(factor),
+        _ => Err(format!("Invalid scale factor: {}", factor)),
+    }
+}
+
+/// `min,avg,max` luminances, in nits
+fn parse_l1_fixed(luminances: &str) -> Result<(f64, f64, f64), String> {
+    let values: Vec<f64> = luminances
+        .split(',')
+        .map(|value| value.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid L1 luminance: {}", e))?;
+
+    let (min, avg, max) = match values.as_slice() {
+        [min, avg, max] => (*min, *avg, *max),
+        _ => return Err("Expected min,avg,max luminances".to_string()),
+    };
+
+    if !(0.0..=10_000.0).contains(&min) || !(0.0..=10_000.0).contains(&max) {
+        Err(format!(
+            "Invalid L1 luminances {},{},{}, from 0 to 10000 nits",
+            min, avg, max
+        ))
+    } else if min > avg || avg > max {
+        Err(format!(
+            "Invalid L1 luminances {},{},{}, expected min <= avg <= max",
+            min, avg, max
+        ))
+    } else {
+        Ok((min, avg, max))
+    }
+}
+
+/// `MaxCLL,MaxFALL`, in nits
+fn parse_light_levels(light_levels: &str) -> Result<(u16, u16), String> {
+    let values: Vec<u16> = light_levels
+        .split(',')
+        .map(|value| value.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid light level: {}", e))?;
+
+    match values.as_slice() {
+        [max_cll, max_fall] if max_fall <= max_cll => Ok((*max_cll, *max_fall)),
+        [max_cll, max_fall] => Err(format!(
+            "Invalid light levels {},{}, MaxFALL is above MaxCLL",
+            max_cll, max_fall
+        )),
+        _ => Err("Expected MaxCLL,MaxFALL light levels".to_string()),
+    }
+}
+
+/// A diagonal sets it, a `min,max` range clamps it
+fn parse_source_diagonal(diagonal: &str) -> Result<(u16, u16), String> {
+    let bounds: Vec<u16> = diagonal
+        .split(',')
+        .map(|bound| bound.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid source diagonal: {}", e))?;
+
+    let (min, max) = match bounds.as_slice() {
+        [diagonal] => (*diagonal, *diagonal),
+        [min, max] => (*min, *max),
+        _ => return Err("Expected a diagonal or a min,max range".to_string()),
+    };
+
+    if max > 1023 {
+        Err(format!("Invalid source diagonal {}, at most 1023", max))
+    } else if min > max {
+        Err(format!("Invalid source diagonal range {},{}", min, max))
+    } else {
+        Ok((min, max))
+    }
+}
+
+fn parse_active_area_deltas(deltas: &str) -> Result<[i32; 4], String> {
+    let deltas: Vec<i32> = deltas
+        .split(',')
+        .map(|delta| delta.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid active area delta: {}", e))?;
+
+    match deltas.as_slice() {
+        [left, right, top, bottom] => Ok([*left, *right, *top, *bottom]),
+        _ => Err("Expected 4 deltas: left,right,top,bottom".to_string()),
+    }
+}